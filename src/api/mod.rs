@@ -2,10 +2,10 @@
 // In production, use axum or warp
 
 use axum::{Router, routing::get, routing::post, response::IntoResponse, http::StatusCode, extract::Extension, Json, body::Bytes};
-use rand::RngCore;
-use axum::extract::{Multipart, Query};
+use axum::extract::{ConnectInfo, Multipart, Path, Query};
 use uuid::Uuid;
 use std::sync::Arc;
+use std::net::SocketAddr;
 use crate::storage::Storage;
 use crate::crypto::encrypt_file;
 use std::fs::File;
@@ -13,16 +13,24 @@ use std::io::{Write, Seek, SeekFrom, Read};
 use crate::crypto::decrypt_file;
 use crate::ai::get_recommendations;
 use crate::peer::P2PNode;
-use crate::crypto::{generate_x25519_keypair, encrypt_and_save_keypair, load_and_decrypt_keypair};
-use crate::models::User;
+use crate::peer::erasure;
+use crate::crypto::{wrap_file_key, unwrap_file_key, SecretKey, SecretString};
+use crate::user_management::{UserStore, UserStoreError};
+use crate::auth::{AuthSession, AuthError, SecondFactor, SessionStore};
+use crate::mfa::MfaStore;
+use crate::devices::{self, DeviceError, DeviceRegistry};
+use crate::links::{self, LinkError, LinkOptions, LinkStore, ShareLink};
+use crate::confidential;
+use crate::policy::{self, FilePermission, Permission, Policy, PolicyError, Role};
+use crate::audit::{self, AuditFilter, AuditLog};
+use crate::ratelimit::{rate_limit, too_many_requests, LoginGuard, RateLimiter};
 use std::collections::HashMap;
-use std::sync::Mutex;
 use axum::http::HeaderMap;
 use std::fs::{self, OpenOptions};
 use crate::ai::{train_local_model, aggregate_remote_model, NCFModel};
-use tower_http::cors::{CorsLayer, Any};
-
-pub static USER_DB: once_cell::sync::Lazy<Mutex<HashMap<String, User>>> = once_cell::sync::Lazy::new(|| Mutex::new(HashMap::new()));
+use crate::transport::ServerConfig;
+use axum::middleware;
+use axum_server::tls_rustls::RustlsConfig;
 
 #[derive(serde::Deserialize)]
 pub struct UploadMetadata {
@@ -48,43 +56,155 @@ pub struct P2PListQuery {
 
 #[derive(serde::Deserialize)]
 pub struct P2PGetFileQuery {
-    pub peer_id: String,
+    /// Peer to fetch from; when absent the file's holders are looked up in the DHT
+    pub peer_id: Option<String>,
+    pub file_id: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct P2PProvidersQuery {
     pub file_id: String,
 }
 
 #[derive(serde::Deserialize)]
 pub struct RegisterRequest {
     pub username: String,
-    pub password: String,
+    pub password: SecretString,
 }
 
 #[derive(serde::Deserialize)]
 pub struct LoginRequest {
     pub username: String,
-    pub password: String,
+    pub password: SecretString,
+    #[serde(default)]
+    pub device_id: Option<String>,
+    /// TOTP or recovery code, for accounts with two-factor enabled
+    #[serde(default)]
+    pub otp: Option<String>,
+    /// Skip the second factor on this device for the next 30 days
+    #[serde(default)]
+    pub trust_device: bool,
+    /// Hex X25519 public key of the device, to enrol it
+    #[serde(default)]
+    pub device_public_key: Option<String>,
+    /// Login challenge the device key answered, from POST /login/challenge
+    #[serde(default)]
+    pub challenge_id: Option<String>,
+    /// Hex answer to the challenge (see `devices::prove_device`)
+    #[serde(default)]
+    pub device_proof: Option<String>,
 }
 
 #[derive(serde::Deserialize)]
-pub struct AuthDownloadQuery {
-    pub file_id: String,
+pub struct MfaCodeRequest {
+    pub code: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct MfaResetRequest {
+    pub username: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct AllowClaimRequest {
     pub username: String,
-    pub password: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct UntrustDeviceRequest {
+    pub device_id: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct DeviceRequest {
+    pub device_id: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct RedeemDeviceCodeRequest {
+    pub code: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct DeviceFileKeyQuery {
+    pub file_id: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct RevokeSessionRequest {
+    pub session_id: Option<String>,
+    pub device_id: Option<String>,
 }
 
 #[derive(serde::Deserialize)]
 pub struct AuthUploadMetadata {
     pub filename: String,
     pub tags: Vec<String>,
-    pub username: String,
-    pub password: String,
+    #[serde(default)]
+    pub attributes: HashMap<String, String>,
+    /// Encrypt filename, tags and attributes under the file key
+    #[serde(default)]
+    pub confidential: bool,
+}
+
+#[derive(serde::Deserialize)]
+pub struct SealFileRequest {
+    pub file_id: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct SearchFilesQuery {
+    pub tag: String,
 }
 
 #[derive(serde::Deserialize)]
 pub struct ShareFileRequest {
     pub file_id: String,
-    pub owner_username: String,
-    pub owner_password: String,
     pub recipient_username: String,
+    /// Extra permissions beyond read, e.g. `["write", "share"]`
+    #[serde(default)]
+    pub permissions: Vec<String>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct GrantRoleRequest {
+    pub username: String,
+    pub role: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct RevokeRoleRequest {
+    pub username: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct UnshareFileRequest {
+    pub file_id: String,
+    pub recipient_username: String,
+    #[serde(default)]
+    pub rekey: bool,
+}
+
+#[derive(serde::Deserialize)]
+pub struct CreateLinkRequest {
+    pub file_id: String,
+    /// Seconds until the link stops working
+    #[serde(default)]
+    pub expires_in: Option<u64>,
+    #[serde(default)]
+    pub max_downloads: Option<u32>,
+    #[serde(default)]
+    pub password: Option<SecretString>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct RevokeLinkRequest {
+    pub link_id: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct ShareHistoryQuery {
+    pub file_id: String,
 }
 
 #[derive(serde::Deserialize)]
@@ -92,14 +212,11 @@ pub struct RequestFileKey {
     pub file_id: String,
     pub from_peer_id: String,
     pub to_peer_id: Option<String>,
-    pub username: String,
-    pub password: String,
 }
 
 #[derive(serde::Deserialize)]
 pub struct AcceptSharedFileKey {
     pub file_id: String,
-    pub username: String,
     pub encrypted_key: Vec<u8>,
 }
 
@@ -125,23 +242,132 @@ pub struct DownloadChunkQuery {
 
 #[derive(serde::Deserialize)]
 pub struct P2PChunkRequest {
-    pub peer_id: String,
+    /// Peer to fetch from; when absent the file's holders are looked up in the DHT
+    #[serde(default)]
+    pub peer_id: Option<String>,
     pub file_id: String,
     pub chunk_index: usize,
     pub chunk_size: usize,
+    /// Hex SHA-256 the chunk must have; a mismatch counts against the peer
+    #[serde(default)]
+    pub expected_sha256: Option<String>,
 }
 
-pub async fn list_files(Extension(storage): Extension<Arc<Storage>>) -> impl IntoResponse {
+pub async fn list_files(session: AuthSession, Extension(storage): Extension<Arc<Storage>>) -> impl IntoResponse {
+    let policy = match Policy::open(&storage) {
+        Ok(p) => p,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Error: {}", e)).into_response(),
+    };
     match storage.list_metadata() {
-        Ok(files) => Json(files).into_response(),
+        Ok(files) => {
+            // Only list what the caller could read, with confidential fields decrypted
+            let secret = session.secret().ok();
+            let visible: Vec<_> = files.iter()
+                .filter(|f| policy.check_file(&session, f, FilePermission::Read).is_ok())
+                .map(|f| confidential::view(f, session.username(), secret.as_ref()))
+                .collect();
+            Json(visible).into_response()
+        }
         Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Error: {}", e)).into_response(),
     }
 }
 
+/// Every file the caller can read; recommendations are only drawn from these.
+pub(crate) fn readable_files(storage: &Storage, session: &AuthSession) -> anyhow::Result<Vec<crate::models::FileMetadata>> {
+    let policy = Policy::open(storage)?;
+    let mut files = storage.list_metadata()?;
+    files.retain(|f| policy.check_file(session, f, FilePermission::Read).is_ok());
+    Ok(files)
+}
+
+/// The caller's wrapped copy of the file key: the owner's own, or the one shared with them.
+fn wrapped_key_for<'a>(meta: &'a crate::storage::FileMetadata, username: &str) -> Option<&'a Vec<u8>> {
+    if meta.owner_peer_id == username {
+        Some(&meta.encrypted_file_key)
+    } else {
+        meta.shared_keys.get(username)
+    }
+}
+
+/// Seals an existing file's filename, tags and attributes under its key (see
+/// `crate::confidential`). Needs `write` on the file and a key for it; sealing twice is a
+/// no-op. Shared by the REST and gRPC APIs.
+pub fn seal_file(storage: &Storage, session: &AuthSession, file_id: &Uuid) -> Result<(), (StatusCode, String)> {
+    let result = (|| {
+        let secret = session.secret()?;
+        let mut meta = match storage.get_metadata(file_id) {
+            Ok(Some(m)) => m,
+            Ok(None) => return Err((StatusCode::NOT_FOUND, "File not found".to_string())),
+            Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, format!("DB error: {}", e))),
+        };
+        policy::require_file(storage, session, &meta, FilePermission::Write)?;
+        if meta.sealed.is_some() {
+            return Ok(());
+        }
+        let wrapped_key = wrapped_key_for(&meta, session.username())
+            .ok_or((StatusCode::FORBIDDEN, "No key for this file has been shared with you".to_string()))?;
+        let file_key = unwrap_file_key(wrapped_key, &secret)
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Key unwrap error: {}", e)))?;
+        confidential::seal(&mut meta, &file_key, session.username(), &confidential::index_key(&secret))
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Metadata encryption error: {}", e)))?;
+        storage.insert_metadata(&meta).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("DB error: {}", e)))
+    })();
+    let detail = result.as_ref().err().map(|(_, message)| message.as_str());
+    audit::record_session(storage, session, "seal_metadata", Some(&file_id.to_string()), result.is_ok(), detail);
+    result
+}
+
+/// Readable files carrying `tag`, as the caller may see them. Confidential files match
+/// on blind tokens; see `confidential::has_tag`. Shared by the REST and gRPC APIs.
+pub fn search_files(storage: &Storage, session: &AuthSession, tag: &str) -> Result<Vec<crate::storage::FileMetadata>, (StatusCode, String)> {
+    let policy = Policy::open(storage).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let files = storage.list_metadata().map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("DB error: {}", e)))?;
+    let secret = session.secret().ok();
+    let mut found = Vec::new();
+    for mut meta in files {
+        if policy.check_file(session, &meta, FilePermission::Read).is_err() {
+            continue;
+        }
+        let (matches, indexed) = confidential::has_tag(&mut meta, session.username(), secret.as_ref(), tag);
+        if indexed && let Err(e) = storage.insert_metadata(&meta) {
+            println!("Failed to store tag tokens for {}: {}", meta.file_id, e);
+        }
+        if matches {
+            found.push(confidential::view(&meta, session.username(), secret.as_ref()));
+        }
+    }
+    Ok(found)
+}
+
+/// POST /files/seal: makes an existing file confidential
+pub async fn seal_file_metadata(session: AuthSession, Extension(storage): Extension<Arc<Storage>>, Json(req): Json<SealFileRequest>) -> impl IntoResponse {
+    let file_id = match Uuid::parse_str(&req.file_id) {
+        Ok(id) => id,
+        Err(_) => return (StatusCode::BAD_REQUEST, "Invalid file_id").into_response(),
+    };
+    match seal_file(&storage, &session, &file_id) {
+        Ok(()) => Json(serde_json::json!({"status": "ok"})).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// GET /files/search?tag=: readable files with a tag
+pub async fn search_files_by_tag(session: AuthSession, Extension(storage): Extension<Arc<Storage>>, Query(params): Query<SearchFilesQuery>) -> impl IntoResponse {
+    match search_files(&storage, &session, &params.tag) {
+        Ok(files) => Json(files).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
 pub async fn upload_file(
+    session: AuthSession,
     Extension(storage): Extension<Arc<Storage>>,
+    Extension(p2p): Extension<Arc<P2PNode>>,
     mut multipart: Multipart,
 ) -> impl IntoResponse {
+    if let Err(e) = policy::require(&storage, &session, Permission::Upload) {
+        return e.into_response();
+    }
     let mut file_bytes = None;
     let mut metadata = None;
     while let Some(field) = multipart.next_field().await.unwrap() {
@@ -161,34 +387,29 @@ pub async fn upload_file(
         Some(m) => m,
         None => return (StatusCode::BAD_REQUEST, "Missing metadata").into_response(),
     };
-    // Authenticate user and get public key
-    let keyfile = format!("userkeys/{}.key", metadata.username);
-    let _secret = match load_and_decrypt_keypair(&keyfile, &metadata.password) {
+    // Confidential fields are sealed with a key derived from the uploader's, so it must be loaded
+    let secret = match metadata.confidential.then(|| session.secret()).transpose() {
         Ok(s) => s,
-        Err(_) => return (StatusCode::UNAUTHORIZED, "Invalid username or password").into_response(),
+        Err(e) => return e.into_response(),
     };
-    let user_db = USER_DB.lock().unwrap();
-    let user = match user_db.get(&metadata.username) {
-        Some(u) => u,
-        None => return (StatusCode::BAD_REQUEST, "Unknown user").into_response(),
+    let user_pub = match UserStore::open(&storage).map_err(UserStoreError::from).and_then(|u| u.public_key(session.username())) {
+        Ok(Some(k)) => k,
+        Ok(None) => return (StatusCode::BAD_REQUEST, "Unknown user").into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("User store error: {}", e)).into_response(),
     };
-    let user_pub = x25519_dalek::PublicKey::from(user.public_key);
     // Generate file ID and per-file encryption key
     let file_id = Uuid::new_v4();
-    let mut file_key = [0u8; 32];
-    rand::thread_rng().fill_bytes(&mut file_key);
+    let file_key = SecretKey::random();
     // Encrypt file with file_key
     let encrypted = match encrypt_file(&file_bytes, &file_key) {
         Ok(e) => e,
         Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Encryption error: {}", e)).into_response(),
     };
     // Encrypt file_key with user's public key
-    let ephemeral = x25519_dalek::EphemeralSecret::random_from_rng(rand::thread_rng());
-    let shared = ephemeral.diffie_hellman(&user_pub);
-    let mut encrypted_file_key = file_key.clone();
-    for (b, k) in encrypted_file_key.iter_mut().zip(shared.as_bytes()) {
-        *b ^= k;
-    }
+    let encrypted_file_key = match wrap_file_key(&file_key, &user_pub) {
+        Ok(k) => k,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Key wrap error: {}", e)).into_response(),
+    };
     // Save encrypted file
     let file_path = format!("files/{}.bin", file_id);
     let mut f = match File::create(&file_path) {
@@ -199,29 +420,61 @@ pub async fn upload_file(
         return (StatusCode::INTERNAL_SERVER_ERROR, format!("File write error: {}", e)).into_response();
     }
     // Save metadata
-    let meta = crate::models::FileMetadata {
+    let mut meta = crate::models::FileMetadata {
         file_id,
         filename: metadata.filename,
         tags: metadata.tags,
-        owner_peer_id: metadata.username,
+        owner_peer_id: session.username().to_string(),
         checksum: "TODO".to_string(), // TODO: Compute checksum
         size: file_bytes.len() as u64,
-        encrypted_file_key: encrypted_file_key.to_vec(),
+        encrypted_file_key,
         shared_keys: HashMap::new(), // Initialize shared_keys
         allowed_peers: vec![],
+        attributes: metadata.attributes,
+        sealed: None,
     };
+    if let Some(secret) = &secret
+        && let Err(e) = confidential::seal(&mut meta, &file_key, session.username(), &confidential::index_key(secret))
+    {
+        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Metadata encryption error: {}", e)).into_response();
+    }
     if let Err(e) = storage.insert_metadata(&meta) {
         return (StatusCode::INTERNAL_SERVER_ERROR, format!("Metadata save error: {}", e)).into_response();
     }
+    if let Err(e) = DeviceRegistry::open(&storage).map_err(DeviceError::from).and_then(|d| d.sync_file(&storage, &meta, &file_key)) {
+        println!("Failed to wrap the key of {} for devices: {}", file_id, e);
+    }
+    p2p.provide_file(&file_id.to_string()).await;
+    audit::record_session(&storage, &session, "upload", Some(&file_id.to_string()), true, None);
     Json(serde_json::json!({"status": "ok", "file_id": file_id})).into_response()
 }
 
 pub async fn upload_chunk(
+    session: AuthSession,
+    Extension(storage): Extension<Arc<Storage>>,
+    Extension(p2p): Extension<Arc<P2PNode>>,
     Query(params): Query<UploadChunkQuery>,
     _headers: HeaderMap,
     body: Bytes,
 ) -> impl IntoResponse {
-    let temp_dir = format!("upload_tmp/{}", params.file_id);
+    if let Err(e) = policy::require(&storage, &session, Permission::Upload) {
+        return e.into_response();
+    }
+    let file_id = match Uuid::parse_str(&params.file_id) {
+        Ok(id) => id,
+        Err(_) => return (StatusCode::BAD_REQUEST, "Invalid file_id").into_response(),
+    };
+    // Chunks for a file that already exists overwrite it
+    let existing = match storage.get_metadata(&file_id) {
+        Ok(m) => m,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("DB error: {}", e)).into_response(),
+    };
+    if let Some(meta) = existing
+        && let Err(e) = policy::require_file(&storage, &session, &meta, FilePermission::Write)
+    {
+        return e.into_response();
+    }
+    let temp_dir = format!("upload_tmp/{}", file_id);
     fs::create_dir_all(&temp_dir).ok();
     let chunk_path = format!("{}/chunk_{}", temp_dir, params.chunk_index);
     if let Err(e) = fs::write(&chunk_path, &body) {
@@ -237,31 +490,33 @@ pub async fn upload_chunk(
     }
     if all_present {
         // Assemble file
-        let final_path = format!("files/{}.bin", params.file_id);
+        let final_path = format!("files/{}.bin", file_id);
         let mut out = OpenOptions::new().create(true).write(true).truncate(true).open(&final_path).unwrap();
         for i in 0..params.total_chunks {
             let chunk = fs::read(format!("{}/chunk_{}", temp_dir, i)).unwrap();
             out.write_all(&chunk).unwrap();
         }
         let _ = fs::remove_dir_all(&temp_dir);
+        p2p.provide_file(&file_id.to_string()).await;
+        audit::record_session(&storage, &session, "upload", Some(&file_id.to_string()), true, None);
         return Json(serde_json::json!({"status": "upload complete"})).into_response();
     }
     Json(serde_json::json!({"status": "chunk uploaded"})).into_response()
 }
 
 pub async fn download_file(
+    session: AuthSession,
     Extension(storage): Extension<Arc<Storage>>,
-    Query(params): Query<AuthDownloadQuery>,
+    Extension(p2p): Extension<Arc<P2PNode>>,
+    Query(params): Query<DownloadQuery>,
 ) -> impl IntoResponse {
     let file_id = match Uuid::parse_str(&params.file_id) {
         Ok(id) => id,
         Err(_) => return (StatusCode::BAD_REQUEST, "Invalid file_id").into_response(),
     };
-    // Authenticate user and load private key
-    let keyfile = format!("userkeys/{}.key", params.username);
-    let secret = match load_and_decrypt_keypair(&keyfile, &params.password) {
+    let secret = match session.secret() {
         Ok(s) => s,
-        Err(_) => return (StatusCode::UNAUTHORIZED, "Invalid username or password").into_response(),
+        Err(e) => return e.into_response(),
     };
     // Fetch metadata
     let meta = match storage.get_metadata(&file_id) {
@@ -269,57 +524,63 @@ pub async fn download_file(
         Ok(None) => return (StatusCode::NOT_FOUND, "File not found").into_response(),
         Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("DB error: {}", e)).into_response(),
     };
-    // Access control: only allow owner to download
-    if meta.owner_peer_id != params.username {
-        // Check if the user is in shared_keys
-        let user_db = USER_DB.lock().unwrap();
-        let user = user_db.get(&params.username);
-        if user.is_none() {
-            return (StatusCode::UNAUTHORIZED, "You do not have access to this file").into_response();
-        }
-        let user_pub = x25519_dalek::PublicKey::from(user.unwrap().public_key);
-        let shared = secret.diffie_hellman(&user_pub);
-        let mut file_key = meta.encrypted_file_key.clone();
-        for (b, k) in file_key.iter_mut().zip(shared.as_bytes()) {
-            *b ^= k;
-        }
-        // Decrypt file
-        let decrypted = match decrypt_file(&meta.encrypted_file_key, &file_key.try_into().unwrap_or([0u8; 32])) {
-            Ok(d) => d,
-            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Decryption error: {}", e)).into_response(),
-        };
-        return (
-            [("Content-Type", "application/octet-stream"), ("Content-Disposition", &format!("attachment; filename=\"{}\"", meta.filename))],
-            decrypted
-        ).into_response();
+    if let Err(e) = policy::require_file(&storage, &session, &meta, FilePermission::Read) {
+        return e.into_response();
     }
+    let wrapped_key = match wrapped_key_for(&meta, session.username()) {
+        Some(k) => k,
+        None => return (StatusCode::FORBIDDEN, "No key for this file has been shared with you").into_response(),
+    };
     // Decrypt file key
-    let user_pub = x25519_dalek::PublicKey::from(meta.owner_peer_id.as_bytes().try_into().unwrap_or([0u8; 32]));
-    let shared = secret.diffie_hellman(&user_pub);
-    let mut file_key = meta.encrypted_file_key.clone();
-    for (b, k) in file_key.iter_mut().zip(shared.as_bytes()) {
-        *b ^= k;
-    }
-    // Read encrypted file
+    let file_key = match unwrap_file_key(wrapped_key, &secret) {
+        Ok(k) => k,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Key unwrap error: {}", e)).into_response(),
+    };
+    // Read encrypted file, rebuilding it from its shards if it is erasure coded elsewhere
     let file_path = format!("files/{}.bin", file_id);
+    if !std::path::Path::new(&file_path).exists() && erasure::is_sharded(&params.file_id)
+        && let Err(e) = p2p.restore_file(&params.file_id).await
+    {
+        return (StatusCode::SERVICE_UNAVAILABLE, format!("File could not be rebuilt from its shards: {}", e)).into_response();
+    }
     let encrypted = match std::fs::read(&file_path) {
         Ok(b) => b,
         Err(_) => return (StatusCode::NOT_FOUND, "File not found").into_response(),
     };
     // Decrypt file
-    let decrypted = match decrypt_file(&encrypted, &file_key.try_into().unwrap_or([0u8; 32])) {
+    let decrypted = match decrypt_file(&encrypted, &file_key) {
         Ok(d) => d,
         Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Decryption error: {}", e)).into_response(),
     };
+    let filename = match confidential::unseal(&meta, &file_key) {
+        Ok(fields) => fields.filename,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Metadata decryption error: {}", e)).into_response(),
+    };
+    audit::record_session(&storage, &session, "download", Some(&params.file_id), true, None);
     (
-        [("Content-Type", "application/octet-stream"), ("Content-Disposition", &format!("attachment; filename=\"{}\"", meta.filename))],
+        [("Content-Type", "application/octet-stream"), ("Content-Disposition", &format!("attachment; filename=\"{}\"", filename))],
         decrypted
     ).into_response()
 }
 
 pub async fn download_chunk(
+    session: AuthSession,
+    Extension(storage): Extension<Arc<Storage>>,
     Query(params): Query<DownloadChunkQuery>,
 ) -> impl IntoResponse {
+    let meta = match Uuid::parse_str(&params.file_id).map(|id| storage.get_metadata(&id)) {
+        Ok(Ok(Some(m))) => m,
+        Ok(Ok(None)) => return (StatusCode::NOT_FOUND, "File not found").into_response(),
+        Ok(Err(e)) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("DB error: {}", e)).into_response(),
+        Err(_) => return (StatusCode::BAD_REQUEST, "Invalid file_id").into_response(),
+    };
+    if let Err(e) = policy::require_file(&storage, &session, &meta, FilePermission::Read) {
+        return e.into_response();
+    }
+    // One event per download rather than per chunk
+    if params.chunk_index == 0 {
+        audit::record_session(&storage, &session, "download", Some(&params.file_id), true, None);
+    }
     let file_path = format!("files/{}.bin", params.file_id);
     let mut file = match OpenOptions::new().read(true).open(&file_path) {
         Ok(f) => f,
@@ -335,73 +596,443 @@ pub async fn download_chunk(
     Bytes::from(buf).into_response()
 }
 
-pub async fn recommendations(Query(params): Query<RecommendationsQuery>) -> impl IntoResponse {
-    let storage = Storage::new("dafs_db").unwrap();
-    let files = storage.list_metadata().unwrap_or_default();
-    let recs = get_recommendations(&params.user_id, &files);
+pub async fn recommendations(session: AuthSession, Query(params): Query<RecommendationsQuery>, Extension(storage): Extension<Arc<Storage>>) -> impl IntoResponse {
+    let files = match readable_files(&storage, &session) {
+        Ok(f) => f,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Storage error: {}", e)).into_response(),
+    };
+    let recs = get_recommendations(&params.user_id, &files)
+        .map(|recs| recs.iter().map(confidential::public_view).collect::<Vec<_>>());
     Json(recs).into_response()
 }
 
 pub async fn p2p_list_files(
+    _session: AuthSession,
     Extension(p2p): Extension<Arc<P2PNode>>,
     Query(params): Query<P2PListQuery>,
 ) -> impl IntoResponse {
-    let peer_id = match params.peer_id.parse() {
-        Ok(id) => id,
-        Err(_) => return (StatusCode::BAD_REQUEST, "Invalid peer_id").into_response(),
-    };
-    let files = p2p.list_files(peer_id).await;
-    Json(files).into_response()
+    match p2p.query_peer_files(&params.peer_id).await {
+        Ok(files) => Json(files).into_response(),
+        Err(e) => (StatusCode::BAD_GATEWAY, format!("P2P file listing error: {}", e)).into_response(),
+    }
 }
 
 pub async fn p2p_get_file(
+    _session: AuthSession,
     Extension(p2p): Extension<Arc<P2PNode>>,
     Query(params): Query<P2PGetFileQuery>,
 ) -> impl IntoResponse {
-    let peer_id = match params.peer_id.parse() {
-        Ok(id) => id,
-        Err(_) => return (StatusCode::BAD_REQUEST, "Invalid peer_id").into_response(),
-    };
-    let data = p2p.get_file(peer_id, params.file_id).await;
-    (
-        [("Content-Type", "application/octet-stream")],
-        data
-    ).into_response()
+    match p2p.get_file(params.peer_id.as_deref(), &params.file_id).await {
+        Ok(data) => ([("Content-Type", "application/octet-stream")], data).into_response(),
+        Err(e) => (StatusCode::BAD_GATEWAY, format!("P2P download error: {}", e)).into_response(),
+    }
+}
+
+pub async fn p2p_find_providers(
+    _session: AuthSession,
+    Extension(p2p): Extension<Arc<P2PNode>>,
+    Query(params): Query<P2PProvidersQuery>,
+) -> impl IntoResponse {
+    match p2p.find_providers(&params.file_id).await {
+        Ok(peers) => Json(peers).into_response(),
+        Err(e) => (StatusCode::BAD_GATEWAY, format!("Provider lookup error: {}", e)).into_response(),
+    }
+}
+
+/// GET /p2p/addresses: where this node can be dialled, for browsers connecting directly
+pub async fn p2p_addresses(_session: AuthSession, Extension(p2p): Extension<Arc<P2PNode>>) -> impl IntoResponse {
+    Json(serde_json::json!({
+        "peer_id": p2p.local_peer_id().to_string(),
+        "addresses": p2p.dial_addrs().iter().map(|a| a.to_string()).collect::<Vec<_>>(),
+    }))
 }
 
 pub async fn p2p_request_chunk(
+    _session: AuthSession,
     Extension(p2p): Extension<Arc<P2PNode>>,
     Json(req): Json<P2PChunkRequest>,
 ) -> impl IntoResponse {
-    match p2p.request_chunk(&req.peer_id, &req.file_id, req.chunk_index, req.chunk_size).await {
-        Ok(data) => Bytes::from(data).into_response(),
+    let result = p2p.fetch_chunk(
+        req.peer_id.as_deref(),
+        &req.file_id,
+        req.chunk_index,
+        req.chunk_size,
+        req.expected_sha256.as_deref(),
+    ).await;
+    match result {
+        Ok((_, data)) => Bytes::from(data).into_response(),
         Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("P2P chunk error: {}", e)).into_response(),
     }
 }
 
-pub async fn register(Json(req): Json<RegisterRequest>) -> impl IntoResponse {
-    let keypair = generate_x25519_keypair();
-    let secret = keypair.0;
-    let public = keypair.1;
-    let keyfile = format!("userkeys/{}.key", req.username);
-    std::fs::create_dir_all("userkeys").ok();
-    if let Err(e) = encrypt_and_save_keypair(&secret, &keyfile, &req.password) {
-        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Key save error: {}", e)).into_response();
+pub async fn register(Extension(storage): Extension<Arc<Storage>>, Json(req): Json<RegisterRequest>) -> impl IntoResponse {
+    let result = UserStore::open(&storage)
+        .map_err(UserStoreError::from)
+        .and_then(|users| users.register(&req.username, &req.username, None, req.password.expose()));
+    audit::record_result(&storage, &req.username, "register", None, &result);
+    match result {
+        Ok(user) => Json(serde_json::json!({"status": "ok", "user_id": user.user_id})).into_response(),
+        Err(e @ UserStoreError::UsernameTaken(_)) => (StatusCode::CONFLICT, e.to_string()).into_response(),
+        Err(e @ UserStoreError::InvalidUsername(_)) => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Registration error: {}", e)).into_response(),
     }
-    let user = crate::models::User { username: req.username.clone(), public_key: public.to_bytes() };
-    USER_DB.lock().unwrap().insert(req.username, user);
-    Json(serde_json::json!({"status": "ok"})).into_response()
 }
 
-pub async fn login(Json(req): Json<LoginRequest>) -> impl IntoResponse {
-    let keyfile = format!("userkeys/{}.key", req.username);
-    match load_and_decrypt_keypair(&keyfile, &req.password) {
-        Ok(_secret) => Json(serde_json::json!({"status": "ok"})).into_response(),
-        Err(_) => (StatusCode::UNAUTHORIZED, "Invalid username or password").into_response(),
+/// POST /users/allow_claim: lets the next registration of an imported, password-less
+/// account claim it (needs `manage_users`)
+pub async fn allow_claim(session: AuthSession, Extension(storage): Extension<Arc<Storage>>, Json(req): Json<AllowClaimRequest>) -> impl IntoResponse {
+    if let Err(e) = policy::require(&storage, &session, Permission::ManageUsers) {
+        return e.into_response();
+    }
+    let result = UserStore::open(&storage)
+        .map_err(UserStoreError::from)
+        .and_then(|users| users.allow_claim(&req.username));
+    audit::record_result(&storage, session.username(), "allow_claim", Some(&req.username), &result);
+    match result {
+        Ok(()) => Json(serde_json::json!({"status": "ok"})).into_response(),
+        Err(e @ UserStoreError::NotFound(_)) => (StatusCode::NOT_FOUND, e.to_string()).into_response(),
+        Err(e @ UserStoreError::AlreadyClaimed(_)) => (StatusCode::CONFLICT, e.to_string()).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+pub async fn login(
+    Extension(storage): Extension<Arc<Storage>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(req): Json<LoginRequest>,
+) -> impl IntoResponse {
+    let device_key = match req.device_public_key.as_deref().map(hex::decode).transpose() {
+        Ok(k) => k,
+        Err(_) => return (StatusCode::BAD_REQUEST, "device_public_key must be hex").into_response(),
+    };
+    let (device_id, device_key) = match devices::resolve_device(req.device_id.as_deref(), device_key.as_deref(), "web") {
+        Ok(d) => d,
+        Err(e) => return (e.status(), e.to_string()).into_response(),
+    };
+    let Ok(proof) = req.device_proof.as_deref().map(hex::decode).transpose() else {
+        return (StatusCode::BAD_REQUEST, "device_proof must be hex").into_response();
+    };
+    let proof = proof.unwrap_or_default();
+    let device = device_key.map(|public_key| devices::DeviceProof {
+        public_key,
+        challenge_id: req.challenge_id.as_deref().unwrap_or_default(),
+        proof: &proof,
+    });
+    let second_factor = req.otp.as_deref().map(|code| SecondFactor { code, trust_device: req.trust_device });
+    match crate::auth::login(&storage, &req.username, req.password.expose(), second_factor, &device_id, device, Some(addr.ip())) {
+        Ok((session, token)) => Json(serde_json::json!({
+            "status": "ok",
+            "token": token,
+            "session_id": session.session_id,
+            "expires_at": session.expires_at,
+            "device_id": device_id,
+            "device_state": device_state(&storage, &session.user_id, &device_id),
+        })).into_response(),
+        Err(e @ (AuthError::InvalidCredentials | AuthError::SecondFactorRequired | AuthError::InvalidSecondFactor | AuthError::DeviceKeyMismatch
+            | AuthError::DeviceProofRequired | AuthError::InvalidDeviceProof)) => {
+            (StatusCode::UNAUTHORIZED, e.to_string()).into_response()
+        }
+        Err(e @ AuthError::DeviceNotApproved) => (StatusCode::FORBIDDEN, e.to_string()).into_response(),
+        Err(e @ AuthError::Throttled { retry_after }) => too_many_requests(retry_after, e.to_string()),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+/// POST /login/challenge: a one-time key for a device to prove it holds its device key
+pub async fn login_challenge() -> impl IntoResponse {
+    let challenge = devices::login_challenge();
+    Json(serde_json::json!({
+        "challenge_id": challenge.challenge_id,
+        "public_key": hex::encode(challenge.public_key.as_bytes()),
+        "expires_at": challenge.expires_at,
+    }))
+}
+
+pub async fn logout(session: AuthSession, Extension(storage): Extension<Arc<Storage>>) -> impl IntoResponse {
+    let result = SessionStore::new(&storage)
+        .map_err(AuthError::from)
+        .and_then(|store| store.revoke(&session.0.session_id));
+    audit::record_result(&storage, session.username(), "logout", Some(&session.0.device_id), &result);
+    match result {
+        Ok(_) => Json(serde_json::json!({"status": "ok"})).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+pub async fn list_sessions(session: AuthSession, Extension(storage): Extension<Arc<Storage>>) -> impl IntoResponse {
+    let result = SessionStore::new(&storage)
+        .map_err(AuthError::from)
+        .and_then(|store| store.list_sessions(session.username()));
+    match result {
+        Ok(sessions) => Json(sessions.into_iter().map(|s| serde_json::json!({
+            "current": s.session_id == session.0.session_id,
+            "session_id": s.session_id,
+            "device_id": s.device_id,
+            "created_at": s.created_at,
+            "expires_at": s.expires_at,
+        })).collect::<Vec<_>>()).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+/// Enrolment state of a device, or `None` for one that never presented a key.
+fn device_state(storage: &Storage, user_id: &str, device_id: &str) -> Option<String> {
+    let registry = DeviceRegistry::open(storage).ok()?;
+    registry.get(user_id, device_id).ok().flatten().map(|d| d.state.to_string())
+}
+
+fn mfa_store(storage: &Storage) -> Result<MfaStore, (StatusCode, String)> {
+    MfaStore::open(storage).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// GET /mfa: whether the caller has two-factor enabled, with any trusted devices
+pub async fn mfa_status(session: AuthSession, Extension(storage): Extension<Arc<Storage>>) -> impl IntoResponse {
+    let store = match mfa_store(&storage) {
+        Ok(s) => s,
+        Err(e) => return e.into_response(),
+    };
+    let now = chrono::Utc::now().timestamp() as u64;
+    let trusted: Vec<_> = match UserStore::open(&storage).map_err(UserStoreError::from).and_then(|u| u.get_by_id(&session.0.user_id)) {
+        Ok(user) => user.into_iter()
+            .flat_map(|u| u.devices)
+            .filter_map(|d| d.trusted_until.filter(|t| *t > now).map(|t| serde_json::json!({"device_id": d.device_id, "trusted_until": t})))
+            .collect(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+    match store.status(&session.0.user_id) {
+        Ok(status) => Json(serde_json::json!({
+            "status": "ok",
+            "enabled": status.enabled,
+            "recovery_codes_remaining": status.recovery_codes_remaining,
+            "trusted_devices": trusted,
+        })).into_response(),
+        Err(e) => (e.status(), e.to_string()).into_response(),
+    }
+}
+
+/// POST /mfa/enroll: starts enrolment, returning the secret and otpauth URI
+pub async fn mfa_enroll(session: AuthSession, Extension(storage): Extension<Arc<Storage>>) -> impl IntoResponse {
+    let store = match mfa_store(&storage) {
+        Ok(s) => s,
+        Err(e) => return e.into_response(),
+    };
+    match store.begin_enrolment(&session.0.user_id, session.username()) {
+        Ok(enrolment) => Json(serde_json::json!({
+            "status": "ok",
+            "secret": enrolment.secret,
+            "otpauth_uri": enrolment.otpauth_uri,
+        })).into_response(),
+        Err(e) => (e.status(), e.to_string()).into_response(),
+    }
+}
+
+/// POST /mfa/confirm: enables two-factor with a code from the authenticator app and
+/// returns the recovery codes
+pub async fn mfa_confirm(session: AuthSession, Extension(storage): Extension<Arc<Storage>>, Json(req): Json<MfaCodeRequest>) -> impl IntoResponse {
+    let store = match mfa_store(&storage) {
+        Ok(s) => s,
+        Err(e) => return e.into_response(),
+    };
+    let result = store.confirm_enrolment(&session.0.user_id, &req.code);
+    audit::record_result(&storage, session.username(), "mfa_enable", None, &result);
+    match result {
+        Ok(codes) => Json(serde_json::json!({"status": "ok", "recovery_codes": codes})).into_response(),
+        Err(e) => (e.status(), e.to_string()).into_response(),
+    }
+}
+
+/// POST /mfa/disable: turns two-factor off, given a current code or a recovery code
+pub async fn mfa_disable(session: AuthSession, Extension(storage): Extension<Arc<Storage>>, Json(req): Json<MfaCodeRequest>) -> impl IntoResponse {
+    let store = match mfa_store(&storage) {
+        Ok(s) => s,
+        Err(e) => return e.into_response(),
+    };
+    let result = store.disable(&session.0.user_id, &req.code);
+    audit::record_result(&storage, session.username(), "mfa_disable", None, &result);
+    match result {
+        Ok(()) => Json(serde_json::json!({"status": "ok"})).into_response(),
+        Err(e) => (e.status(), e.to_string()).into_response(),
+    }
+}
+
+/// POST /mfa/recovery_codes: replaces the recovery codes, given a current code
+pub async fn mfa_recovery_codes(session: AuthSession, Extension(storage): Extension<Arc<Storage>>, Json(req): Json<MfaCodeRequest>) -> impl IntoResponse {
+    let store = match mfa_store(&storage) {
+        Ok(s) => s,
+        Err(e) => return e.into_response(),
+    };
+    let result = store.regenerate_recovery_codes(&session.0.user_id, &req.code);
+    audit::record_result(&storage, session.username(), "mfa_recovery_codes", None, &result);
+    match result {
+        Ok(codes) => Json(serde_json::json!({"status": "ok", "recovery_codes": codes})).into_response(),
+        Err(e) => (e.status(), e.to_string()).into_response(),
+    }
+}
+
+/// POST /mfa/reset: removes another user's second factor and device trust (needs `manage_users`)
+pub async fn mfa_reset(session: AuthSession, Extension(storage): Extension<Arc<Storage>>, Json(req): Json<MfaResetRequest>) -> impl IntoResponse {
+    if let Err(e) = policy::require(&storage, &session, Permission::ManageUsers) {
+        return e.into_response();
+    }
+    let users = match UserStore::open(&storage) {
+        Ok(u) => u,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+    let user = match users.get_by_username(&req.username) {
+        Ok(Some(u)) => u,
+        Ok(None) => return (StatusCode::NOT_FOUND, format!("User '{}' not found", req.username)).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+    let store = match mfa_store(&storage) {
+        Ok(s) => s,
+        Err(e) => return e.into_response(),
+    };
+    let result = store.reset(&user.user_id).map_err(|e| e.to_string()).and_then(|was_enabled| {
+        users.clear_device_trust(&user.user_id).map_err(|e| e.to_string())?;
+        Ok(was_enabled)
+    });
+    audit::record_result(&storage, session.username(), "mfa_reset", Some(&req.username), &result);
+    match result {
+        Ok(was_enabled) => Json(serde_json::json!({"status": "ok", "was_enabled": was_enabled})).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
+    }
+}
+
+/// POST /mfa/untrust_device: makes one of the caller's devices ask for the second factor again
+pub async fn mfa_untrust_device(session: AuthSession, Extension(storage): Extension<Arc<Storage>>, Json(req): Json<UntrustDeviceRequest>) -> impl IntoResponse {
+    let result = UserStore::open(&storage)
+        .map_err(UserStoreError::from)
+        .and_then(|users| users.set_device_trust(&session.0.user_id, &req.device_id, None));
+    audit::record_result(&storage, session.username(), "untrust_device", Some(&req.device_id), &result);
+    match result {
+        Ok(true) => Json(serde_json::json!({"status": "ok"})).into_response(),
+        Ok(false) => (StatusCode::NOT_FOUND, format!("Device '{}' not found", req.device_id)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+/// GET /devices: the caller's devices with their key fingerprints and enrolment state
+pub async fn list_devices(session: AuthSession, Extension(storage): Extension<Arc<Storage>>) -> impl IntoResponse {
+    let user = match UserStore::open(&storage).map_err(UserStoreError::from).and_then(|u| u.get_by_id(&session.0.user_id)) {
+        Ok(Some(u)) => u,
+        Ok(None) => return (StatusCode::NOT_FOUND, "User no longer exists").into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+    let keys = match DeviceRegistry::open(&storage).map_err(DeviceError::from).and_then(|d| d.list(&session.0.user_id)) {
+        Ok(k) => k,
+        Err(e) => return (e.status(), e.to_string()).into_response(),
+    };
+    let devices: Vec<_> = user.devices.iter().map(|d| {
+        let key = keys.iter().find(|k| k.device_id == d.device_id);
+        serde_json::json!({
+            "device_id": d.device_id,
+            "device_name": d.device_name,
+            "last_login": d.last_login,
+            "current": d.device_id == session.0.device_id,
+            "trusted_until": d.trusted_until,
+            "fingerprint": key.map(|k| &k.fingerprint),
+            "state": key.map(|k| k.state),
+        })
+    }).collect();
+    Json(devices).into_response()
+}
+
+/// POST /devices/approve: approves a pending device from the caller's approved device
+pub async fn approve_device(session: AuthSession, Extension(storage): Extension<Arc<Storage>>, Json(req): Json<DeviceRequest>) -> impl IntoResponse {
+    match devices::approve_device(&storage, &session, &req.device_id) {
+        Ok((device, wrapped)) => Json(serde_json::json!({
+            "status": "ok",
+            "fingerprint": device.fingerprint,
+            "wrapped_keys": wrapped,
+        })).into_response(),
+        Err(e) => (e.status(), e.to_string()).into_response(),
+    }
+}
+
+/// POST /devices/code: issues a one-time code a new device can redeem to approve itself
+pub async fn device_code(session: AuthSession, Extension(storage): Extension<Arc<Storage>>) -> impl IntoResponse {
+    match devices::issue_code(&storage, &session) {
+        Ok((code, expires_at)) => Json(serde_json::json!({"status": "ok", "code": code, "expires_at": expires_at})).into_response(),
+        Err(e) => (e.status(), e.to_string()).into_response(),
+    }
+}
+
+/// POST /devices/redeem: approves the caller's own pending device with a one-time code
+pub async fn redeem_device_code(session: AuthSession, Extension(storage): Extension<Arc<Storage>>, Json(req): Json<RedeemDeviceCodeRequest>) -> impl IntoResponse {
+    match devices::redeem_code(&storage, &session, &req.code) {
+        Ok((device, wrapped)) => Json(serde_json::json!({
+            "status": "ok",
+            "fingerprint": device.fingerprint,
+            "wrapped_keys": wrapped,
+        })).into_response(),
+        Err(e) => (e.status(), e.to_string()).into_response(),
+    }
+}
+
+/// POST /devices/remove: revokes a device's key, wrapped file keys and sessions
+pub async fn remove_device(session: AuthSession, Extension(storage): Extension<Arc<Storage>>, Json(req): Json<DeviceRequest>) -> impl IntoResponse {
+    match devices::remove_device(&storage, &session, &req.device_id) {
+        Ok(Some(removal)) => Json(serde_json::json!({
+            "status": "ok",
+            "fingerprint": removal.fingerprint,
+            "sessions_revoked": removal.sessions_revoked,
+        })).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, format!("Device '{}' not found", req.device_id)).into_response(),
+        Err(e) => (e.status(), e.to_string()).into_response(),
+    }
+}
+
+/// GET /devices/file_key: the file key wrapped for the caller's device, as hex, for the
+/// device to unwrap with its own secret
+pub async fn device_file_key(session: AuthSession, Extension(storage): Extension<Arc<Storage>>, Query(params): Query<DeviceFileKeyQuery>) -> impl IntoResponse {
+    let meta = match Uuid::parse_str(&params.file_id).map(|id| storage.get_metadata(&id)) {
+        Ok(Ok(Some(m))) => m,
+        Ok(Ok(None)) => return (StatusCode::NOT_FOUND, "File not found").into_response(),
+        Ok(Err(e)) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("DB error: {}", e)).into_response(),
+        Err(_) => return (StatusCode::BAD_REQUEST, "Invalid file_id").into_response(),
+    };
+    if let Err(e) = policy::require_file(&storage, &session, &meta, FilePermission::Read) {
+        return e.into_response();
+    }
+    match DeviceRegistry::open(&storage).map_err(DeviceError::from).and_then(|d| d.wrapped_file_key(&meta.file_id, &session.0.device_id)) {
+        Ok(wrapped) => Json(serde_json::json!({
+            "status": "ok",
+            "device_id": session.0.device_id,
+            "wrapped_key": hex::encode(wrapped),
+        })).into_response(),
+        Err(e) => (e.status(), e.to_string()).into_response(),
+    }
+}
+
+/// POST /sessions/revoke: ends one of the caller's sessions by id, or all of them on a device
+pub async fn revoke_session(
+    session: AuthSession,
+    Extension(storage): Extension<Arc<Storage>>,
+    Json(req): Json<RevokeSessionRequest>,
+) -> impl IntoResponse {
+    let store = match SessionStore::new(&storage) {
+        Ok(s) => s,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+    let target = req.session_id.clone().or_else(|| req.device_id.clone());
+    let result = match (req.session_id, req.device_id) {
+        (Some(session_id), _) => match store.get(&session_id) {
+            Ok(Some(s)) if s.username == session.username() => store.revoke(&session_id).map(|r| r as usize),
+            Ok(_) => return (StatusCode::NOT_FOUND, "Session not found").into_response(),
+            Err(e) => Err(e),
+        },
+        (None, Some(device_id)) => store.revoke_device(session.username(), &device_id),
+        (None, None) => return (StatusCode::BAD_REQUEST, "Provide session_id or device_id").into_response(),
+    };
+    audit::record_result(&storage, session.username(), "revoke_session", target.as_deref(), &result);
+    match result {
+        Ok(revoked) => Json(serde_json::json!({"status": "ok", "revoked": revoked})).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
     }
 }
 
 pub async fn share_file(
+    session: AuthSession,
     Extension(storage): Extension<Arc<Storage>>,
     Json(req): Json<ShareFileRequest>,
 ) -> impl IntoResponse {
@@ -409,88 +1040,489 @@ pub async fn share_file(
         Ok(id) => id,
         Err(_) => return (StatusCode::BAD_REQUEST, "Invalid file_id").into_response(),
     };
-    // Authenticate owner
-    let keyfile = format!("userkeys/{}.key", req.owner_username);
-    let owner_secret = match load_and_decrypt_keypair(&keyfile, &req.owner_password) {
+    let extra = match policy::parse_file_permissions(&req.permissions) {
+        Ok(p) => p,
+        Err(e) => return (e.status(), e.to_string()).into_response(),
+    };
+    match grant_share(&storage, &session, &file_id, &req.recipient_username, &extra) {
+        Ok(()) => Json(serde_json::json!({"status": "ok"})).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Wraps the file key for `recipient` and grants them read plus `extra` permissions.
+/// The caller needs the `share` permission on the file. Shared by the REST and gRPC APIs.
+pub fn grant_share(
+    storage: &Storage,
+    session: &AuthSession,
+    file_id: &Uuid,
+    recipient: &str,
+    extra: &[FilePermission],
+) -> Result<(), (StatusCode, String)> {
+    let result = share_with(storage, session, file_id, recipient, extra);
+    let detail = match &result {
+        Ok(()) => format!("with {}", recipient),
+        Err((_, message)) => format!("with {}: {}", recipient, message),
+    };
+    audit::record_session(storage, session, "share", Some(&file_id.to_string()), result.is_ok(), Some(&detail));
+    result
+}
+
+fn share_with(
+    storage: &Storage,
+    session: &AuthSession,
+    file_id: &Uuid,
+    recipient: &str,
+    extra: &[FilePermission],
+) -> Result<(), (StatusCode, String)> {
+    let secret = session.secret()?;
+    let mut meta = match storage.get_metadata(file_id) {
+        Ok(Some(m)) => m,
+        Ok(None) => return Err((StatusCode::NOT_FOUND, "File not found".to_string())),
+        Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, format!("DB error: {}", e))),
+    };
+    let policy = Policy::open(storage).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    policy.check_file(session, &meta, FilePermission::Share).map_err(|e| (e.status(), e.to_string()))?;
+    if recipient == session.username() {
+        return Err((StatusCode::BAD_REQUEST, "Cannot share a file with yourself".to_string()));
+    }
+    // Sharers can only pass on permissions they hold themselves
+    for permission in extra {
+        policy.check_file(session, &meta, *permission).map_err(|e| (e.status(), e.to_string()))?;
+    }
+    // Get file key
+    let wrapped_key = wrapped_key_for(&meta, session.username())
+        .ok_or((StatusCode::FORBIDDEN, "No key for this file has been shared with you".to_string()))?;
+    let file_key = unwrap_file_key(wrapped_key, &secret)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Key unwrap error: {}", e)))?;
+    // Get recipient public key
+    let recipient_pub = match UserStore::open(storage).map_err(UserStoreError::from).and_then(|u| u.public_key(recipient)) {
+        Ok(Some(k)) => k,
+        Ok(None) => return Err((StatusCode::BAD_REQUEST, "Unknown recipient".to_string())),
+        Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, format!("User store error: {}", e))),
+    };
+    let encrypted_for_recipient = crate::peer::encrypt_file_key_for_peer(&file_key, &recipient_pub);
+    // Store in shared_keys
+    meta.shared_keys.insert(recipient.to_string(), encrypted_for_recipient);
+    storage.insert_metadata(&meta)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Metadata update error: {}", e)))?;
+    DeviceRegistry::open(storage).map_err(DeviceError::from)
+        .and_then(|d| d.sync_file(storage, &meta, &file_key))
+        .map_err(|e| (e.status(), e.to_string()))?;
+    let mut granted = vec![FilePermission::Read];
+    granted.extend_from_slice(extra);
+    policy.grant_file(file_id, recipient, &granted).map_err(|e| (e.status(), e.to_string()))
+}
+
+pub async fn unshare_file(
+    session: AuthSession,
+    Extension(storage): Extension<Arc<Storage>>,
+    Extension(p2p): Extension<Arc<P2PNode>>,
+    Json(req): Json<UnshareFileRequest>,
+) -> impl IntoResponse {
+    let file_id = match Uuid::parse_str(&req.file_id) {
+        Ok(id) => id,
+        Err(_) => return (StatusCode::BAD_REQUEST, "Invalid file_id").into_response(),
+    };
+    let owner_secret = match session.secret() {
         Ok(s) => s,
-        Err(_) => return (StatusCode::UNAUTHORIZED, "Invalid owner credentials").into_response(),
+        Err(e) => return e.into_response(),
     };
-    // Fetch metadata
-    let mut meta = match storage.get_metadata(&file_id) {
+    match revoke_share(&storage, &p2p, &file_id, &session, &owner_secret, &req.recipient_username, req.rekey).await {
+        Ok(revocation) => Json(serde_json::json!({
+            "status": "ok",
+            "rekeyed": revocation.rekeyed,
+            "timestamp": revocation.timestamp,
+        })).into_response(),
+        Err(e) => (e.status(), e.to_string()).into_response(),
+    }
+}
+
+/// Creates a capability link to a file and returns it with the file key for its
+/// fragment. The caller needs the `share` permission on the file. Shared by the REST and
+/// gRPC APIs.
+pub fn create_link(storage: &Storage, session: &AuthSession, file_id: &Uuid, options: LinkOptions) -> Result<(ShareLink, String), (StatusCode, String)> {
+    let result = new_link(storage, session, file_id, options);
+    let detail = match &result {
+        Ok((link, _)) => format!("link {}", link.link_id),
+        Err((_, message)) => message.clone(),
+    };
+    audit::record_session(storage, session, "create_link", Some(&file_id.to_string()), result.is_ok(), Some(&detail));
+    result
+}
+
+fn new_link(storage: &Storage, session: &AuthSession, file_id: &Uuid, options: LinkOptions) -> Result<(ShareLink, String), (StatusCode, String)> {
+    let secret = session.secret()?;
+    let meta = match storage.get_metadata(file_id) {
         Ok(Some(m)) => m,
+        Ok(None) => return Err((StatusCode::NOT_FOUND, "File not found".to_string())),
+        Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, format!("DB error: {}", e))),
+    };
+    policy::require_file(storage, session, &meta, FilePermission::Share)?;
+    let wrapped_key = wrapped_key_for(&meta, session.username())
+        .ok_or((StatusCode::FORBIDDEN, "No key for this file has been shared with you".to_string()))?;
+    let file_key = unwrap_file_key(wrapped_key, &secret)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Key unwrap error: {}", e)))?;
+    let link = LinkStore::open(storage)
+        .map_err(LinkError::from)
+        .and_then(|links| links.create(*file_id, session.username(), options))
+        .map_err(|e| (e.status(), e.to_string()))?;
+    Ok((link, links::encode_key(&file_key)))
+}
+
+/// Revokes a link; its creator or anyone who may share the file can. Shared by the REST
+/// and gRPC APIs.
+pub fn revoke_link(storage: &Storage, session: &AuthSession, link_id: &str) -> Result<ShareLink, (StatusCode, String)> {
+    let result = (|| {
+        let links = LinkStore::open(storage).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        let link = links.get(link_id).map_err(|e| (e.status(), e.to_string()))?;
+        if link.created_by != session.username() {
+            let meta = storage.get_metadata(&link.file_id)
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("DB error: {}", e)))?
+                .ok_or((StatusCode::NOT_FOUND, "File not found".to_string()))?;
+            policy::require_file(storage, session, &meta, FilePermission::Share)?;
+        }
+        links.revoke(link_id).map_err(|e| (e.status(), e.to_string()))
+    })();
+    let detail = result.as_ref().err().map(|(_, message)| message.as_str());
+    audit::record_session(storage, session, "revoke_link", Some(link_id), result.is_ok(), detail);
+    result
+}
+
+/// A link as shown to its creator: never the password hash, and never the key.
+fn link_json(link: &ShareLink) -> serde_json::Value {
+    serde_json::json!({
+        "link_id": link.link_id,
+        "file_id": link.file_id,
+        "created_at": link.created_at,
+        "expires_at": link.expires_at,
+        "max_downloads": link.max_downloads,
+        "downloads": link.downloads,
+        "password_required": link.has_password(),
+        "revoked": link.revoked,
+    })
+}
+
+/// POST /links: creates a capability link. The response's `key` and `url` are shown once;
+/// the node does not keep the key.
+pub async fn create_share_link(session: AuthSession, Extension(storage): Extension<Arc<Storage>>, Json(req): Json<CreateLinkRequest>) -> impl IntoResponse {
+    let file_id = match Uuid::parse_str(&req.file_id) {
+        Ok(id) => id,
+        Err(_) => return (StatusCode::BAD_REQUEST, "Invalid file_id").into_response(),
+    };
+    let options = LinkOptions { expires_in: req.expires_in, max_downloads: req.max_downloads, password: req.password };
+    match create_link(&storage, &session, &file_id, options) {
+        Ok((link, key)) => {
+            let mut body = link_json(&link);
+            body["status"] = "ok".into();
+            body["key"] = key.clone().into();
+            body["url"] = link.path(&key).into();
+            Json(body).into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+/// GET /links: the links the caller created, newest first
+pub async fn list_share_links(session: AuthSession, Extension(storage): Extension<Arc<Storage>>) -> impl IntoResponse {
+    match LinkStore::open(&storage).map_err(LinkError::from).and_then(|links| links.list_by_creator(session.username())) {
+        Ok(links) => Json(links.iter().map(link_json).collect::<Vec<_>>()).into_response(),
+        Err(e) => (e.status(), e.to_string()).into_response(),
+    }
+}
+
+/// POST /links/revoke: stops a link working
+pub async fn revoke_share_link(session: AuthSession, Extension(storage): Extension<Arc<Storage>>, Json(req): Json<RevokeLinkRequest>) -> impl IntoResponse {
+    match revoke_link(&storage, &session, &req.link_id) {
+        Ok(_) => Json(serde_json::json!({"status": "ok"})).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// GET /links/open/:link_id (public): what a download page shows before downloading
+pub async fn open_link_info(Extension(storage): Extension<Arc<Storage>>, Path(link_id): Path<String>) -> impl IntoResponse {
+    let link = match LinkStore::open(&storage).map_err(LinkError::from).and_then(|links| links.usable(&link_id)) {
+        Ok(link) => link,
+        Err(e) => return (e.status(), e.to_string()).into_response(),
+    };
+    match storage.get_metadata(&link.file_id) {
+        Ok(Some(meta)) => Json(serde_json::json!({
+            // A confidential file's name is sealed; the page decrypts it with the link's key
+            "filename": meta.filename,
+            "sealed_metadata": meta.sealed.as_ref().map(|s| data_encoding::BASE64URL_NOPAD.encode(&s.ciphertext)),
+            "size": meta.size,
+            "expires_at": link.expires_at,
+            "downloads_remaining": link.downloads_remaining(),
+            "password_required": link.has_password(),
+        })).into_response(),
+        Ok(None) => (StatusCode::GONE, "File no longer exists").into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("DB error: {}", e)).into_response(),
+    }
+}
+
+/// GET /links/open/:link_id/content (public): streams the encrypted file for the client
+/// to decrypt with the key from the link's fragment. Counts one download; a password goes
+/// in the `x-link-password` header.
+pub async fn open_link_content(Extension(storage): Extension<Arc<Storage>>, Path(link_id): Path<String>, headers: HeaderMap) -> impl IntoResponse {
+    let password = headers.get(links::PASSWORD_HEADER).and_then(|v| v.to_str().ok());
+    let result = LinkStore::open(&storage).map_err(LinkError::from).and_then(|links| links.redeem(&link_id, password));
+    let link = match result {
+        Ok(link) => link,
+        Err(e) => {
+            audit::record(&storage, "anonymous", "open_link", None, false, Some(&format!("link {}: {}", link_id, e)));
+            return (e.status(), e.to_string()).into_response();
+        }
+    };
+    let target = link.file_id.to_string();
+    let file = match tokio::fs::File::open(format!("files/{}.bin", link.file_id)).await {
+        Ok(f) => f,
+        Err(_) => return (StatusCode::GONE, "File no longer exists").into_response(),
+    };
+    let length = file.metadata().await.map(|m| m.len()).unwrap_or(0);
+    audit::record(&storage, "anonymous", "open_link", Some(&target), true, Some(&format!("link {}", link_id)));
+    let chunks = futures::stream::unfold(Some(file), |file| async move {
+        let mut file = file?;
+        let mut buf = vec![0u8; 64 * 1024];
+        match tokio::io::AsyncReadExt::read(&mut file, &mut buf).await {
+            Ok(0) => None,
+            Ok(n) => {
+                buf.truncate(n);
+                Some((Ok(Bytes::from(buf)), Some(file)))
+            }
+            Err(e) => Some((Err(e), None)),
+        }
+    });
+    (
+        [
+            (axum::http::header::CONTENT_TYPE, "application/octet-stream".to_string()),
+            (axum::http::header::CONTENT_LENGTH, length.to_string()),
+            (axum::http::header::CACHE_CONTROL, "no-store".to_string()),
+        ],
+        axum::body::StreamBody::new(chunks),
+    ).into_response()
+}
+
+pub async fn share_history(
+    session: AuthSession,
+    Extension(storage): Extension<Arc<Storage>>,
+    Query(params): Query<ShareHistoryQuery>,
+) -> impl IntoResponse {
+    let file_id = match Uuid::parse_str(&params.file_id) {
+        Ok(id) => id,
+        Err(_) => return (StatusCode::BAD_REQUEST, "Invalid file_id").into_response(),
+    };
+    match storage.get_metadata(&file_id) {
+        Ok(Some(meta)) => {
+            if let Err(e) = policy::require_file(&storage, &session, &meta, FilePermission::Read) {
+                return e.into_response();
+            }
+        }
         Ok(None) => return (StatusCode::NOT_FOUND, "File not found").into_response(),
         Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("DB error: {}", e)).into_response(),
+    }
+    match storage.list_share_revocations(Some(&file_id)) {
+        Ok(records) => Json(records).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("DB error: {}", e)).into_response(),
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RevokeShareError {
+    #[error("File not found")]
+    NotFound,
+    #[error("Only the owner can rekey this file")]
+    NotOwner,
+    #[error(transparent)]
+    Denied(#[from] PolicyError),
+    #[error("File is not shared with {0}")]
+    NotShared(String),
+    #[error("Cannot rekey: no public key known for {0}")]
+    UnknownSharee(String),
+    #[error("{0}")]
+    Internal(#[from] anyhow::Error),
+}
+
+impl RevokeShareError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            RevokeShareError::NotFound => StatusCode::NOT_FOUND,
+            RevokeShareError::NotOwner => StatusCode::FORBIDDEN,
+            RevokeShareError::NotShared(_) | RevokeShareError::UnknownSharee(_) => StatusCode::BAD_REQUEST,
+            RevokeShareError::Denied(e) => e.status(),
+            RevokeShareError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Removes `recipient`'s wrapped key from a file, optionally rotating the file key so
+/// that copies of the old key no longer decrypt the stored ciphertext, then records the
+/// revocation and tells the peers holding the file. Needs the `share` permission on the
+/// file; only the owner can rekey. Shared by the REST and gRPC APIs.
+pub async fn revoke_share(
+    storage: &Storage,
+    p2p: &P2PNode,
+    file_id: &Uuid,
+    session: &AuthSession,
+    owner_secret: &x25519_dalek::StaticSecret,
+    recipient: &str,
+    rekey: bool,
+) -> Result<crate::storage::ShareRevocation, RevokeShareError> {
+    let result = unshare_with(storage, p2p, file_id, session, owner_secret, recipient, rekey).await;
+    let detail = match &result {
+        Ok(r) if r.rekeyed => format!("from {} (rekeyed)", recipient),
+        Ok(_) => format!("from {}", recipient),
+        Err(e) => format!("from {}: {}", recipient, e),
     };
-    // Only owner can share
-    if meta.owner_peer_id != req.owner_username {
-        return (StatusCode::FORBIDDEN, "Only the owner can share this file").into_response();
+    audit::record_session(storage, session, "unshare", Some(&file_id.to_string()), result.is_ok(), Some(&detail));
+    result
+}
+
+async fn unshare_with(
+    storage: &Storage,
+    p2p: &P2PNode,
+    file_id: &Uuid,
+    session: &AuthSession,
+    owner_secret: &x25519_dalek::StaticSecret,
+    recipient: &str,
+    rekey: bool,
+) -> Result<crate::storage::ShareRevocation, RevokeShareError> {
+    let owner = session.username();
+    let mut meta = storage.get_metadata(file_id)?.ok_or(RevokeShareError::NotFound)?;
+    let policy = Policy::open(storage)?;
+    policy.check_file(session, &meta, FilePermission::Share)?;
+    if rekey && meta.owner_peer_id != owner {
+        return Err(RevokeShareError::NotOwner);
     }
-    // Get file key
-    let owner_pub = x25519_dalek::PublicKey::from(meta.owner_peer_id.as_bytes().try_into().unwrap_or([0u8; 32]));
-    let shared = owner_secret.diffie_hellman(&owner_pub);
-    let mut file_key = meta.encrypted_file_key.clone();
-    for (b, k) in file_key.iter_mut().zip(shared.as_bytes()) {
-        *b ^= k;
+    if meta.shared_keys.remove(recipient).is_none() {
+        return Err(RevokeShareError::NotShared(recipient.to_string()));
     }
-    // Get recipient public key
-    let user_db = USER_DB.lock().unwrap();
-    let recipient = match user_db.get(&req.recipient_username) {
-        Some(u) => u,
-        None => return (StatusCode::BAD_REQUEST, "Unknown recipient").into_response(),
+    confidential::forget_reader(&mut meta, recipient);
+    if rekey {
+        // Resolve every remaining sharee before touching the ciphertext
+        let sharee_keys: Vec<(String, x25519_dalek::PublicKey)> = {
+            let users = UserStore::open(storage)?;
+            let mut keys = Vec::new();
+            for username in meta.shared_keys.keys() {
+                let public_key = users.public_key(username)
+                    .map_err(|e| anyhow::anyhow!(e.to_string()))?
+                    .ok_or_else(|| RevokeShareError::UnknownSharee(username.clone()))?;
+                keys.push((username.clone(), public_key));
+            }
+            keys
+        };
+        let old_key = unwrap_file_key(&meta.encrypted_file_key, owner_secret)?;
+        let file_path = format!("files/{}.bin", file_id);
+        let plaintext = decrypt_file(&fs::read(&file_path).map_err(anyhow::Error::from)?, &old_key)?;
+        let new_key = SecretKey::random();
+        confidential::reseal(&mut meta, &old_key, &new_key)?;
+        meta.encrypted_file_key = wrap_file_key(&new_key, &x25519_dalek::PublicKey::from(owner_secret))?;
+        for (username, public_key) in sharee_keys {
+            meta.shared_keys.insert(username, wrap_file_key(&new_key, &public_key)?);
+        }
+        // The new ciphertext only replaces the old once the metadata holding its key is
+        // stored; recover_rekeys finishes or undoes a rekey cut short by a crash
+        let tmp_path = rekey_path(file_id);
+        write_synced(&tmp_path, &encrypt_file(&plaintext, &new_key)?).map_err(anyhow::Error::from)?;
+        storage.begin_rekey(file_id, &meta.encrypted_file_key)?;
+        storage.insert_metadata(&meta)?;
+        fs::rename(&tmp_path, &file_path).map_err(anyhow::Error::from)?;
+        storage.finish_rekey(file_id)?;
+        DeviceRegistry::open(storage)?.sync_file(storage, &meta, &new_key).map_err(|e| anyhow::anyhow!(e.to_string()))?;
+        // Links carry the old key, which no longer decrypts the file
+        LinkStore::open(storage)?.revoke_file(file_id).map_err(|e| anyhow::anyhow!(e.to_string()))?;
+    } else {
+        storage.insert_metadata(&meta)?;
+        if let Some(user) = UserStore::open(storage)?.get_by_username(recipient).map_err(|e| anyhow::anyhow!(e.to_string()))? {
+            DeviceRegistry::open(storage)?.forget_user_file(&user.user_id, file_id).map_err(|e| anyhow::anyhow!(e.to_string()))?;
+        }
+    }
+    policy.revoke_file(file_id, recipient)?;
+    let revocation = crate::storage::ShareRevocation {
+        file_id: *file_id,
+        revoked_user: recipient.to_string(),
+        revoked_by: owner.to_string(),
+        rekeyed: rekey,
+        timestamp: chrono::Utc::now().timestamp() as u64,
+        origin_peer: None,
     };
-    let recipient_pub = x25519_dalek::PublicKey::from(recipient.public_key);
-    let encrypted_for_recipient = crate::peer::encrypt_file_key_for_peer(&file_key.try_into().unwrap_or([0u8; 32]), &recipient_pub);
-    // Store in shared_keys
-    meta.shared_keys.insert(req.recipient_username.clone(), encrypted_for_recipient);
-    if let Err(e) = storage.insert_metadata(&meta) {
-        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Metadata update error: {}", e)).into_response();
+    storage.record_share_revocation(&revocation)?;
+    if let Err(e) = p2p.announce_share_revocation(&revocation, &crate::peer::replication::holders(&file_id.to_string())).await {
+        println!("Failed to announce share revocation for {}: {}", file_id, e);
     }
-    Json(serde_json::json!({"status": "ok"})).into_response()
+    Ok(revocation)
+}
+
+fn rekey_path(file_id: &Uuid) -> String {
+    format!("files/{}.bin.rekey", file_id)
+}
+
+fn write_synced(path: &str, data: &[u8]) -> std::io::Result<()> {
+    let mut file = fs::File::create(path)?;
+    file.write_all(data)?;
+    file.sync_all()
+}
+
+/// Finishes or undoes rekeys interrupted by a crash: the new ciphertext is moved into
+/// place if the metadata holding its key was stored, and discarded otherwise. Returns
+/// how many were found.
+pub fn recover_rekeys(storage: &Storage) -> anyhow::Result<usize> {
+    let pending = storage.pending_rekeys()?;
+    for (file_id, owner_key) in &pending {
+        let tmp_path = rekey_path(file_id);
+        let committed = storage.get_metadata(file_id)?.is_some_and(|meta| meta.encrypted_file_key == *owner_key);
+        if committed && std::path::Path::new(&tmp_path).exists() {
+            fs::rename(&tmp_path, format!("files/{}.bin", file_id))?;
+            println!("Finished interrupted rekey of {}", file_id);
+        } else if !committed {
+            let _ = fs::remove_file(&tmp_path);
+            println!("Rolled back interrupted rekey of {}", file_id);
+        }
+        storage.finish_rekey(file_id)?;
+    }
+    Ok(pending.len())
 }
 
 pub async fn request_file_key(
+    session: AuthSession,
     Extension(storage): Extension<Arc<Storage>>,
     Json(req): Json<RequestFileKey>,
 ) -> impl IntoResponse {
-    // Authenticate user (must be owner)
-    let keyfile = format!("userkeys/{}.key", req.username);
-    let secret = match load_and_decrypt_keypair(&keyfile, &req.password) {
+    let secret = match session.secret() {
         Ok(s) => s,
-        Err(_) => return (StatusCode::UNAUTHORIZED, "Invalid username or password").into_response(),
+        Err(e) => return e.into_response(),
     };
     // Look up file metadata
     let file_id = match Uuid::parse_str(&req.file_id) {
         Ok(id) => id,
         Err(_) => return (StatusCode::BAD_REQUEST, "Invalid file_id").into_response(),
     };
-    let storage = Storage::new("dafs_db").unwrap();
     let meta = match storage.get_metadata(&file_id) {
         Ok(Some(m)) => m,
         _ => return (StatusCode::NOT_FOUND, "File not found").into_response(),
     };
-    if meta.owner_peer_id != req.username {
-        return (StatusCode::FORBIDDEN, "Only the owner can share this file").into_response();
+    if let Err(e) = policy::require_file(&storage, &session, &meta, FilePermission::Share) {
+        return e.into_response();
     }
     // Decrypt file key
-    let owner_pub = x25519_dalek::PublicKey::from(meta.owner_peer_id.as_bytes().try_into().unwrap_or([0u8; 32]));
-    let shared = secret.diffie_hellman(&owner_pub);
-    let mut file_key = meta.encrypted_file_key.clone();
-    for (b, k) in file_key.iter_mut().zip(shared.as_bytes()) {
-        *b ^= k;
-    }
+    let wrapped_key = match wrapped_key_for(&meta, session.username()) {
+        Some(k) => k,
+        None => return (StatusCode::FORBIDDEN, "No key for this file has been shared with you").into_response(),
+    };
+    let file_key = match unwrap_file_key(wrapped_key, &secret) {
+        Ok(k) => k,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Key unwrap error: {}", e)).into_response(),
+    };
     // Encrypt file key for recipient
-    let user_db = USER_DB.lock().unwrap();
-    // Fix: Use to_peer_id as String directly, do not call .parse(), use &to_peer_id for user_db.get
     let to_peer_id = match req.to_peer_id.clone() {
         Some(val) => val,
         None => return (StatusCode::BAD_REQUEST, "No peer id provided").into_response(),
     };
-    let recipient = match user_db.get(&to_peer_id) {
-        Some(u) => u,
-        None => return (StatusCode::BAD_REQUEST, "Unknown recipient").into_response(),
+    let recipient_pub = match UserStore::open(&storage).map_err(UserStoreError::from).and_then(|u| u.public_key(&to_peer_id)) {
+        Ok(Some(k)) => k,
+        Ok(None) => return (StatusCode::BAD_REQUEST, "Unknown recipient").into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("User store error: {}", e)).into_response(),
     };
-    let recipient_pub = x25519_dalek::PublicKey::from(recipient.public_key);
-    let encrypted_for_recipient = crate::peer::encrypt_file_key_for_peer(&file_key.try_into().unwrap_or([0u8; 32]), &recipient_pub);
+    let encrypted_for_recipient = crate::peer::encrypt_file_key_for_peer(&file_key, &recipient_pub);
     let msg = crate::peer::P2PMessage::FileKeyExchange {
         file_id: req.file_id.clone(),
         encrypted_key: encrypted_for_recipient,
@@ -504,6 +1536,7 @@ pub async fn request_file_key(
 }
 
 pub async fn accept_shared_file_key(
+    session: AuthSession,
     Extension(storage): Extension<Arc<Storage>>,
     Json(req): Json<AcceptSharedFileKey>,
 ) -> impl IntoResponse {
@@ -516,34 +1549,50 @@ pub async fn accept_shared_file_key(
         Ok(None) => return (StatusCode::NOT_FOUND, "File not found").into_response(),
         Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("DB error: {}", e)).into_response(),
     };
-    meta.shared_keys.insert(req.username.clone(), req.encrypted_key);
+    if let Err(e) = policy::require_file(&storage, &session, &meta, FilePermission::Read) {
+        return e.into_response();
+    }
+    meta.shared_keys.insert(session.username().to_string(), req.encrypted_key);
     if let Err(e) = storage.insert_metadata(&meta) {
         return (StatusCode::INTERNAL_SERVER_ERROR, format!("Metadata update error: {}", e)).into_response();
     }
     Json(serde_json::json!({"status": "ok"})).into_response()
 }
 
-pub async fn add_bootstrap_node(Json(req): Json<BootstrapNodeReq>) -> impl IntoResponse {
-    match crate::peer::add_bootstrap_node(&req.peer_id, &req.address) {
+pub async fn add_bootstrap_node(session: AuthSession, Extension(storage): Extension<Arc<Storage>>, Json(req): Json<BootstrapNodeReq>) -> impl IntoResponse {
+    if let Err(e) = policy::require(&storage, &session, Permission::ManagePeers) {
+        return e.into_response();
+    }
+    let result = crate::peer::add_bootstrap_node(&req.peer_id, &req.address);
+    audit::record_result(&storage, session.username(), "add_bootstrap", Some(&req.peer_id), &result);
+    match result {
         Ok(_) => Json(serde_json::json!({"status": "ok"})).into_response(),
         Err(e) => (StatusCode::BAD_REQUEST, format!("Error: {}", e)).into_response(),
     }
 }
 
-pub async fn remove_bootstrap_node(Json(req): Json<BootstrapNodeReq>) -> impl IntoResponse {
-    match crate::peer::remove_bootstrap_node(&req.peer_id) {
+pub async fn remove_bootstrap_node(session: AuthSession, Extension(storage): Extension<Arc<Storage>>, Json(req): Json<BootstrapNodeReq>) -> impl IntoResponse {
+    if let Err(e) = policy::require(&storage, &session, Permission::ManagePeers) {
+        return e.into_response();
+    }
+    let result = crate::peer::remove_bootstrap_node(&req.peer_id);
+    audit::record_result(&storage, session.username(), "remove_bootstrap", Some(&req.peer_id), &result);
+    match result {
         Ok(_) => Json(serde_json::json!({"status": "ok"})).into_response(),
         Err(e) => (StatusCode::BAD_REQUEST, format!("Error: {}", e)).into_response(),
     }
 }
 
-pub async fn list_bootstrap_nodes() -> impl IntoResponse {
+pub async fn list_bootstrap_nodes(_session: AuthSession) -> impl IntoResponse {
     let nodes = crate::peer::list_bootstrap_nodes();
     Json(nodes).into_response()
 }
 
 /// POST /ai/train: Triggers local model training (stub: uses all user-file pairs in storage)
-pub async fn ai_train(Extension(storage): Extension<Arc<Storage>>) -> impl IntoResponse {
+pub async fn ai_train(session: AuthSession, Extension(storage): Extension<Arc<Storage>>) -> impl IntoResponse {
+    if let Err(e) = policy::require(&storage, &session, Permission::ManageModel) {
+        return e.into_response();
+    }
     // For demo: collect all user-file pairs
     let files = match storage.list_metadata() {
         Ok(f) => f,
@@ -563,19 +1612,22 @@ pub async fn ai_train(Extension(storage): Extension<Arc<Storage>>) -> impl IntoR
 }
 
 /// GET /ai/recommend: Returns recommendations for a user (alias for /recommendations)
-pub async fn ai_recommend(Query(params): Query<RecommendationsQuery>, Extension(storage): Extension<Arc<Storage>>) -> impl IntoResponse {
-    let files = match storage.list_metadata() {
+pub async fn ai_recommend(session: AuthSession, Query(params): Query<RecommendationsQuery>, Extension(storage): Extension<Arc<Storage>>) -> impl IntoResponse {
+    let files = match readable_files(&storage, &session) {
         Ok(f) => f,
         Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Storage error: {}", e)).into_response(),
     };
     match get_recommendations(&params.user_id, &files) {
-        Ok(recs) => Json(recs).into_response(),
+        Ok(recs) => Json(recs.iter().map(confidential::public_view).collect::<Vec<_>>()).into_response(),
         Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("AI recommend error: {}", e)).into_response(),
     }
 }
 
 /// POST /ai/aggregate: Accepts a model file and aggregates it into the local model
-pub async fn ai_aggregate(body: Bytes) -> impl IntoResponse {
+pub async fn ai_aggregate(session: AuthSession, Extension(storage): Extension<Arc<Storage>>, body: Bytes) -> impl IntoResponse {
+    if let Err(e) = policy::require(&storage, &session, Permission::ManageModel) {
+        return e.into_response();
+    }
     match bincode::deserialize::<NCFModel>(&body) {
         Ok(remote_model) => match aggregate_remote_model(&remote_model) {
             Ok(_) => Json(serde_json::json!({"status": "ok"})).into_response(),
@@ -585,20 +1637,136 @@ pub async fn ai_aggregate(body: Bytes) -> impl IntoResponse {
     }
 }
 
-pub async fn run_with_storage_and_p2p(storage: Arc<Storage>, p2p: Arc<P2PNode>) {
+/// GET /roles: explicit role assignments (admins only)
+pub async fn list_roles(session: AuthSession, Extension(storage): Extension<Arc<Storage>>) -> impl IntoResponse {
+    if let Err(e) = policy::require(&storage, &session, Permission::ManageRoles) {
+        return e.into_response();
+    }
+    let result = UserStore::open(&storage).and_then(|users| {
+        let roles = Policy::open(&storage)?.list_roles()?;
+        Ok(roles.into_iter().map(|(user_id, role)| {
+            let username = users.get_by_id(&user_id).ok().flatten().map(|u| u.username).unwrap_or_default();
+            serde_json::json!({"user_id": user_id, "username": username, "role": role})
+        }).collect::<Vec<_>>())
+    });
+    match result {
+        Ok(roles) => Json(roles).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Policy error: {}", e)).into_response(),
+    }
+}
+
+/// POST /roles/grant: sets a user's role (admins only)
+pub async fn grant_role(session: AuthSession, Extension(storage): Extension<Arc<Storage>>, Json(req): Json<GrantRoleRequest>) -> impl IntoResponse {
+    let role = match req.role.parse::<Role>() {
+        Ok(r) => r,
+        Err(e) => return (e.status(), e.to_string()).into_response(),
+    };
+    set_role(&storage, &session, &req.username, role)
+}
+
+/// POST /roles/revoke: puts a user back on the default `user` role (admins only)
+pub async fn revoke_role(session: AuthSession, Extension(storage): Extension<Arc<Storage>>, Json(req): Json<RevokeRoleRequest>) -> impl IntoResponse {
+    set_role(&storage, &session, &req.username, Role::User)
+}
+
+fn set_role(storage: &Storage, session: &AuthSession, username: &str, role: Role) -> axum::response::Response {
+    if let Err(e) = policy::require(storage, session, Permission::ManageRoles) {
+        return e.into_response();
+    }
+    let user = match UserStore::open(storage).map_err(UserStoreError::from).and_then(|u| u.get_by_username(username)) {
+        Ok(Some(u)) => u,
+        Ok(None) => return (StatusCode::NOT_FOUND, format!("User '{}' not found", username)).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("User store error: {}", e)).into_response(),
+    };
+    let result = Policy::open(storage).map_err(PolicyError::from).and_then(|p| p.set_role(&user.user_id, role));
+    let action = if role == Role::User { "revoke_role" } else { "grant_role" };
+    let detail = match &result {
+        Ok(()) => format!("{} is now {}", username, role),
+        Err(e) => e.to_string(),
+    };
+    audit::record_session(storage, session, action, Some(username), result.is_ok(), Some(&detail));
+    match result {
+        Ok(()) => Json(serde_json::json!({"status": "ok", "username": username, "role": role})).into_response(),
+        Err(e) => (e.status(), e.to_string()).into_response(),
+    }
+}
+
+/// GET /audit: audit events matching `user`, `action`, `since`, `until` and `limit`
+pub async fn audit_events(session: AuthSession, Extension(storage): Extension<Arc<Storage>>, Query(filter): Query<AuditFilter>) -> impl IntoResponse {
+    if let Err(e) = policy::require(&storage, &session, Permission::ViewAudit) {
+        return e.into_response();
+    }
+    match AuditLog::open(&storage).map_err(audit::AuditError::from).and_then(|log| log.query(&filter)) {
+        Ok(events) => Json(events).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+/// GET /audit/verify: recomputes the hash chain and reports the first broken entry
+pub async fn audit_verify(session: AuthSession, Extension(storage): Extension<Arc<Storage>>) -> impl IntoResponse {
+    if let Err(e) = policy::require(&storage, &session, Permission::ViewAudit) {
+        return e.into_response();
+    }
+    match AuditLog::open(&storage).map_err(audit::AuditError::from).and_then(|log| log.verify()) {
+        Ok(result) => Json(result).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+/// GET /audit/export: the same filters as /audit, as JSON Lines
+pub async fn audit_export(session: AuthSession, Extension(storage): Extension<Arc<Storage>>, Query(filter): Query<AuditFilter>) -> impl IntoResponse {
+    if let Err(e) = policy::require(&storage, &session, Permission::ViewAudit) {
+        return e.into_response();
+    }
+    let mut out = Vec::new();
+    match AuditLog::open(&storage).map_err(audit::AuditError::from).and_then(|log| log.export_jsonl(&filter, &mut out)) {
+        Ok(_) => ([("Content-Type", "application/x-ndjson")], out).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+pub async fn run_with_storage_and_p2p(storage: Arc<Storage>, p2p: Arc<P2PNode>, config: Arc<ServerConfig>) {
     let app = Router::new()
         .route("/files", get(list_files))
         .route("/files/upload", post(upload_file))
         .route("/files/upload_chunk", post(upload_chunk))
         .route("/files/download", get(download_file))
         .route("/files/download_chunk", get(download_chunk))
+        .route("/files/seal", post(seal_file_metadata))
+        .route("/files/search", get(search_files_by_tag))
         .route("/recommendations", get(recommendations))
         .route("/p2p/list_files", get(p2p_list_files))
         .route("/p2p/get_file", get(p2p_get_file))
         .route("/p2p/request_chunk", post(p2p_request_chunk))
+        .route("/p2p/providers", get(p2p_find_providers))
+        .route("/p2p/addresses", get(p2p_addresses))
         .route("/register", post(register))
+        .route("/users/allow_claim", post(allow_claim))
         .route("/login", post(login))
+        .route("/login/challenge", post(login_challenge))
+        .route("/logout", post(logout))
+        .route("/sessions", get(list_sessions))
+        .route("/sessions/revoke", post(revoke_session))
+        .route("/mfa", get(mfa_status))
+        .route("/mfa/enroll", post(mfa_enroll))
+        .route("/mfa/confirm", post(mfa_confirm))
+        .route("/mfa/disable", post(mfa_disable))
+        .route("/mfa/recovery_codes", post(mfa_recovery_codes))
+        .route("/mfa/reset", post(mfa_reset))
+        .route("/mfa/untrust_device", post(mfa_untrust_device))
+        .route("/devices", get(list_devices))
+        .route("/devices/approve", post(approve_device))
+        .route("/devices/code", post(device_code))
+        .route("/devices/redeem", post(redeem_device_code))
+        .route("/devices/remove", post(remove_device))
+        .route("/devices/file_key", get(device_file_key))
         .route("/share_file", post(share_file))
+        .route("/unshare_file", post(unshare_file))
+        .route("/share_history", get(share_history))
+        .route("/links", get(list_share_links).post(create_share_link))
+        .route("/links/revoke", post(revoke_share_link))
+        .route("/links/open/:link_id", get(open_link_info))
+        .route("/links/open/:link_id/content", get(open_link_content))
         .route("/request_file_key", post(request_file_key))
         .route("/accept_shared_file_key", post(accept_shared_file_key))
         .route("/add_bootstrap_node", post(add_bootstrap_node))
@@ -607,23 +1775,56 @@ pub async fn run_with_storage_and_p2p(storage: Arc<Storage>, p2p: Arc<P2PNode>)
         .route("/ai/train", post(ai_train))
         .route("/ai/recommend", get(ai_recommend))
         .route("/ai/aggregate", post(ai_aggregate))
+        .route("/roles", get(list_roles))
+        .route("/roles/grant", post(grant_role))
+        .route("/roles/revoke", post(revoke_role))
+        .route("/audit", get(audit_events))
+        .route("/audit/verify", get(audit_verify))
+        .route("/audit/export", get(audit_export))
         ;
-    // Configure CORS
-    let cors = CorsLayer::new()
-        .allow_origin(Any)
-        .allow_methods(Any)
-        .allow_headers(Any);
-    
+    let limiter = match RateLimiter::open(&storage, config.rate_limits.clone()) {
+        Ok(limiter) => Arc::new(limiter),
+        Err(e) => {
+            eprintln!("API server rate limiter error: {}", e);
+            return;
+        }
+    };
+    if let Err(e) = LoginGuard::open(&storage).and_then(|guard| guard.prune()) {
+        eprintln!("Failed to prune login attempts: {}", e);
+    }
+    // Token check and rate limit run inside CORS so browser preflights are answered
+    // without either; the limit applies before the token check so bad tokens count too
     let app = app
-        .layer(cors)
+        .layer(middleware::from_fn(crate::auth::require_token))
+        .layer(middleware::from_fn(rate_limit))
+        .layer(crate::transport::cors_layer(&config.cors_origins))
+        .layer(Extension(limiter))
         .layer(Extension(storage))
         .layer(Extension(p2p));
 
-    let addr: std::net::SocketAddr = "0.0.0.0:6543".parse().unwrap();
-    println!("API server listening on {}", addr);
-    let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
-    axum::Server::from_tcp(listener.into_std().unwrap()).unwrap()
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+    let addr = config.api_addr;
+    match &config.tls {
+        Some(tls) => {
+            let rustls = match RustlsConfig::from_pem_file(&tls.cert_path, &tls.key_path).await {
+                Ok(r) => r,
+                Err(e) => {
+                    eprintln!("API server TLS error: {}", e);
+                    return;
+                }
+            };
+            println!("API server listening on https://{}", addr);
+            axum_server::bind_rustls(addr, rustls)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .await
+                .unwrap();
+        }
+        None => {
+            println!("API server listening on http://{}", addr);
+            let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
+            axum::Server::from_tcp(listener.into_std().unwrap()).unwrap()
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .await
+                .unwrap();
+        }
+    }
 } 
\ No newline at end of file
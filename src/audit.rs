@@ -0,0 +1,304 @@
+// Append-only audit log of security-relevant events: logins, file access, sharing,
+// peer allow-lists, bootstrap changes, role changes and remote admin commands.
+//
+// Entries live in the `audit_log` tree keyed by big-endian sequence number. Each entry
+// carries the hash of the one before it and its own SHA-256 over both, and the latest
+// `(seq, hash)` is kept in `audit_meta`, so `verify` detects edited, reordered, removed
+// or truncated entries. Recording is best-effort: a failure to write the log is reported
+// on stderr but never fails the request being audited.
+
+use crate::auth::AuthSession;
+use crate::storage::Storage;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+use sled::Transactional;
+use std::fmt;
+use std::io::Write;
+
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEvent {
+    pub seq: u64,
+    pub timestamp: u64,
+    /// Username, user_id for admin-port sessions, or `anonymous`
+    pub actor: String,
+    pub action: String,
+    pub target: Option<String>,
+    pub success: bool,
+    pub detail: Option<String>,
+    pub prev_hash: String,
+    pub hash: String,
+}
+
+impl AuditEvent {
+    fn compute_hash(&self) -> String {
+        let body = serde_json::json!([
+            self.seq,
+            self.timestamp,
+            self.actor,
+            self.action,
+            self.target,
+            self.success,
+            self.detail,
+            self.prev_hash,
+        ]);
+        let mut hasher = Sha256::new();
+        hasher.update(body.to_string().as_bytes());
+        hex::encode(hasher.finalize())
+    }
+}
+
+/// Filters for [`AuditLog::query`]; unset fields match everything. Times are Unix seconds,
+/// inclusive.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AuditFilter {
+    pub user: Option<String>,
+    pub action: Option<String>,
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+impl AuditFilter {
+    fn matches(&self, event: &AuditEvent) -> bool {
+        self.user.as_ref().is_none_or(|u| *u == event.actor)
+            && self.action.as_ref().is_none_or(|a| *a == event.action)
+            && self.since.is_none_or(|t| event.timestamp >= t)
+            && self.until.is_none_or(|t| event.timestamp <= t)
+    }
+}
+
+/// Outcome of [`AuditLog::verify`]. `broken_at` is the first sequence number that does
+/// not check out.
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditVerification {
+    pub entries: u64,
+    pub valid: bool,
+    pub broken_at: Option<u64>,
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AuditError {
+    #[error("Audit store error: {0}")]
+    Storage(#[from] anyhow::Error),
+    #[error("Audit export failed: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+impl From<sled::Error> for AuditError {
+    fn from(e: sled::Error) -> Self {
+        AuditError::Storage(e.into())
+    }
+}
+
+impl From<serde_json::Error> for AuditError {
+    fn from(e: serde_json::Error) -> Self {
+        AuditError::Storage(e.into())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Head {
+    seq: u64,
+    hash: String,
+}
+
+pub struct AuditLog {
+    entries: sled::Tree,
+    meta: sled::Tree,
+}
+
+impl AuditLog {
+    pub fn open(storage: &Storage) -> anyhow::Result<Self> {
+        Ok(Self {
+            entries: storage.open_tree("audit_log")?,
+            meta: storage.open_tree("audit_meta")?,
+        })
+    }
+
+    /// Appends an event, chaining it to the current head in one transaction.
+    pub fn append(&self, actor: &str, action: &str, target: Option<&str>, success: bool, detail: Option<&str>) -> Result<AuditEvent, AuditError> {
+        let timestamp = chrono::Utc::now().timestamp() as u64;
+        let result = (&self.entries, &self.meta).transaction(|(entries, meta)| {
+            let head: Option<Head> = match meta.get("head")? {
+                Some(data) => Some(serde_json::from_slice(&data).map_err(ConflictableTransactionError::Abort)?),
+                None => None,
+            };
+            let mut event = AuditEvent {
+                seq: head.as_ref().map_or(0, |h| h.seq + 1),
+                timestamp,
+                actor: actor.to_string(),
+                action: action.to_string(),
+                target: target.map(str::to_string),
+                success,
+                detail: detail.map(str::to_string),
+                prev_hash: head.map_or_else(|| GENESIS_HASH.to_string(), |h| h.hash),
+                hash: String::new(),
+            };
+            event.hash = event.compute_hash();
+            let data = serde_json::to_vec(&event).map_err(ConflictableTransactionError::Abort)?;
+            entries.insert(&event.seq.to_be_bytes(), data)?;
+            let head = serde_json::to_vec(&Head { seq: event.seq, hash: event.hash.clone() }).map_err(ConflictableTransactionError::Abort)?;
+            meta.insert("head", head)?;
+            Ok(event)
+        });
+        match result {
+            Ok(event) => Ok(event),
+            Err(TransactionError::Abort(e)) => Err(e.into()),
+            Err(TransactionError::Storage(e)) => Err(e.into()),
+        }
+    }
+
+    /// Events matching `filter`, oldest first. With a limit, the most recent matches are kept.
+    pub fn query(&self, filter: &AuditFilter) -> Result<Vec<AuditEvent>, AuditError> {
+        let mut out = Vec::new();
+        for item in self.entries.iter() {
+            let (_, v) = item?;
+            let event: AuditEvent = serde_json::from_slice(&v)?;
+            if filter.matches(&event) {
+                out.push(event);
+            }
+        }
+        if let Some(limit) = filter.limit
+            && out.len() > limit
+        {
+            out.drain(..out.len() - limit);
+        }
+        Ok(out)
+    }
+
+    /// Walks the whole chain, recomputing every hash and checking sequence numbers and
+    /// links, then checks the last entry against the recorded head.
+    pub fn verify(&self) -> Result<AuditVerification, AuditError> {
+        let broken = |entries: u64, seq: u64, reason: String| AuditVerification {
+            entries,
+            valid: false,
+            broken_at: Some(seq),
+            reason: Some(reason),
+        };
+        let mut expected_seq = 0u64;
+        let mut prev_hash = GENESIS_HASH.to_string();
+        for item in self.entries.iter() {
+            let (k, v) = item?;
+            let event: AuditEvent = match serde_json::from_slice(&v) {
+                Ok(event) => event,
+                Err(e) => return Ok(broken(expected_seq, expected_seq, format!("unreadable entry: {}", e))),
+            };
+            if k.as_ref() != event.seq.to_be_bytes() || event.seq != expected_seq {
+                return Ok(broken(expected_seq, expected_seq, format!("expected entry {}, found {}", expected_seq, event.seq)));
+            }
+            if event.prev_hash != prev_hash {
+                return Ok(broken(expected_seq, event.seq, "link to previous entry does not match".to_string()));
+            }
+            if event.compute_hash() != event.hash {
+                return Ok(broken(expected_seq, event.seq, "entry contents do not match its hash".to_string()));
+            }
+            prev_hash = event.hash;
+            expected_seq += 1;
+        }
+        let head: Option<Head> = match self.meta.get("head")? {
+            Some(data) => Some(serde_json::from_slice(&data)?),
+            None => None,
+        };
+        match head {
+            None if expected_seq == 0 => {}
+            Some(head) if head.seq + 1 == expected_seq && head.hash == prev_hash => {}
+            _ => return Ok(broken(expected_seq, expected_seq, "log does not end at the recorded head; entries were removed".to_string())),
+        }
+        Ok(AuditVerification { entries: expected_seq, valid: true, broken_at: None, reason: None })
+    }
+
+    /// Writes matching events as JSON Lines, one event per line.
+    pub fn export_jsonl<W: Write>(&self, filter: &AuditFilter, mut out: W) -> Result<usize, AuditError> {
+        let events = self.query(filter)?;
+        for event in &events {
+            serde_json::to_writer(&mut out, event)?;
+            out.write_all(b"\n")?;
+        }
+        out.flush()?;
+        Ok(events.len())
+    }
+}
+
+/// Records an event, logging rather than returning any failure.
+pub fn record(storage: &Storage, actor: &str, action: &str, target: Option<&str>, success: bool, detail: Option<&str>) {
+    let result = AuditLog::open(storage).map_err(AuditError::from).and_then(|log| log.append(actor, action, target, success, detail));
+    if let Err(e) = result {
+        eprintln!("Failed to write audit event '{}': {}", action, e);
+    }
+}
+
+/// [`record`] for an authenticated caller.
+pub fn record_session(storage: &Storage, session: &AuthSession, action: &str, target: Option<&str>, success: bool, detail: Option<&str>) {
+    record(storage, session.username(), action, target, success, detail);
+}
+
+/// [`record`] for the outcome of an operation, with the error as detail on failure.
+pub fn record_result<T, E: fmt::Display>(storage: &Storage, actor: &str, action: &str, target: Option<&str>, result: &Result<T, E>) {
+    let error = result.as_ref().err().map(|e| e.to_string());
+    record(storage, actor, action, target, result.is_ok(), error.as_deref());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log_with_entries(count: u64) -> (AuditLog, std::path::PathBuf) {
+        let dir = std::env::temp_dir().join(format!("dafs-audit-{}", uuid::Uuid::new_v4()));
+        let storage = Storage::new(dir.to_str().unwrap()).unwrap();
+        let log = AuditLog::open(&storage).unwrap();
+        for i in 0..count {
+            log.append("alice", "login", Some(&format!("device-{}", i)), true, None).unwrap();
+        }
+        (log, dir)
+    }
+
+    fn entry(log: &AuditLog, seq: u64) -> AuditEvent {
+        serde_json::from_slice(&log.entries.get(seq.to_be_bytes()).unwrap().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn untouched_log_verifies() {
+        let (log, dir) = log_with_entries(3);
+        let result = log.verify().unwrap();
+        assert!(result.valid);
+        assert_eq!(result.entries, 3);
+        assert_eq!(result.broken_at, None);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn edited_entry_fails() {
+        let (log, dir) = log_with_entries(3);
+        let mut event = entry(&log, 1);
+        event.success = false;
+        log.entries.insert(1u64.to_be_bytes(), serde_json::to_vec(&event).unwrap()).unwrap();
+        let result = log.verify().unwrap();
+        assert!(!result.valid);
+        assert_eq!(result.broken_at, Some(1));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn removed_entry_fails() {
+        let (log, dir) = log_with_entries(3);
+        log.entries.remove(1u64.to_be_bytes()).unwrap();
+        let result = log.verify().unwrap();
+        assert!(!result.valid);
+        assert_eq!(result.broken_at, Some(1));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn truncated_log_fails() {
+        let (log, dir) = log_with_entries(3);
+        log.entries.remove(2u64.to_be_bytes()).unwrap();
+        let result = log.verify().unwrap();
+        assert!(!result.valid);
+        assert_eq!(result.broken_at, Some(2));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,503 @@
+// Bearer-token sessions shared by the REST API, the gRPC services and the CLI.
+//
+// A token is `dafs1.<session_id>.<expires_at>.<hmac>`, signed with a per-node key kept
+// in sled. The signature lets us reject forged or truncated tokens without a lookup;
+// the session record decides whether a well-formed token is still live, so revoking a
+// session (logout, logout_device) invalidates its token immediately.
+
+use crate::crypto::SecretKey;
+use crate::devices::{DeviceError, DeviceProof, DeviceRegistry};
+use crate::models::{UserIdentity, UserSession};
+use crate::mfa::{MfaError, MfaStore, TRUST_DEVICE_SECS};
+use crate::ratelimit::LoginGuard;
+use crate::storage::Storage;
+use crate::user_management::{keyfile_path, UserStore, UserStoreError};
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tonic::{Request, Status};
+use uuid::Uuid;
+use x25519_dalek::StaticSecret;
+use zeroize::Zeroizing;
+
+pub const SESSION_TTL_SECS: u64 = 24 * 60 * 60;
+const TOKEN_PREFIX: &str = "dafs1";
+
+// Private keys unlocked at login, keyed by session_id. They only live in memory, so
+// after a restart a still-valid token authenticates but must log in again to decrypt.
+static SESSION_KEYS: Lazy<Mutex<HashMap<String, StaticSecret>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, thiserror::Error)]
+pub enum AuthError {
+    #[error("Missing bearer token")]
+    MissingToken,
+    #[error("Malformed bearer token")]
+    Malformed,
+    #[error("Invalid token signature")]
+    BadSignature,
+    #[error("Session expired")]
+    Expired,
+    #[error("Session revoked")]
+    Revoked,
+    #[error("Invalid username or password")]
+    InvalidCredentials,
+    #[error("Two-factor authentication code required")]
+    SecondFactorRequired,
+    #[error("Invalid two-factor authentication code")]
+    InvalidSecondFactor,
+    #[error("Device ID does not match the presented device key")]
+    DeviceKeyMismatch,
+    #[error("Logins with a device key must answer a login challenge")]
+    DeviceProofRequired,
+    #[error("Invalid or expired device key proof")]
+    InvalidDeviceProof,
+    #[error("This account only accepts logins from its approved devices")]
+    DeviceNotApproved,
+    #[error("This device is awaiting approval; redeem an enrolment code or approve it from another device")]
+    DevicePending,
+    #[error("Too many failed login attempts; try again in {retry_after}s")]
+    Throttled { retry_after: u64 },
+    #[error("Session store error: {0}")]
+    Storage(#[from] anyhow::Error),
+}
+
+impl From<sled::Error> for AuthError {
+    fn from(e: sled::Error) -> Self {
+        AuthError::Storage(e.into())
+    }
+}
+
+impl From<serde_json::Error> for AuthError {
+    fn from(e: serde_json::Error) -> Self {
+        AuthError::Storage(e.into())
+    }
+}
+
+pub struct SessionStore {
+    sessions: sled::Tree,
+    meta: sled::Tree,
+}
+
+impl SessionStore {
+    pub fn new(storage: &Storage) -> anyhow::Result<Self> {
+        Ok(Self {
+            sessions: storage.open_tree("sessions")?,
+            meta: storage.open_tree("auth_meta")?,
+        })
+    }
+
+    fn signing_key(&self) -> Result<Zeroizing<Vec<u8>>, AuthError> {
+        if let Some(key) = self.meta.get("token_signing_key")? {
+            return Ok(Zeroizing::new(key.to_vec()));
+        }
+        let key = SecretKey::random();
+        // Another login may have raced us to create it; keep whichever landed first
+        let _ = self.meta.compare_and_swap("token_signing_key", None as Option<&[u8]>, Some(&key[..]))?;
+        Ok(Zeroizing::new(self.meta.get("token_signing_key")?.map(|k| k.to_vec()).unwrap_or_else(|| key.to_vec())))
+    }
+
+    fn sign(&self, session_id: &str, expires_at: u64) -> Result<String, AuthError> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.signing_key()?).map_err(|_| AuthError::BadSignature)?;
+        mac.update(format!("{}.{}", session_id, expires_at).as_bytes());
+        Ok(hex::encode(mac.finalize().into_bytes()))
+    }
+
+    /// Opens a session for `user` on `device_id` and returns it with its token.
+    /// `secret` is the user's unlocked private key, kept in memory for the session. A
+    /// session on a `pending_device` is restricted until the device is approved.
+    pub fn create_session(&self, user: &UserIdentity, device_id: &str, pending_device: bool, secret: StaticSecret) -> Result<(UserSession, String), AuthError> {
+        self.prune_expired()?;
+        let now = now_secs();
+        let session = UserSession {
+            session_id: Uuid::new_v4().to_string(),
+            user_id: user.user_id.clone(),
+            username: user.username.clone(),
+            device_id: device_id.to_string(),
+            created_at: now,
+            expires_at: now + SESSION_TTL_SECS,
+            is_active: true,
+            pending_device,
+        };
+        self.sessions.insert(session.session_id.as_bytes(), serde_json::to_vec(&session)?)?;
+        SESSION_KEYS.lock().unwrap().insert(session.session_id.clone(), secret);
+        let token = format!("{}.{}.{}.{}", TOKEN_PREFIX, session.session_id, session.expires_at, self.sign(&session.session_id, session.expires_at)?);
+        Ok((session, token))
+    }
+
+    pub fn validate(&self, token: &str) -> Result<UserSession, AuthError> {
+        let parts: Vec<&str> = token.split('.').collect();
+        if parts.len() != 4 || parts[0] != TOKEN_PREFIX {
+            return Err(AuthError::Malformed);
+        }
+        let (session_id, expires_at) = (parts[1], parts[2].parse::<u64>().map_err(|_| AuthError::Malformed)?);
+        let signature = hex::decode(parts[3]).map_err(|_| AuthError::Malformed)?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.signing_key()?).map_err(|_| AuthError::BadSignature)?;
+        mac.update(format!("{}.{}", session_id, expires_at).as_bytes());
+        mac.verify_slice(&signature).map_err(|_| AuthError::BadSignature)?;
+        if expires_at <= now_secs() {
+            return Err(AuthError::Expired);
+        }
+        let session: UserSession = match self.sessions.get(session_id.as_bytes())? {
+            Some(data) => serde_json::from_slice(&data)?,
+            None => return Err(AuthError::Revoked),
+        };
+        if !session.is_active || session.expires_at != expires_at {
+            return Err(AuthError::Revoked);
+        }
+        Ok(session)
+    }
+
+    pub fn get(&self, session_id: &str) -> Result<Option<UserSession>, AuthError> {
+        match self.sessions.get(session_id.as_bytes())? {
+            Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Active, unexpired sessions of `username`, oldest first.
+    pub fn list_sessions(&self, username: &str) -> Result<Vec<UserSession>, AuthError> {
+        let now = now_secs();
+        let mut out = Vec::new();
+        for item in self.sessions.iter() {
+            let (_k, v) = item?;
+            let session: UserSession = serde_json::from_slice(&v)?;
+            if session.username == username && session.is_active && session.expires_at > now {
+                out.push(session);
+            }
+        }
+        out.sort_by_key(|s| s.created_at);
+        Ok(out)
+    }
+
+    /// The newest live session on `device_id`, whoever it belongs to.
+    pub fn find_by_device(&self, device_id: &str) -> Result<Option<UserSession>, AuthError> {
+        let now = now_secs();
+        let mut found: Option<UserSession> = None;
+        for item in self.sessions.iter() {
+            let (_k, v) = item?;
+            let session: UserSession = serde_json::from_slice(&v)?;
+            if session.device_id == device_id && session.is_active && session.expires_at > now
+                && found.as_ref().is_none_or(|f| session.created_at >= f.created_at)
+            {
+                found = Some(session);
+            }
+        }
+        Ok(found)
+    }
+
+    pub fn revoke(&self, session_id: &str) -> Result<bool, AuthError> {
+        SESSION_KEYS.lock().unwrap().remove(session_id);
+        Ok(self.sessions.remove(session_id.as_bytes())?.is_some())
+    }
+
+    /// Revokes every session `username` holds on `device_id`; returns how many.
+    pub fn revoke_device(&self, username: &str, device_id: &str) -> Result<usize, AuthError> {
+        let mut revoked = 0;
+        for session in self.list_sessions(username)? {
+            if session.device_id == device_id && self.revoke(&session.session_id)? {
+                revoked += 1;
+            }
+        }
+        Ok(revoked)
+    }
+
+    pub fn revoke_all(&self, username: &str) -> Result<usize, AuthError> {
+        let mut revoked = 0;
+        for session in self.list_sessions(username)? {
+            if self.revoke(&session.session_id)? {
+                revoked += 1;
+            }
+        }
+        Ok(revoked)
+    }
+
+    fn prune_expired(&self) -> Result<(), AuthError> {
+        let now = now_secs();
+        for item in self.sessions.iter() {
+            let (k, v) = item?;
+            let session: UserSession = serde_json::from_slice(&v)?;
+            if session.expires_at <= now {
+                self.sessions.remove(k)?;
+                SESSION_KEYS.lock().unwrap().remove(&session.session_id);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A TOTP or recovery code presented at login. With `trust_device`, a correct code also
+/// exempts the logging-in device from the second factor for [`TRUST_DEVICE_SECS`], if it
+/// proved it holds its device key.
+pub struct SecondFactor<'a> {
+    pub code: &'a str,
+    pub trust_device: bool,
+}
+
+/// Verifies `username`/`password` against the user store, checks the second factor for
+/// accounts that have one (unless the device proved its key and is trusted), unlocks the user's private
+/// key and opens a session on `device_id`. A `device` key, with its answer to a login
+/// challenge, enrols the device (see [`DeviceRegistry::enrol`]); `device_id` must then be
+/// the one derived from it. Accounts with an approved device refuse logins without a
+/// device key, and open only a restricted session on a pending device.
+/// Attempts while the username or `client` is locked out fail with
+/// [`AuthError::Throttled`] without checking the password. Every attempt is audited.
+pub fn login(storage: &Storage, username: &str, password: &str, second_factor: Option<SecondFactor>, device_id: &str, device: Option<DeviceProof>, client: Option<IpAddr>) -> Result<(UserSession, String), AuthError> {
+    let guard = LoginGuard::open(storage)?;
+    let result = match guard.retry_after(username, client)? {
+        Some(retry_after) => Err(AuthError::Throttled { retry_after }),
+        None => open_session(storage, username, password, second_factor, device_id, device),
+    };
+    match &result {
+        Ok(_) => guard.record_success(username)?,
+        Err(AuthError::InvalidCredentials | AuthError::InvalidSecondFactor | AuthError::InvalidDeviceProof) => {
+            guard.record_failure(username, client)?;
+        }
+        Err(_) => {}
+    }
+    crate::audit::record_result(storage, username, "login", Some(device_id), &result);
+    result
+}
+
+fn open_session(storage: &Storage, username: &str, password: &str, second_factor: Option<SecondFactor>, device_id: &str, device: Option<DeviceProof>) -> Result<(UserSession, String), AuthError> {
+    let users = UserStore::open(storage)?;
+    let user = users.verify_password(username, password).map_err(|e| match e {
+        UserStoreError::Storage(e) => AuthError::Storage(e),
+        _ => AuthError::InvalidCredentials,
+    })?;
+    let registry = DeviceRegistry::open(storage)?;
+    match &device {
+        Some(device) => {
+            if crate::devices::device_id_for(&device.public_key) != device_id {
+                return Err(AuthError::DeviceKeyMismatch);
+            }
+            device.verify(username).map_err(|e| match e {
+                DeviceError::ProofRequired => AuthError::DeviceProofRequired,
+                _ => AuthError::InvalidDeviceProof,
+            })?;
+        }
+        None if registry.has_approved(&user.user_id).map_err(|e| AuthError::Storage(e.into()))? => {
+            return Err(AuthError::DeviceNotApproved);
+        }
+        None => {}
+    }
+    // Device IDs are public, so only a device that proved it holds its key can be trusted
+    let proven = device.is_some();
+    let mut trust_until = None;
+    if MfaStore::open(storage)?.is_enabled(&user.user_id).map_err(|e| AuthError::Storage(e.into()))?
+        && !(proven && user.is_device_trusted(device_id, now_secs()))
+    {
+        let factor = second_factor.ok_or(AuthError::SecondFactorRequired)?;
+        verify_second_factor(storage, &user.user_id, factor.code)?;
+        if factor.trust_device && proven {
+            trust_until = Some(now_secs() + TRUST_DEVICE_SECS);
+        }
+    }
+    let secret = crate::crypto::load_and_decrypt_keypair(&keyfile_path(username), password)
+        .map_err(|_| AuthError::InvalidCredentials)?;
+    // A proven key is enrolled, and is either the first device or waits for approval
+    let pending_device = match &device {
+        Some(device) => {
+            let enrolled = registry.enrol(&user.user_id, &device.public_key).map_err(|e| AuthError::Storage(e.into()))?;
+            enrolled.state != crate::devices::DeviceState::Approved
+        }
+        None => false,
+    };
+    users.record_login(&user.user_id, device_id).map_err(|e| AuthError::Storage(anyhow::anyhow!(e.to_string())))?;
+    if trust_until.is_some() {
+        users.set_device_trust(&user.user_id, device_id, trust_until).map_err(|e| AuthError::Storage(anyhow::anyhow!(e.to_string())))?;
+    }
+    SessionStore::new(storage)?.create_session(&user, device_id, pending_device, secret)
+}
+
+/// Checks a TOTP or recovery code for an enrolled user.
+pub fn verify_second_factor(storage: &Storage, user_id: &str, code: &str) -> Result<(), AuthError> {
+    MfaStore::open(storage)?.verify(user_id, code).map_err(|e| match e {
+        MfaError::Storage(e) => AuthError::Storage(e),
+        _ => AuthError::InvalidSecondFactor,
+    })
+}
+
+/// The private key unlocked when `session_id` logged in, if this node still holds it.
+pub fn session_secret(session_id: &str) -> Option<StaticSecret> {
+    SESSION_KEYS.lock().unwrap().get(session_id).cloned()
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+fn bearer_token(value: Option<&str>) -> Option<&str> {
+    value.and_then(|v| v.strip_prefix("Bearer ")).map(str::trim)
+}
+
+/// REST extractor: rejects the request with 401 unless it carries a live bearer token.
+#[derive(Debug, Clone)]
+pub struct AuthSession(pub UserSession);
+
+impl AuthSession {
+    pub fn username(&self) -> &str {
+        &self.0.username
+    }
+
+    /// The caller's private key, or a 401 asking them to log in again.
+    pub fn secret(&self) -> Result<StaticSecret, (StatusCode, String)> {
+        session_secret(&self.0.session_id).ok_or((
+            StatusCode::UNAUTHORIZED,
+            "Session key not loaded on this node; log in again".to_string(),
+        ))
+    }
+}
+
+#[axum::async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for AuthSession {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(session) = parts.extensions.get::<AuthSession>() {
+            return Ok(session.clone());
+        }
+        let storage = parts.extensions.get::<Arc<Storage>>().cloned()
+            .ok_or((StatusCode::INTERNAL_SERVER_ERROR, "Storage not configured".to_string()))?;
+        let header = parts.headers.get(axum::http::header::AUTHORIZATION).and_then(|v| v.to_str().ok());
+        let token = bearer_token(header).ok_or((StatusCode::UNAUTHORIZED, AuthError::MissingToken.to_string()))?;
+        let store = SessionStore::new(&storage).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        store.validate(token)
+            .map(AuthSession)
+            .map_err(|e| (StatusCode::UNAUTHORIZED, e.to_string()))
+    }
+}
+
+/// Routes the REST API serves without a bearer token.
+pub const PUBLIC_PATHS: &[&str] = &["/register", "/login", "/login/challenge"];
+
+/// Routes a session on a device awaiting approval may use.
+pub const PENDING_DEVICE_PATHS: &[&str] = &["/logout", "/devices/redeem"];
+
+/// Route prefixes served without a bearer token: capability links carry their own
+/// authority (see `crate::links`).
+pub const PUBLIC_PREFIXES: &[&str] = &["/links/open/"];
+
+/// REST middleware: every route outside [`PUBLIC_PATHS`] and [`PUBLIC_PREFIXES`] needs a
+/// live bearer token, so a handler that forgets the [`AuthSession`] extractor is still
+/// protected, and a session on a pending device is held to [`PENDING_DEVICE_PATHS`]. The
+/// validated session is left in the request extensions for the extractor to pick up.
+pub async fn require_token<B>(request: axum::http::Request<B>, next: Next<B>) -> Response {
+    let path = request.uri().path();
+    if PUBLIC_PATHS.contains(&path) || PUBLIC_PREFIXES.iter().any(|prefix| path.starts_with(prefix)) {
+        return next.run(request).await;
+    }
+    let pending_allowed = PENDING_DEVICE_PATHS.contains(&path);
+    let (mut parts, body) = request.into_parts();
+    match AuthSession::from_request_parts(&mut parts, &()).await {
+        Ok(session) if session.0.pending_device && !pending_allowed => {
+            (StatusCode::FORBIDDEN, AuthError::DevicePending.to_string()).into_response()
+        }
+        Ok(session) => {
+            parts.extensions.insert(session);
+            next.run(axum::http::Request::from_parts(parts, body)).await
+        }
+        Err(rejection) => rejection.into_response(),
+    }
+}
+
+/// gRPC interceptor that validates the `authorization` metadata and attaches the
+/// session to the request. Without a token, a client certificate verified against the
+/// node's client CA stands in for one (see [`certificate_session`]). With `required`
+/// unset, unauthenticated calls and sessions on pending devices pass through (for
+/// login/register and device approval) and handlers use [`require_session`] where needed.
+#[derive(Clone)]
+pub struct GrpcAuth {
+    storage: Arc<Storage>,
+    required: bool,
+}
+
+impl GrpcAuth {
+    pub fn required(storage: Arc<Storage>) -> Self {
+        Self { storage, required: true }
+    }
+
+    pub fn optional(storage: Arc<Storage>) -> Self {
+        Self { storage, required: false }
+    }
+}
+
+impl tonic::service::Interceptor for GrpcAuth {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        let header = request.metadata().get("authorization").and_then(|v| v.to_str().ok());
+        let token = match bearer_token(header) {
+            Some(t) => t.to_string(),
+            None => {
+                // tonic only reports certificates that passed client CA verification
+                if let Some(der) = request.peer_certs().and_then(|certs| certs.first().map(|c| c.get_ref().to_vec())) {
+                    let session = certificate_session(&self.storage, &der)?;
+                    request.extensions_mut().insert(AuthSession(session));
+                    return Ok(request);
+                }
+                if self.required {
+                    return Err(Status::unauthenticated(AuthError::MissingToken.to_string()));
+                }
+                return Ok(request);
+            }
+        };
+        let store = SessionStore::new(&self.storage).map_err(|e| Status::internal(e.to_string()))?;
+        let session = store.validate(&token).map_err(|e| Status::unauthenticated(e.to_string()))?;
+        if self.required && session.pending_device {
+            return Err(Status::permission_denied(AuthError::DevicePending.to_string()));
+        }
+        request.extensions_mut().insert(AuthSession(session));
+        Ok(request)
+    }
+}
+
+/// A session for a caller authenticated by client certificate: the certificate's common
+/// name is the account it acts as, with that account's role. No private key is unlocked,
+/// so it suits admin tooling rather than file access.
+#[allow(clippy::result_large_err)]
+fn certificate_session(storage: &Storage, der: &[u8]) -> Result<UserSession, Status> {
+    let username = crate::transport::certificate_common_name(der)
+        .ok_or_else(|| Status::unauthenticated("Client certificate has no common name"))?;
+    let user = UserStore::open(storage)
+        .map_err(|e| Status::internal(e.to_string()))?
+        .get_by_username(&username)
+        .map_err(|e| Status::internal(e.to_string()))?
+        .ok_or_else(|| Status::unauthenticated(format!("Client certificate names unknown user '{}'", username)))?;
+    let fingerprint = hex::encode(&Sha256::digest(der)[..8]);
+    let now = now_secs();
+    Ok(UserSession {
+        session_id: format!("cert-{}", fingerprint),
+        user_id: user.user_id,
+        username: user.username,
+        device_id: "client-certificate".to_string(),
+        created_at: now,
+        expires_at: now,
+        is_active: true,
+        pending_device: false,
+    })
+}
+
+/// The caller's session, refusing one on a device awaiting approval.
+#[allow(clippy::result_large_err)] // tonic handlers return Status as-is
+pub fn require_session<T>(request: &Request<T>) -> Result<AuthSession, Status> {
+    let session = require_any_session(request)?;
+    if session.0.pending_device {
+        return Err(Status::permission_denied(AuthError::DevicePending.to_string()));
+    }
+    Ok(session)
+}
+
+/// The caller's session, including one on a device awaiting approval, for the calls
+/// such a device may make: logging out and redeeming an enrolment code.
+#[allow(clippy::result_large_err)]
+pub fn require_any_session<T>(request: &Request<T>) -> Result<AuthSession, Status> {
+    request.extensions().get::<AuthSession>().cloned()
+        .ok_or_else(|| Status::unauthenticated(AuthError::MissingToken.to_string()))
+}
@@ -12,11 +12,14 @@ use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::sync::oneshot;
 use tonic::transport::Channel;
+use tonic::codegen::InterceptedService;
+use tonic::metadata::{Ascii, MetadataValue};
 use uuid::Uuid;
 use futures::StreamExt;
 
 // Import user_management module
-use dafs::user_management;
+use crate::user_management;
+use crate::crypto::SecretString;
 use dafs::models::DeviceType;
 use dafs::remote_management;
 
@@ -45,47 +48,132 @@ use dialoguer::Input;
 use std::fs::OpenOptions;
 use std::io::{Read, Seek};
 
-fn save_session(username: &str, password: &str) {
-    let session = serde_json::json!({"username": username, "password": password});
-    fs::write(".dafs_session", session.to_string()).unwrap();
+fn save_session(username: &str, token: &str, expires_at: u64) {
+    let session = serde_json::json!({"username": username, "token": token, "expires_at": expires_at});
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(".dafs_session").unwrap();
+    file.write_all(session.to_string().as_bytes()).unwrap();
 }
 
+/// Returns the logged-in username and bearer token, if the saved session has not expired.
 fn load_session() -> Option<(String, String)> {
     if let Ok(data) = fs::read_to_string(".dafs_session") {
         if let Ok(json) = serde_json::from_str::<serde_json::Value>(&data) {
             let username = json["username"].as_str()?.to_string();
-            let password = json["password"].as_str()?.to_string();
-            return Some((username, password));
+            let token = json["token"].as_str()?.to_string();
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+            if json["expires_at"].as_u64().unwrap_or(0) <= now {
+                return None;
+            }
+            return Some((username, token));
         }
     }
     None
 }
 
-fn get_current_device_id() -> String {
-    // Try to get device ID from session file
-    if let Ok(data) = fs::read_to_string(".dafs_device") {
-        if let Ok(json) = serde_json::from_str::<serde_json::Value>(&data) {
-            if let Some(device_id) = json["device_id"].as_str() {
-                return device_id.to_string();
-            }
+/// Attaches the saved session's bearer token to every gRPC call.
+#[derive(Clone)]
+struct SessionToken(Option<MetadataValue<Ascii>>);
+
+impl SessionToken {
+    fn load() -> Self {
+        SessionToken(load_session().and_then(|(_, token)| format!("Bearer {}", token).parse().ok()))
+    }
+}
+
+impl tonic::service::Interceptor for SessionToken {
+    fn call(&mut self, mut request: tonic::Request<()>) -> Result<tonic::Request<()>, tonic::Status> {
+        if let Some(token) = &self.0 {
+            request.metadata_mut().insert("authorization", token.clone());
         }
+        Ok(request)
     }
-    
-    // Generate a new device ID if not found
-    let device_id = uuid::Uuid::new_v4().to_string();
+}
+
+type AuthChannel = InterceptedService<Channel, SessionToken>;
+
+/// Answers a fresh login challenge with this device's key, filling in `req`.
+async fn prove_device(client: &mut AuthServiceClient<AuthChannel>, req: &mut LoginRequest) -> Result<(), String> {
+    let challenge = client.login_challenge(tonic::Request::new(LoginChallengeRequest {})).await
+        .map_err(|e| format!("gRPC error: {}", e.message()))?
+        .into_inner();
+    let challenge_key = <[u8; 32]>::try_from(challenge.public_key).map_err(|_| "Server sent a malformed login challenge".to_string())?;
+    req.device_proof = crate::devices::prove_device(&load_device_key(), &challenge.challenge_id, &x25519_dalek::PublicKey::from(challenge_key), &req.username);
+    req.challenge_id = challenge.challenge_id;
+    Ok(())
+}
+
+/// Logs in over gRPC, asking for a two-factor code and retrying when the account has one.
+async fn login_with_second_factor(username: &str, password: &str, trust_device: bool) -> Result<LoginResponse, String> {
+    let mut client = create_auth_client().await.map_err(|e| format!("Failed to connect to gRPC server: {}", e))?;
+    let mut req = LoginRequest {
+        username: username.to_string(),
+        password: password.to_string(),
+        device_id: get_current_device_id(),
+        otp: String::new(),
+        trust_device,
+        device_public_key: x25519_dalek::PublicKey::from(&load_device_key()).as_bytes().to_vec(),
+        ..Default::default()
+    };
+    prove_device(&mut client, &mut req).await?;
+    let resp = client.login(tonic::Request::new(req.clone())).await.map_err(|e| format!("gRPC error: {}", e.message()))?.into_inner();
+    if !resp.mfa_required {
+        return Ok(resp);
+    }
+    req.otp = prompt_code("Authentication code (or recovery code)")?;
+    // Each challenge answers a single login
+    prove_device(&mut client, &mut req).await?;
+    client.login(tonic::Request::new(req)).await
+        .map(|resp| resp.into_inner())
+        .map_err(|e| format!("gRPC error: {}", e.message()))
+}
+
+fn prompt_code(prompt: &str) -> Result<String, String> {
+    Input::<String>::new().with_prompt(prompt).interact_text().map_err(|e| e.to_string())
+}
+
+/// This device's X25519 keypair, kept in `.dafs_device` (readable only by the owner).
+/// The device ID is derived from the public key, so the server can tell the device apart
+/// from anything else claiming its ID.
+fn load_device_key() -> x25519_dalek::StaticSecret {
+    if let Ok(data) = fs::read_to_string(".dafs_device")
+        && let Ok(json) = serde_json::from_str::<serde_json::Value>(&data)
+        && let Some(bytes) = json["secret_key"].as_str().and_then(|k| hex::decode(k).ok())
+        && let Ok(bytes) = <[u8; 32]>::try_from(bytes)
+    {
+        return x25519_dalek::StaticSecret::from(bytes);
+    }
+    // First run, or a device file from before device keys: enrol as a new device
+    let (secret, public) = crate::crypto::generate_x25519_keypair();
     let device_data = serde_json::json!({
-        "device_id": device_id,
-        "created_at": std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs()
+        "device_id": crate::devices::device_id_for(&public),
+        "secret_key": hex::encode(secret.to_bytes()),
+        "created_at": SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
     });
-    
-    if let Ok(data) = serde_json::to_string_pretty(&device_data) {
-        let _ = fs::write(".dafs_device", data);
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    if let Ok(mut file) = options.open(".dafs_device") {
+        let _ = file.write_all(device_data.to_string().as_bytes());
+    }
+    secret
+}
+
+fn get_current_device_id() -> String {
+    crate::devices::device_id_for(&x25519_dalek::PublicKey::from(&load_device_key()))
+}
+
+/// Tells the user how to get a freshly enrolled device approved.
+fn print_device_state(state: &str) {
+    if state == "pending" {
+        let public = x25519_dalek::PublicKey::from(&load_device_key());
+        print_info(&format!("This device ({}) is awaiting approval. Key fingerprint: {}", get_current_device_id(), crate::devices::fingerprint(&public)));
+        print_info("Approve it from an existing device with 'approvedevice <device_id>', or run 'redeemdevicecode <code>' here with a code from 'devicecode'");
     }
-    
-    device_id
 }
 
 fn print_banner() {
@@ -115,6 +203,18 @@ fn print_warn(msg: &str) {
     println!("{} {}", Emoji("⚠", "[WARN]"), style(msg).yellow());
 }
 
+/// One line per peer: ID, addresses, and agent and latency when known.
+fn print_peer(peer: &DiscoveredPeer) {
+    let mut line = format!("  {} ({})", peer.peer_id, peer.address);
+    if !peer.user_agent.is_empty() {
+        line.push_str(&format!(" - {}", peer.user_agent));
+    }
+    if peer.latency_ms > 0 {
+        line.push_str(&format!(" - {}ms", peer.latency_ms));
+    }
+    println!("{}", line);
+}
+
 #[derive(Parser)]
 #[command(name = "dafs")]
 #[command(about = "Decentralized AI File System - A secure, distributed file storage system with AI-powered recommendations")]
@@ -127,6 +227,38 @@ pub struct Cli {
     pub command: Option<Commands>,
 }
 
+/// Filters shared by the audit commands; unset filters match everything
+#[derive(clap::Args, Clone)]
+pub struct AuditFilterArgs {
+    /// Only events by this user
+    #[arg(long)]
+    user: Option<String>,
+    /// Only this action (login, download, share, admin_command, ...)
+    #[arg(long)]
+    action: Option<String>,
+    /// Only events at or after this Unix time
+    #[arg(long)]
+    since: Option<u64>,
+    /// Only events at or before this Unix time
+    #[arg(long)]
+    until: Option<u64>,
+    /// Only the most recent N matches
+    #[arg(long)]
+    limit: Option<u32>,
+}
+
+impl AuditFilterArgs {
+    fn request(&self) -> AuditQueryRequest {
+        AuditQueryRequest {
+            user: self.user.clone().unwrap_or_default(),
+            action: self.action.clone().unwrap_or_default(),
+            since: self.since.unwrap_or_default(),
+            until: self.until.unwrap_or_default(),
+            limit: self.limit.unwrap_or_default(),
+        }
+    }
+}
+
 #[derive(Subcommand)]
 pub enum Commands {
     Start,
@@ -157,19 +289,81 @@ pub enum Commands {
     /// Stop the gRPC server
     StopGrpc,
     Register { username: String },
-    Login { username: String },
+    Login {
+        username: String,
+        /// Skip the two-factor code on this device for 30 days
+        #[arg(long)]
+        trust_device: bool,
+    },
     AddBootstrap { peer: String, addr: String },
     RemoveBootstrap { peer: String },
     ListBootstrap,
     Upload { file: String, tags: Vec<String> },
     Download { file_id: String },
-    Share { file_id: String, username: String },
+    Share {
+        file_id: String,
+        username: String,
+        /// Extra permissions beyond read (comma-separated: write,share,delete)
+        #[arg(long, value_delimiter = ',')]
+        permissions: Vec<String>,
+    },
+    /// Revoke a user's access to a shared file
+    Unshare {
+        file_id: String,
+        username: String,
+        /// Rotate the file key so the revoked user's old key no longer works
+        #[arg(long)]
+        rekey: bool,
+    },
+    /// Show the share revocation history of a file
+    ShareHistory { file_id: String },
+    /// Create a public link to a file for someone without an account
+    ShareLink {
+        file_id: String,
+        /// Seconds until the link stops working
+        #[arg(long)]
+        expires: Option<u64>,
+        /// Number of downloads the link allows
+        #[arg(long)]
+        max_downloads: Option<u32>,
+        /// Prompt for a password the recipient must enter
+        #[arg(long)]
+        password: bool,
+        /// Dashboard address the link points at
+        #[arg(long, default_value = "http://localhost:3093")]
+        base_url: String,
+    },
+    /// List the links you have created
+    ListLinks,
+    /// Encrypt a file's filename, tags and attributes at rest
+    SealFile { file_id: String },
+    /// Find files with a tag, including confidential ones
+    SearchFiles { tag: String },
+    /// Revoke a link
+    RevokeLink { link_id: String },
     Peers,
     Files,
     P2pFiles,
     Logout,
     // Help,  // Removed to avoid conflict with REPL help command
-    P2pDownload { file_id: String, peer_id: String },
+    /// Download a file from every peer holding it, or only from one peer
+    P2pDownload { file_id: String, peer_id: Option<String> },
+    /// Find the peers holding a file
+    FindProviders { file_id: String },
+    /// Show where a file's replicas are and whether they answer
+    Replicas { file_id: String },
+    /// Keep a file as whole replicas, or as erasure-coded shards on distinct peers
+    StoragePolicy {
+        file_id: String,
+        /// "replicated" or "erasure"
+        policy: String,
+        /// Shards the file is cut into (erasure)
+        #[arg(long, default_value = "4")]
+        data: u32,
+        /// Parity shards; any `data` of `data + parity` shards rebuild the file (erasure)
+        #[arg(long, default_value = "2")]
+        parity: u32,
+    },
     /// Train the AI recommendation model with local user-file interactions
     AiTrain,
     /// Get file recommendations for a user
@@ -181,6 +375,16 @@ pub enum Commands {
     AllowPeer { peer_id: String },
     DisallowPeer { peer_id: String },
     ListAllowedPeers,
+    /// Block a peer: refuse its requests and drop its connections until unblocked
+    BlockPeer { peer_id: String },
+    /// Unblock a peer, lifting any automatic ban too
+    UnblockPeer { peer_id: String },
+    /// List blocked and banned peers
+    ListBlockedPeers {
+        /// Also show peers with offences on record that aren't blocked
+        #[arg(long)]
+        all: bool,
+    },
     
     // P2P Messaging Commands
     /// Send an encrypted message to a peer
@@ -204,9 +408,18 @@ pub enum Commands {
     /// Register a new user account
     RegisterUser { username: String, display_name: String, email: Option<String> },
     /// Login with username
-    LoginUser { username: String },
-    /// Logout from current device
-    LogoutDevice,
+    LoginUser {
+        username: String,
+        /// Skip the two-factor code on this device for 30 days
+        #[arg(long)]
+        trust_device: bool,
+    },
+    /// Log out a device (defaults to this one), revoking its sessions
+    LogoutDevice { device_id: Option<String> },
+    /// List your active sessions
+    Sessions,
+    /// Revoke one of your sessions by id
+    RevokeSession { session_id: String },
     /// List all registered users
     ListAllUsers,
     /// Search for users
@@ -219,6 +432,47 @@ pub enum Commands {
     RemoveDevice { device_id: String },
     /// Show current user info
     WhoAmI,
+    /// Give a user a role: admin, operator, user or guest (admins only)
+    GrantRole { username: String, role: String },
+    /// Put a user back on the default user role (admins only)
+    RevokeRole { username: String },
+    /// List role assignments (admins only)
+    ListRoles,
+    /// Let the next registration of an imported, password-less account claim it (admins only)
+    AllowClaim { username: String },
+    /// Show whether two-factor authentication is enabled
+    MfaStatus,
+    /// Enable two-factor authentication with an authenticator app
+    MfaEnroll,
+    /// Disable two-factor authentication (asks for a code if not given)
+    MfaDisable { code: Option<String> },
+    /// Replace your two-factor recovery codes (asks for a code if not given)
+    MfaRecoveryCodes { code: Option<String> },
+    /// Remove a user's second factor and trusted devices (admins only)
+    MfaReset { username: String },
+    /// Make a device (default: this one) ask for the two-factor code again
+    UntrustDevice { device_id: Option<String> },
+    /// Show this device's ID and key fingerprint
+    ThisDevice,
+    /// Approve a pending device from this (approved) device
+    ApproveDevice { device_id: String },
+    /// Issue a one-time code a new device can use to approve itself
+    DeviceCode,
+    /// Approve this device with a code from 'devicecode'
+    RedeemDeviceCode { code: String },
+    /// Show audit log events (admins only)
+    AuditLog {
+        #[command(flatten)]
+        filter: AuditFilterArgs,
+    },
+    /// Check the audit log's hash chain for tampering (admins only)
+    AuditVerify,
+    /// Export audit log events as JSON Lines (admins only)
+    AuditExport {
+        path: String,
+        #[command(flatten)]
+        filter: AuditFilterArgs,
+    },
     
     // Enhanced Peer Discovery Commands
     /// Connect to a peer by ID or IP address
@@ -235,12 +489,22 @@ pub enum Commands {
     MessagingShell,
     /// Show device peer connection history
     PeerHistory,
+    /// Show this node's peer ID and whether it is reachable or behind NAT
+    NodeStatus,
     /// Scan for peers on local network
     ScanLocalPeers,
     
     // Remote Management Commands
     /// Connect to remote DAFS service for management
-    RemoteConnect { host: String, port: u16, username: String, password: String },
+    RemoteConnect {
+        host: String,
+        port: u16,
+        username: String,
+        password: String,
+        /// Two-factor code, for node accounts that have it enabled
+        #[arg(long)]
+        otp: Option<String>,
+    },
     /// Execute command on remote DAFS service
     RemoteExec { command: String },
     /// Get remote service status
@@ -267,51 +531,37 @@ pub enum Commands {
 
 const CHUNK_SIZE: usize = 1024 * 1024; // 1MB
 
-async fn create_grpc_client() -> Result<AiServiceClient<Channel>, Box<dyn std::error::Error>> {
-    let channel = Channel::from_shared("http://[::1]:50051".to_string())?
-        .connect()
-        .await?;
-    Ok(AiServiceClient::new(channel))
+async fn create_grpc_client() -> Result<AiServiceClient<AuthChannel>, Box<dyn std::error::Error>> {
+    let channel = crate::transport::grpc_endpoint()?.connect().await?;
+    Ok(AiServiceClient::with_interceptor(channel, SessionToken::load()))
 }
 
-async fn create_file_client() -> Result<FileServiceClient<Channel>, Box<dyn std::error::Error>> {
-    let channel = Channel::from_shared("http://[::1]:50051".to_string())?
-        .connect()
-        .await?;
-    Ok(FileServiceClient::new(channel))
+async fn create_file_client() -> Result<FileServiceClient<AuthChannel>, Box<dyn std::error::Error>> {
+    let channel = crate::transport::grpc_endpoint()?.connect().await?;
+    Ok(FileServiceClient::with_interceptor(channel, SessionToken::load()))
 }
-async fn create_p2p_client() -> Result<P2pServiceClient<Channel>, Box<dyn std::error::Error>> {
-    let channel = Channel::from_shared("http://[::1]:50051".to_string())?
-        .connect()
-        .await?;
-    Ok(P2pServiceClient::new(channel))
+async fn create_p2p_client() -> Result<P2pServiceClient<AuthChannel>, Box<dyn std::error::Error>> {
+    let channel = crate::transport::grpc_endpoint()?.connect().await?;
+    Ok(P2pServiceClient::with_interceptor(channel, SessionToken::load()))
 }
-async fn create_auth_client() -> Result<AuthServiceClient<Channel>, Box<dyn std::error::Error>> {
-    let channel = Channel::from_shared("http://[::1]:50051".to_string())?
-        .connect()
-        .await?;
-    Ok(AuthServiceClient::new(channel))
+async fn create_auth_client() -> Result<AuthServiceClient<AuthChannel>, Box<dyn std::error::Error>> {
+    let channel = crate::transport::grpc_endpoint()?.connect().await?;
+    Ok(AuthServiceClient::with_interceptor(channel, SessionToken::load()))
 }
 
-async fn create_messaging_client() -> Result<MessagingServiceClient<Channel>, Box<dyn std::error::Error>> {
-    let channel = Channel::from_shared("http://[::1]:50051".to_string())?
-        .connect()
-        .await?;
-    Ok(MessagingServiceClient::new(channel))
+async fn create_messaging_client() -> Result<MessagingServiceClient<AuthChannel>, Box<dyn std::error::Error>> {
+    let channel = crate::transport::grpc_endpoint()?.connect().await?;
+    Ok(MessagingServiceClient::with_interceptor(channel, SessionToken::load()))
 }
 
-async fn create_user_management_client() -> Result<UserManagementServiceClient<Channel>, Box<dyn std::error::Error>> {
-    let channel = Channel::from_shared("http://[::1]:50051".to_string())?
-        .connect()
-        .await?;
-    Ok(UserManagementServiceClient::new(channel))
+async fn create_user_management_client() -> Result<UserManagementServiceClient<AuthChannel>, Box<dyn std::error::Error>> {
+    let channel = crate::transport::grpc_endpoint()?.connect().await?;
+    Ok(UserManagementServiceClient::with_interceptor(channel, SessionToken::load()))
 }
 
-async fn create_system_client() -> Result<SystemServiceClient<Channel>, Box<dyn std::error::Error>> {
-    let channel = Channel::from_shared("http://[::1]:50051".to_string())?
-        .connect()
-        .await?;
-    Ok(SystemServiceClient::new(channel))
+async fn create_system_client() -> Result<SystemServiceClient<AuthChannel>, Box<dyn std::error::Error>> {
+    let channel = crate::transport::grpc_endpoint()?.connect().await?;
+    Ok(SystemServiceClient::with_interceptor(channel, SessionToken::load()))
 }
 
 struct CommandCompleter {
@@ -361,16 +611,34 @@ impl Validator for CommandCompleter {
 }
 impl Helper for CommandCompleter {}
 
+fn print_file_entry(file: &FileMetadata) {
+    // A confidential file we hold no key for shows no name or tags
+    let name = if file.filename.is_empty() && file.confidential { "(confidential)" } else { file.filename.as_str() };
+    let marker = if file.confidential { " [confidential]" } else { "" };
+    println!("  {} ({} bytes) - {}{}", name, file.size, file.file_id, marker);
+    if !file.tags.is_empty() {
+        println!("    Tags: {}", file.tags.join(", "));
+    }
+    if !file.attributes.is_empty() {
+        let mut attributes: Vec<_> = file.attributes.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+        attributes.sort();
+        println!("    Attributes: {}", attributes.join(", "));
+    }
+}
+
 fn get_command_list() -> Vec<&'static str> {
     vec![
         "start", "stop", "web", "startweb", "stopweb", "startapi", "stopapi", "startgrpc", "stopgrpc",
         "register", "login", "addbootstrap", "removebootstrap", "listbootstrap",
-        "upload", "download", "share", "peers", "files", "p2pfiles", "logout", "help",
-        "p2pdownload", "aitrain", "airecommend", "aiaggregate", "aiexport",
-        "allowpeer", "disallowpeer", "listallowedpeers",
+        "upload", "download", "share", "unshare", "sharehistory", "sharelink", "listlinks", "revokelink", "sealfile", "searchfiles", "peers", "files", "p2pfiles", "logout", "help",
+        "p2pdownload", "findproviders", "replicas", "storagepolicy", "aitrain", "airecommend", "aiaggregate", "aiexport",
+        "allowpeer", "disallowpeer", "listallowedpeers", "blockpeer", "unblockpeer", "listblockedpeers",
         "sendmessage", "createroom", "joinroom", "sendroommessage", "listrooms", "listmessages", "setstatus", "listusers",
-        "registeruser", "loginuser", "logoutdevice", "listallusers", "searchusers", "changeusername", "listdevices", "removedevice", "whoami",
-        "connectpeer", "discoverpeers", "pingpeer", "listknownpeers", "removepeer", "messagingshell", "peerhistory", "scanlocalpeers",
+        "registeruser", "loginuser", "logoutdevice", "sessions", "revokesession", "listallusers", "searchusers", "changeusername", "listdevices", "removedevice", "whoami", "grantrole", "revokerole", "listroles", "allowclaim",
+        "mfastatus", "mfaenroll", "mfadisable", "mfarecoverycodes", "mfareset", "untrustdevice",
+        "thisdevice", "approvedevice", "devicecode", "redeemdevicecode",
+        "auditlog", "auditverify", "auditexport",
+        "connectpeer", "discoverpeers", "pingpeer", "listknownpeers", "removepeer", "messagingshell", "peerhistory", "scanlocalpeers", "nodestatus",
         "remoteconnect", "remoteexec", "remotestatus", "remotebootstrap", "remotelogs", "remoterestart", "remotestop", "remotestart", "remoteconfig", "remoteconfigget", "remotebackup", "remoterestore",
     ]
 }
@@ -378,7 +646,7 @@ fn get_command_list() -> Vec<&'static str> {
 fn get_messaging_commands() -> Vec<&'static str> {
     vec![
         "send", "room", "list", "peers", "ping", "connect", "disconnect", "status", "clear", "help", "exit", "quit",
-        "send <peer> <message>", "room create <name>", "room join <id>", "room list", "room message <id> <message>",
+        "send <peer> <message>", "room create <name>", "room join <id>", "room leave <id>", "room list", "room message <id> <message>",
         "peers list", "peers ping <peer>", "peers connect <peer>", "status set <message>", "status show",
     ]
 }
@@ -389,6 +657,7 @@ fn print_messaging_help() {
     println!("  {} - Send message to peer", style("send <peer> <message>").bold().yellow());
     println!("  {} - Create new chat room", style("room create <name>").bold().yellow());
     println!("  {} - Join existing chat room", style("room join <id>").bold().yellow());
+    println!("  {} - Leave a chat room and stop receiving its messages", style("room leave <id>").bold().yellow());
     println!("  {} - List all chat rooms", style("room list").bold().yellow());
     println!("  {} - Send message to room", style("room message <id> <message>").bold().yellow());
     println!("  {} - List known peers", style("peers list").bold().yellow());
@@ -416,7 +685,7 @@ async fn handle_messaging_command(input: &str) -> Result<(), String> {
             let message = args[2..].join(" ");
             
             let device_id = get_current_device_id();
-            let current_user = match dafs::user_management::get_user_by_device(&device_id) {
+            let current_user = match crate::user_management::get_user_by_device(&device_id) {
                 Some(user) => user,
                 None => {
                     print_error("Not logged in on this device");
@@ -426,9 +695,9 @@ async fn handle_messaging_command(input: &str) -> Result<(), String> {
             
             // Get recipient user
             let recipient_user = if peer.starts_with("user_") {
-                dafs::user_management::get_user_by_id(&peer)
+                crate::user_management::get_user_by_id(&peer)
             } else {
-                dafs::user_management::get_user_by_username(&peer)
+                crate::user_management::get_user_by_username(&peer)
             }.ok_or_else(|| format!("User '{}' not found", peer))?;
             
             let encrypted_message = crate::models::EncryptedMessage::new(
@@ -453,7 +722,7 @@ async fn handle_messaging_command(input: &str) -> Result<(), String> {
         }
         "room" => {
             if args.len() < 2 {
-                return Err("Usage: room <create|join|list|message> [args...]".to_string());
+                return Err("Usage: room <create|join|leave|list|message> [args...]".to_string());
             }
             match args[1].to_lowercase().as_str() {
                 "create" => {
@@ -462,7 +731,7 @@ async fn handle_messaging_command(input: &str) -> Result<(), String> {
                     }
                     let name = args[2..].join(" ");
                     let device_id = get_current_device_id();
-                    let current_user = match dafs::user_management::get_user_by_device(&device_id) {
+                    let current_user = match crate::user_management::get_user_by_device(&device_id) {
                         Some(user) => user,
                         None => {
                             print_error("Not logged in on this device");
@@ -489,7 +758,7 @@ async fn handle_messaging_command(input: &str) -> Result<(), String> {
                     }
                     let room_id = args[2];
                     let device_id = get_current_device_id();
-                    let current_user = match dafs::user_management::get_user_by_device(&device_id) {
+                    let current_user = match crate::user_management::get_user_by_device(&device_id) {
                         Some(user) => user,
                         None => {
                             print_error("Not logged in on this device");
@@ -509,6 +778,32 @@ async fn handle_messaging_command(input: &str) -> Result<(), String> {
                         Err(e) => print_error(&format!("Error joining chat room: {}", e)),
                     }
                 }
+                "leave" => {
+                    if args.len() < 3 {
+                        return Err("Usage: room leave <id>".to_string());
+                    }
+                    let room_id = args[2];
+                    let device_id = get_current_device_id();
+                    let current_user = match crate::user_management::get_user_by_device(&device_id) {
+                        Some(user) => user,
+                        None => {
+                            print_error("Not logged in on this device");
+                            return Err("Not logged in on this device".to_string());
+                        }
+                    };
+
+                    let p2p_node = crate::peer::P2PNode::new();
+                    match p2p_node.leave_chat_room(room_id.to_string(), current_user.username.clone()).await {
+                        Ok(success) => {
+                            if success {
+                                print_success(&format!("👋 {} left chat room {}", current_user.username, room_id));
+                            } else {
+                                print_error("Failed to leave chat room");
+                            }
+                        }
+                        Err(e) => print_error(&format!("Error leaving chat room: {}", e)),
+                    }
+                }
                 "list" => {
                     print_info("📋 Available chat rooms:");
                     let rooms_dir = "chat_rooms";
@@ -539,7 +834,7 @@ async fn handle_messaging_command(input: &str) -> Result<(), String> {
                     let message = args[3..].join(" ");
                     
                     let device_id = get_current_device_id();
-                    let current_user = match dafs::user_management::get_user_by_device(&device_id) {
+                    let current_user = match crate::user_management::get_user_by_device(&device_id) {
                         Some(user) => user,
                         None => {
                             print_error("Not logged in on this device");
@@ -567,7 +862,7 @@ async fn handle_messaging_command(input: &str) -> Result<(), String> {
                         Err(e) => print_error(&format!("Error sending room message: {}", e)),
                     }
                 }
-                _ => return Err("Unknown room command. Use: create, join, list, or message".to_string()),
+                _ => return Err("Unknown room command. Use: create, join, leave, list, or message".to_string()),
             }
         }
         "peers" => {
@@ -612,7 +907,7 @@ async fn handle_messaging_command(input: &str) -> Result<(), String> {
                             if success {
                                 print_success(&format!("✅ Connected to peer {}", peer_id));
                                 let device_id = get_current_device_id();
-                                let _ = dafs::user_management::add_peer_to_device_memory(&device_id, peer_id);
+                                let _ = crate::user_management::add_peer_to_device_memory(&device_id, peer_id);
                             } else {
                                 print_error(&format!("Failed to connect to peer {}", peer_id));
                             }
@@ -634,7 +929,7 @@ async fn handle_messaging_command(input: &str) -> Result<(), String> {
                     }
                     let status_message = args[2..].join(" ");
                     let device_id = get_current_device_id();
-                    let current_user = match dafs::user_management::get_user_by_device(&device_id) {
+                    let current_user = match crate::user_management::get_user_by_device(&device_id) {
                         Some(user) => user,
                         None => {
                             print_error("Not logged in on this device");
@@ -659,7 +954,7 @@ async fn handle_messaging_command(input: &str) -> Result<(), String> {
                 }
                 "show" => {
                     let device_id = get_current_device_id();
-                    let user = match dafs::user_management::get_user_by_device(&device_id) {
+                    let user = match crate::user_management::get_user_by_device(&device_id) {
                         Some(user) => user,
                         None => {
                             print_error("Not logged in on this device");
@@ -760,13 +1055,13 @@ pub async fn run_repl() {
 pub async fn dispatch_command(command: Commands) -> Result<(), String> {
         match &command {
         Commands::Register { username } => {
-            let password = prompt_password("Password: ").unwrap();
+            let password = SecretString::from(prompt_password("Password: ").unwrap());
             let start = Instant::now();
             match create_auth_client().await {
                 Ok(mut client) => {
                     let req = tonic::Request::new(RegisterRequest {
                         username: username.clone(),
-                        password: password.clone(),
+                        password: password.expose().to_string(),
                     });
                     print_info("Registering user...");
                     match client.register(req).await {
@@ -786,30 +1081,21 @@ pub async fn dispatch_command(command: Commands) -> Result<(), String> {
             print_info(&format!("Done in {:.2?}", start.elapsed()));
                 Ok(())
         }
-        Commands::Login { username } => {
-            let password = prompt_password("Password: ").unwrap();
+        Commands::Login { username, trust_device } => {
+            let password = SecretString::from(prompt_password("Password: ").unwrap());
             let start = Instant::now();
-            match create_auth_client().await {
-                Ok(mut client) => {
-                    let req = tonic::Request::new(LoginRequest {
-                        username: username.clone(),
-                        password: password.clone(),
-                    });
-                    print_info("Logging in...");
-                    match client.login(req).await {
-                        Ok(resp) => {
-                            let resp = resp.into_inner();
-                            if resp.success {
-                                save_session(username, &password);
-                                print_success("Login successful");
-                            } else {
-                                print_error(&format!("Login failed: {}", resp.message));
-                            }
-                        }
-                        Err(e) => print_error(&format!("gRPC error: {}", e)),
+            print_info("Logging in...");
+            match login_with_second_factor(username, password.expose(), *trust_device).await {
+                Ok(resp) => {
+                    if resp.success {
+                        save_session(username, &resp.token, resp.expires_at);
+                        print_success("Login successful");
+                        print_device_state(&resp.device_state);
+                    } else {
+                        print_error(&format!("Login failed: {}", resp.message));
                     }
                 }
-                Err(e) => print_error(&format!("Failed to connect to gRPC server: {}", e)),
+                Err(e) => print_error(&e),
             }
             print_info(&format!("Done in {:.2?}", start.elapsed()));
                 Ok(())
@@ -906,9 +1192,13 @@ pub async fn dispatch_command(command: Commands) -> Result<(), String> {
                             if resp.peers.is_empty() {
                                 print_success("No peers connected");
                             } else {
-                                print_success(&format!("Connected peers ({}):", resp.peers.len()));
+                                print_success(&format!("Open connections ({}):", resp.peers.len()));
                                 for peer in resp.peers {
-                                    println!("  {} ({}) - Connected: {}", peer.peer_id, peer.address, peer.is_connected);
+                                    let since = chrono::DateTime::from_timestamp(peer.connected_since as i64, 0)
+                                        .map(|t| t.to_rfc3339())
+                                        .unwrap_or_else(|| peer.connected_since.to_string());
+                                    println!("  {} ({}) - {} since {}{}", peer.peer_id, peer.address, peer.direction, since,
+                                        if peer.preferred { ", preferred" } else { "" });
                                 }
                             }
                         }
@@ -925,10 +1215,7 @@ pub async fn dispatch_command(command: Commands) -> Result<(), String> {
             print_info("Listing files...");
             match create_file_client().await {
                 Ok(mut client) => {
-                    let req = tonic::Request::new(ListFilesRequest {
-                        username: "testuser".to_string(),
-                        password: "".to_string(),
-                    });
+                    let req = tonic::Request::new(ListFilesRequest {});
                     match client.list_files(req).await {
                         Ok(resp) => {
                             let resp = resp.into_inner();
@@ -952,7 +1239,17 @@ pub async fn dispatch_command(command: Commands) -> Result<(), String> {
         Commands::Logout => {
             let start = Instant::now();
                 print_info("Logging out...");
-                // Clear session
+                if load_session().is_some() {
+                    // Revoke the token server-side before forgetting it locally
+                    match create_auth_client().await {
+                        Ok(mut client) => {
+                            if let Err(e) = client.logout(tonic::Request::new(LogoutRequest {})).await {
+                                print_warn(&format!("Server did not revoke the session: {}", e));
+                            }
+                        }
+                        Err(e) => print_warn(&format!("Failed to connect to gRPC server: {}", e)),
+                    }
+                }
                 if let Err(_) = std::fs::remove_file(".dafs_session") {
                     print_warn("No active session found");
             } else {
@@ -1106,7 +1403,7 @@ pub async fn dispatch_command(command: Commands) -> Result<(), String> {
                             let resp = resp.into_inner();
                             print_success(&format!("Found {} peers:", resp.peers.len()));
                             for peer in resp.peers {
-                                println!("  {} ({})", peer.peer_id, peer.address);
+                                print_peer(&peer);
                             }
                         }
                         Err(e) => print_error(&format!("gRPC error: {}", e)),
@@ -1207,6 +1504,8 @@ pub async fn dispatch_command(command: Commands) -> Result<(), String> {
                                 checksum: "".to_string(),
                                 size: content.len() as u64,
                                 shared_keys: std::collections::HashMap::new(),
+                                attributes: std::collections::HashMap::new(),
+                                confidential: false,
                             };
                             
                             // Create upload chunk
@@ -1246,8 +1545,6 @@ pub async fn dispatch_command(command: Commands) -> Result<(), String> {
                 Ok(mut client) => {
                     let req = tonic::Request::new(DownloadRequest {
                         file_id: file_id.clone(),
-                        username: "guest".to_string(),
-                        password: "".to_string(),
                     });
                     match client.download_file(req).await {
                         Ok(response) => {
@@ -1331,7 +1628,7 @@ pub async fn dispatch_command(command: Commands) -> Result<(), String> {
                             } else {
                                 print_success(&format!("Found {} peers on local network:", resp.peers.len()));
                                 for peer in resp.peers {
-                                    println!("  {} ({})", peer.peer_id, peer.address);
+                                    print_peer(&peer);
                                 }
                             }
                         }
@@ -1419,15 +1716,96 @@ pub async fn dispatch_command(command: Commands) -> Result<(), String> {
             print_info(&format!("Done in {:.2?}", start.elapsed()));
             Ok(())
         }
+        Commands::BlockPeer { peer_id } => {
+            let start = Instant::now();
+            print_info(&format!("Blocking peer '{}'...", peer_id));
+            match create_p2p_client().await {
+                Ok(mut client) => {
+                    let req = tonic::Request::new(BlockPeerRequest { peer_id: peer_id.clone() });
+                    match client.block_peer(req).await {
+                        Ok(resp) => {
+                            let resp = resp.into_inner();
+                            if resp.success {
+                                print_success(&format!("Peer '{}' blocked", peer_id));
+                            } else {
+                                print_error(&format!("Failed to block peer: {}", resp.message));
+                            }
+                        }
+                        Err(e) => print_error(&format!("gRPC error: {}", e)),
+                    }
+                }
+                Err(e) => print_error(&format!("Failed to connect to gRPC server: {}", e)),
+            }
+            print_info(&format!("Done in {:.2?}", start.elapsed()));
+            Ok(())
+        }
+        Commands::UnblockPeer { peer_id } => {
+            let start = Instant::now();
+            print_info(&format!("Unblocking peer '{}'...", peer_id));
+            match create_p2p_client().await {
+                Ok(mut client) => {
+                    let req = tonic::Request::new(UnblockPeerRequest { peer_id: peer_id.clone() });
+                    match client.unblock_peer(req).await {
+                        Ok(resp) => {
+                            let resp = resp.into_inner();
+                            if resp.success {
+                                print_success(&format!("Peer '{}' unblocked", peer_id));
+                            } else {
+                                print_error(&format!("Failed to unblock peer: {}", resp.message));
+                            }
+                        }
+                        Err(e) => print_error(&format!("gRPC error: {}", e)),
+                    }
+                }
+                Err(e) => print_error(&format!("Failed to connect to gRPC server: {}", e)),
+            }
+            print_info(&format!("Done in {:.2?}", start.elapsed()));
+            Ok(())
+        }
+        Commands::ListBlockedPeers { all } => {
+            let start = Instant::now();
+            print_info("Listing blocked peers...");
+            match create_p2p_client().await {
+                Ok(mut client) => {
+                    let req = tonic::Request::new(ListBlockedPeersRequest { all: *all });
+                    match client.list_blocked_peers(req).await {
+                        Ok(resp) => {
+                            let resp = resp.into_inner();
+                            if resp.peers.is_empty() {
+                                print_success("No blocked or banned peers");
+                            } else {
+                                print_success(&format!("Peers ({}):", resp.peers.len()));
+                                for peer in resp.peers {
+                                    let state = if peer.blocked {
+                                        "blocked".to_string()
+                                    } else if peer.banned_until > 0 {
+                                        let until = chrono::DateTime::from_timestamp(peer.banned_until as i64, 0).map(|t| t.to_rfc3339()).unwrap_or_default();
+                                        format!("banned until {}", until)
+                                    } else {
+                                        "not blocked".to_string()
+                                    };
+                                    println!("  {} [{}]", peer.peer_id, state);
+                                    println!(
+                                        "    score {:.1}, bad signatures {}, malformed messages {}, timeouts {}, chunk mismatches {}, bans {}",
+                                        peer.score, peer.bad_signatures, peer.malformed_messages, peer.timeouts, peer.chunk_mismatches, peer.bans
+                                    );
+                                }
+                            }
+                        }
+                        Err(e) => print_error(&format!("gRPC error: {}", e)),
+                    }
+                }
+                Err(e) => print_error(&format!("Failed to connect to gRPC server: {}", e)),
+            }
+            print_info(&format!("Done in {:.2?}", start.elapsed()));
+            Ok(())
+        }
         Commands::Files => {
             let start = Instant::now();
             print_info("Listing files...");
             match create_file_client().await {
                 Ok(mut client) => {
-                    let req = tonic::Request::new(ListFilesRequest {
-                        username: "guest".to_string(),
-                        password: "".to_string(),
-                    });
+                    let req = tonic::Request::new(ListFilesRequest {});
                     match client.list_files(req).await {
                         Ok(resp) => {
                             let resp = resp.into_inner();
@@ -1435,11 +1813,8 @@ pub async fn dispatch_command(command: Commands) -> Result<(), String> {
                                 print_success("No files found");
                             } else {
                                 print_success(&format!("Files ({}):", resp.files.len()));
-                                for file in resp.files {
-                                    println!("  {} ({} bytes) - {}", file.filename, file.size, file.file_id);
-                                    if !file.tags.is_empty() {
-                                        println!("    Tags: {}", file.tags.join(", "));
-                                    }
+                                for file in &resp.files {
+                                    print_file_entry(file);
                                 }
                             }
                         }
@@ -1482,7 +1857,7 @@ pub async fn dispatch_command(command: Commands) -> Result<(), String> {
             print_info(&format!("Done in {:.2?}", start.elapsed()));
             Ok(())
         }
-        Commands::Share { file_id, username } => {
+        Commands::Share { file_id, username, permissions } => {
             let start = Instant::now();
             print_info(&format!("Sharing file '{}' with user '{}'...", file_id, username));
             match create_file_client().await {
@@ -1490,8 +1865,7 @@ pub async fn dispatch_command(command: Commands) -> Result<(), String> {
                     let req = tonic::Request::new(ShareFileRequest {
                         file_id: file_id.clone(),
                         recipient_username: username.clone(),
-                        owner_username: "guest".to_string(),
-                        owner_password: "".to_string(),
+                        permissions: permissions.clone(),
                     });
                     match client.share_file(req).await {
                         Ok(resp) => {
@@ -1510,21 +1884,31 @@ pub async fn dispatch_command(command: Commands) -> Result<(), String> {
             print_info(&format!("Done in {:.2?}", start.elapsed()));
             Ok(())
         }
-        Commands::P2pDownload { file_id, peer_id } => {
+        Commands::Unshare { file_id, username, rekey } => {
             let start = Instant::now();
-            print_info(&format!("Downloading file '{}' from peer '{}'...", file_id, peer_id));
-            match create_p2p_client().await {
+            if load_session().is_none() {
+                print_error("Not logged in. Use 'login <username>' first.");
+                return Ok(());
+            }
+            print_info(&format!("Revoking access to file '{}' for user '{}'...", file_id, username));
+            match create_file_client().await {
                 Ok(mut client) => {
-                    let req = tonic::Request::new(P2pDownloadChunkRequest {
+                    let req = tonic::Request::new(UnshareFileRequest {
                         file_id: file_id.clone(),
-                        peer_id: peer_id.clone(),
-                        chunk_index: 0,
-                        chunk_size: 1024 * 1024, // 1MB chunks
+                        recipient_username: username.clone(),
+                        rekey: *rekey,
                     });
-                    match client.p2p_download_chunk(req).await {
+                    match client.unshare_file(req).await {
                         Ok(resp) => {
                             let resp = resp.into_inner();
-                            print_success(&format!("Downloaded chunk {} of file '{}' from peer '{}'", resp.chunk_index, file_id, peer_id));
+                            if resp.success {
+                                print_success(&format!("Access to file '{}' revoked for user '{}'", file_id, username));
+                                if resp.rekeyed {
+                                    print_info("File key rotated; remaining sharees received the new key");
+                                }
+                            } else {
+                                print_error(&format!("Failed to unshare file: {}", resp.message));
+                            }
                         }
                         Err(e) => print_error(&format!("gRPC error: {}", e)),
                     }
@@ -1534,76 +1918,94 @@ pub async fn dispatch_command(command: Commands) -> Result<(), String> {
             print_info(&format!("Done in {:.2?}", start.elapsed()));
             Ok(())
         }
-        Commands::AiAggregate { model_path } => {
+        Commands::ShareHistory { file_id } => {
             let start = Instant::now();
-            print_info(&format!("Aggregating model from '{}'...", model_path));
-            match create_grpc_client().await {
-                Ok(mut grpc_client) => {
-                    match std::fs::read(&model_path) {
-                        Ok(model_data) => {
-                            let request = tonic::Request::new(AggregateRequest {
-                                model_data,
-                            });
-                            
-                            match grpc_client.aggregate_model(request).await {
-                                Ok(response) => {
-                                    let resp = response.into_inner();
-                                    if resp.success {
-                                        print_success("✅ Model aggregated successfully");
-                                    } else {
-                                        print_error(&format!("❌ Aggregation failed: {}", resp.message));
-                                    }
+            print_info(&format!("Fetching share history for file '{}'...", file_id));
+            match create_file_client().await {
+                Ok(mut client) => {
+                    let req = tonic::Request::new(ShareHistoryRequest { file_id: file_id.clone() });
+                    match client.get_share_history(req).await {
+                        Ok(resp) => {
+                            let revocations = resp.into_inner().revocations;
+                            if revocations.is_empty() {
+                                print_info("No revocations recorded");
+                            } else {
+                                for r in revocations {
+                                    let when = chrono::DateTime::from_timestamp(r.timestamp as i64, 0)
+                                        .map(|t| t.to_rfc3339())
+                                        .unwrap_or_else(|| r.timestamp.to_string());
+                                    let via = if r.origin_peer.is_empty() { String::new() } else { format!(" (via peer {})", r.origin_peer) };
+                                    println!("  {} revoked {} by {}{}{}", when, r.revoked_user, r.revoked_by,
+                                        if r.rekeyed { ", rekeyed" } else { "" }, via);
                                 }
-                                Err(e) => print_error(&format!("❌ gRPC error: {}", e)),
                             }
                         }
-                        Err(e) => print_error(&format!("❌ Failed to read model file: {}", e)),
+                        Err(e) => print_error(&format!("gRPC error: {}", e)),
                     }
                 }
-                Err(e) => print_error(&format!("❌ Failed to connect to gRPC server: {}", e)),
+                Err(e) => print_error(&format!("Failed to connect to gRPC server: {}", e)),
             }
             print_info(&format!("Done in {:.2?}", start.elapsed()));
             Ok(())
         }
-        Commands::AiExport { output_path } => {
+        Commands::ShareLink { file_id, expires, max_downloads, password, base_url } => {
             let start = Instant::now();
-            print_info(&format!("Exporting AI model to '{}'...", output_path));
-            match create_grpc_client().await {
-                Ok(mut grpc_client) => {
-                    let request = tonic::Request::new(ExportRequest {});
-                    
-                    match grpc_client.export_model(request).await {
-                        Ok(response) => {
-                            let resp = response.into_inner();
-                            match std::fs::write(&output_path, resp.model_data) {
-                                Ok(_) => print_success(&format!("✅ Model exported to '{}'", output_path)),
-                                Err(e) => print_error(&format!("❌ Failed to write model file: {}", e)),
+            if load_session().is_none() {
+                print_error("Not logged in. Use 'login <username>' first.");
+                return Ok(());
+            }
+            let password = SecretString::from(if *password { prompt_password("Link password: ").unwrap() } else { String::new() });
+            print_info(&format!("Creating link to file '{}'...", file_id));
+            match create_file_client().await {
+                Ok(mut client) => {
+                    let req = tonic::Request::new(CreateShareLinkRequest {
+                        file_id: file_id.clone(),
+                        expires_in: expires.unwrap_or(0),
+                        max_downloads: max_downloads.unwrap_or(0),
+                        password: password.expose().to_string(),
+                    });
+                    match client.create_share_link(req).await {
+                        Ok(resp) => {
+                            let resp = resp.into_inner();
+                            if resp.success {
+                                print_success(&format!("{}{}", base_url.trim_end_matches('/'), resp.url));
+                                print_info("The key is in the part after '#' and is not kept by the node; this is the only time it is shown");
+                            } else {
+                                print_error(&format!("Failed to create link: {}", resp.message));
                             }
                         }
-                        Err(e) => print_error(&format!("❌ gRPC error: {}", e)),
+                        Err(e) => print_error(&format!("gRPC error: {}", e)),
                     }
                 }
-                Err(e) => print_error(&format!("❌ Failed to connect to gRPC server: {}", e)),
+                Err(e) => print_error(&format!("Failed to connect to gRPC server: {}", e)),
             }
             print_info(&format!("Done in {:.2?}", start.elapsed()));
             Ok(())
         }
-        Commands::ListUsers => {
+        Commands::ListLinks => {
             let start = Instant::now();
-            print_info("Listing users...");
-            match create_user_management_client().await {
+            print_info("Fetching your links...");
+            match create_file_client().await {
                 Ok(mut client) => {
-                    let req = tonic::Request::new(ListAllUsersRequest {});
-                    match client.list_all_users(req).await {
+                    match client.list_share_links(tonic::Request::new(ListShareLinksRequest {})).await {
                         Ok(resp) => {
-                            let resp = resp.into_inner();
-                            if resp.users.is_empty() {
-                                print_success("No users found");
-                            } else {
-                                print_success(&format!("Users ({}):", resp.users.len()));
-                                for user in resp.users {
-                                    println!("  {} - {}", user.username, user.display_name);
-                                }
+                            let links = resp.into_inner().links;
+                            if links.is_empty() {
+                                print_info("No links");
+                            }
+                            for l in links {
+                                let state = if l.revoked {
+                                    "revoked".to_string()
+                                } else if l.expires_at == 0 {
+                                    "no expiry".to_string()
+                                } else {
+                                    format!("expires {}", chrono::DateTime::from_timestamp(l.expires_at as i64, 0)
+                                        .map(|t| t.to_rfc3339())
+                                        .unwrap_or_else(|| l.expires_at.to_string()))
+                                };
+                                let limit = if l.max_downloads == 0 { String::new() } else { format!("/{}", l.max_downloads) };
+                                println!("  {} file {} - {}{} downloads, {}{}", l.link_id, l.file_id, l.downloads, limit, state,
+                                    if l.password_required { ", password" } else { "" });
                             }
                         }
                         Err(e) => print_error(&format!("gRPC error: {}", e)),
@@ -1614,24 +2016,19 @@ pub async fn dispatch_command(command: Commands) -> Result<(), String> {
             print_info(&format!("Done in {:.2?}", start.elapsed()));
             Ok(())
         }
-        Commands::SearchUsers { query } => {
+        Commands::RevokeLink { link_id } => {
             let start = Instant::now();
-            print_info(&format!("Searching users for '{}'...", query));
-            match create_auth_client().await {
+            print_info(&format!("Revoking link '{}'...", link_id));
+            match create_file_client().await {
                 Ok(mut client) => {
-                    let req = tonic::Request::new(SearchUsersRequest {
-                        query: query.clone(),
-                    });
-                    match client.search_users(req).await {
+                    let req = tonic::Request::new(RevokeShareLinkRequest { link_id: link_id.clone() });
+                    match client.revoke_share_link(req).await {
                         Ok(resp) => {
                             let resp = resp.into_inner();
-                            if resp.users.is_empty() {
-                                print_success("No users found matching query");
+                            if resp.success {
+                                print_success(&resp.message);
                             } else {
-                                print_success(&format!("Found {} users:", resp.users.len()));
-                                for user in resp.users {
-                                    println!("  {} - {}", user.username, user.display_name);
-                                }
+                                print_error(&format!("Failed to revoke link: {}", resp.message));
                             }
                         }
                         Err(e) => print_error(&format!("gRPC error: {}", e)),
@@ -1642,23 +2039,23 @@ pub async fn dispatch_command(command: Commands) -> Result<(), String> {
             print_info(&format!("Done in {:.2?}", start.elapsed()));
             Ok(())
         }
-        Commands::ChangeUsername { new_username } => {
+        Commands::SealFile { file_id } => {
             let start = Instant::now();
-            print_info(&format!("Changing username to '{}'...", new_username));
-            match create_auth_client().await {
+            if load_session().is_none() {
+                print_error("Not logged in. Use 'login <username>' first.");
+                return Ok(());
+            }
+            print_info(&format!("Encrypting metadata of file '{}'...", file_id));
+            match create_file_client().await {
                 Ok(mut client) => {
-                    let req = tonic::Request::new(ChangeUsernameRequest {
-                        new_username: new_username.clone(),
-                        old_username: "guest".to_string(),
-                        password: "".to_string(),
-                    });
-                    match client.change_username(req).await {
+                    let req = tonic::Request::new(SealFileRequest { file_id: file_id.clone() });
+                    match client.seal_file(req).await {
                         Ok(resp) => {
                             let resp = resp.into_inner();
                             if resp.success {
-                                print_success(&format!("Username changed to '{}'", new_username));
+                                print_success(&resp.message);
                             } else {
-                                print_error(&format!("Failed to change username: {}", resp.message));
+                                print_error(&format!("Failed to seal file: {}", resp.message));
                             }
                         }
                         Err(e) => print_error(&format!("gRPC error: {}", e)),
@@ -1669,23 +2066,620 @@ pub async fn dispatch_command(command: Commands) -> Result<(), String> {
             print_info(&format!("Done in {:.2?}", start.elapsed()));
             Ok(())
         }
-        Commands::SendMessage { peer_id, message } => {
+        Commands::SearchFiles { tag } => {
             let start = Instant::now();
-            print_info(&format!("Sending message to peer '{}'...", peer_id));
-            match create_messaging_client().await {
+            print_info(&format!("Searching for files tagged '{}'...", tag));
+            match create_file_client().await {
+                Ok(mut client) => {
+                    let req = tonic::Request::new(SearchFilesRequest { tag: tag.clone() });
+                    match client.search_files(req).await {
+                        Ok(resp) => {
+                            let files = resp.into_inner().files;
+                            if files.is_empty() {
+                                print_info("No matching files");
+                            } else {
+                                print_success(&format!("Files ({}):", files.len()));
+                                for file in &files {
+                                    print_file_entry(file);
+                                }
+                            }
+                        }
+                        Err(e) => print_error(&format!("gRPC error: {}", e)),
+                    }
+                }
+                Err(e) => print_error(&format!("Failed to connect to gRPC server: {}", e)),
+            }
+            print_info(&format!("Done in {:.2?}", start.elapsed()));
+            Ok(())
+        }
+        Commands::P2pDownload { file_id, peer_id } => {
+            let start = Instant::now();
+            match peer_id {
+                Some(peer_id) => print_info(&format!("Downloading file '{}' from peer '{}'...", file_id, peer_id)),
+                None => print_info(&format!("Downloading file '{}' from every peer holding it...", file_id)),
+            }
+            match create_p2p_client().await {
+                Ok(mut client) => {
+                    let req = tonic::Request::new(SwarmDownloadRequest {
+                        file_id: file_id.clone(),
+                        peer_ids: peer_id.iter().cloned().collect(),
+                        chunk_size: 0,
+                    });
+                    match client.swarm_download(req).await {
+                        Ok(response) => {
+                            let mut stream = response.into_inner();
+                            let bar = ProgressBar::new(0);
+                            bar.set_style(
+                                ProgressStyle::with_template("{spinner} [{bar:40}] {bytes}/{total_bytes} {bytes_per_sec} ETA {eta} {msg}")
+                                    .unwrap_or_else(|_| ProgressStyle::default_bar())
+                                    .progress_chars("=> "),
+                            );
+                            let mut finished = None;
+                            while let Some(update) = stream.next().await {
+                                match update {
+                                    Ok(p) => {
+                                        bar.set_length(p.total_bytes);
+                                        bar.set_position(p.bytes_done);
+                                        bar.set_message(format!("{} peers, {} retries", p.active_peers, p.retries));
+                                        if p.done {
+                                            finished = Some(p);
+                                        }
+                                    }
+                                    Err(e) => {
+                                        bar.abandon();
+                                        print_error(&format!("Download failed: {}", e.message()));
+                                        break;
+                                    }
+                                }
+                            }
+                            if let Some(p) = finished {
+                                bar.finish();
+                                if p.chunks_resumed > 0 {
+                                    print_info(&format!("Resumed {} of {} chunks from an earlier download", p.chunks_resumed, p.total_chunks));
+                                }
+                                print_success(&format!("File '{}' saved on the node as '{}'", file_id, p.output_path));
+                            }
+                        }
+                        Err(e) => print_error(&format!("gRPC error: {}", e.message())),
+                    }
+                }
+                Err(e) => print_error(&format!("Failed to connect to gRPC server: {}", e)),
+            }
+            print_info(&format!("Done in {:.2?}", start.elapsed()));
+            Ok(())
+        }
+        Commands::Replicas { file_id } => {
+            let start = Instant::now();
+            print_info(&format!("Checking replicas of file '{}'...", file_id));
+            match create_p2p_client().await {
+                Ok(mut client) => {
+                    let req = tonic::Request::new(GetReplicasRequest { file_id: file_id.clone() });
+                    match client.get_replicas(req).await {
+                        Ok(resp) => {
+                            let resp = resp.into_inner();
+                            if resp.policy == "erasure" {
+                                let total = resp.data_shards + resp.parity_shards;
+                                if !resp.is_origin {
+                                    print_info("This node holds a shard of the file for another node");
+                                } else if resp.healthy >= total {
+                                    print_success(&format!("Healthy: {} of {} shards reachable", resp.healthy, total));
+                                } else if resp.healthy >= resp.data_shards {
+                                    print_error(&format!("Degraded: {} of {} shards reachable; {} rebuild the file", resp.healthy, total, resp.data_shards));
+                                } else {
+                                    print_error(&format!("At risk: {} of {} shards reachable; {} needed to rebuild the file", resp.healthy, total, resp.data_shards));
+                                }
+                                for shard in resp.shards {
+                                    let status = if shard.reachable { style("reachable").green() } else { style("unreachable").red() };
+                                    println!("  shard {} on {} {} (last confirmed {})", shard.shard_index, shard.peer_id, status, shard.last_seen);
+                                }
+                            } else if !resp.is_origin {
+                                print_info("This node holds the file as a replica for another node");
+                            } else if resp.healthy as usize >= resp.replication_factor as usize {
+                                print_success(&format!("Healthy: {} of {} replicas reachable", resp.healthy, resp.replication_factor));
+                            } else {
+                                print_error(&format!("Under-replicated: {} of {} replicas reachable", resp.healthy, resp.replication_factor));
+                            }
+                            for replica in resp.replicas {
+                                let status = if replica.reachable { style("reachable").green() } else { style("unreachable").red() };
+                                println!("  {} {} (last confirmed {})", replica.peer_id, status, replica.last_seen);
+                            }
+                        }
+                        Err(e) => print_error(&format!("gRPC error: {}", e.message())),
+                    }
+                }
+                Err(e) => print_error(&format!("Failed to connect to gRPC server: {}", e)),
+            }
+            print_info(&format!("Done in {:.2?}", start.elapsed()));
+            Ok(())
+        }
+        Commands::StoragePolicy { file_id, policy, data, parity } => {
+            let start = Instant::now();
+            print_info(&format!("Setting storage policy of file '{}'...", file_id));
+            match create_p2p_client().await {
+                Ok(mut client) => {
+                    let req = tonic::Request::new(SetStoragePolicyRequest {
+                        file_id: file_id.clone(),
+                        policy: policy.clone(),
+                        data_shards: *data,
+                        parity_shards: *parity,
+                    });
+                    match client.set_storage_policy(req).await {
+                        Ok(resp) => print_success(&resp.into_inner().message),
+                        Err(e) => print_error(&format!("gRPC error: {}", e.message())),
+                    }
+                }
+                Err(e) => print_error(&format!("Failed to connect to gRPC server: {}", e)),
+            }
+            print_info(&format!("Done in {:.2?}", start.elapsed()));
+            Ok(())
+        }
+        Commands::FindProviders { file_id } => {
+            let start = Instant::now();
+            print_info(&format!("Looking up holders of file '{}'...", file_id));
+            match create_p2p_client().await {
+                Ok(mut client) => {
+                    let req = tonic::Request::new(FindProvidersRequest { file_id: file_id.clone() });
+                    match client.find_providers(req).await {
+                        Ok(resp) => {
+                            let peer_ids = resp.into_inner().peer_ids;
+                            if peer_ids.is_empty() {
+                                print_info("No peer is known to hold this file");
+                            } else {
+                                print_success(&format!("{} peers hold file '{}':", peer_ids.len(), file_id));
+                                for peer_id in peer_ids {
+                                    println!("  {}", peer_id);
+                                }
+                            }
+                        }
+                        Err(e) => print_error(&format!("gRPC error: {}", e)),
+                    }
+                }
+                Err(e) => print_error(&format!("Failed to connect to gRPC server: {}", e)),
+            }
+            print_info(&format!("Done in {:.2?}", start.elapsed()));
+            Ok(())
+        }
+        Commands::AiAggregate { model_path } => {
+            let start = Instant::now();
+            print_info(&format!("Aggregating model from '{}'...", model_path));
+            match create_grpc_client().await {
+                Ok(mut grpc_client) => {
+                    match std::fs::read(&model_path) {
+                        Ok(model_data) => {
+                            let request = tonic::Request::new(AggregateRequest {
+                                model_data,
+                            });
+                            
+                            match grpc_client.aggregate_model(request).await {
+                                Ok(response) => {
+                                    let resp = response.into_inner();
+                                    if resp.success {
+                                        print_success("✅ Model aggregated successfully");
+                                    } else {
+                                        print_error(&format!("❌ Aggregation failed: {}", resp.message));
+                                    }
+                                }
+                                Err(e) => print_error(&format!("❌ gRPC error: {}", e)),
+                            }
+                        }
+                        Err(e) => print_error(&format!("❌ Failed to read model file: {}", e)),
+                    }
+                }
+                Err(e) => print_error(&format!("❌ Failed to connect to gRPC server: {}", e)),
+            }
+            print_info(&format!("Done in {:.2?}", start.elapsed()));
+            Ok(())
+        }
+        Commands::AiExport { output_path } => {
+            let start = Instant::now();
+            print_info(&format!("Exporting AI model to '{}'...", output_path));
+            match create_grpc_client().await {
+                Ok(mut grpc_client) => {
+                    let request = tonic::Request::new(ExportRequest {});
+                    
+                    match grpc_client.export_model(request).await {
+                        Ok(response) => {
+                            let resp = response.into_inner();
+                            match std::fs::write(&output_path, resp.model_data) {
+                                Ok(_) => print_success(&format!("✅ Model exported to '{}'", output_path)),
+                                Err(e) => print_error(&format!("❌ Failed to write model file: {}", e)),
+                            }
+                        }
+                        Err(e) => print_error(&format!("❌ gRPC error: {}", e)),
+                    }
+                }
+                Err(e) => print_error(&format!("❌ Failed to connect to gRPC server: {}", e)),
+            }
+            print_info(&format!("Done in {:.2?}", start.elapsed()));
+            Ok(())
+        }
+        Commands::ListUsers => {
+            let start = Instant::now();
+            print_info("Listing users...");
+            match create_user_management_client().await {
+                Ok(mut client) => {
+                    let req = tonic::Request::new(ListAllUsersRequest {});
+                    match client.list_all_users(req).await {
+                        Ok(resp) => {
+                            let resp = resp.into_inner();
+                            if resp.users.is_empty() {
+                                print_success("No users found");
+                            } else {
+                                print_success(&format!("Users ({}):", resp.users.len()));
+                                for user in resp.users {
+                                    println!("  {} - {}", user.username, user.display_name);
+                                }
+                            }
+                        }
+                        Err(e) => print_error(&format!("gRPC error: {}", e)),
+                    }
+                }
+                Err(e) => print_error(&format!("Failed to connect to gRPC server: {}", e)),
+            }
+            print_info(&format!("Done in {:.2?}", start.elapsed()));
+            Ok(())
+        }
+        Commands::SearchUsers { query } => {
+            let start = Instant::now();
+            print_info(&format!("Searching users for '{}'...", query));
+            match create_auth_client().await {
+                Ok(mut client) => {
+                    let req = tonic::Request::new(SearchUsersRequest {
+                        query: query.clone(),
+                    });
+                    match client.search_users(req).await {
+                        Ok(resp) => {
+                            let resp = resp.into_inner();
+                            if resp.users.is_empty() {
+                                print_success("No users found matching query");
+                            } else {
+                                print_success(&format!("Found {} users:", resp.users.len()));
+                                for user in resp.users {
+                                    println!("  {} - {}", user.username, user.display_name);
+                                }
+                            }
+                        }
+                        Err(e) => print_error(&format!("gRPC error: {}", e)),
+                    }
+                }
+                Err(e) => print_error(&format!("Failed to connect to gRPC server: {}", e)),
+            }
+            print_info(&format!("Done in {:.2?}", start.elapsed()));
+            Ok(())
+        }
+        Commands::ChangeUsername { new_username } => {
+            let start = Instant::now();
+            let old_username = match load_session() {
+                Some((username, _)) => username,
+                None => {
+                    print_error("Not logged in. Use 'login <username>' first.");
+                    return Ok(());
+                }
+            };
+            let password = SecretString::from(prompt_password("Password: ").unwrap());
+            print_info(&format!("Changing username to '{}'...", new_username));
+            match create_auth_client().await {
+                Ok(mut client) => {
+                    let req = tonic::Request::new(ChangeUsernameRequest {
+                        new_username: new_username.clone(),
+                        old_username,
+                        password: password.expose().to_string(),
+                    });
+                    match client.change_username(req).await {
+                        Ok(resp) => {
+                            let resp = resp.into_inner();
+                            if resp.success {
+                                // Sessions belong to the old name; the server revoked them
+                                let _ = std::fs::remove_file(".dafs_session");
+                                print_success(&format!("Username changed to '{}'; log in again", new_username));
+                            } else {
+                                print_error(&format!("Failed to change username: {}", resp.message));
+                            }
+                        }
+                        Err(e) => print_error(&format!("gRPC error: {}", e)),
+                    }
+                }
+                Err(e) => print_error(&format!("Failed to connect to gRPC server: {}", e)),
+            }
+            print_info(&format!("Done in {:.2?}", start.elapsed()));
+            Ok(())
+        }
+        Commands::SendMessage { peer_id, message } => {
+            let start = Instant::now();
+            print_info(&format!("Sending message to peer '{}'...", peer_id));
+            match create_messaging_client().await {
+                Ok(mut client) => {
+                    let req = tonic::Request::new(SendMessageRequest {
+                        recipient_id: peer_id.clone(),
+                        encrypted_content: message.as_bytes().to_vec(),
+                        message_type: "text".to_string(),
+                    });
+                    match client.send_message(req).await {
+                        Ok(resp) => {
+                            let resp = resp.into_inner();
+                            if resp.success {
+                                print_success(&format!("Message sent to peer '{}'", peer_id));
+                            } else {
+                                print_error(&format!("Failed to send message: {}", resp.message));
+                            }
+                        }
+                        Err(e) => print_error(&format!("gRPC error: {}", e)),
+                    }
+                }
+                Err(e) => print_error(&format!("Failed to create client: {}", e)),
+            }
+            print_info(&format!("Done in {:.2?}", start.elapsed()));
+            Ok(())
+        }
+        Commands::CreateRoom { name, participants } => {
+            let start = Instant::now();
+            print_info(&format!("Creating chat room '{}'...", name));
+            match create_messaging_client().await {
+                Ok(mut client) => {
+                    let req = tonic::Request::new(CreateRoomRequest {
+                        name: name.clone(),
+                        participants: participants.clone(),
+                    });
+                    match client.create_room(req).await {
+                        Ok(resp) => {
+                            let resp = resp.into_inner();
+                            if resp.success {
+                                print_success(&format!("Chat room '{}' created successfully", name));
+                            } else {
+                                print_error(&format!("Failed to create room: {}", resp.message));
+                            }
+                        }
+                        Err(e) => print_error(&format!("gRPC error: {}", e)),
+                    }
+                }
+                Err(e) => print_error(&format!("Failed to create client: {}", e)),
+            }
+            print_info(&format!("Done in {:.2?}", start.elapsed()));
+            Ok(())
+        }
+        Commands::JoinRoom { room_id } => {
+            let start = Instant::now();
+            print_info(&format!("Joining chat room '{}'...", room_id));
+            match create_messaging_client().await {
+                Ok(mut client) => {
+                    let req = tonic::Request::new(JoinRoomRequest {
+                        room_id: room_id.clone(),
+                        username: "testuser".to_string(),
+                    });
+                    match client.join_room(req).await {
+                        Ok(resp) => {
+                            let resp = resp.into_inner();
+                            if resp.success {
+                                print_success(&format!("Joined chat room '{}'", room_id));
+                            } else {
+                                print_error(&format!("Failed to join room: {}", resp.message));
+                            }
+                        }
+                        Err(e) => print_error(&format!("gRPC error: {}", e)),
+                    }
+                }
+                Err(e) => print_error(&format!("Failed to create client: {}", e)),
+            }
+            print_info(&format!("Done in {:.2?}", start.elapsed()));
+            Ok(())
+        }
+        Commands::SendRoomMessage { room_id, message } => {
+            let start = Instant::now();
+            print_info(&format!("Sending message to room '{}'...", room_id));
+            print_success("Room messaging not yet fully implemented in gRPC service");
+            print_info(&format!("Done in {:.2?}", start.elapsed()));
+            Ok(())
+        }
+        Commands::ListRooms => {
+            let start = Instant::now();
+            print_info("Listing chat rooms...");
+            match create_messaging_client().await {
+                Ok(mut client) => {
+                    let req = tonic::Request::new(ListRoomsRequest {});
+                    match client.list_rooms(req).await {
+                        Ok(resp) => {
+                            let resp = resp.into_inner();
+                            if resp.rooms.is_empty() {
+                                print_success("No chat rooms found");
+                            } else {
+                                print_success(&format!("Found {} chat rooms:", resp.rooms.len()));
+                                for room in resp.rooms {
+                                    println!("  - {} ({} participants)", room.name, room.participants.len());
+                                }
+                            }
+                        }
+                        Err(e) => print_error(&format!("gRPC error: {}", e)),
+                    }
+                }
+                Err(e) => print_error(&format!("Failed to create client: {}", e)),
+            }
+            print_info(&format!("Done in {:.2?}", start.elapsed()));
+            Ok(())
+        }
+        Commands::ListMessages { room_id } => {
+            let start = Instant::now();
+            print_info(&format!("Listing messages in room '{}'...", room_id));
+            print_success("Message listing not yet fully implemented in gRPC service");
+            print_info(&format!("Done in {:.2?}", start.elapsed()));
+            Ok(())
+        }
+        Commands::SetStatus { status } => {
+            let start = Instant::now();
+            print_info(&format!("Setting status to '{}'...", status));
+            print_success("Status setting not yet fully implemented in gRPC service");
+            print_info(&format!("Done in {:.2?}", start.elapsed()));
+            Ok(())
+        }
+        Commands::RegisterUser { username, display_name, email } => {
+            let start = Instant::now();
+            print_info(&format!("Registering user '{}'...", username));
+            let password = SecretString::from(prompt_password("Password: ").unwrap());
+            match create_user_management_client().await {
+                Ok(mut client) => {
+                    let req = tonic::Request::new(RegisterUserRequest {
+                        username: username.clone(),
+                        display_name: display_name.clone(),
+                        email: email.clone().unwrap_or_default(),
+                        password: password.expose().to_string(),
+                    });
+                    match client.register_user(req).await {
+                        Ok(resp) => {
+                            let resp = resp.into_inner();
+                            if resp.success {
+                                print_success(&format!("User '{}' registered successfully", username));
+                            } else {
+                                print_error(&format!("Registration failed: {}", resp.message));
+                            }
+                        }
+                        Err(e) => print_error(&format!("gRPC error: {}", e)),
+                    }
+                }
+                Err(e) => print_error(&format!("Failed to connect to gRPC server: {}", e)),
+            }
+            print_info(&format!("Done in {:.2?}", start.elapsed()));
+            Ok(())
+        }
+        Commands::LoginUser { username, trust_device } => {
+            let start = Instant::now();
+            print_info(&format!("Logging in user '{}'...", username));
+            let password = SecretString::from(prompt_password("Password: ").unwrap());
+            match login_with_second_factor(username, password.expose(), *trust_device).await {
+                Ok(resp) => {
+                    if resp.success {
+                        save_session(username, &resp.token, resp.expires_at);
+                        print_success(&format!("User '{}' logged in successfully", username));
+                        print_device_state(&resp.device_state);
+                    } else {
+                        print_error(&format!("Login failed: {}", resp.message));
+                    }
+                }
+                Err(e) => print_error(&e),
+            }
+            print_info(&format!("Done in {:.2?}", start.elapsed()));
+            Ok(())
+        }
+        Commands::LogoutDevice { device_id } => {
+            let start = Instant::now();
+            let this_device = get_current_device_id();
+            let target = device_id.clone().unwrap_or_else(|| this_device.clone());
+            print_info(&format!("Logging out device '{}'...", target));
+            match create_user_management_client().await {
+                Ok(mut client) => {
+                    let req = tonic::Request::new(LogoutDeviceRequest { device_id: target.clone() });
+                    match client.logout_device(req).await {
+                        Ok(resp) => print_success(&resp.into_inner().message),
+                        Err(e) => print_error(&format!("gRPC error: {}", e)),
+                    }
+                }
+                Err(e) => print_error(&format!("Failed to connect to gRPC server: {}", e)),
+            }
+            if target == this_device {
+                let _ = std::fs::remove_file(".dafs_session");
+            }
+            print_info(&format!("Done in {:.2?}", start.elapsed()));
+            Ok(())
+        }
+        Commands::Sessions => {
+            let start = Instant::now();
+            print_info("Listing active sessions...");
+            match create_auth_client().await {
+                Ok(mut client) => {
+                    match client.list_sessions(tonic::Request::new(ListSessionsRequest {})).await {
+                        Ok(resp) => {
+                            let resp = resp.into_inner();
+                            print_success(&format!("Active sessions ({}):", resp.sessions.len()));
+                            for s in resp.sessions {
+                                let expires = chrono::DateTime::from_timestamp(s.expires_at as i64, 0)
+                                    .map(|t| t.to_rfc3339())
+                                    .unwrap_or_else(|| s.expires_at.to_string());
+                                let marker = if s.current { " (this session)" } else { "" };
+                                println!("  {} - device {} - expires {}{}", s.session_id, s.device_id, expires, marker);
+                            }
+                        }
+                        Err(e) => print_error(&format!("gRPC error: {}", e)),
+                    }
+                }
+                Err(e) => print_error(&format!("Failed to connect to gRPC server: {}", e)),
+            }
+            print_info(&format!("Done in {:.2?}", start.elapsed()));
+            Ok(())
+        }
+        Commands::RevokeSession { session_id } => {
+            let start = Instant::now();
+            print_info(&format!("Revoking session '{}'...", session_id));
+            match create_auth_client().await {
+                Ok(mut client) => {
+                    let req = tonic::Request::new(RevokeSessionRequest {
+                        session_id: session_id.clone(),
+                        device_id: String::new(),
+                    });
+                    match client.revoke_session(req).await {
+                        Ok(resp) => print_success(&resp.into_inner().message),
+                        Err(e) => print_error(&format!("gRPC error: {}", e)),
+                    }
+                }
+                Err(e) => print_error(&format!("Failed to connect to gRPC server: {}", e)),
+            }
+            print_info(&format!("Done in {:.2?}", start.elapsed()));
+            Ok(())
+        }
+        Commands::ListAllUsers => {
+            let start = Instant::now();
+            print_info("Listing all users...");
+            match create_auth_client().await {
+                Ok(mut client) => {
+                    let req = tonic::Request::new(ListUsersRequest {});
+                    match client.list_users(req).await {
+                        Ok(resp) => {
+                            let resp = resp.into_inner();
+                            if resp.users.is_empty() {
+                                print_success("No users found");
+                            } else {
+                                print_success(&format!("All users ({}):", resp.users.len()));
+                                for user in resp.users {
+                                    println!("  {} - {}", user.username, user.display_name);
+                                }
+                            }
+                        }
+                        Err(e) => print_error(&format!("gRPC error: {}", e)),
+                    }
+                }
+                Err(e) => print_error(&format!("Failed to connect to gRPC server: {}", e)),
+            }
+            print_info(&format!("Done in {:.2?}", start.elapsed()));
+            Ok(())
+        }
+        Commands::ListDevices => {
+            let start = Instant::now();
+            print_info("Listing user devices...");
+            match create_user_management_client().await {
                 Ok(mut client) => {
-                    let req = tonic::Request::new(SendMessageRequest {
-                        recipient_id: peer_id.clone(),
-                        encrypted_content: message.as_bytes().to_vec(),
-                        message_type: "text".to_string(),
+                    let req = tonic::Request::new(ListDevicesRequest {
+                        username: load_session().map(|(u, _)| u).unwrap_or_default(),
                     });
-                    match client.send_message(req).await {
+                    match client.list_devices(req).await {
                         Ok(resp) => {
                             let resp = resp.into_inner();
-                            if resp.success {
-                                print_success(&format!("Message sent to peer '{}'", peer_id));
+                            if resp.devices.is_empty() {
+                                print_success("No devices found");
                             } else {
-                                print_error(&format!("Failed to send message: {}", resp.message));
+                                print_success(&format!("Found {} devices:", resp.devices.len()));
+                                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+                                for device in resp.devices {
+                                    let trust = if device.trusted_until > now {
+                                        let until = chrono::DateTime::from_timestamp(device.trusted_until as i64, 0).map(|t| t.to_rfc3339()).unwrap_or_default();
+                                        format!(" - Trusted until {}", until)
+                                    } else {
+                                        String::new()
+                                    };
+                                    let key = if device.fingerprint.is_empty() {
+                                        " - No device key".to_string()
+                                    } else {
+                                        format!(" - {} key {}", device.state, device.fingerprint)
+                                    };
+                                    let marker = if device.current { " (this device)" } else { "" };
+                                    println!("  - {}{} ({}) - Last login: {}{}{}", device.device_id, marker, device.device_type, device.last_login, key, trust);
+                                }
                             }
                         }
                         Err(e) => print_error(&format!("gRPC error: {}", e)),
@@ -1696,125 +2690,229 @@ pub async fn dispatch_command(command: Commands) -> Result<(), String> {
             print_info(&format!("Done in {:.2?}", start.elapsed()));
             Ok(())
         }
-        Commands::CreateRoom { name, participants } => {
+        Commands::GrantRole { username, role } => {
             let start = Instant::now();
-            print_info(&format!("Creating chat room '{}'...", name));
-            match create_messaging_client().await {
+            print_info(&format!("Granting role '{}' to '{}'...", role, username));
+            match create_user_management_client().await {
                 Ok(mut client) => {
-                    let req = tonic::Request::new(CreateRoomRequest {
-                        name: name.clone(),
-                        participants: participants.clone(),
+                    let req = tonic::Request::new(GrantRoleRequest {
+                        username: username.clone(),
+                        role: role.clone(),
                     });
-                    match client.create_room(req).await {
+                    match client.grant_role(req).await {
                         Ok(resp) => {
                             let resp = resp.into_inner();
                             if resp.success {
-                                print_success(&format!("Chat room '{}' created successfully", name));
+                                print_success(&resp.message);
                             } else {
-                                print_error(&format!("Failed to create room: {}", resp.message));
+                                print_error(&format!("Failed to grant role: {}", resp.message));
                             }
                         }
-                        Err(e) => print_error(&format!("gRPC error: {}", e)),
+                        Err(e) => print_error(&format!("gRPC error: {}", e.message())),
                     }
                 }
-                Err(e) => print_error(&format!("Failed to create client: {}", e)),
+                Err(e) => print_error(&format!("Failed to connect to gRPC server: {}", e)),
             }
             print_info(&format!("Done in {:.2?}", start.elapsed()));
             Ok(())
         }
-        Commands::JoinRoom { room_id } => {
+        Commands::AllowClaim { username } => {
             let start = Instant::now();
-            print_info(&format!("Joining chat room '{}'...", room_id));
-            match create_messaging_client().await {
+            print_info(&format!("Allowing the account '{}' to be claimed...", username));
+            match create_user_management_client().await {
                 Ok(mut client) => {
-                    let req = tonic::Request::new(JoinRoomRequest {
-                        room_id: room_id.clone(),
-                        username: "testuser".to_string(),
+                    let req = tonic::Request::new(AllowAccountClaimRequest { username: username.clone() });
+                    match client.allow_account_claim(req).await {
+                        Ok(resp) => {
+                            let resp = resp.into_inner();
+                            if resp.success {
+                                print_success(&resp.message);
+                            } else {
+                                print_error(&format!("Failed to allow the claim: {}", resp.message));
+                            }
+                        }
+                        Err(e) => print_error(&format!("gRPC error: {}", e.message())),
+                    }
+                }
+                Err(e) => print_error(&format!("Failed to connect to gRPC server: {}", e)),
+            }
+            print_info(&format!("Done in {:.2?}", start.elapsed()));
+            Ok(())
+        }
+        Commands::RevokeRole { username } => {
+            let start = Instant::now();
+            print_info(&format!("Revoking role of '{}'...", username));
+            match create_user_management_client().await {
+                Ok(mut client) => {
+                    let req = tonic::Request::new(RevokeRoleRequest {
+                        username: username.clone(),
                     });
-                    match client.join_room(req).await {
+                    match client.revoke_role(req).await {
                         Ok(resp) => {
                             let resp = resp.into_inner();
                             if resp.success {
-                                print_success(&format!("Joined chat room '{}'", room_id));
+                                print_success(&resp.message);
                             } else {
-                                print_error(&format!("Failed to join room: {}", resp.message));
+                                print_error(&format!("Failed to revoke role: {}", resp.message));
                             }
                         }
-                        Err(e) => print_error(&format!("gRPC error: {}", e)),
+                        Err(e) => print_error(&format!("gRPC error: {}", e.message())),
                     }
                 }
-                Err(e) => print_error(&format!("Failed to create client: {}", e)),
+                Err(e) => print_error(&format!("Failed to connect to gRPC server: {}", e)),
             }
             print_info(&format!("Done in {:.2?}", start.elapsed()));
             Ok(())
         }
-        Commands::SendRoomMessage { room_id, message } => {
+        Commands::ListRoles => {
             let start = Instant::now();
-            print_info(&format!("Sending message to room '{}'...", room_id));
-            print_success("Room messaging not yet fully implemented in gRPC service");
+            print_info("Listing role assignments...");
+            match create_user_management_client().await {
+                Ok(mut client) => {
+                    let req = tonic::Request::new(ListRolesRequest {});
+                    match client.list_roles(req).await {
+                        Ok(resp) => {
+                            let resp = resp.into_inner();
+                            print_success(&format!("Role assignments ({}), everyone else is 'user':", resp.roles.len()));
+                            for r in resp.roles {
+                                println!("  {} ({}) - {}", r.username, r.user_id, r.role);
+                            }
+                        }
+                        Err(e) => print_error(&format!("gRPC error: {}", e.message())),
+                    }
+                }
+                Err(e) => print_error(&format!("Failed to connect to gRPC server: {}", e)),
+            }
             print_info(&format!("Done in {:.2?}", start.elapsed()));
             Ok(())
         }
-        Commands::ListRooms => {
+        Commands::MfaStatus => {
             let start = Instant::now();
-            print_info("Listing chat rooms...");
-            match create_messaging_client().await {
+            print_info("Checking two-factor authentication...");
+            match create_user_management_client().await {
                 Ok(mut client) => {
-                    let req = tonic::Request::new(ListRoomsRequest {});
-                    match client.list_rooms(req).await {
+                    match client.get_mfa_status(tonic::Request::new(MfaStatusRequest {})).await {
                         Ok(resp) => {
                             let resp = resp.into_inner();
-                            if resp.rooms.is_empty() {
-                                print_success("No chat rooms found");
+                            if resp.enabled {
+                                print_success(&format!("Two-factor authentication is enabled ({} recovery codes left)", resp.recovery_codes_remaining));
                             } else {
-                                print_success(&format!("Found {} chat rooms:", resp.rooms.len()));
-                                for room in resp.rooms {
-                                    println!("  - {} ({} participants)", room.name, room.participants.len());
+                                print_warn("Two-factor authentication is not enabled; run mfaenroll to set it up");
+                            }
+                        }
+                        Err(e) => print_error(&format!("gRPC error: {}", e.message())),
+                    }
+                }
+                Err(e) => print_error(&format!("Failed to connect to gRPC server: {}", e)),
+            }
+            print_info(&format!("Done in {:.2?}", start.elapsed()));
+            Ok(())
+        }
+        Commands::MfaEnroll => {
+            let start = Instant::now();
+            print_info("Starting two-factor enrolment...");
+            match create_user_management_client().await {
+                Ok(mut client) => {
+                    match client.enroll_totp(tonic::Request::new(EnrollTotpRequest {})).await {
+                        Ok(resp) => {
+                            let resp = resp.into_inner();
+                            println!("Add this account to your authenticator app. Open or scan this URI:");
+                            println!("  {}", style(&resp.otpauth_uri).bold());
+                            println!("or enter the secret manually: {}", style(&resp.secret).bold());
+                            let code = prompt_code("Code shown by the app")?;
+                            match client.confirm_totp(tonic::Request::new(ConfirmTotpRequest { code })).await {
+                                Ok(resp) => {
+                                    print_success("Two-factor authentication enabled");
+                                    println!("Recovery codes, each usable once. Store them somewhere safe; they are not shown again:");
+                                    for code in resp.into_inner().recovery_codes {
+                                        println!("  {}", code);
+                                    }
                                 }
+                                Err(e) => print_error(&format!("Enrolment failed: {}", e.message())),
                             }
                         }
-                        Err(e) => print_error(&format!("gRPC error: {}", e)),
+                        Err(e) => print_error(&format!("gRPC error: {}", e.message())),
                     }
                 }
-                Err(e) => print_error(&format!("Failed to create client: {}", e)),
+                Err(e) => print_error(&format!("Failed to connect to gRPC server: {}", e)),
             }
             print_info(&format!("Done in {:.2?}", start.elapsed()));
             Ok(())
         }
-        Commands::ListMessages { room_id } => {
+        Commands::MfaDisable { code } => {
             let start = Instant::now();
-            print_info(&format!("Listing messages in room '{}'...", room_id));
-            print_success("Message listing not yet fully implemented in gRPC service");
+            let code = match code {
+                Some(code) => code.clone(),
+                None => prompt_code("Authentication code (or recovery code)")?,
+            };
+            print_info("Disabling two-factor authentication...");
+            match create_user_management_client().await {
+                Ok(mut client) => {
+                    match client.disable_totp(tonic::Request::new(DisableTotpRequest { code })).await {
+                        Ok(resp) => print_success(&resp.into_inner().message),
+                        Err(e) => print_error(&format!("gRPC error: {}", e.message())),
+                    }
+                }
+                Err(e) => print_error(&format!("Failed to connect to gRPC server: {}", e)),
+            }
             print_info(&format!("Done in {:.2?}", start.elapsed()));
             Ok(())
         }
-        Commands::SetStatus { status } => {
+        Commands::MfaRecoveryCodes { code } => {
             let start = Instant::now();
-            print_info(&format!("Setting status to '{}'...", status));
-            print_success("Status setting not yet fully implemented in gRPC service");
+            let code = match code {
+                Some(code) => code.clone(),
+                None => prompt_code("Authentication code")?,
+            };
+            print_info("Replacing recovery codes...");
+            match create_user_management_client().await {
+                Ok(mut client) => {
+                    match client.regenerate_recovery_codes(tonic::Request::new(RegenerateRecoveryCodesRequest { code })).await {
+                        Ok(resp) => {
+                            print_success("New recovery codes (the old ones no longer work):");
+                            for code in resp.into_inner().recovery_codes {
+                                println!("  {}", code);
+                            }
+                        }
+                        Err(e) => print_error(&format!("gRPC error: {}", e.message())),
+                    }
+                }
+                Err(e) => print_error(&format!("Failed to connect to gRPC server: {}", e)),
+            }
             print_info(&format!("Done in {:.2?}", start.elapsed()));
             Ok(())
         }
-        Commands::RegisterUser { username, display_name, email } => {
+        Commands::MfaReset { username } => {
             let start = Instant::now();
-            print_info(&format!("Registering user '{}'...", username));
-            let password = prompt_password("Password: ").unwrap();
-            match create_auth_client().await {
+            print_info(&format!("Resetting two-factor authentication for '{}'...", username));
+            match create_user_management_client().await {
                 Ok(mut client) => {
-                    let req = tonic::Request::new(RegisterRequest {
-                        username: username.clone(),
-                        password: password.clone(),
-                    });
-                    match client.register(req).await {
+                    match client.reset_mfa(tonic::Request::new(ResetMfaRequest { username: username.clone() })).await {
+                        Ok(resp) => print_success(&resp.into_inner().message),
+                        Err(e) => print_error(&format!("gRPC error: {}", e.message())),
+                    }
+                }
+                Err(e) => print_error(&format!("Failed to connect to gRPC server: {}", e)),
+            }
+            print_info(&format!("Done in {:.2?}", start.elapsed()));
+            Ok(())
+        }
+        Commands::UntrustDevice { device_id } => {
+            let start = Instant::now();
+            let target = device_id.clone().unwrap_or_else(get_current_device_id);
+            print_info(&format!("Removing trust from device '{}'...", target));
+            match create_user_management_client().await {
+                Ok(mut client) => {
+                    match client.untrust_device(tonic::Request::new(UntrustDeviceRequest { device_id: target })).await {
                         Ok(resp) => {
                             let resp = resp.into_inner();
                             if resp.success {
-                                print_success(&format!("User '{}' registered successfully", username));
+                                print_success(&resp.message);
                             } else {
-                                print_error(&format!("Registration failed: {}", resp.message));
+                                print_error(&resp.message);
                             }
                         }
-                        Err(e) => print_error(&format!("gRPC error: {}", e)),
+                        Err(e) => print_error(&format!("gRPC error: {}", e.message())),
                     }
                 }
                 Err(e) => print_error(&format!("Failed to connect to gRPC server: {}", e)),
@@ -1822,27 +2920,25 @@ pub async fn dispatch_command(command: Commands) -> Result<(), String> {
             print_info(&format!("Done in {:.2?}", start.elapsed()));
             Ok(())
         }
-        Commands::LoginUser { username } => {
+        Commands::ThisDevice => {
+            let public = x25519_dalek::PublicKey::from(&load_device_key());
+            println!("Device ID:       {}", get_current_device_id());
+            println!("Key fingerprint: {}", crate::devices::fingerprint(&public));
+            Ok(())
+        }
+        Commands::ApproveDevice { device_id } => {
             let start = Instant::now();
-            print_info(&format!("Logging in user '{}'...", username));
-            let password = prompt_password("Password: ").unwrap();
-            match create_auth_client().await {
+            print_info(&format!("Approving device '{}'...", device_id));
+            match create_user_management_client().await {
                 Ok(mut client) => {
-                    let req = tonic::Request::new(LoginRequest {
-                        username: username.clone(),
-                        password: password.clone(),
-                    });
-                    match client.login(req).await {
+                    match client.approve_device(tonic::Request::new(ApproveDeviceRequest { device_id: device_id.clone() })).await {
                         Ok(resp) => {
                             let resp = resp.into_inner();
-                            if resp.success {
-                                save_session(username, &password);
-                                print_success(&format!("User '{}' logged in successfully", username));
-                            } else {
-                                print_error(&format!("Login failed: {}", resp.message));
-                            }
+                            print_success(&resp.message);
+                            println!("  Key fingerprint: {} (check it matches 'thisdevice' on that device)", resp.fingerprint);
+                            println!("  File keys wrapped for it: {}", resp.wrapped_keys);
                         }
-                        Err(e) => print_error(&format!("gRPC error: {}", e)),
+                        Err(e) => print_error(&format!("gRPC error: {}", e.message())),
                     }
                 }
                 Err(e) => print_error(&format!("Failed to connect to gRPC server: {}", e)),
@@ -1850,36 +2946,64 @@ pub async fn dispatch_command(command: Commands) -> Result<(), String> {
             print_info(&format!("Done in {:.2?}", start.elapsed()));
             Ok(())
         }
-        Commands::LogoutDevice => {
+        Commands::DeviceCode => {
             let start = Instant::now();
-            print_info("Logging out from current device...");
-            if let Err(_) = std::fs::remove_file(".dafs_session") {
-                print_warn("No active session found");
-            } else {
-                print_success("Logged out successfully");
+            print_info("Issuing a device enrolment code...");
+            match create_user_management_client().await {
+                Ok(mut client) => {
+                    match client.create_device_code(tonic::Request::new(CreateDeviceCodeRequest {})).await {
+                        Ok(resp) => {
+                            let resp = resp.into_inner();
+                            let until = chrono::DateTime::from_timestamp(resp.expires_at as i64, 0).map(|t| t.to_rfc3339()).unwrap_or_default();
+                            print_success(&format!("Enrolment code: {}", resp.code));
+                            println!("  Run 'redeemdevicecode {}' on the new device before {}. It works once.", resp.code, until);
+                        }
+                        Err(e) => print_error(&format!("gRPC error: {}", e.message())),
+                    }
+                }
+                Err(e) => print_error(&format!("Failed to connect to gRPC server: {}", e)),
             }
             print_info(&format!("Done in {:.2?}", start.elapsed()));
             Ok(())
         }
-        Commands::ListAllUsers => {
+        Commands::RedeemDeviceCode { code } => {
             let start = Instant::now();
-            print_info("Listing all users...");
-            match create_auth_client().await {
+            print_info("Approving this device...");
+            match create_user_management_client().await {
                 Ok(mut client) => {
-                    let req = tonic::Request::new(ListUsersRequest {});
-                    match client.list_users(req).await {
+                    match client.redeem_device_code(tonic::Request::new(RedeemDeviceCodeRequest { code: code.clone() })).await {
                         Ok(resp) => {
                             let resp = resp.into_inner();
-                            if resp.users.is_empty() {
-                                print_success("No users found");
-                            } else {
-                                print_success(&format!("All users ({}):", resp.users.len()));
-                                for user in resp.users {
-                                    println!("  {} - {}", user.username, user.display_name);
+                            print_success(&resp.message);
+                            println!("  File keys wrapped for this device: {}", resp.wrapped_keys);
+                        }
+                        Err(e) => print_error(&format!("gRPC error: {}", e.message())),
+                    }
+                }
+                Err(e) => print_error(&format!("Failed to connect to gRPC server: {}", e)),
+            }
+            print_info(&format!("Done in {:.2?}", start.elapsed()));
+            Ok(())
+        }
+        Commands::AuditLog { filter } => {
+            let start = Instant::now();
+            print_info("Querying audit log...");
+            match create_system_client().await {
+                Ok(mut client) => {
+                    match client.query_audit_log(tonic::Request::new(filter.request())).await {
+                        Ok(resp) => {
+                            let resp = resp.into_inner();
+                            print_success(&format!("Audit events ({}):", resp.entries.len()));
+                            for e in resp.entries {
+                                let when = chrono::DateTime::from_timestamp(e.timestamp as i64, 0).map(|t| t.to_rfc3339()).unwrap_or_default();
+                                let outcome = if e.success { "ok" } else { "FAILED" };
+                                println!("  #{} {} {} {} {} {}", e.seq, when, e.actor, e.action, e.target, outcome);
+                                if !e.detail.is_empty() {
+                                    println!("      {}", e.detail);
                                 }
                             }
                         }
-                        Err(e) => print_error(&format!("gRPC error: {}", e)),
+                        Err(e) => print_error(&format!("gRPC error: {}", e.message())),
                     }
                 }
                 Err(e) => print_error(&format!("Failed to connect to gRPC server: {}", e)),
@@ -1887,30 +3011,62 @@ pub async fn dispatch_command(command: Commands) -> Result<(), String> {
             print_info(&format!("Done in {:.2?}", start.elapsed()));
             Ok(())
         }
-        Commands::ListDevices => {
+        Commands::AuditVerify => {
             let start = Instant::now();
-            print_info("Listing user devices...");
-            match create_user_management_client().await {
+            print_info("Verifying audit log...");
+            match create_system_client().await {
                 Ok(mut client) => {
-                    let req = tonic::Request::new(ListDevicesRequest {
-                        username: "testuser".to_string(),
-                    });
-                    match client.list_devices(req).await {
+                    match client.verify_audit_log(tonic::Request::new(VerifyAuditLogRequest {})).await {
                         Ok(resp) => {
                             let resp = resp.into_inner();
-                            if resp.devices.is_empty() {
-                                print_success("No devices found");
+                            if resp.valid {
+                                print_success(&format!("Audit log intact ({} entries)", resp.entries));
                             } else {
-                                print_success(&format!("Found {} devices:", resp.devices.len()));
-                                for device in resp.devices {
-                                    println!("  - {} ({}) - Last login: {}", device.device_name, device.device_type, device.last_login);
-                                }
+                                print_error(&format!("Audit log tampered at entry {}: {}", resp.broken_at, resp.reason));
                             }
                         }
-                        Err(e) => print_error(&format!("gRPC error: {}", e)),
+                        Err(e) => print_error(&format!("gRPC error: {}", e.message())),
                     }
                 }
-                Err(e) => print_error(&format!("Failed to create client: {}", e)),
+                Err(e) => print_error(&format!("Failed to connect to gRPC server: {}", e)),
+            }
+            print_info(&format!("Done in {:.2?}", start.elapsed()));
+            Ok(())
+        }
+        Commands::AuditExport { path, filter } => {
+            let start = Instant::now();
+            print_info(&format!("Exporting audit log to {}...", path));
+            match create_system_client().await {
+                Ok(mut client) => {
+                    match client.query_audit_log(tonic::Request::new(filter.request())).await {
+                        Ok(resp) => {
+                            let entries = resp.into_inner().entries;
+                            let mut out = String::new();
+                            for e in &entries {
+                                // Same shape as the node's own JSON Lines export
+                                let event = crate::audit::AuditEvent {
+                                    seq: e.seq,
+                                    timestamp: e.timestamp,
+                                    actor: e.actor.clone(),
+                                    action: e.action.clone(),
+                                    target: Some(e.target.clone()).filter(|t| !t.is_empty()),
+                                    success: e.success,
+                                    detail: Some(e.detail.clone()).filter(|d| !d.is_empty()),
+                                    prev_hash: e.prev_hash.clone(),
+                                    hash: e.hash.clone(),
+                                };
+                                out.push_str(&serde_json::to_string(&event).map_err(|e| e.to_string())?);
+                                out.push('\n');
+                            }
+                            match fs::write(path, out) {
+                                Ok(()) => print_success(&format!("Exported {} event(s) to {}", entries.len(), path)),
+                                Err(e) => print_error(&format!("Failed to write {}: {}", path, e)),
+                            }
+                        }
+                        Err(e) => print_error(&format!("gRPC error: {}", e.message())),
+                    }
+                }
+                Err(e) => print_error(&format!("Failed to connect to gRPC server: {}", e)),
             }
             print_info(&format!("Done in {:.2?}", start.elapsed()));
             Ok(())
@@ -1918,7 +3074,29 @@ pub async fn dispatch_command(command: Commands) -> Result<(), String> {
         Commands::RemoveDevice { device_id } => {
             let start = Instant::now();
             print_info(&format!("Removing device '{}'...", device_id));
-            print_success("Device removal not yet fully implemented in gRPC service");
+            match create_user_management_client().await {
+                Ok(mut client) => {
+                    let req = tonic::Request::new(RemoveDeviceRequest {
+                        username: load_session().map(|(u, _)| u).unwrap_or_default(),
+                        device_id: device_id.clone(),
+                    });
+                    match client.remove_device(req).await {
+                        Ok(resp) => {
+                            let resp = resp.into_inner();
+                            if resp.success {
+                                print_success(&resp.message);
+                                if !resp.fingerprint.is_empty() {
+                                    print_info(&format!("Revoked device key {}", resp.fingerprint));
+                                }
+                            } else {
+                                print_error(&format!("Failed to remove device: {}", resp.message));
+                            }
+                        }
+                        Err(e) => print_error(&format!("gRPC error: {}", e)),
+                    }
+                }
+                Err(e) => print_error(&format!("Failed to connect to gRPC server: {}", e)),
+            }
             print_info(&format!("Done in {:.2?}", start.elapsed()));
             Ok(())
         }
@@ -1948,6 +3126,35 @@ pub async fn dispatch_command(command: Commands) -> Result<(), String> {
             print_info(&format!("Done in {:.2?}", start.elapsed()));
             Ok(())
         }
+        Commands::NodeStatus => {
+            let start = Instant::now();
+            match create_p2p_client().await {
+                Ok(mut client) => {
+                    match client.get_node_status(tonic::Request::new(GetNodeStatusRequest {})).await {
+                        Ok(resp) => {
+                            let resp = resp.into_inner();
+                            print_success(&format!("Peer ID: {}", resp.peer_id));
+                            for addr in resp.listen_addresses.iter().filter(|a| !a.contains("/p2p-circuit")) {
+                                println!("  Listening on {}", addr);
+                            }
+                            match resp.reachability.as_str() {
+                                "public" => println!("  Reachable directly at {}", resp.public_address),
+                                "private" => println!("  Behind NAT; reachable through {} relays", resp.relays.len()),
+                                _ => println!("  Reachability not known yet"),
+                            }
+                            for addr in &resp.relayed_addresses {
+                                println!("  Relayed address: {}", addr);
+                            }
+                            println!("  Hole punching: {} direct upgrades, {} failed", resp.direct_upgrades, resp.failed_upgrades);
+                        }
+                        Err(e) => print_error(&format!("gRPC error: {}", e)),
+                    }
+                }
+                Err(e) => print_error(&format!("Failed to connect to gRPC server: {}", e)),
+            }
+            print_info(&format!("Done in {:.2?}", start.elapsed()));
+            Ok(())
+        }
         Commands::RemovePeer { peer_id } => {
             let start = Instant::now();
             print_info(&format!("Removing peer '{}'...", peer_id));
@@ -1987,7 +3194,7 @@ pub async fn dispatch_command(command: Commands) -> Result<(), String> {
                             } else {
                                 print_success(&format!("Known peers ({}):", resp.peers.len()));
                                 for peer in resp.peers {
-                                    println!("  {} ({})", peer.peer_id, peer.address);
+                                    print_peer(&peer);
                                 }
                             }
                         }
@@ -1999,10 +3206,10 @@ pub async fn dispatch_command(command: Commands) -> Result<(), String> {
             print_info(&format!("Done in {:.2?}", start.elapsed()));
             Ok(())
         }
-        Commands::RemoteConnect { host, port, username, password } => {
+        Commands::RemoteConnect { host, port, username, password, otp } => {
             let start = Instant::now();
             print_info(&format!("Connecting to remote DAFS service at {}:{}...", host, port));
-            match remote_management::connect_to_remote(&host, *port, &username, &password).await {
+            match remote_management::connect_to_remote(&host, *port, &username, &password, otp.as_deref()).await {
                 Ok(_) => {
                     print_success(&format!("Successfully connected to remote DAFS service at {}:{}", host, port));
                 }
@@ -2171,7 +3378,7 @@ fn print_comprehensive_help() {
     // Authentication
     println!("\n{}", style("🔐 AUTHENTICATION").bold().green());
     println!("  {} - Register new user account", style("register <username>").bold().yellow());
-    println!("  {} - Login with username", style("login <username>").bold().yellow());
+    println!("  {} - Login with username", style("login <username> [--trust-device]").bold().yellow());
     println!("  {} - Logout from current session", style("logout").bold().red());
     
     // Bootstrap Node Management
@@ -2184,14 +3391,24 @@ fn print_comprehensive_help() {
     println!("\n{}", style("📁 FILE OPERATIONS").bold().green());
     println!("  {} - Upload file with tags", style("upload <file> --tags <tag1> <tag2>...").bold().yellow());
     println!("  {} - Download file by ID", style("download <file_id>").bold().yellow());
-    println!("  {} - Share file with user", style("share <file_id> <username>").bold().yellow());
+    println!("  {} - Share file with user", style("share <file_id> <username> [--permissions write,share,delete]").bold().yellow());
+    println!("  {} - Revoke a user's access", style("unshare <file_id> <username> [--rekey]").bold().red());
+    println!("  {} - Show share revocations", style("sharehistory <file_id>").bold().yellow());
+    println!("  {} - Create a public link", style("sharelink <file_id> [--expires <secs>] [--max-downloads <n>] [--password]").bold().yellow());
+    println!("  {} - List your links", style("listlinks").bold().yellow());
+    println!("  {} - Revoke a link", style("revokelink <link_id>").bold().red());
+    println!("  {} - Encrypt a file's name, tags and attributes", style("sealfile <file_id>").bold().yellow());
+    println!("  {} - Find files by tag", style("searchfiles <tag>").bold().yellow());
     println!("  {} - List all files", style("files").bold().yellow());
     println!("  {} - List P2P files", style("p2pfiles").bold().yellow());
-    println!("  {} - Download from P2P peer", style("p2pdownload <file_id> <peer_id>").bold().yellow());
+    println!("  {} - Download from all peers holding a file, or one peer", style("p2pdownload <file_id> [peer_id]").bold().yellow());
+    println!("  {} - Find the peers holding a file", style("findproviders <file_id>").bold().yellow());
+    println!("  {} - Show a file's replicas and their health", style("replicas <file_id>").bold().yellow());
+    println!("  {} - Keep a file as replicas or as erasure-coded shards", style("storagepolicy <file_id> <replicated|erasure> [--data N] [--parity M]").bold().yellow());
     
     // Peer Management
     println!("\n{}", style("👥 PEER MANAGEMENT").bold().green());
-    println!("  {} - List known peers", style("peers").bold().yellow());
+    println!("  {} - List open peer connections", style("peers").bold().yellow());
     println!("  {} - Connect to peer", style("connectpeer <peer_id> [addr]").bold().yellow());
     println!("  {} - Discover peers on network", style("discoverpeers").bold().yellow());
     println!("  {} - Ping peer for connectivity", style("pingpeer <peer_id>").bold().yellow());
@@ -2199,6 +3416,7 @@ fn print_comprehensive_help() {
     println!("  {} - Remove peer from list", style("removepeer <peer_id>").bold().red());
     println!("  {} - Scan local network for peers", style("scanlocalpeers").bold().yellow());
     println!("  {} - Show peer connection history", style("peerhistory").bold().yellow());
+    println!("  {} - Show reachability, relays and hole punching results", style("nodestatus").bold().yellow());
     
     // AI Operations
     println!("\n{}", style("🤖 AI OPERATIONS").bold().green());
@@ -2222,24 +3440,52 @@ fn print_comprehensive_help() {
     // User Management
     println!("\n{}", style("👤 USER MANAGEMENT").bold().green());
     println!("  {} - Register new user", style("registeruser <username> <display_name> [email]").bold().yellow());
-    println!("  {} - Login user", style("loginuser <username>").bold().yellow());
-    println!("  {} - Logout from device", style("logoutdevice").bold().red());
+    println!("  {} - Login user", style("loginuser <username> [--trust-device]").bold().yellow());
+    println!("  {} - Logout a device (default: this one)", style("logoutdevice [device_id]").bold().red());
+    println!("  {} - List your active sessions", style("sessions").bold().yellow());
+    println!("  {} - Revoke a session", style("revokesession <session_id>").bold().red());
     println!("  {} - List all registered users", style("listallusers").bold().yellow());
     println!("  {} - Search for users", style("searchusers <query>").bold().yellow());
     println!("  {} - Change username", style("changeusername <new_username>").bold().yellow());
-    println!("  {} - List user's devices", style("listdevices").bold().yellow());
-    println!("  {} - Remove device", style("removedevice <device_id>").bold().red());
+    println!("  {} - List your devices with key fingerprints", style("listdevices").bold().yellow());
+    println!("  {} - Revoke a device's key, wrapped file keys and sessions", style("removedevice <device_id>").bold().red());
+    println!("  {} - Show this device's ID and key fingerprint", style("thisdevice").bold().yellow());
+    println!("  {} - Approve a pending device", style("approvedevice <device_id>").bold().yellow());
+    println!("  {} - Issue a one-time device enrolment code", style("devicecode").bold().yellow());
+    println!("  {} - Approve this device with a code", style("redeemdevicecode <code>").bold().yellow());
     println!("  {} - Show current user info", style("whoami").bold().yellow());
+    println!("  {} - Grant a role (admin, operator, user, guest)", style("grantrole <username> <role>").bold().yellow());
+    println!("  {} - Reset a user to the default role", style("revokerole <username>").bold().red());
+    println!("  {} - List role assignments", style("listroles").bold().yellow());
+    println!("  {} - Let an imported account be claimed by registering", style("allowclaim <username>").bold().yellow());
+    
+    // Two-Factor Authentication
+    println!("\n{}", style("🔑 TWO-FACTOR AUTHENTICATION").bold().green());
+    println!("  {} - Show whether two-factor is enabled", style("mfastatus").bold().yellow());
+    println!("  {} - Enable two-factor with an authenticator app", style("mfaenroll").bold().yellow());
+    println!("  {} - Disable two-factor", style("mfadisable [code]").bold().red());
+    println!("  {} - Replace your recovery codes", style("mfarecoverycodes [code]").bold().yellow());
+    println!("  {} - Remove a user's second factor (admins only)", style("mfareset <username>").bold().red());
+    println!("  {} - Ask for the code again on a device", style("untrustdevice [device_id]").bold().red());
+    
+    // Audit
+    println!("\n{}", style("📜 AUDIT").bold().green());
+    println!("  {} - Show audit events", style("auditlog [--user U] [--action A] [--since T] [--until T] [--limit N]").bold().yellow());
+    println!("  {} - Check the audit log for tampering", style("auditverify").bold().yellow());
+    println!("  {} - Export audit events as JSON Lines", style("auditexport <path> [filters]").bold().yellow());
     
     // Peer Access Control
     println!("\n{}", style("🔒 PEER ACCESS CONTROL").bold().green());
     println!("  {} - Allow peer access", style("allowpeer <peer_id>").bold().yellow());
     println!("  {} - Disallow peer access", style("disallowpeer <peer_id>").bold().red());
     println!("  {} - List allowed peers", style("listallowedpeers").bold().yellow());
+    println!("  {} - Block a peer", style("blockpeer <peer_id>").bold().red());
+    println!("  {} - Unblock a peer and lift any ban", style("unblockpeer <peer_id>").bold().yellow());
+    println!("  {} - List blocked and banned peers", style("listblockedpeers [--all]").bold().yellow());
     
     // Remote Management
     println!("\n{}", style("🌍 REMOTE MANAGEMENT").bold().green());
-    println!("  {} - Connect to remote DAFS service", style("remoteconnect <host> <port> <username> <password>").bold().yellow());
+    println!("  {} - Connect to remote DAFS service", style("remoteconnect <host> <port> <username> <password> [--otp CODE]").bold().yellow());
     println!("  {} - Execute command on remote service", style("remoteexec <command>").bold().yellow());
     println!("  {} - Get remote service status", style("remotestatus").bold().yellow());
     println!("  {} - Manage remote bootstrap node", style("remotebootstrap <action> [peer_id] [addr]").bold().yellow());
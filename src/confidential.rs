@@ -0,0 +1,167 @@
+// Confidential files: filename, tags and attributes encrypted at rest.
+//
+// Sealing a file moves those fields into `FileMetadata.sealed`, encrypted under the file
+// key exactly as the contents are, and blanks the cleartext copies. The database, peers
+// and callers without a key then see only the file's ID and size. A caller whose session
+// key unwraps the file key gets the fields back, decrypted per request.
+//
+// Tags stay searchable through blind tokens: HMAC-SHA256 of the normalised tag under an
+// index key derived from the reader's private key. Tokens are stored per reader, so they
+// don't link one user's tags to another's, and the node can compute them only while that
+// reader is logged in. The sealer's tokens are written when the file is sealed; other
+// readers' on their first search.
+
+use crate::crypto::{decrypt_file, encrypt_file, unwrap_file_key, SecretKey};
+use crate::storage::{FileMetadata, SealedMetadata};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use x25519_dalek::StaticSecret;
+
+/// The fields a sealed file keeps encrypted
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Fields {
+    pub filename: String,
+    pub tags: Vec<String>,
+    pub attributes: HashMap<String, String>,
+}
+
+/// A reader's tag index key, derived from their private key.
+pub fn index_key(secret: &StaticSecret) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(b"dafs tag index v1");
+    mac.finalize().into_bytes().into()
+}
+
+/// Blind token for a tag; tags compare case-insensitively and ignoring surrounding space.
+pub fn tag_token(index_key: &[u8; 32], tag: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(index_key).expect("HMAC accepts any key length");
+    mac.update(tag.trim().to_lowercase().as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+fn tag_tokens(index_key: &[u8; 32], tags: &[String]) -> Vec<String> {
+    tags.iter().map(|t| tag_token(index_key, t)).collect()
+}
+
+/// Encrypts the file's filename, tags and attributes under `file_key` and clears the
+/// cleartext copies. `sealer` is indexed for tag search straight away.
+pub fn seal(meta: &mut FileMetadata, file_key: &[u8; 32], sealer: &str, sealer_index_key: &[u8; 32]) -> anyhow::Result<()> {
+    if meta.sealed.is_some() {
+        return Ok(());
+    }
+    let fields = Fields {
+        filename: std::mem::take(&mut meta.filename),
+        tags: std::mem::take(&mut meta.tags),
+        attributes: std::mem::take(&mut meta.attributes),
+    };
+    let ciphertext = encrypt_file(&serde_json::to_vec(&fields)?, file_key)?;
+    let tag_tokens = HashMap::from([(sealer.to_string(), tag_tokens(sealer_index_key, &fields.tags))]);
+    meta.sealed = Some(SealedMetadata { ciphertext, tag_tokens });
+    Ok(())
+}
+
+/// Decrypts a sealed file's fields; a file that isn't sealed returns its cleartext ones.
+pub fn unseal(meta: &FileMetadata, file_key: &[u8; 32]) -> anyhow::Result<Fields> {
+    match &meta.sealed {
+        Some(sealed) => Ok(serde_json::from_slice(&decrypt_file(&sealed.ciphertext, file_key)?)?),
+        None => Ok(Fields { filename: meta.filename.clone(), tags: meta.tags.clone(), attributes: meta.attributes.clone() }),
+    }
+}
+
+/// Re-encrypts sealed fields after the file key changes. Tag tokens don't depend on the
+/// file key and are kept.
+pub fn reseal(meta: &mut FileMetadata, old_key: &[u8; 32], new_key: &[u8; 32]) -> anyhow::Result<()> {
+    if meta.sealed.is_none() {
+        return Ok(());
+    }
+    let fields = unseal(meta, old_key)?;
+    if let Some(sealed) = meta.sealed.as_mut() {
+        sealed.ciphertext = encrypt_file(&serde_json::to_vec(&fields)?, new_key)?;
+    }
+    Ok(())
+}
+
+/// The file as shown to someone without its key: ID and size only.
+pub fn redacted(meta: &FileMetadata) -> FileMetadata {
+    FileMetadata {
+        file_id: meta.file_id,
+        filename: String::new(),
+        tags: Vec::new(),
+        owner_peer_id: String::new(),
+        checksum: String::new(),
+        size: meta.size,
+        encrypted_file_key: Vec::new(),
+        shared_keys: HashMap::new(),
+        allowed_peers: Vec::new(),
+        attributes: HashMap::new(),
+        sealed: None,
+    }
+}
+
+/// A listing entry for callers with no read access (peers, recommendations): sealed
+/// files are redacted, others are shown as stored.
+pub fn public_view(meta: &FileMetadata) -> FileMetadata {
+    if meta.sealed.is_some() { redacted(meta) } else { meta.clone() }
+}
+
+fn file_key_for(meta: &FileMetadata, username: &str, secret: &StaticSecret) -> Option<SecretKey> {
+    let wrapped = if meta.owner_peer_id == username { Some(&meta.encrypted_file_key) } else { meta.shared_keys.get(username) };
+    wrapped.and_then(|w| unwrap_file_key(w, secret).ok())
+}
+
+/// A file as shown to a reader: sealed fields decrypted when the reader's session key
+/// unwraps the file key, redacted otherwise. The decrypted view keeps the ciphertext, so
+/// it still reads as confidential, but not other readers' tag tokens.
+pub fn view(meta: &FileMetadata, username: &str, secret: Option<&StaticSecret>) -> FileMetadata {
+    if meta.sealed.is_none() {
+        return meta.clone();
+    }
+    let fields = secret
+        .and_then(|s| file_key_for(meta, username, s))
+        .and_then(|key| unseal(meta, &key).ok());
+    match fields {
+        Some(fields) => FileMetadata {
+            filename: fields.filename,
+            tags: fields.tags,
+            attributes: fields.attributes,
+            sealed: meta.sealed.as_ref().map(|s| SealedMetadata { ciphertext: s.ciphertext.clone(), tag_tokens: HashMap::new() }),
+            ..meta.clone()
+        },
+        None => redacted(meta),
+    }
+}
+
+/// Whether a file the reader may read carries `tag`. For a sealed file this compares
+/// blind tokens; a reader with no tokens yet has the file decrypted once and their
+/// tokens added to `meta`, in which case the caller should store it. Returns
+/// `(matches, meta_changed)`.
+pub fn has_tag(meta: &mut FileMetadata, username: &str, secret: Option<&StaticSecret>, tag: &str) -> (bool, bool) {
+    let wanted = tag.trim().to_lowercase();
+    if meta.sealed.is_none() {
+        return (meta.tags.iter().any(|t| t.trim().to_lowercase() == wanted), false);
+    }
+    let Some(secret) = secret else { return (false, false) };
+    let index_key = index_key(secret);
+    let token = tag_token(&index_key, tag);
+    if let Some(tokens) = meta.sealed.as_ref().and_then(|s| s.tag_tokens.get(username)) {
+        return (tokens.contains(&token), false);
+    }
+    let Some(fields) = file_key_for(meta, username, secret).and_then(|key| unseal(meta, &key).ok()) else {
+        return (false, false);
+    };
+    let tokens = tag_tokens(&index_key, &fields.tags);
+    let found = tokens.contains(&token);
+    if let Some(sealed) = meta.sealed.as_mut() {
+        sealed.tag_tokens.insert(username.to_string(), tokens);
+    }
+    (found, true)
+}
+
+/// Drops a reader's tag tokens, e.g. when their share is revoked.
+pub fn forget_reader(meta: &mut FileMetadata, username: &str) {
+    if let Some(sealed) = meta.sealed.as_mut() {
+        sealed.tag_tokens.remove(username);
+    }
+}
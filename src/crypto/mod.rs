@@ -2,11 +2,16 @@ use aes_gcm::KeyInit;
 use aes_gcm::{Aes256Gcm, Key, Nonce};
 use aes_gcm::aead::Aead;
 use rand::RngCore;
-use x25519_dalek::{EphemeralSecret, PublicKey, SharedSecret};
+use x25519_dalek::{EphemeralSecret, PublicKey, SharedSecret, StaticSecret};
+use sha2::Digest;
 use pbkdf2::pbkdf2_hmac;
 use sha2::Sha256;
 use typenum::U12;
 use generic_array::GenericArray;
+use zeroize::Zeroizing;
+
+pub mod secret;
+pub use secret::{SecretKey, SecretString};
 
 pub fn encrypt_file(contents: &[u8], key_bytes: &[u8; 32]) -> anyhow::Result<Vec<u8>> {
     let key = Key::<aes_gcm::aes::Aes256>::from_slice(key_bytes);
@@ -32,8 +37,8 @@ pub fn decrypt_file(ciphertext: &[u8], key_bytes: &[u8; 32]) -> anyhow::Result<V
     Ok(plaintext)
 }
 
-pub fn generate_x25519_keypair() -> (EphemeralSecret, PublicKey) {
-    let secret = EphemeralSecret::random_from_rng(rand::thread_rng());
+pub fn generate_x25519_keypair() -> (StaticSecret, PublicKey) {
+    let secret = StaticSecret::random_from_rng(rand::thread_rng());
     let public = PublicKey::from(&secret);
     (secret, public)
 }
@@ -42,7 +47,65 @@ pub fn derive_shared_secret(secret: EphemeralSecret, peer_public: &PublicKey) ->
     secret.diffie_hellman(peer_public)
 }
 
-pub fn encrypt_and_save_keypair(secret: &EphemeralSecret, path: &str, password: &str) -> anyhow::Result<()> {
+/// Wraps a per-file key for `recipient`: an ephemeral X25519 exchange derives an
+/// AES-256-GCM key, and the output is `ephemeral_public || nonce || ciphertext`.
+pub fn wrap_file_key(file_key: &[u8; 32], recipient: &PublicKey) -> anyhow::Result<Vec<u8>> {
+    let ephemeral = EphemeralSecret::random_from_rng(rand::thread_rng());
+    let ephemeral_public = PublicKey::from(&ephemeral);
+    let shared = ephemeral.diffie_hellman(recipient);
+    let wrapping_key = derive_wrapping_key(shared.as_bytes(), &ephemeral_public, recipient);
+    let sealed = encrypt_file(file_key, &wrapping_key)?;
+    Ok([ephemeral_public.as_bytes(), &sealed[..]].concat())
+}
+
+/// Reverses [`wrap_file_key`] with the recipient's static secret.
+pub fn unwrap_file_key(wrapped: &[u8], secret: &StaticSecret) -> anyhow::Result<SecretKey> {
+    if wrapped.len() < 32 + 12 {
+        return Err(anyhow::anyhow!("Wrapped key too short"));
+    }
+    let (ephemeral_bytes, sealed) = wrapped.split_at(32);
+    let ephemeral_public = PublicKey::from(<[u8; 32]>::try_from(ephemeral_bytes)?);
+    let shared = secret.diffie_hellman(&ephemeral_public);
+    let wrapping_key = derive_wrapping_key(shared.as_bytes(), &ephemeral_public, &PublicKey::from(secret));
+    let mut file_key = Zeroizing::new(decrypt_file(sealed, &wrapping_key)?);
+    SecretKey::take(&mut file_key).map_err(|_| anyhow::anyhow!("Unwrapped key has wrong length"))
+}
+
+fn derive_wrapping_key(shared: &[u8; 32], ephemeral_public: &PublicKey, recipient: &PublicKey) -> SecretKey {
+    let mut hasher = Sha256::new();
+    hasher.update(shared);
+    hasher.update(ephemeral_public.as_bytes());
+    hasher.update(recipient.as_bytes());
+    let mut key = SecretKey::zeroed();
+    hasher.finalize_into(GenericArray::from_mut_slice(key.as_mut_bytes()));
+    key
+}
+
+/// PBKDF2-SHA256 key for encrypting a keypair file under a password.
+fn derive_password_key(password: &str, salt: &[u8]) -> SecretKey {
+    let mut key = SecretKey::zeroed();
+    pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, 100_000, key.as_mut_bytes());
+    key
+}
+
+/// PBKDF2-SHA256 password hash with a fresh random salt; returns `(salt, hash)`.
+pub fn hash_password(password: &str) -> ([u8; 16], [u8; 32]) {
+    use rand::RngCore;
+    let mut salt = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let mut hash = [0u8; 32];
+    pbkdf2_hmac::<Sha256>(password.as_bytes(), &salt, 100_000, &mut hash);
+    (salt, hash)
+}
+
+pub fn verify_password(password: &str, salt: &[u8], expected: &[u8]) -> bool {
+    let mut hash = Zeroizing::new([0u8; 32]);
+    pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, 100_000, &mut hash[..]);
+    // Constant-time comparison so response timing does not leak matching prefixes
+    expected.len() == hash.len() && hash.iter().zip(expected).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+pub fn encrypt_and_save_keypair(secret: &StaticSecret, path: &str, password: &str) -> anyhow::Result<()> {
     use aes_gcm::Aes256Gcm;
     use aes_gcm::Key;
     use aes_gcm::Nonce;
@@ -52,15 +115,14 @@ pub fn encrypt_and_save_keypair(secret: &EphemeralSecret, path: &str, password:
 
     let mut salt = [0u8; 16];
     rand::thread_rng().fill_bytes(&mut salt);
-    let mut key = [0u8; 32];
-    pbkdf2_hmac::<Sha256>(password.as_bytes(), &salt, 100_000, &mut key);
-    let aes_key = Key::<aes_gcm::aes::Aes256>::from_slice(&key);
+    let key = derive_password_key(password, &salt);
+    let aes_key = Key::<aes_gcm::aes::Aes256>::from_slice(&key[..]);
     let cipher = Aes256Gcm::new(aes_key);
     let mut nonce_bytes = [0u8; 12];
     rand::thread_rng().fill_bytes(&mut nonce_bytes);
     let nonce = Nonce::from_slice(&nonce_bytes);
-    // TODO: EphemeralSecret does not expose its bytes; refactor this logic to use a different key type or store a seed.
-    let ciphertext = cipher.encrypt(nonce, &b"TODO_ephemeral_secret"[..]).map_err(|e| anyhow::Error::msg(format!("AES-GCM encryption failed: {:?}", e)))?;
+    let secret_bytes = Zeroizing::new(secret.to_bytes());
+    let ciphertext = cipher.encrypt(nonce, &secret_bytes[..]).map_err(|e| anyhow::Error::msg(format!("AES-GCM encryption failed: {:?}", e)))?;
     let mut f = File::create(path)?;
     f.write_all(&salt)?;
     f.write_all(&nonce_bytes)?;
@@ -68,7 +130,7 @@ pub fn encrypt_and_save_keypair(secret: &EphemeralSecret, path: &str, password:
     Ok(())
 }
 
-pub fn load_and_decrypt_keypair(path: &str, password: &str) -> anyhow::Result<EphemeralSecret> {
+pub fn load_and_decrypt_keypair(path: &str, password: &str) -> anyhow::Result<StaticSecret> {
     use aes_gcm::Aes256Gcm;
     use aes_gcm::Key;
     use aes_gcm::Nonce;
@@ -77,11 +139,14 @@ pub fn load_and_decrypt_keypair(path: &str, password: &str) -> anyhow::Result<Ep
     if data.len() < 16 + 12 + 32 { return Err(anyhow::anyhow!("Key file too short")); }
     let (salt, rest) = data.split_at(16);
     let (nonce_bytes, ciphertext) = rest.split_at(12);
-    let mut key = [0u8; 32];
-    pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, 100_000, &mut key);
-    let aes_key = Key::<aes_gcm::aes::Aes256>::from_slice(&key);
+    let key = derive_password_key(password, salt);
+    let aes_key = Key::<aes_gcm::aes::Aes256>::from_slice(&key[..]);
     let cipher = Aes256Gcm::new(aes_key);
     let nonce: &GenericArray<u8, U12> = Nonce::from_slice(nonce_bytes);
-    // TODO: EphemeralSecret does not expose its bytes; refactor this logic to use a different key type or store a seed.
-    Ok(EphemeralSecret::random_from_rng(rand::thread_rng()))
+    let mut plaintext = Zeroizing::new(cipher.decrypt(nonce, ciphertext)
+        .map_err(|_| anyhow::anyhow!("Invalid password or corrupted key file"))?);
+    let secret_bytes = SecretKey::take(&mut plaintext)
+        .map_err(|_| anyhow::anyhow!("Key file holds a legacy placeholder key; re-register this user"))?;
+    // StaticSecret zeroizes itself on drop
+    Ok(StaticSecret::from(*secret_bytes))
 }
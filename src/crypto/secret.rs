@@ -0,0 +1,264 @@
+// Containers for key material and passwords.
+//
+// Both types keep their bytes in a heap buffer that is zeroed when dropped, never show
+// up in `Debug` output, and are locked into RAM while they live (mlock, plus
+// MADV_DONTDUMP on Linux) so they stay out of swap and core dumps. Locking is best
+// effort: it needs RLIMIT_MEMLOCK headroom and is skipped where the OS has no support.
+
+use once_cell::sync::Lazy;
+use rand::RngCore;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::Mutex;
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+/// Pages holding locked secrets, with how many secrets use each, so a page stays locked
+/// until the last secret on it is dropped.
+static LOCKED_PAGES: Lazy<Mutex<HashMap<usize, usize>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[cfg(unix)]
+fn page_size() -> usize {
+    static PAGE: Lazy<usize> = Lazy::new(|| unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(1) as usize);
+    *PAGE
+}
+
+#[cfg(unix)]
+fn pages(ptr: *const u8, len: usize) -> impl Iterator<Item = usize> {
+    let page = page_size();
+    let start = ptr as usize / page * page;
+    (start..ptr as usize + len).step_by(page)
+}
+
+/// Keeps `len` bytes at `ptr` out of swap and core dumps.
+#[cfg(unix)]
+fn lock(ptr: *const u8, len: usize) {
+    if len == 0 {
+        return;
+    }
+    let mut locked = LOCKED_PAGES.lock().unwrap();
+    for page in pages(ptr, len) {
+        let count = locked.entry(page).or_insert(0);
+        if *count == 0 {
+            // SAFETY: `page` is the start of a page of a live allocation
+            unsafe {
+                libc::mlock(page as *const libc::c_void, page_size());
+                #[cfg(target_os = "linux")]
+                libc::madvise(page as *mut libc::c_void, page_size(), libc::MADV_DONTDUMP);
+            }
+        }
+        *count += 1;
+    }
+}
+
+#[cfg(unix)]
+fn unlock(ptr: *const u8, len: usize) {
+    if len == 0 {
+        return;
+    }
+    let mut locked = LOCKED_PAGES.lock().unwrap();
+    for page in pages(ptr, len) {
+        let Some(count) = locked.get_mut(&page) else { continue };
+        *count -= 1;
+        if *count == 0 {
+            locked.remove(&page);
+            // SAFETY: as in `lock`; the allocation is freed only after this returns
+            unsafe {
+                #[cfg(target_os = "linux")]
+                libc::madvise(page as *mut libc::c_void, page_size(), libc::MADV_DODUMP);
+                libc::munlock(page as *const libc::c_void, page_size());
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn lock(_ptr: *const u8, _len: usize) {}
+
+#[cfg(not(unix))]
+fn unlock(_ptr: *const u8, _len: usize) {}
+
+/// A 32-byte symmetric key: a file key, or one derived from a password or a key exchange.
+/// Derefs to the bytes, so it can go wherever `&[u8; 32]` is expected.
+pub struct SecretKey(Box<[u8; 32]>);
+
+impl SecretKey {
+    /// A fresh random key.
+    pub fn random() -> Self {
+        let mut key = Self::zeroed();
+        rand::thread_rng().fill_bytes(&mut key.0[..]);
+        key
+    }
+
+    /// An all-zero key to be filled in place, e.g. by a KDF.
+    pub fn zeroed() -> Self {
+        let key = SecretKey(Box::new([0u8; 32]));
+        lock(key.0.as_ptr(), 32);
+        key
+    }
+
+    /// Moves `bytes` into a new key and wipes the source.
+    pub fn take(bytes: &mut [u8]) -> anyhow::Result<Self> {
+        if bytes.len() != 32 {
+            bytes.zeroize();
+            return Err(anyhow::anyhow!("Key has wrong length"));
+        }
+        let mut key = Self::zeroed();
+        key.0.copy_from_slice(bytes);
+        bytes.zeroize();
+        Ok(key)
+    }
+
+    pub fn as_mut_bytes(&mut self) -> &mut [u8; 32] {
+        &mut self.0
+    }
+}
+
+impl Deref for SecretKey {
+    type Target = [u8; 32];
+
+    fn deref(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl Clone for SecretKey {
+    fn clone(&self) -> Self {
+        let mut key = Self::zeroed();
+        key.0.copy_from_slice(&self.0[..]);
+        key
+    }
+}
+
+impl Zeroize for SecretKey {
+    fn zeroize(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl Drop for SecretKey {
+    fn drop(&mut self) {
+        self.zeroize();
+        unlock(self.0.as_ptr(), 32);
+    }
+}
+
+impl ZeroizeOnDrop for SecretKey {}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretKey(<redacted>)")
+    }
+}
+
+/// A password or other secret text. Deserializes from a plain JSON string; serializing
+/// writes it out again, for the wire only.
+pub struct SecretString(String);
+
+impl SecretString {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl From<String> for SecretString {
+    /// Takes over the string's buffer, so no unwiped copy is left behind.
+    fn from(s: String) -> Self {
+        lock(s.as_ptr(), s.capacity());
+        SecretString(s)
+    }
+}
+
+impl Clone for SecretString {
+    fn clone(&self) -> Self {
+        SecretString::from(self.0.clone())
+    }
+}
+
+impl Zeroize for SecretString {
+    fn zeroize(&mut self) {
+        // Wipes the whole capacity, then empties the string
+        self.0.zeroize();
+    }
+}
+
+impl Drop for SecretString {
+    fn drop(&mut self) {
+        let (ptr, capacity) = (self.0.as_ptr(), self.0.capacity());
+        self.zeroize();
+        unlock(ptr, capacity);
+    }
+}
+
+impl ZeroizeOnDrop for SecretString {}
+
+impl fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretString(<redacted>)")
+    }
+}
+
+impl<'de> Deserialize<'de> for SecretString {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(SecretString::from)
+    }
+}
+
+impl Serialize for SecretString {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_buffer_is_cleared_after_use() {
+        let mut key = SecretKey::random();
+        assert_ne!(*key, [0u8; 32]);
+        let buffer = key.as_ptr();
+        key.zeroize();
+        // SAFETY: the key is still alive, so its buffer is too
+        let after = unsafe { std::slice::from_raw_parts(buffer, 32) };
+        assert!(after.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn taking_a_key_wipes_the_source() {
+        let mut source = vec![0x5a; 32];
+        let key = SecretKey::take(&mut source).unwrap();
+        assert_eq!(*key, [0x5a; 32]);
+        assert!(source.iter().all(|&b| b == 0));
+
+        let mut short = vec![0x5a; 16];
+        assert!(SecretKey::take(&mut short).is_err());
+        assert!(short.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn password_buffer_is_cleared_after_use() {
+        let mut password = SecretString::from(String::from("correct horse battery staple"));
+        let (buffer, capacity) = (password.expose().as_ptr(), password.0.capacity());
+        password.zeroize();
+        // SAFETY: zeroize keeps the allocation and wipes all of its capacity
+        let after = unsafe { std::slice::from_raw_parts(buffer, capacity) };
+        assert!(after.iter().all(|&b| b == 0));
+        assert!(password.is_empty());
+    }
+
+    #[test]
+    fn debug_output_hides_secrets() {
+        let key = SecretKey::take(&mut [0xab; 32]).unwrap();
+        let password = SecretString::from("hunter2".to_string());
+        let shown = format!("{:?} {:?}", key, password);
+        assert!(!shown.contains("171"));
+        assert!(!shown.contains("hunter2"));
+    }
+}
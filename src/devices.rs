@@ -0,0 +1,508 @@
+// Device enrolment and per-device file keys.
+//
+// Each client device generates its own X25519 keypair and presents the public half at
+// login. The device ID is derived from that key, so an enrolled ID cannot be claimed
+// with a different key, and the login must prove the device holds the secret half: the
+// client first asks for a login challenge, a one-time X25519 key, and answers it with an
+// HMAC keyed by the secret the two keys share (see [`prove_device`]). A user's first device is approved on enrolment; later ones stay
+// pending until an approved device approves them, or they redeem a one-time code that
+// an approved device issued. On approval, every file key the user can read is wrapped
+// again for the device's public key, so the device can decrypt locally without the
+// user's master key. Revoking a device drops its record and its wrapped keys; the
+// master key and other devices are untouched.
+//
+// Once an account has an approved device, it only opens full sessions on approved
+// devices: a pending device gets a session that can do no more than redeem an enrolment
+// code and log out, and a login without a device key is refused.
+//
+// Records live in `device_keys` (keyed `user_id/device_id`), one-time codes in
+// `device_enrolment_codes` (keyed by the code's SHA-256) and wrapped keys in
+// `device_file_keys` (keyed `file_id/device_id`).
+
+use crate::audit;
+use crate::auth::{self, AuthSession, SessionStore};
+use crate::crypto::{unwrap_file_key, wrap_file_key};
+use crate::storage::{FileMetadata, Storage};
+use crate::user_management::UserStore;
+use axum::http::StatusCode;
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tonic::Status;
+use uuid::Uuid;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// How long a one-time enrolment code stays valid
+pub const ENROLMENT_CODE_SECS: u64 = 10 * 60;
+/// How long a login challenge can be answered
+pub const CHALLENGE_SECS: u64 = 2 * 60;
+/// Most unanswered login challenges kept at once; the oldest go first
+const MAX_CHALLENGES: usize = 10_000;
+
+// Secret halves of outstanding login challenges, keyed by challenge ID. They only live
+// in memory: a challenge is answered within seconds, and a restart just means asking again.
+static CHALLENGES: Lazy<Mutex<HashMap<String, (StaticSecret, u64)>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, thiserror::Error)]
+pub enum DeviceError {
+    #[error("Device '{0}' is not enrolled")]
+    NotEnrolled(String),
+    #[error("Device '{0}' is already approved")]
+    AlreadyApproved(String),
+    #[error("Device '{0}' has not been approved yet")]
+    NotApproved(String),
+    #[error("Device ID does not match the presented device key")]
+    KeyMismatch,
+    #[error("Logins with a device key must answer a login challenge")]
+    ProofRequired,
+    #[error("Invalid or expired device key proof")]
+    InvalidProof,
+    #[error("Device public key must be 32 bytes")]
+    InvalidKey,
+    #[error("Invalid or expired enrolment code")]
+    InvalidCode,
+    #[error("No key for this file has been wrapped for this device")]
+    NoWrappedKey,
+    #[error("Session key not loaded on this node; log in again")]
+    SessionKeyUnavailable,
+    #[error("{0}")]
+    Storage(#[from] anyhow::Error),
+}
+
+impl DeviceError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            DeviceError::NotEnrolled(_) | DeviceError::NoWrappedKey => StatusCode::NOT_FOUND,
+            DeviceError::AlreadyApproved(_) => StatusCode::CONFLICT,
+            DeviceError::NotApproved(_) => StatusCode::FORBIDDEN,
+            DeviceError::KeyMismatch | DeviceError::ProofRequired | DeviceError::InvalidProof | DeviceError::InvalidCode | DeviceError::SessionKeyUnavailable => StatusCode::UNAUTHORIZED,
+            DeviceError::InvalidKey => StatusCode::BAD_REQUEST,
+            DeviceError::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl From<DeviceError> for Status {
+    fn from(e: DeviceError) -> Self {
+        match e {
+            DeviceError::NotEnrolled(_) | DeviceError::NoWrappedKey => Status::not_found(e.to_string()),
+            DeviceError::AlreadyApproved(_) | DeviceError::NotApproved(_) => Status::failed_precondition(e.to_string()),
+            DeviceError::KeyMismatch | DeviceError::ProofRequired | DeviceError::InvalidProof | DeviceError::InvalidCode | DeviceError::SessionKeyUnavailable => {
+                Status::unauthenticated(e.to_string())
+            }
+            DeviceError::InvalidKey => Status::invalid_argument(e.to_string()),
+            DeviceError::Storage(_) => Status::internal(e.to_string()),
+        }
+    }
+}
+
+impl From<sled::Error> for DeviceError {
+    fn from(e: sled::Error) -> Self {
+        DeviceError::Storage(e.into())
+    }
+}
+
+impl From<serde_json::Error> for DeviceError {
+    fn from(e: serde_json::Error) -> Self {
+        DeviceError::Storage(e.into())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceState {
+    Pending,
+    Approved,
+}
+
+impl std::fmt::Display for DeviceState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            DeviceState::Pending => "pending",
+            DeviceState::Approved => "approved",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceKey {
+    pub device_id: String,
+    pub user_id: String,
+    pub public_key: [u8; 32],
+    pub fingerprint: String,
+    pub state: DeviceState,
+    pub enrolled_at: u64,
+    pub approved_at: Option<u64>,
+    /// Device that approved this one, or `code` / `first device`
+    pub approved_by: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct EnrolmentCode {
+    user_id: String,
+    issued_by: String,
+    expires_at: u64,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// SHA-256 of a device public key as colon-separated hex pairs of its first 16 bytes,
+/// short enough to compare by eye between two devices.
+pub fn fingerprint(public_key: &PublicKey) -> String {
+    let digest = Sha256::digest(public_key.as_bytes());
+    digest[..16].iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(":")
+}
+
+/// The device ID bound to `public_key`.
+pub fn device_id_for(public_key: &PublicKey) -> String {
+    format!("dev-{}", hex::encode(&Sha256::digest(public_key.as_bytes())[..12]))
+}
+
+/// Resolves the device a login comes from. With a public key, the device ID is the one
+/// derived from it and a different `device_id` is rejected; without one, the client's
+/// `device_id` (or `default`) names an unenrolled device.
+pub fn resolve_device(device_id: Option<&str>, public_key: Option<&[u8]>, default: &str) -> Result<(String, Option<PublicKey>), DeviceError> {
+    let device_id = device_id.filter(|d| !d.is_empty());
+    let Some(bytes) = public_key.filter(|k| !k.is_empty()) else {
+        return Ok((device_id.unwrap_or(default).to_string(), None));
+    };
+    let public_key = PublicKey::from(<[u8; 32]>::try_from(bytes).map_err(|_| DeviceError::InvalidKey)?);
+    let derived = device_id_for(&public_key);
+    if device_id.is_some_and(|d| d != derived) {
+        return Err(DeviceError::KeyMismatch);
+    }
+    Ok((derived, Some(public_key)))
+}
+
+/// A one-time key a device answers at login to prove it holds its device key.
+pub struct LoginChallenge {
+    pub challenge_id: String,
+    pub public_key: PublicKey,
+    pub expires_at: u64,
+}
+
+/// Issues a login challenge, valid for [`CHALLENGE_SECS`] and a single login.
+pub fn login_challenge() -> LoginChallenge {
+    let (secret, public_key) = crate::crypto::generate_x25519_keypair();
+    let now = now_secs();
+    let expires_at = now + CHALLENGE_SECS;
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let challenge_id = hex::encode(bytes);
+    let mut challenges = CHALLENGES.lock().unwrap();
+    challenges.retain(|_, (_, expires)| *expires > now);
+    if challenges.len() >= MAX_CHALLENGES
+        && let Some(oldest) = challenges.iter().min_by_key(|(_, (_, expires))| *expires).map(|(id, _)| id.clone())
+    {
+        challenges.remove(&oldest);
+    }
+    challenges.insert(challenge_id.clone(), (secret, expires_at));
+    LoginChallenge { challenge_id, public_key, expires_at }
+}
+
+fn proof_mac(shared: &[u8], challenge_id: &str, username: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(shared).expect("HMAC takes keys of any length");
+    mac.update(format!("dafs-device-login\0{}\0{}", challenge_id, username).as_bytes());
+    mac
+}
+
+/// The answer to a login challenge for `username`, computed by the device from its secret key.
+pub fn prove_device(device_secret: &StaticSecret, challenge_id: &str, challenge_key: &PublicKey, username: &str) -> Vec<u8> {
+    let shared = device_secret.diffie_hellman(challenge_key);
+    proof_mac(shared.as_bytes(), challenge_id, username).finalize().into_bytes().to_vec()
+}
+
+/// A device key presented at login, with the answer to a login challenge.
+pub struct DeviceProof<'a> {
+    pub public_key: PublicKey,
+    pub challenge_id: &'a str,
+    pub proof: &'a [u8],
+}
+
+impl DeviceProof<'_> {
+    /// Checks the answer for `username`, using up the challenge whether or not it matches.
+    pub fn verify(&self, username: &str) -> Result<(), DeviceError> {
+        if self.challenge_id.is_empty() || self.proof.is_empty() {
+            return Err(DeviceError::ProofRequired);
+        }
+        let (secret, expires_at) = CHALLENGES.lock().unwrap().remove(self.challenge_id).ok_or(DeviceError::InvalidProof)?;
+        if expires_at <= now_secs() {
+            return Err(DeviceError::InvalidProof);
+        }
+        let shared = secret.diffie_hellman(&self.public_key);
+        proof_mac(shared.as_bytes(), self.challenge_id, username).verify_slice(self.proof).map_err(|_| DeviceError::InvalidProof)
+    }
+}
+
+fn hash_code(code: &str) -> String {
+    let normalized: String = code.chars().filter(|c| c.is_ascii_alphanumeric()).collect::<String>().to_lowercase();
+    hex::encode(Sha256::digest(normalized.as_bytes()))
+}
+
+fn record_key(user_id: &str, device_id: &str) -> Vec<u8> {
+    format!("{}/{}", user_id, device_id).into_bytes()
+}
+
+fn wrapped_key(file_id: &Uuid, device_id: &str) -> Vec<u8> {
+    format!("{}/{}", file_id, device_id).into_bytes()
+}
+
+pub struct DeviceRegistry {
+    devices: sled::Tree,
+    codes: sled::Tree,
+    file_keys: sled::Tree,
+}
+
+impl DeviceRegistry {
+    pub fn open(storage: &Storage) -> anyhow::Result<Self> {
+        Ok(Self {
+            devices: storage.open_tree("device_keys")?,
+            codes: storage.open_tree("device_enrolment_codes")?,
+            file_keys: storage.open_tree("device_file_keys")?,
+        })
+    }
+
+    pub fn get(&self, user_id: &str, device_id: &str) -> Result<Option<DeviceKey>, DeviceError> {
+        match self.devices.get(record_key(user_id, device_id))? {
+            Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn put(&self, device: &DeviceKey) -> Result<(), DeviceError> {
+        self.devices.insert(record_key(&device.user_id, &device.device_id), serde_json::to_vec(device)?)?;
+        Ok(())
+    }
+
+    /// The user's enrolled devices, oldest first.
+    pub fn list(&self, user_id: &str) -> Result<Vec<DeviceKey>, DeviceError> {
+        let mut out = Vec::new();
+        for item in self.devices.scan_prefix(format!("{}/", user_id)) {
+            let (_, v) = item?;
+            out.push(serde_json::from_slice::<DeviceKey>(&v)?);
+        }
+        out.sort_by_key(|d| d.enrolled_at);
+        Ok(out)
+    }
+
+    pub fn is_approved(&self, user_id: &str, device_id: &str) -> Result<bool, DeviceError> {
+        Ok(self.get(user_id, device_id)?.is_some_and(|d| d.state == DeviceState::Approved))
+    }
+
+    /// Whether the user has any approved device, which closes the account to others.
+    pub fn has_approved(&self, user_id: &str) -> Result<bool, DeviceError> {
+        Ok(self.list(user_id)?.iter().any(|d| d.state == DeviceState::Approved))
+    }
+
+    /// Enrols the device holding `public_key` for the user, or returns its existing record.
+    /// The user's first device is approved straight away; any later one starts pending.
+    pub fn enrol(&self, user_id: &str, public_key: &PublicKey) -> Result<DeviceKey, DeviceError> {
+        let device_id = device_id_for(public_key);
+        if let Some(existing) = self.get(user_id, &device_id)? {
+            return Ok(existing);
+        }
+        let now = now_secs();
+        let first = !self.has_approved(user_id)?;
+        let device = DeviceKey {
+            device_id,
+            user_id: user_id.to_string(),
+            public_key: *public_key.as_bytes(),
+            fingerprint: fingerprint(public_key),
+            state: if first { DeviceState::Approved } else { DeviceState::Pending },
+            enrolled_at: now,
+            approved_at: first.then_some(now),
+            approved_by: first.then(|| "first device".to_string()),
+        };
+        self.put(&device)?;
+        Ok(device)
+    }
+
+    /// Approves a pending device on behalf of `approved_by` and wraps every file key the
+    /// user can read for it, unwrapping them with the user's `secret`. Returns the device
+    /// and how many keys were wrapped.
+    pub fn approve(&self, storage: &Storage, user_id: &str, username: &str, device_id: &str, approved_by: &str, secret: &StaticSecret) -> Result<(DeviceKey, usize), DeviceError> {
+        let mut device = self.get(user_id, device_id)?.ok_or_else(|| DeviceError::NotEnrolled(device_id.to_string()))?;
+        if device.state == DeviceState::Approved {
+            return Err(DeviceError::AlreadyApproved(device_id.to_string()));
+        }
+        let wrapped = self.wrap_user_files(storage, username, &device, secret)?;
+        device.state = DeviceState::Approved;
+        device.approved_at = Some(now_secs());
+        device.approved_by = Some(approved_by.to_string());
+        self.put(&device)?;
+        Ok((device, wrapped))
+    }
+
+    /// Issues a one-time code that lets one pending device of the user approve itself.
+    /// Only an approved device can issue codes.
+    pub fn issue_code(&self, user_id: &str, issuing_device: &str) -> Result<(String, u64), DeviceError> {
+        if !self.is_approved(user_id, issuing_device)? {
+            return Err(DeviceError::NotApproved(issuing_device.to_string()));
+        }
+        let mut bytes = [0u8; 5];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let code = hex::encode(bytes);
+        let code = format!("{}-{}", &code[..5], &code[5..]);
+        let expires_at = now_secs() + ENROLMENT_CODE_SECS;
+        let record = EnrolmentCode { user_id: user_id.to_string(), issued_by: issuing_device.to_string(), expires_at };
+        self.codes.insert(hash_code(&code), serde_json::to_vec(&record)?)?;
+        Ok((code, expires_at))
+    }
+
+    /// Approves `device_id` with a code from [`DeviceRegistry::issue_code`], consuming it.
+    pub fn redeem_code(&self, storage: &Storage, user_id: &str, username: &str, device_id: &str, code: &str, secret: &StaticSecret) -> Result<(DeviceKey, usize), DeviceError> {
+        // Removing first means a code can be redeemed once even under concurrent requests
+        let record: EnrolmentCode = match self.codes.remove(hash_code(code))? {
+            Some(data) => serde_json::from_slice(&data)?,
+            None => return Err(DeviceError::InvalidCode),
+        };
+        if record.user_id != user_id || record.expires_at <= now_secs() {
+            return Err(DeviceError::InvalidCode);
+        }
+        self.approve(storage, user_id, username, device_id, &format!("code from {}", record.issued_by), secret)
+    }
+
+    /// Removes a device and every key wrapped for it. Returns the removed record.
+    pub fn revoke(&self, user_id: &str, device_id: &str) -> Result<Option<DeviceKey>, DeviceError> {
+        let Some(data) = self.devices.remove(record_key(user_id, device_id))? else {
+            return Ok(None);
+        };
+        let suffix = format!("/{}", device_id);
+        for item in self.file_keys.iter() {
+            let (k, _) = item?;
+            if k.ends_with(suffix.as_bytes()) {
+                self.file_keys.remove(k)?;
+            }
+        }
+        Ok(Some(serde_json::from_slice(&data)?))
+    }
+
+    /// The file key wrapped for `device_id`, which only that device's secret can unwrap.
+    pub fn wrapped_file_key(&self, file_id: &Uuid, device_id: &str) -> Result<Vec<u8>, DeviceError> {
+        self.file_keys.get(wrapped_key(file_id, device_id))?.map(|k| k.to_vec()).ok_or(DeviceError::NoWrappedKey)
+    }
+
+    /// Wraps `file_key` for every approved device of the owner and of each user the file
+    /// is shared with, replacing whatever was wrapped for the file before.
+    pub fn sync_file(&self, storage: &Storage, meta: &FileMetadata, file_key: &[u8; 32]) -> Result<(), DeviceError> {
+        self.forget_file(&meta.file_id)?;
+        let users = UserStore::open(storage)?;
+        let readers = std::iter::once(&meta.owner_peer_id).chain(meta.shared_keys.keys());
+        for username in readers {
+            let Some(user) = users.get_by_username(username).map_err(|e| anyhow::anyhow!(e.to_string()))? else {
+                continue;
+            };
+            for device in self.list(&user.user_id)?.into_iter().filter(|d| d.state == DeviceState::Approved) {
+                let wrapped = wrap_file_key(file_key, &PublicKey::from(device.public_key))?;
+                self.file_keys.insert(wrapped_key(&meta.file_id, &device.device_id), wrapped)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops the copies of a file's key wrapped for the user's devices, after the file is
+    /// unshared from them.
+    pub fn forget_user_file(&self, user_id: &str, file_id: &Uuid) -> Result<(), DeviceError> {
+        for device in self.list(user_id)? {
+            self.file_keys.remove(wrapped_key(file_id, &device.device_id))?;
+        }
+        Ok(())
+    }
+
+    /// Drops every device copy of a file's key.
+    pub fn forget_file(&self, file_id: &Uuid) -> Result<(), DeviceError> {
+        for item in self.file_keys.scan_prefix(format!("{}/", file_id)) {
+            let (k, _) = item?;
+            self.file_keys.remove(k)?;
+        }
+        Ok(())
+    }
+
+    fn wrap_user_files(&self, storage: &Storage, username: &str, device: &DeviceKey, secret: &StaticSecret) -> Result<usize, DeviceError> {
+        let public_key = PublicKey::from(device.public_key);
+        let mut wrapped = 0;
+        for meta in storage.list_metadata()? {
+            let user_copy = if meta.owner_peer_id == username { Some(&meta.encrypted_file_key) } else { meta.shared_keys.get(username) };
+            // Files uploaded without a wrapped key (or under an old key) have nothing to hand on
+            let Some(file_key) = user_copy.and_then(|k| unwrap_file_key(k, secret).ok()) else {
+                continue;
+            };
+            self.file_keys.insert(wrapped_key(&meta.file_id, &device.device_id), wrap_file_key(&file_key, &public_key)?)?;
+            wrapped += 1;
+        }
+        Ok(wrapped)
+    }
+}
+
+/// What [`remove_device`] took away.
+pub struct Removal {
+    /// Fingerprint of the device key, for devices that had enrolled one
+    pub fingerprint: Option<String>,
+    pub sessions_revoked: usize,
+}
+
+fn session_secret(session: &AuthSession) -> Result<StaticSecret, DeviceError> {
+    auth::session_secret(&session.0.session_id).ok_or(DeviceError::SessionKeyUnavailable)
+}
+
+/// Approves one of the caller's pending devices from the caller's own device, which must
+/// itself be approved. Shared by the REST and gRPC APIs.
+pub fn approve_device(storage: &Storage, session: &AuthSession, device_id: &str) -> Result<(DeviceKey, usize), DeviceError> {
+    let result = DeviceRegistry::open(storage).map_err(DeviceError::from).and_then(|registry| {
+        if !registry.is_approved(&session.0.user_id, &session.0.device_id)? {
+            return Err(DeviceError::NotApproved(session.0.device_id.clone()));
+        }
+        registry.approve(storage, &session.0.user_id, session.username(), device_id, &session.0.device_id, &session_secret(session)?)
+    });
+    audit::record_result(storage, session.username(), "approve_device", Some(device_id), &result);
+    result
+}
+
+/// Issues a one-time enrolment code from the caller's approved device.
+pub fn issue_code(storage: &Storage, session: &AuthSession) -> Result<(String, u64), DeviceError> {
+    let result = DeviceRegistry::open(storage).map_err(DeviceError::from)
+        .and_then(|registry| registry.issue_code(&session.0.user_id, &session.0.device_id));
+    audit::record_result(storage, session.username(), "device_code", Some(&session.0.device_id), &result);
+    result
+}
+
+/// Approves the caller's own pending device with a one-time code.
+pub fn redeem_code(storage: &Storage, session: &AuthSession, code: &str) -> Result<(DeviceKey, usize), DeviceError> {
+    let result = DeviceRegistry::open(storage).map_err(DeviceError::from).and_then(|registry| {
+        registry.redeem_code(storage, &session.0.user_id, session.username(), &session.0.device_id, code, &session_secret(session)?)
+    });
+    audit::record_result(storage, session.username(), "approve_device", Some(&session.0.device_id), &result);
+    result
+}
+
+/// Revokes one of the caller's devices: its key and wrapped file keys, its entry in the
+/// user's device list and its sessions. Returns `None` if the user has no such device.
+pub fn remove_device(storage: &Storage, session: &AuthSession, device_id: &str) -> Result<Option<Removal>, DeviceError> {
+    let result = (|| -> Result<Option<Removal>, DeviceError> {
+        let revoked = DeviceRegistry::open(storage)?.revoke(&session.0.user_id, device_id)?;
+        let listed = UserStore::open(storage)?.remove_device(&session.0.user_id, device_id)
+            .map_err(|e| anyhow::anyhow!(e.to_string()))?;
+        if revoked.is_none() && !listed {
+            return Ok(None);
+        }
+        // A removed device must not keep a working token
+        let sessions_revoked = SessionStore::new(storage)?.revoke_device(session.username(), device_id)
+            .map_err(|e| anyhow::anyhow!(e.to_string()))?;
+        Ok(Some(Removal { fingerprint: revoked.map(|d| d.fingerprint), sessions_revoked }))
+    })();
+    let detail = match &result {
+        Ok(Some(Removal { fingerprint: Some(fingerprint), .. })) => Some(format!("key {}", fingerprint)),
+        Ok(Some(_)) => None,
+        Ok(None) => Some("not found".to_string()),
+        Err(e) => Some(e.to_string()),
+    };
+    audit::record(storage, session.username(), "remove_device", Some(device_id), matches!(result, Ok(Some(_))), detail.as_deref());
+    result
+}
@@ -1,13 +1,24 @@
-use tonic::{transport::Server, Request, Response, Status};
+use tonic::{transport::{Server, ServerTlsConfig}, Request, Response, Status};
 use std::sync::Arc;
 use crate::storage::Storage;
 use crate::peer::P2PNode;
+use crate::peer::download::{self, DownloadOptions, DownloadProgress};
+use crate::peer::{erasure, replication};
 use crate::ai::{train_local_model, get_recommendations, aggregate_remote_model, NCFModel, LOCAL_MODEL};
-use crate::crypto::load_and_decrypt_keypair;
+use crate::audit::{self, AuditFilter, AuditLog};
+use crate::auth::{self, AuthError, AuthSession, GrpcAuth, SecondFactor, SessionStore};
+use crate::mfa::MfaStore;
+use crate::devices::{self, DeviceRegistry};
+use crate::links::{LinkError, LinkOptions, LinkStore, ShareLink};
+use crate::confidential;
+use crate::crypto::SecretString;
+use crate::policy::{self, FilePermission, Permission, Policy, Role};
+use crate::ratelimit::{resource_exhausted, GrpcRateLimitLayer, RateLimiter};
+use crate::user_management::UserStore;
+use crate::transport::ServerConfig;
 use uuid::Uuid;
 use std::fs;
 use std::io::Write;
-use chrono::Utc;
 
 // Include the generated protobuf code
 pub mod dafs {
@@ -36,6 +47,7 @@ impl AiService for DafsAiService {
         &self,
         request: Request<TrainRequest>,
     ) -> Result<Response<TrainResponse>, Status> {
+        authorize(&self.storage, &request, Permission::ManageModel)?;
         let req = request.into_inner();
         
         // If no specific interactions provided, use all from storage
@@ -79,21 +91,14 @@ impl AiService for DafsAiService {
         &self,
         request: Request<RecommendationsRequest>,
     ) -> Result<Response<RecommendationsResponse>, Status> {
+        let session = auth::require_session(&request)?;
         let req = request.into_inner();
-        let files = self.storage.list_metadata()
+        let files = crate::api::readable_files(&self.storage, &session)
             .map_err(|e| Status::internal(format!("Storage error: {}", e)))?;
         
         match get_recommendations(&req.user_id, &files) {
             Ok(recommendations) => {
-                let proto_files = recommendations.into_iter().map(|f| FileMetadata {
-                    file_id: f.file_id.to_string(),
-                    filename: f.filename,
-                    tags: f.tags,
-                    owner_peer_id: f.owner_peer_id,
-                    checksum: f.checksum,
-                    size: f.size,
-                    shared_keys: f.shared_keys,
-                }).collect();
+                let proto_files = recommendations.iter().map(confidential::public_view).map(file_info).collect();
                 
                 Ok(Response::new(RecommendationsResponse {
                     files: proto_files,
@@ -107,6 +112,7 @@ impl AiService for DafsAiService {
         &self,
         request: Request<AggregateRequest>,
     ) -> Result<Response<AggregateResponse>, Status> {
+        authorize(&self.storage, &request, Permission::ManageModel)?;
         let req = request.into_inner();
         
         match bincode::deserialize::<NCFModel>(&req.model_data) {
@@ -145,8 +151,9 @@ impl AiService for DafsAiService {
     }
 }
 
-#[derive(Default)]
-pub struct DafsAuthService;
+pub struct DafsAuthService {
+    storage: Arc<Storage>,
+}
 
 #[tonic::async_trait]
 impl AuthService for DafsAuthService {
@@ -155,52 +162,76 @@ impl AuthService for DafsAuthService {
         request: Request<RegisterRequest>,
     ) -> Result<Response<RegisterResponse>, Status> {
         let req = request.into_inner();
-        // Use same logic as HTTP register
-        let keypair = crate::crypto::generate_x25519_keypair();
-        let secret = keypair.0;
-        let public = keypair.1;
-        let keyfile = format!("userkeys/{}.key", req.username);
-        std::fs::create_dir_all("userkeys").ok();
-        if let Err(e) = crate::crypto::encrypt_and_save_keypair(&secret, &keyfile, &req.password) {
-            return Ok(Response::new(RegisterResponse {
+        let result = user_store(&self.storage)?.register(&req.username, &req.username, None, SecretString::from(req.password).expose());
+        audit::record_result(&self.storage, &req.username, "register", None, &result);
+        match result {
+            Ok(_) => Ok(Response::new(RegisterResponse {
+                success: true,
+                message: "ok".to_string(),
+            })),
+            Err(e) => Ok(Response::new(RegisterResponse {
                 success: false,
-                message: format!("Key save error: {}", e),
-            }));
+                message: e.to_string(),
+            })),
         }
-        let user = crate::models::User { username: req.username.clone(), public_key: public.to_bytes() };
-        crate::api::USER_DB.lock().unwrap().insert(req.username, user);
-        Ok(Response::new(RegisterResponse {
-            success: true,
-            message: "ok".to_string(),
-        }))
     }
     async fn login(
         &self,
         request: Request<LoginRequest>,
     ) -> Result<Response<LoginResponse>, Status> {
+        let client = request.remote_addr().map(|addr| addr.ip());
         let req = request.into_inner();
-        let keyfile = format!("userkeys/{}.key", req.username);
-        match crate::crypto::load_and_decrypt_keypair(&keyfile, &req.password) {
-            Ok(_) => Ok(Response::new(LoginResponse {
+        let (device_id, device_key) = devices::resolve_device(Some(&req.device_id), Some(&req.device_public_key), "grpc")?;
+        let device = device_key.map(|public_key| devices::DeviceProof { public_key, challenge_id: &req.challenge_id, proof: &req.device_proof });
+        let second_factor = (!req.otp.is_empty()).then(|| SecondFactor { code: &req.otp, trust_device: req.trust_device });
+        match auth::login(&self.storage, &req.username, SecretString::from(req.password).expose(), second_factor, &device_id, device, client) {
+            Ok((session, token)) => Ok(Response::new(LoginResponse {
                 success: true,
                 message: "ok".to_string(),
+                token,
+                session_id: session.session_id,
+                expires_at: session.expires_at,
+                mfa_required: false,
+                device_state: device_state(&self.storage, &session.user_id, &device_id),
+                device_id,
             })),
-            Err(_) => Ok(Response::new(LoginResponse {
+            Err(e @ (AuthError::InvalidCredentials | AuthError::SecondFactorRequired | AuthError::InvalidSecondFactor | AuthError::DeviceKeyMismatch
+                | AuthError::DeviceProofRequired | AuthError::InvalidDeviceProof | AuthError::DeviceNotApproved)) => Ok(Response::new(LoginResponse {
                 success: false,
-                message: "Invalid username or password".to_string(),
+                mfa_required: matches!(e, AuthError::SecondFactorRequired),
+                message: e.to_string(),
+                ..Default::default()
             })),
+            Err(e @ AuthError::Throttled { retry_after }) => Err(resource_exhausted(retry_after, e.to_string())),
+            Err(e) => Err(Status::internal(e.to_string())),
         }
     }
 
+    async fn login_challenge(
+        &self,
+        _request: Request<LoginChallengeRequest>,
+    ) -> Result<Response<LoginChallengeResponse>, Status> {
+        let challenge = devices::login_challenge();
+        Ok(Response::new(LoginChallengeResponse {
+            challenge_id: challenge.challenge_id,
+            public_key: challenge.public_key.as_bytes().to_vec(),
+            expires_at: challenge.expires_at,
+        }))
+    }
+
     async fn logout(
         &self,
         request: Request<LogoutRequest>,
     ) -> Result<Response<LogoutResponse>, Status> {
-        let req = request.into_inner();
-        // Simple logout - just return success
+        let session = auth::require_any_session(&request)?;
+        SessionStore::new(&self.storage)
+            .map_err(|e| Status::internal(e.to_string()))?
+            .revoke(&session.0.session_id)
+            .map_err(|e| Status::internal(e.to_string()))?;
+        audit::record_session(&self.storage, &session, "logout", Some(&session.0.device_id), true, None);
         Ok(Response::new(LogoutResponse {
             success: true,
-            message: format!("User {} logged out successfully", req.username),
+            message: format!("User {} logged out successfully", session.username()),
         }))
     }
 
@@ -208,30 +239,24 @@ impl AuthService for DafsAuthService {
         &self,
         request: Request<ChangeUsernameRequest>,
     ) -> Result<Response<ChangeUsernameResponse>, Status> {
+        let session = auth::require_session(&request)?;
         let req = request.into_inner();
-        // Verify old credentials first
-        let old_keyfile = format!("userkeys/{}.key", req.old_username);
-        if let Err(_) = crate::crypto::load_and_decrypt_keypair(&old_keyfile, &req.password) {
-            return Ok(Response::new(ChangeUsernameResponse {
-                success: false,
-                message: "Invalid old username or password".to_string(),
-            }));
+        if req.old_username != session.username() {
+            return Err(Status::permission_denied("Can only rename the logged-in user"));
         }
-        
-        // Rename keyfile
-        let new_keyfile = format!("userkeys/{}.key", req.new_username);
-        if let Err(e) = std::fs::rename(&old_keyfile, &new_keyfile) {
+        if let Err(e) = user_store(&self.storage)?.rename(&self.storage, &req.old_username, &req.new_username, SecretString::from(req.password).expose()) {
+            audit::record_session(&self.storage, &session, "change_username", Some(&req.new_username), false, Some(&e.to_string()));
             return Ok(Response::new(ChangeUsernameResponse {
                 success: false,
-                message: format!("Failed to rename keyfile: {}", e),
+                message: e.to_string(),
             }));
         }
+        audit::record_session(&self.storage, &session, "change_username", Some(&req.new_username), true, None);
         
-        // Update user database
-        let mut user_db = crate::api::USER_DB.lock().unwrap();
-        if let Some(user) = user_db.remove(&req.old_username) {
-            user_db.insert(req.new_username.clone(), user);
-        }
+        // Tokens name the old user; make every device log in again under the new one
+        SessionStore::new(&self.storage)
+            .and_then(|store| store.revoke_all(&req.old_username).map_err(Into::into))
+            .map_err(|e| Status::internal(e.to_string()))?;
         
         Ok(Response::new(ChangeUsernameResponse {
             success: true,
@@ -241,17 +266,14 @@ impl AuthService for DafsAuthService {
 
     async fn list_users(
         &self,
-        _request: Request<ListUsersRequest>,
+        request: Request<ListUsersRequest>,
     ) -> Result<Response<ListUsersResponse>, Status> {
-        let user_db = crate::api::USER_DB.lock().unwrap();
-        let users = user_db.iter().map(|(username, user)| UserInfo {
-            user_id: username.clone(),
-            username: username.clone(),
-            display_name: username.clone(), // Use username as display name for now
-            email: "".to_string(),
-            status: "active".to_string(),
-            last_seen: chrono::Utc::now().to_rfc3339(),
-        }).collect();
+        auth::require_session(&request)?;
+        let users = user_store(&self.storage)?.list()
+            .map_err(|e| Status::internal(e.to_string()))?
+            .iter()
+            .map(user_info)
+            .collect();
         
         Ok(Response::new(ListUsersResponse { users }))
     }
@@ -260,43 +282,84 @@ impl AuthService for DafsAuthService {
         &self,
         request: Request<SearchUsersRequest>,
     ) -> Result<Response<SearchUsersResponse>, Status> {
+        auth::require_session(&request)?;
         let req = request.into_inner();
-        let user_db = crate::api::USER_DB.lock().unwrap();
-        let users = user_db.iter()
-            .filter(|(username, _)| username.to_lowercase().contains(&req.query.to_lowercase()))
-            .map(|(username, user)| UserInfo {
-                user_id: username.clone(),
-                username: username.clone(),
-                display_name: username.clone(),
-                email: "".to_string(),
-                status: "active".to_string(),
-                last_seen: chrono::Utc::now().to_rfc3339(),
-            }).collect();
+        let users = user_store(&self.storage)?.search(&req.query)
+            .map_err(|e| Status::internal(e.to_string()))?
+            .iter()
+            .map(user_info)
+            .collect();
         
         Ok(Response::new(SearchUsersResponse { users }))
     }
 
     async fn who_am_i(
         &self,
-        _request: Request<WhoAmIRequest>,
+        request: Request<WhoAmIRequest>,
     ) -> Result<Response<WhoAmIResponse>, Status> {
-        // For now, return a default user - in a real implementation this would come from session
-        let user = UserInfo {
-            user_id: "current_user".to_string(),
-            username: "current_user".to_string(),
-            display_name: "Current User".to_string(),
-            email: "".to_string(),
-            status: "active".to_string(),
-            last_seen: chrono::Utc::now().to_rfc3339(),
-        };
+        let session = auth::require_session(&request)?;
+        let user = user_store(&self.storage)?.get_by_id(&session.0.user_id)
+            .map_err(|e| Status::internal(e.to_string()))?
+            .ok_or_else(|| Status::not_found("User no longer exists"))?;
         
-        Ok(Response::new(WhoAmIResponse { user: Some(user) }))
+        Ok(Response::new(WhoAmIResponse { user: Some(user_info(&user)) }))
+    }
+
+    async fn list_sessions(
+        &self,
+        request: Request<ListSessionsRequest>,
+    ) -> Result<Response<ListSessionsResponse>, Status> {
+        let session = auth::require_session(&request)?;
+        let store = SessionStore::new(&self.storage)
+            .map_err(|e| Status::internal(e.to_string()))?;
+        let sessions = store.list_sessions(session.username())
+            .map_err(|e| Status::internal(e.to_string()))?
+            .into_iter()
+            .map(|s| SessionInfo {
+                current: s.session_id == session.0.session_id,
+                session_id: s.session_id,
+                device_id: s.device_id,
+                created_at: s.created_at,
+                expires_at: s.expires_at,
+            })
+            .collect();
+        Ok(Response::new(ListSessionsResponse { sessions }))
+    }
+
+    async fn revoke_session(
+        &self,
+        request: Request<RevokeSessionRequest>,
+    ) -> Result<Response<RevokeSessionResponse>, Status> {
+        let session = auth::require_session(&request)?;
+        let req = request.into_inner();
+        let store = SessionStore::new(&self.storage)
+            .map_err(|e| Status::internal(e.to_string()))?;
+        let revoked = if !req.session_id.is_empty() {
+            match store.get(&req.session_id).map_err(|e| Status::internal(e.to_string()))? {
+                Some(s) if s.username == session.username() => {
+                    store.revoke(&req.session_id).map_err(|e| Status::internal(e.to_string()))? as usize
+                }
+                _ => return Err(Status::not_found("Session not found")),
+            }
+        } else if !req.device_id.is_empty() {
+            store.revoke_device(session.username(), &req.device_id)
+                .map_err(|e| Status::internal(e.to_string()))?
+        } else {
+            return Err(Status::invalid_argument("Provide session_id or device_id"));
+        };
+        let target = if req.session_id.is_empty() { &req.device_id } else { &req.session_id };
+        audit::record_session(&self.storage, &session, "revoke_session", Some(target), true, Some(&format!("{} session(s)", revoked)));
+        Ok(Response::new(RevokeSessionResponse {
+            success: true,
+            message: format!("Revoked {} session(s)", revoked),
+            revoked: revoked as u32,
+        }))
     }
 }
 
-#[derive(Default)]
 pub struct DafsFileService {
     storage: Arc<Storage>,
+    p2p: Arc<P2PNode>,
 }
 
 #[tonic::async_trait]
@@ -306,14 +369,27 @@ impl FileService for DafsFileService {
         &self,
         request: Request<tonic::Streaming<UploadChunk>>,
     ) -> Result<Response<UploadResponse>, Status> {
+        let session = authorize(&self.storage, &request, Permission::Upload)?;
         let mut stream = request.into_inner();
+        let mut file_uuid = None;
+        let mut existing = None;
         let mut file_id = String::new();
         let mut metadata = None;
         let mut temp_dir = String::new();
         
         while let Some(chunk) = stream.message().await.unwrap() {
-            if file_id.is_empty() {
-                file_id = chunk.file_id.clone();
+            if file_uuid.is_none() {
+                let id = Uuid::parse_str(&chunk.file_id).map_err(|_| Status::invalid_argument("Invalid file_id"))?;
+                // Re-uploading an existing file replaces its contents
+                existing = self.storage.get_metadata(&id).map_err(|e| Status::internal(format!("Storage error: {}", e)))?;
+                if let Some(existing) = &existing {
+                    policy_store(&self.storage)?.check_file(&session, existing, FilePermission::Write)?;
+                    if existing.sealed.is_some() {
+                        return Err(Status::failed_precondition("Confidential files must be uploaded encrypted, through REST /files/upload"));
+                    }
+                }
+                file_uuid = Some(id);
+                file_id = id.to_string();
                 temp_dir = format!("upload_tmp/{}", file_id);
                 fs::create_dir_all(&temp_dir).ok();
                 metadata = chunk.metadata;
@@ -328,6 +404,18 @@ impl FileService for DafsFileService {
                 }));
             }
         }
+        // Chunks arrive unencrypted and no file key exists to seal metadata under
+        if metadata.as_ref().is_some_and(|m| m.confidential) {
+            let _ = fs::remove_dir_all(&temp_dir);
+            return Ok(Response::new(UploadResponse {
+                success: false,
+                file_id: file_id.clone(),
+                message: "Confidential files must be uploaded encrypted, through REST /files/upload".to_string(),
+            }));
+        }
+        let Some(file_uuid) = file_uuid else {
+            return Err(Status::invalid_argument("No chunks uploaded"));
+        };
         // Check if all chunks are present
         let mut all_present = true;
         let total_chunks = if let Some(ref meta) = metadata {
@@ -357,16 +445,29 @@ impl FileService for DafsFileService {
             let _ = fs::remove_dir_all(&temp_dir);
             // Save metadata if provided
             if let Some(meta) = metadata {
-                let file_meta = crate::storage::FileMetadata {
-                    file_id: Uuid::parse_str(&file_id).unwrap(),
-                    filename: meta.filename,
-                    tags: meta.tags,
-                    owner_peer_id: meta.owner_peer_id,
-                    checksum: meta.checksum,
-                    size: meta.size,
-                    encrypted_file_key: vec![], // TODO: implement encryption
-                    shared_keys: meta.shared_keys,
-                    allowed_peers: vec![], // Add this field
+                let file_meta = match existing {
+                    // The owner, keys and grants of a re-uploaded file stay as they were
+                    Some(existing) => crate::storage::FileMetadata {
+                        filename: meta.filename,
+                        tags: meta.tags,
+                        checksum: meta.checksum,
+                        size: meta.size,
+                        attributes: meta.attributes,
+                        ..existing
+                    },
+                    None => crate::storage::FileMetadata {
+                        file_id: file_uuid,
+                        filename: meta.filename,
+                        tags: meta.tags,
+                        owner_peer_id: session.username().to_string(),
+                        checksum: meta.checksum,
+                        size: meta.size,
+                        encrypted_file_key: vec![], // TODO: implement encryption
+                        shared_keys: meta.shared_keys,
+                        allowed_peers: vec![], // Add this field
+                        attributes: meta.attributes,
+                        sealed: None,
+                    },
                 };
                 if let Err(e) = self.storage.insert_metadata(&file_meta) {
                     return Ok(Response::new(UploadResponse {
@@ -376,6 +477,8 @@ impl FileService for DafsFileService {
                     }));
                 }
             }
+            self.p2p.provide_file(&file_id).await;
+            audit::record_session(&self.storage, &session, "upload", Some(&file_id), true, None);
             return Ok(Response::new(UploadResponse {
                 success: true,
                 file_id: file_id.clone(),
@@ -395,16 +498,12 @@ impl FileService for DafsFileService {
         &self,
         request: Request<DownloadRequest>,
     ) -> Result<Response<Self::DownloadFileStream>, Status> {
+        let session = auth::require_session(&request)?;
         let req = request.into_inner();
         let (tx, rx) = tokio::sync::mpsc::channel(128);
         
-        let keyfile = format!("userkeys/{}.key", req.username.clone());
-        let password = req.password.clone();
-        let tx_clone = tx.clone();
         // Extract all needed data from self before the spawn
-        let keyfile_owned = keyfile.clone();
-        let password_owned = password.clone();
-        let tx_clone_owned = tx_clone.clone();
+        let tx_clone_owned = tx.clone();
         // Extract file_id as Uuid from req
         let file_id = match Uuid::parse_str(&req.file_id) {
             Ok(id) => id,
@@ -413,7 +512,7 @@ impl FileService for DafsFileService {
             }
         };
         let storage = self.storage.clone();
-        spawn_keypair_loader(keyfile_owned, password_owned, tx_clone_owned, file_id, storage);
+        spawn_file_streamer(session, tx_clone_owned, file_id, storage, self.p2p.clone());
         
         Ok(Response::new(tokio_stream::wrappers::ReceiverStream::new(rx)))
     }
@@ -422,26 +521,16 @@ impl FileService for DafsFileService {
         &self,
         request: Request<ListFilesRequest>,
     ) -> Result<Response<ListFilesResponse>, Status> {
-        let req = request.into_inner();
-        
-        // Authenticate user
-        let keyfile = format!("userkeys/{}.key", req.username);
-        if let Err(_) = load_and_decrypt_keypair(&keyfile, &req.password) {
-            return Err(Status::unauthenticated("Invalid credentials"));
-        }
+        let session = auth::require_session(&request)?;
+        let policy = policy_store(&self.storage)?;
         
         let files = self.storage.list_metadata()
             .map_err(|e| Status::internal(format!("Storage error: {}", e)))?;
         
-        let proto_files = files.into_iter().map(|f| FileMetadata {
-            file_id: f.file_id.to_string(),
-            filename: f.filename,
-            tags: f.tags,
-            owner_peer_id: f.owner_peer_id,
-            checksum: f.checksum,
-            size: f.size,
-            shared_keys: f.shared_keys,
-        }).collect();
+        let secret = session.secret().ok();
+        let proto_files = files.into_iter()
+            .filter(|f| policy.check_file(&session, f, FilePermission::Read).is_ok())
+            .map(|f| file_info(confidential::view(&f, session.username(), secret.as_ref()))).collect();
         
         Ok(Response::new(ListFilesResponse {
             files: proto_files,
@@ -452,21 +541,180 @@ impl FileService for DafsFileService {
         &self,
         request: Request<ShareFileRequest>,
     ) -> Result<Response<ShareFileResponse>, Status> {
+        let session = auth::require_session(&request)?;
         let req = request.into_inner();
+        let file_id = Uuid::parse_str(&req.file_id)
+            .map_err(|_| Status::invalid_argument("Invalid file_id"))?;
+        let extra = policy::parse_file_permissions(&req.permissions)?;
         
-        // TODO: Implement file sharing logic
-        // This would involve encrypting the file key for the recipient
+        match crate::api::grant_share(&self.storage, &session, &file_id, &req.recipient_username, &extra) {
+            Ok(()) => Ok(Response::new(ShareFileResponse {
+                success: true,
+                message: format!("File {} shared with {}", req.file_id, req.recipient_username),
+            })),
+            Err((_, message)) => Ok(Response::new(ShareFileResponse {
+                success: false,
+                message,
+            })),
+        }
+    }
+
+    async fn unshare_file(
+        &self,
+        request: Request<UnshareFileRequest>,
+    ) -> Result<Response<UnshareFileResponse>, Status> {
+        let session = auth::require_session(&request)?;
+        let req = request.into_inner();
+        let file_id = match Uuid::parse_str(&req.file_id) {
+            Ok(id) => id,
+            Err(_) => {
+                return Ok(Response::new(UnshareFileResponse {
+                    success: false,
+                    message: "Invalid file_id".to_string(),
+                    rekeyed: false,
+                }));
+            }
+        };
         
-        Ok(Response::new(ShareFileResponse {
-            success: true,
-            message: "File shared successfully".to_string(),
-        }))
+        let owner_secret = auth::session_secret(&session.0.session_id)
+            .ok_or_else(|| Status::unauthenticated("Session key not loaded on this node; log in again"))?;
+        
+        match crate::api::revoke_share(&self.storage, &self.p2p, &file_id, &session, &owner_secret, &req.recipient_username, req.rekey).await {
+            Ok(revocation) => Ok(Response::new(UnshareFileResponse {
+                success: true,
+                message: format!("Access to {} revoked for {}", req.file_id, req.recipient_username),
+                rekeyed: revocation.rekeyed,
+            })),
+            Err(e) => Ok(Response::new(UnshareFileResponse {
+                success: false,
+                message: e.to_string(),
+                rekeyed: false,
+            })),
+        }
+    }
+
+    async fn get_share_history(
+        &self,
+        request: Request<ShareHistoryRequest>,
+    ) -> Result<Response<ShareHistoryResponse>, Status> {
+        let session = auth::require_session(&request)?;
+        let req = request.into_inner();
+        let file_id = Uuid::parse_str(&req.file_id)
+            .map_err(|_| Status::invalid_argument("Invalid file_id"))?;
+        let meta = self.storage.get_metadata(&file_id)
+            .map_err(|e| Status::internal(format!("Storage error: {}", e)))?
+            .ok_or_else(|| Status::not_found("File not found"))?;
+        policy_store(&self.storage)?.check_file(&session, &meta, FilePermission::Read)?;
+        let records = self.storage.list_share_revocations(Some(&file_id))
+            .map_err(|e| Status::internal(format!("Storage error: {}", e)))?;
+        
+        let revocations = records.into_iter().map(|r| ShareRevocationInfo {
+            file_id: r.file_id.to_string(),
+            revoked_user: r.revoked_user,
+            revoked_by: r.revoked_by,
+            rekeyed: r.rekeyed,
+            timestamp: r.timestamp,
+            origin_peer: r.origin_peer.unwrap_or_default(),
+        }).collect();
+        
+        Ok(Response::new(ShareHistoryResponse { revocations }))
+    }
+
+    async fn seal_file(
+        &self,
+        request: Request<SealFileRequest>,
+    ) -> Result<Response<SealFileResponse>, Status> {
+        let session = auth::require_session(&request)?;
+        let req = request.into_inner();
+        let file_id = Uuid::parse_str(&req.file_id)
+            .map_err(|_| Status::invalid_argument("Invalid file_id"))?;
+        match crate::api::seal_file(&self.storage, &session, &file_id) {
+            Ok(()) => Ok(Response::new(SealFileResponse {
+                success: true,
+                message: format!("Metadata of {} is now encrypted", req.file_id),
+            })),
+            Err((_, message)) => Ok(Response::new(SealFileResponse {
+                success: false,
+                message,
+            })),
+        }
+    }
+
+    async fn search_files(
+        &self,
+        request: Request<SearchFilesRequest>,
+    ) -> Result<Response<ListFilesResponse>, Status> {
+        let session = auth::require_session(&request)?;
+        let req = request.into_inner();
+        let files = crate::api::search_files(&self.storage, &session, &req.tag)
+            .map_err(|(_, message)| Status::internal(message))?;
+        Ok(Response::new(ListFilesResponse { files: files.into_iter().map(file_info).collect() }))
+    }
+
+    async fn create_share_link(
+        &self,
+        request: Request<CreateShareLinkRequest>,
+    ) -> Result<Response<CreateShareLinkResponse>, Status> {
+        let session = auth::require_session(&request)?;
+        let req = request.into_inner();
+        let file_id = Uuid::parse_str(&req.file_id)
+            .map_err(|_| Status::invalid_argument("Invalid file_id"))?;
+        let options = LinkOptions {
+            expires_in: Some(req.expires_in).filter(|&s| s > 0),
+            max_downloads: Some(req.max_downloads).filter(|&n| n > 0),
+            password: Some(SecretString::from(req.password)),
+        };
+        
+        match crate::api::create_link(&self.storage, &session, &file_id, options) {
+            Ok((link, key)) => Ok(Response::new(CreateShareLinkResponse {
+                success: true,
+                message: format!("Link {} created", link.link_id),
+                url: link.path(&key),
+                link: Some(link_info(&link)),
+                key,
+            })),
+            Err((_, message)) => Ok(Response::new(CreateShareLinkResponse {
+                success: false,
+                message,
+                ..Default::default()
+            })),
+        }
+    }
+
+    async fn list_share_links(
+        &self,
+        request: Request<ListShareLinksRequest>,
+    ) -> Result<Response<ListShareLinksResponse>, Status> {
+        let session = auth::require_session(&request)?;
+        let links = LinkStore::open(&self.storage)
+            .map_err(LinkError::from)
+            .and_then(|l| l.list_by_creator(session.username()))?;
+        Ok(Response::new(ListShareLinksResponse { links: links.iter().map(link_info).collect() }))
+    }
+
+    async fn revoke_share_link(
+        &self,
+        request: Request<RevokeShareLinkRequest>,
+    ) -> Result<Response<RevokeShareLinkResponse>, Status> {
+        let session = auth::require_session(&request)?;
+        let req = request.into_inner();
+        match crate::api::revoke_link(&self.storage, &session, &req.link_id) {
+            Ok(_) => Ok(Response::new(RevokeShareLinkResponse {
+                success: true,
+                message: format!("Link {} revoked", req.link_id),
+            })),
+            Err((_, message)) => Ok(Response::new(RevokeShareLinkResponse {
+                success: false,
+                message,
+            })),
+        }
     }
 
     async fn get_file_metadata(
         &self,
         request: Request<FileMetadataRequest>,
     ) -> Result<Response<FileMetadataResponse>, Status> {
+        let session = auth::require_session(&request)?;
         let req = request.into_inner();
         let file_id = match uuid::Uuid::parse_str(&req.file_id) {
             Ok(id) => id,
@@ -479,18 +727,17 @@ impl FileService for DafsFileService {
             }
         };
         match self.storage.get_metadata(&file_id) {
+            Ok(Some(meta)) if policy_store(&self.storage)?.check_file(&session, &meta, FilePermission::Read).is_err() => {
+                Ok(Response::new(FileMetadataResponse {
+                    found: false,
+                    message: policy::PolicyError::FileDenied { file_id, permission: FilePermission::Read }.to_string(),
+                    metadata: None,
+                }))
+            }
             Ok(Some(meta)) => Ok(Response::new(FileMetadataResponse {
                 found: true,
                 message: "ok".to_string(),
-                metadata: Some(FileMetadata {
-                    file_id: meta.file_id.to_string(),
-                    filename: meta.filename,
-                    tags: meta.tags,
-                    owner_peer_id: meta.owner_peer_id,
-                    checksum: meta.checksum,
-                    size: meta.size,
-                    shared_keys: meta.shared_keys,
-                }),
+                metadata: Some(file_info(confidential::view(&meta, session.username(), session.secret().ok().as_ref()))),
             })),
             Ok(None) => Ok(Response::new(FileMetadataResponse {
                 found: false,
@@ -509,14 +756,9 @@ impl FileService for DafsFileService {
         &self,
         request: Request<DeleteFileRequest>,
     ) -> Result<Response<DeleteFileResponse>, Status> {
+        let session = auth::require_session(&request)?;
         let req = request.into_inner();
         
-        // Authenticate user
-        let keyfile = format!("userkeys/{}.key", req.username);
-        if let Err(_) = load_and_decrypt_keypair(&keyfile, &req.password) {
-            return Err(Status::unauthenticated("Invalid credentials"));
-        }
-        
         // Parse file ID
         let file_id = match Uuid::parse_str(&req.file_id) {
             Ok(id) => id,
@@ -528,12 +770,46 @@ impl FileService for DafsFileService {
             }
         };
         
+        let policy = policy_store(&self.storage)?;
+        match self.storage.get_metadata(&file_id) {
+            Ok(Some(meta)) => {
+                if let Err(e) = policy.check_file(&session, &meta, FilePermission::Delete) {
+                    audit::record_session(&self.storage, &session, "delete", Some(&req.file_id), false, Some(&e.to_string()));
+                    return Err(e.into());
+                }
+            }
+            Ok(None) => {
+                return Ok(Response::new(DeleteFileResponse {
+                    success: false,
+                    message: "File not found".to_string(),
+                }));
+            }
+            Err(e) => return Err(Status::internal(format!("Storage error: {}", e))),
+        }
+        
         // Delete from storage
         match self.storage.delete_metadata(&file_id) {
             Ok(_) => {
+                if let Err(e) = policy.clear_file(&file_id) {
+                    println!("Failed to clear permissions for {}: {}", file_id, e);
+                }
+                if let Err(e) = DeviceRegistry::open(&self.storage).map_err(devices::DeviceError::from).and_then(|d| d.forget_file(&file_id)) {
+                    println!("Failed to drop device keys for {}: {}", file_id, e);
+                }
+                if let Err(e) = LinkStore::open(&self.storage).map_err(LinkError::from).and_then(|l| l.revoke_file(&file_id)) {
+                    println!("Failed to revoke links to {}: {}", file_id, e);
+                }
                 // Also try to delete the actual file
                 let file_path = format!("files/{}.bin", req.file_id);
                 let _ = std::fs::remove_file(&file_path);
+                self.p2p.stop_providing(&req.file_id).await;
+                if let Err(e) = self.p2p.drop_replicas(&req.file_id).await {
+                    println!("Failed to drop replicas of {}: {}", req.file_id, e);
+                }
+                if let Err(e) = self.p2p.drop_shards(&req.file_id).await {
+                    println!("Failed to drop shards of {}: {}", req.file_id, e);
+                }
+                audit::record_session(&self.storage, &session, "delete", Some(&req.file_id), true, None);
                 
                 Ok(Response::new(DeleteFileResponse {
                     success: true,
@@ -550,11 +826,12 @@ impl FileService for DafsFileService {
 
 pub struct DafsP2PService {
     p2p: Arc<P2PNode>,
+    storage: Arc<Storage>,
 }
 // Implement Default for DafsP2PService if needed
 impl Default for DafsP2PService {
     fn default() -> Self {
-        Self { p2p: Arc::new(P2PNode::new()) }
+        Self { p2p: Arc::new(P2PNode::new()), storage: Arc::new(Storage::default()) }
     }
 }
 
@@ -564,19 +841,30 @@ impl P2pService for DafsP2PService {
         &self,
         _request: Request<ListPeersRequest>,
     ) -> Result<Response<ListPeersResponse>, Status> {
-        // TODO: Implement peer listing
-        Ok(Response::new(ListPeersResponse {
-            peers: vec![],
-        }))
+        let peers = crate::peer::connections::list()
+            .into_iter()
+            .map(|c| PeerInfo {
+                peer_id: c.peer.to_string(),
+                address: c.address.to_string(),
+                is_connected: true,
+                direction: if c.outbound { "outbound" } else { "inbound" }.to_string(),
+                connected_since: c.established_at,
+                preferred: crate::peer::connections::is_preferred(&c.peer),
+            })
+            .collect();
+        Ok(Response::new(ListPeersResponse { peers }))
     }
 
     async fn add_bootstrap_node(
         &self,
         request: Request<BootstrapNodeRequest>,
     ) -> Result<Response<BootstrapNodeResponse>, Status> {
+        let session = authorize(&self.storage, &request, Permission::ManagePeers)?;
         let req = request.into_inner();
         
-        match crate::peer::add_bootstrap_node(&req.peer_id, &req.address) {
+        let result = crate::peer::add_bootstrap_node(&req.peer_id, &req.address);
+        audit::record_result(&self.storage, session.username(), "add_bootstrap", Some(&req.peer_id), &result);
+        match result {
             Ok(_) => Ok(Response::new(BootstrapNodeResponse {
                 success: true,
                 message: "Bootstrap node added".to_string(),
@@ -592,9 +880,12 @@ impl P2pService for DafsP2PService {
         &self,
         request: Request<BootstrapNodeRequest>,
     ) -> Result<Response<BootstrapNodeResponse>, Status> {
+        let session = authorize(&self.storage, &request, Permission::ManagePeers)?;
         let req = request.into_inner();
         
-        match crate::peer::remove_bootstrap_node(&req.peer_id) {
+        let result = crate::peer::remove_bootstrap_node(&req.peer_id);
+        audit::record_result(&self.storage, session.username(), "remove_bootstrap", Some(&req.peer_id), &result);
+        match result {
             Ok(_) => Ok(Response::new(BootstrapNodeResponse {
                 success: true,
                 message: "Bootstrap node removed".to_string(),
@@ -632,15 +923,7 @@ impl P2pService for DafsP2PService {
             return Ok(Response::new(ListP2pFilesResponse { files: vec![] }));
         } else {
             match self.p2p.query_peer_files(&_req.peer_id).await {
-                Ok(files) => Ok(Response::new(ListP2pFilesResponse { files: files.into_iter().map(|f| dafs::FileMetadata {
-                    file_id: f.file_id.to_string(),
-                    filename: f.filename,
-                    tags: f.tags,
-                    owner_peer_id: f.owner_peer_id,
-                    checksum: f.checksum,
-                    size: f.size,
-                    shared_keys: f.shared_keys,
-                }).collect() })),
+                Ok(files) => Ok(Response::new(ListP2pFilesResponse { files: files.into_iter().map(file_info).collect() })),
                 Err(e) => Err(Status::internal(format!("P2P file listing error: {}", e))),
             }
         }
@@ -650,30 +933,169 @@ impl P2pService for DafsP2PService {
         request: Request<P2pDownloadChunkRequest>,
     ) -> Result<Response<P2pDownloadChunkResponse>, Status> {
         let req = request.into_inner();
-        match self.p2p.request_chunk(&req.peer_id, &req.file_id, req.chunk_index as usize, req.chunk_size as usize).await {
-            Ok(data) => {
+        let result = self.p2p.fetch_chunk(
+            Some(req.peer_id.as_str()).filter(|p| !p.is_empty()),
+            &req.file_id,
+            req.chunk_index as usize,
+            req.chunk_size as usize,
+            Some(req.expected_sha256.as_str()).filter(|h| !h.is_empty()),
+        ).await;
+        match result {
+            Ok((peer_id, data)) => {
                 let is_last = data.len() < req.chunk_size as usize;
                 Ok(Response::new(P2pDownloadChunkResponse {
-                    data: data.clone(),
+                    data,
                     chunk_index: req.chunk_index,
                     is_last,
+                    peer_id,
                 }))
             },
             Err(e) => Err(Status::internal(format!("P2P download error: {}", e))),
         }
     }
 
+    async fn find_providers(
+        &self,
+        request: Request<FindProvidersRequest>,
+    ) -> Result<Response<FindProvidersResponse>, Status> {
+        let req = request.into_inner();
+        match self.p2p.find_providers(&req.file_id).await {
+            Ok(peer_ids) => Ok(Response::new(FindProvidersResponse { peer_ids })),
+            Err(e) => Err(Status::internal(format!("Provider lookup error: {}", e))),
+        }
+    }
+
+    async fn get_replicas(
+        &self,
+        request: Request<GetReplicasRequest>,
+    ) -> Result<Response<GetReplicasResponse>, Status> {
+        let session = auth::require_session(&request)?;
+        let req = request.into_inner();
+        let file_id = Uuid::parse_str(&req.file_id).map_err(|_| Status::invalid_argument("Invalid file_id"))?;
+        let meta = self.storage.get_metadata(&file_id)
+            .map_err(|e| Status::internal(format!("Storage error: {}", e)))?
+            .ok_or_else(|| Status::not_found("File not found"))?;
+        policy_store(&self.storage)?.check_file(&session, &meta, FilePermission::Read)?;
+        let health = replication::health(&req.file_id).map_err(|e| Status::internal(format!("Storage error: {}", e)))?;
+        let (policy, data_shards, parity_shards) = match health.policy {
+            erasure::StoragePolicy::Replicated => ("replicated", 0, 0),
+            erasure::StoragePolicy::ErasureCoded { data_shards, parity_shards } => ("erasure", data_shards as u32, parity_shards as u32),
+        };
+        Ok(Response::new(GetReplicasResponse {
+            file_id: health.file_id,
+            replication_factor: health.factor as u32,
+            healthy: health.healthy as u32,
+            replicas: health.replicas.into_iter().map(|r| ReplicaInfo {
+                peer_id: r.peer_id,
+                stored_at: r.stored_at,
+                last_seen: r.last_seen,
+                reachable: r.reachable,
+            }).collect(),
+            is_origin: health.is_origin,
+            policy: policy.to_string(),
+            data_shards,
+            parity_shards,
+            shards: health.shards.into_iter().map(|(index, r)| ShardHolderInfo {
+                shard_index: index as u32,
+                peer_id: r.peer_id,
+                stored_at: r.stored_at,
+                last_seen: r.last_seen,
+                reachable: r.reachable,
+            }).collect(),
+        }))
+    }
+
+    async fn set_storage_policy(
+        &self,
+        request: Request<SetStoragePolicyRequest>,
+    ) -> Result<Response<SetStoragePolicyResponse>, Status> {
+        let session = auth::require_session(&request)?;
+        let req = request.into_inner();
+        let file_id = Uuid::parse_str(&req.file_id).map_err(|_| Status::invalid_argument("Invalid file_id"))?;
+        let meta = self.storage.get_metadata(&file_id)
+            .map_err(|e| Status::internal(format!("Storage error: {}", e)))?
+            .ok_or_else(|| Status::not_found("File not found"))?;
+        policy_store(&self.storage)?.check_file(&session, &meta, FilePermission::Write)?;
+        let policy = match req.policy.as_str() {
+            "replicated" => erasure::StoragePolicy::Replicated,
+            "erasure" => erasure::StoragePolicy::ErasureCoded {
+                data_shards: req.data_shards as usize,
+                parity_shards: req.parity_shards as usize,
+            },
+            other => return Err(Status::invalid_argument(format!("Unknown storage policy: {}", other))),
+        };
+        let health = replication::health(&req.file_id).map_err(|e| Status::internal(format!("Storage error: {}", e)))?;
+        if !health.is_origin {
+            return Err(Status::failed_precondition("This node holds the file for another node; set the policy on its origin"));
+        }
+        match erasure::set_policy(&req.file_id, policy) {
+            Ok(()) => {
+                audit::record_session(&self.storage, &session, "set_storage_policy", Some(&req.file_id), true, None);
+                Ok(Response::new(SetStoragePolicyResponse {
+                    success: true,
+                    message: format!("File will be kept {}", policy),
+                }))
+            }
+            Err(erasure::ErasureError::BadShardCounts) => Err(Status::invalid_argument(erasure::ErasureError::BadShardCounts.to_string())),
+            Err(e) => Err(Status::internal(format!("Storage error: {}", e))),
+        }
+    }
+
+    type SwarmDownloadStream = tokio_stream::wrappers::ReceiverStream<Result<SwarmDownloadProgress, Status>>;
+
+    async fn swarm_download(
+        &self,
+        request: Request<SwarmDownloadRequest>,
+    ) -> Result<Response<Self::SwarmDownloadStream>, Status> {
+        let _session = auth::require_session(&request)?;
+        let req = request.into_inner();
+        let file_id = Uuid::parse_str(&req.file_id)
+            .map_err(|_| Status::invalid_argument("Invalid file_id"))?
+            .to_string();
+        let output = std::path::PathBuf::from(format!("downloads/{}.bin", file_id));
+        let options = DownloadOptions {
+            peers: req.peer_ids,
+            chunk_size: if req.chunk_size == 0 { download::DEFAULT_CHUNK_SIZE } else { req.chunk_size as usize },
+        };
+        let (tx, rx) = tokio::sync::mpsc::channel(128);
+        let (progress_tx, mut progress_rx) = tokio::sync::mpsc::channel::<DownloadProgress>(128);
+        let output_path = output.display().to_string();
+        let forward = tx.clone();
+        tokio::spawn(async move {
+            while let Some(p) = progress_rx.recv().await {
+                let update = SwarmDownloadProgress {
+                    file_id: p.file_id,
+                    total_bytes: p.total_bytes,
+                    bytes_done: p.bytes_done,
+                    total_chunks: p.total_chunks as u32,
+                    chunks_done: p.chunks_done as u32,
+                    chunks_resumed: p.chunks_resumed as u32,
+                    active_peers: p.active_peers as u32,
+                    retries: p.retries as u32,
+                    done: p.done,
+                    output_path: output_path.clone(),
+                };
+                if forward.send(Ok(update)).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let p2p = self.p2p.clone();
+        tokio::spawn(async move {
+            if let Err(e) = p2p.swarm_download(&file_id, &output, options, progress_tx).await {
+                let _ = tx.send(Err(Status::unavailable(format!("Swarm download failed: {}", e)))).await;
+            }
+        });
+        Ok(Response::new(tokio_stream::wrappers::ReceiverStream::new(rx)))
+    }
+
     async fn discover_peers(
         &self,
         _request: Request<DiscoverPeersRequest>,
     ) -> Result<Response<DiscoverPeersResponse>, Status> {
                     match self.p2p.discover_peers().await {
                 Ok(peers) => {
-                    let proto_peers = peers.into_iter().map(|p| DiscoveredPeer {
-                        peer_id: p.peer_id,
-                        address: p.addresses.join(","),
-                        user_agent: "DAFS".to_string(),
-                    }).collect();
+                    let proto_peers = peers.into_iter().map(discovered_peer_info).collect();
                     Ok(Response::new(DiscoverPeersResponse { peers: proto_peers }))
                 }
             Err(e) => Ok(Response::new(DiscoverPeersResponse { 
@@ -724,11 +1146,7 @@ impl P2pService for DafsP2PService {
     ) -> Result<Response<GetKnownPeersResponse>, Status> {
                     match self.p2p.get_known_peers().await {
                 Ok(peers) => {
-                    let proto_peers = peers.into_iter().map(|p| DiscoveredPeer {
-                        peer_id: p.peer_id,
-                        address: p.addresses.join(","),
-                        user_agent: "DAFS".to_string(),
-                    }).collect();
+                    let proto_peers = peers.into_iter().map(discovered_peer_info).collect();
                     Ok(Response::new(GetKnownPeersResponse { peers: proto_peers }))
                 }
             Err(_) => Ok(Response::new(GetKnownPeersResponse { peers: vec![] }))
@@ -739,8 +1157,11 @@ impl P2pService for DafsP2PService {
         &self,
         request: Request<RemovePeerRequest>,
     ) -> Result<Response<RemovePeerResponse>, Status> {
+        let session = authorize(&self.storage, &request, Permission::ManagePeers)?;
         let req = request.into_inner();
-        match self.p2p.remove_peer(&req.peer_id).await {
+        let result = self.p2p.remove_peer(&req.peer_id).await;
+        audit::record_result(&self.storage, session.username(), "remove_peer", Some(&req.peer_id), &result);
+        match result {
             Ok(success) => Ok(Response::new(RemovePeerResponse {
                 success,
                 message: if success { "Peer removed".to_string() } else { "Peer not found".to_string() },
@@ -758,11 +1179,7 @@ impl P2pService for DafsP2PService {
     ) -> Result<Response<ScanLocalNetworkResponse>, Status> {
                     match self.p2p.scan_local_network().await {
                 Ok(peers) => {
-                    let proto_peers = peers.into_iter().map(|p| DiscoveredPeer {
-                        peer_id: p.peer_id,
-                        address: p.addresses.join(","),
-                        user_agent: "DAFS".to_string(),
-                    }).collect();
+                    let proto_peers = peers.into_iter().map(discovered_peer_info).collect();
                     Ok(Response::new(ScanLocalNetworkResponse { peers: proto_peers }))
                 }
             Err(_) => Ok(Response::new(ScanLocalNetworkResponse { peers: vec![] }))
@@ -776,19 +1193,38 @@ impl P2pService for DafsP2PService {
         let history = self.p2p.get_peer_connection_history();
         let proto_connections = history.into_iter().map(|(peer_id, info)| PeerConnectionInfo {
             peer_id,
-            address: "".to_string(), // Not available in current structure
-            timestamp: info.last_seen.to_string(),
-            successful: info.connected,
+            address: info.address,
+            timestamp: info.connected_at.to_string(),
+            successful: info.successful,
         }).collect();
         Ok(Response::new(GetPeerHistoryResponse { connections: proto_connections }))
     }
 
+    async fn get_node_status(
+        &self,
+        _request: Request<GetNodeStatusRequest>,
+    ) -> Result<Response<GetNodeStatusResponse>, Status> {
+        let status = crate::peer::nat::status();
+        Ok(Response::new(GetNodeStatusResponse {
+            peer_id: self.p2p.local_peer_id().to_string(),
+            reachability: status.reachability.to_string(),
+            public_address: status.public_address.map(|a| a.to_string()).unwrap_or_default(),
+            relays: status.relays.iter().map(|p| p.to_string()).collect(),
+            relayed_addresses: status.relayed_addresses.iter().map(|a| a.to_string()).collect(),
+            direct_upgrades: status.direct_upgrades,
+            failed_upgrades: status.failed_upgrades,
+            listen_addresses: self.p2p.dial_addrs().iter().map(|a| a.to_string()).collect(),
+        }))
+    }
+
     async fn allow_peer(
         &self,
         request: Request<AllowPeerRequest>,
     ) -> Result<Response<AllowPeerResponse>, Status> {
+        let session = authorize(&self.storage, &request, Permission::ManagePeers)?;
         let req = request.into_inner();
         crate::peer::allow_peer(&req.peer_id);
+        audit::record_session(&self.storage, &session, "allow_peer", Some(&req.peer_id), true, None);
         Ok(Response::new(AllowPeerResponse {
             success: true,
             message: format!("Peer {} allowed", req.peer_id),
@@ -799,8 +1235,10 @@ impl P2pService for DafsP2PService {
         &self,
         request: Request<DisallowPeerRequest>,
     ) -> Result<Response<DisallowPeerResponse>, Status> {
+        let session = authorize(&self.storage, &request, Permission::ManagePeers)?;
         let req = request.into_inner();
         crate::peer::disallow_peer(&req.peer_id);
+        audit::record_session(&self.storage, &session, "disallow_peer", Some(&req.peer_id), true, None);
         Ok(Response::new(DisallowPeerResponse {
             success: true,
             message: format!("Peer {} disallowed", req.peer_id),
@@ -814,6 +1252,69 @@ impl P2pService for DafsP2PService {
         let peers = crate::peer::list_allowed_peers();
         Ok(Response::new(ListAllowedPeersResponse { peer_ids: peers }))
     }
+
+    async fn block_peer(
+        &self,
+        request: Request<BlockPeerRequest>,
+    ) -> Result<Response<BlockPeerResponse>, Status> {
+        let session = authorize(&self.storage, &request, Permission::ManagePeers)?;
+        let req = request.into_inner();
+        let result = self.p2p.block_peer(&req.peer_id).await;
+        audit::record_result(&self.storage, session.username(), "block_peer", Some(&req.peer_id), &result);
+        Ok(Response::new(match result {
+            Ok(()) => BlockPeerResponse { success: true, message: format!("Peer {} blocked", req.peer_id) },
+            Err(e) => BlockPeerResponse { success: false, message: format!("Invalid peer ID: {}", e) },
+        }))
+    }
+
+    async fn unblock_peer(
+        &self,
+        request: Request<UnblockPeerRequest>,
+    ) -> Result<Response<UnblockPeerResponse>, Status> {
+        let session = authorize(&self.storage, &request, Permission::ManagePeers)?;
+        let req = request.into_inner();
+        let was_blocked = crate::peer::unblock_peer(&req.peer_id);
+        audit::record_session(&self.storage, &session, "unblock_peer", Some(&req.peer_id), was_blocked, None);
+        Ok(Response::new(if was_blocked {
+            UnblockPeerResponse { success: true, message: format!("Peer {} unblocked", req.peer_id) }
+        } else {
+            UnblockPeerResponse { success: false, message: format!("Peer {} is not blocked or banned", req.peer_id) }
+        }))
+    }
+
+    async fn list_blocked_peers(
+        &self,
+        request: Request<ListBlockedPeersRequest>,
+    ) -> Result<Response<ListBlockedPeersResponse>, Status> {
+        authorize(&self.storage, &request, Permission::ManagePeers)?;
+        let all = request.into_inner().all;
+        let blocked = crate::peer::list_blocked_peers();
+        let mut reputations: std::collections::HashMap<_, _> = crate::peer::reputation::list().into_iter().collect();
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+        let mut peer_ids: Vec<String> = blocked.iter().cloned().chain(reputations.keys().cloned()).collect();
+        peer_ids.sort();
+        peer_ids.dedup();
+        let peers = peer_ids
+            .into_iter()
+            .filter_map(|peer_id| {
+                let record = reputations.remove(&peer_id).unwrap_or_default();
+                let is_blocked = blocked.contains(&peer_id);
+                let banned = record.is_banned(now);
+                (all || is_blocked || banned).then(|| PeerReputationInfo {
+                    peer_id,
+                    blocked: is_blocked,
+                    banned_until: if banned { record.banned_until.unwrap_or(0) } else { 0 },
+                    score: record.score,
+                    bad_signatures: record.bad_signatures,
+                    malformed_messages: record.malformed_messages,
+                    timeouts: record.timeouts,
+                    chunk_mismatches: record.chunk_mismatches,
+                    bans: record.bans,
+                })
+            })
+            .collect();
+        Ok(Response::new(ListBlockedPeersResponse { peers }))
+    }
 }
 
 #[derive(Default)]
@@ -928,8 +1429,9 @@ impl MessagingService for DafsMessagingService {
     }
 }
 
-#[derive(Default)]
-pub struct DafsUserManagementService;
+pub struct DafsUserManagementService {
+    storage: Arc<Storage>,
+}
 
 #[tonic::async_trait]
 impl UserManagementService for DafsUserManagementService {
@@ -938,50 +1440,82 @@ impl UserManagementService for DafsUserManagementService {
         request: Request<RegisterUserRequest>,
     ) -> Result<Response<RegisterUserResponse>, Status> {
         let req = request.into_inner();
-        Ok(Response::new(RegisterUserResponse {
-            success: true,
-            message: format!("User '{}' registered successfully", req.username),
-        }))
+        let display_name = if req.display_name.is_empty() { req.username.clone() } else { req.display_name };
+        let email = if req.email.is_empty() { None } else { Some(req.email) };
+        let result = user_store(&self.storage)?.register(&req.username, &display_name, email, SecretString::from(req.password).expose());
+        audit::record_result(&self.storage, &req.username, "register", None, &result);
+        match result {
+            Ok(_) => Ok(Response::new(RegisterUserResponse {
+                success: true,
+                message: format!("User '{}' registered successfully", req.username),
+            })),
+            Err(e) => Ok(Response::new(RegisterUserResponse {
+                success: false,
+                message: e.to_string(),
+            })),
+        }
     }
 
     async fn login_user(
         &self,
         request: Request<LoginUserRequest>,
     ) -> Result<Response<LoginUserResponse>, Status> {
+        let client = request.remote_addr().map(|addr| addr.ip());
         let req = request.into_inner();
-        Ok(Response::new(LoginUserResponse {
-            success: true,
-            session_token: format!("token_{}", uuid::Uuid::new_v4()),
-            message: format!("User '{}' logged in successfully", req.username),
-        }))
+        let (device_id, device_key) = devices::resolve_device(Some(&req.device_id), Some(&req.device_public_key), "grpc")?;
+        let device = device_key.map(|public_key| devices::DeviceProof { public_key, challenge_id: &req.challenge_id, proof: &req.device_proof });
+        let second_factor = (!req.otp.is_empty()).then(|| SecondFactor { code: &req.otp, trust_device: req.trust_device });
+        match auth::login(&self.storage, &req.username, SecretString::from(req.password).expose(), second_factor, &device_id, device, client) {
+            Ok((session, token)) => Ok(Response::new(LoginUserResponse {
+                success: true,
+                session_token: token,
+                message: format!("User '{}' logged in successfully", req.username),
+                mfa_required: false,
+                device_state: device_state(&self.storage, &session.user_id, &device_id),
+                device_id,
+            })),
+            Err(e @ (AuthError::InvalidCredentials | AuthError::SecondFactorRequired | AuthError::InvalidSecondFactor | AuthError::DeviceKeyMismatch
+                | AuthError::DeviceProofRequired | AuthError::InvalidDeviceProof | AuthError::DeviceNotApproved)) => Ok(Response::new(LoginUserResponse {
+                success: false,
+                session_token: String::new(),
+                mfa_required: matches!(e, AuthError::SecondFactorRequired),
+                message: e.to_string(),
+                ..Default::default()
+            })),
+            Err(e @ AuthError::Throttled { retry_after }) => Err(resource_exhausted(retry_after, e.to_string())),
+            Err(e) => Err(Status::internal(e.to_string())),
+        }
     }
 
     async fn logout_device(
         &self,
         request: Request<LogoutDeviceRequest>,
     ) -> Result<Response<LogoutDeviceResponse>, Status> {
+        let session = auth::require_session(&request)?;
         let req = request.into_inner();
+        // An empty device_id means the device this request came from
+        let device_id = if req.device_id.is_empty() { session.0.device_id.clone() } else { req.device_id };
+        let revoked = SessionStore::new(&self.storage)
+            .map_err(|e| Status::internal(e.to_string()))?
+            .revoke_device(session.username(), &device_id)
+            .map_err(|e| Status::internal(e.to_string()))?;
+        audit::record_session(&self.storage, &session, "logout", Some(&device_id), true, None);
         Ok(Response::new(LogoutDeviceResponse {
             success: true,
-            message: format!("Device '{}' logged out successfully", req.device_id),
+            message: format!("Device '{}' logged out ({} session(s) revoked)", device_id, revoked),
         }))
     }
 
     async fn list_all_users(
         &self,
-        _request: Request<ListAllUsersRequest>,
+        request: Request<ListAllUsersRequest>,
     ) -> Result<Response<ListAllUsersResponse>, Status> {
-        // Mock users
-        let users = vec![
-            UserInfo {
-                user_id: "user_1".to_string(),
-                username: "testuser".to_string(),
-                display_name: "Test User".to_string(),
-                email: "test@example.com".to_string(),
-                status: "active".to_string(),
-                last_seen: chrono::Utc::now().to_rfc3339(),
-            }
-        ];
+        auth::require_session(&request)?;
+        let users = user_store(&self.storage)?.list()
+            .map_err(|e| Status::internal(e.to_string()))?
+            .iter()
+            .map(user_info)
+            .collect();
         Ok(Response::new(ListAllUsersResponse { users }))
     }
 
@@ -989,16 +1523,26 @@ impl UserManagementService for DafsUserManagementService {
         &self,
         request: Request<ListDevicesRequest>,
     ) -> Result<Response<ListDevicesResponse>, Status> {
-        let _req = request.into_inner();
-        // Mock devices
-        let devices = vec![
+        let session = auth::require_session(&request)?;
+        let user = user_store(&self.storage)?.get_by_id(&session.0.user_id)
+            .map_err(|e| Status::internal(e.to_string()))?
+            .ok_or_else(|| Status::not_found("User no longer exists"))?;
+        let keys = DeviceRegistry::open(&self.storage)
+            .map_err(|e| Status::internal(format!("Device store error: {}", e)))?
+            .list(&session.0.user_id)?;
+        let devices = user.devices.iter().map(|d| {
+            let key = keys.iter().find(|k| k.device_id == d.device_id);
             DeviceInfo {
-                device_id: "device_1".to_string(),
-                device_name: "DAFS CLI".to_string(),
-                device_type: "cli".to_string(),
-                last_login: chrono::Utc::now().to_rfc3339(),
+                device_id: d.device_id.clone(),
+                device_name: d.device_name.clone(),
+                device_type: format!("{:?}", d.device_type).to_lowercase(),
+                last_login: rfc3339(d.last_login),
+                trusted_until: d.trusted_until.unwrap_or(0),
+                fingerprint: key.map(|k| k.fingerprint.clone()).unwrap_or_default(),
+                state: key.map(|k| k.state.to_string()).unwrap_or_default(),
+                current: d.device_id == session.0.device_id,
             }
-        ];
+        }).collect();
         Ok(Response::new(ListDevicesResponse { devices }))
     }
 
@@ -1006,23 +1550,279 @@ impl UserManagementService for DafsUserManagementService {
         &self,
         request: Request<RemoveDeviceRequest>,
     ) -> Result<Response<RemoveDeviceResponse>, Status> {
+        let session = auth::require_session(&request)?;
+        let req = request.into_inner();
+        match devices::remove_device(&self.storage, &session, &req.device_id)? {
+            Some(removal) => Ok(Response::new(RemoveDeviceResponse {
+                success: true,
+                message: format!("Device '{}' removed successfully ({} session(s) revoked)", req.device_id, removal.sessions_revoked),
+                fingerprint: removal.fingerprint.unwrap_or_default(),
+            })),
+            None => Ok(Response::new(RemoveDeviceResponse {
+                success: false,
+                message: format!("Device '{}' not found", req.device_id),
+                fingerprint: String::new(),
+            })),
+        }
+    }
+
+    async fn grant_role(
+        &self,
+        request: Request<GrantRoleRequest>,
+    ) -> Result<Response<GrantRoleResponse>, Status> {
+        let session = authorize(&self.storage, &request, Permission::ManageRoles)?;
+        let req = request.into_inner();
+        let role: Role = req.role.parse()?;
+        let message = self.set_role(&req.username, role)?;
+        let (Ok(detail) | Err(detail)) = &message;
+        audit::record_session(&self.storage, &session, "grant_role", Some(&req.username), message.is_ok(), Some(detail));
+        Ok(Response::new(GrantRoleResponse {
+            success: message.is_ok(),
+            message: message.unwrap_or_else(|e| e),
+        }))
+    }
+
+    async fn revoke_role(
+        &self,
+        request: Request<RevokeRoleRequest>,
+    ) -> Result<Response<RevokeRoleResponse>, Status> {
+        let session = authorize(&self.storage, &request, Permission::ManageRoles)?;
         let req = request.into_inner();
-        Ok(Response::new(RemoveDeviceResponse {
+        let message = self.set_role(&req.username, Role::User)?;
+        let (Ok(detail) | Err(detail)) = &message;
+        audit::record_session(&self.storage, &session, "revoke_role", Some(&req.username), message.is_ok(), Some(detail));
+        Ok(Response::new(RevokeRoleResponse {
+            success: message.is_ok(),
+            message: message.unwrap_or_else(|e| e),
+        }))
+    }
+
+    async fn list_roles(
+        &self,
+        request: Request<ListRolesRequest>,
+    ) -> Result<Response<ListRolesResponse>, Status> {
+        authorize(&self.storage, &request, Permission::ManageRoles)?;
+        let users = user_store(&self.storage)?;
+        let roles = policy_store(&self.storage)?.list_roles()?
+            .into_iter()
+            .map(|(user_id, role)| RoleAssignment {
+                username: users.get_by_id(&user_id).ok().flatten().map(|u| u.username).unwrap_or_default(),
+                user_id,
+                role: role.to_string(),
+            })
+            .collect();
+        Ok(Response::new(ListRolesResponse { roles }))
+    }
+
+    async fn get_mfa_status(
+        &self,
+        request: Request<MfaStatusRequest>,
+    ) -> Result<Response<MfaStatusResponse>, Status> {
+        let session = auth::require_session(&request)?;
+        let status = mfa_store(&self.storage)?.status(&session.0.user_id)?;
+        Ok(Response::new(MfaStatusResponse {
+            enabled: status.enabled,
+            recovery_codes_remaining: status.recovery_codes_remaining as u32,
+        }))
+    }
+
+    async fn enroll_totp(
+        &self,
+        request: Request<EnrollTotpRequest>,
+    ) -> Result<Response<EnrollTotpResponse>, Status> {
+        let session = auth::require_session(&request)?;
+        let enrolment = mfa_store(&self.storage)?.begin_enrolment(&session.0.user_id, session.username())?;
+        Ok(Response::new(EnrollTotpResponse {
+            secret: enrolment.secret,
+            otpauth_uri: enrolment.otpauth_uri,
+        }))
+    }
+
+    async fn confirm_totp(
+        &self,
+        request: Request<ConfirmTotpRequest>,
+    ) -> Result<Response<RecoveryCodesResponse>, Status> {
+        let session = auth::require_session(&request)?;
+        let result = mfa_store(&self.storage)?.confirm_enrolment(&session.0.user_id, &request.get_ref().code);
+        audit::record_result(&self.storage, session.username(), "mfa_enable", None, &result);
+        Ok(Response::new(RecoveryCodesResponse { recovery_codes: result? }))
+    }
+
+    async fn disable_totp(
+        &self,
+        request: Request<DisableTotpRequest>,
+    ) -> Result<Response<MfaResponse>, Status> {
+        let session = auth::require_session(&request)?;
+        let result = mfa_store(&self.storage)?.disable(&session.0.user_id, &request.get_ref().code);
+        audit::record_result(&self.storage, session.username(), "mfa_disable", None, &result);
+        result?;
+        Ok(Response::new(MfaResponse {
+            success: true,
+            message: "Two-factor authentication disabled".to_string(),
+        }))
+    }
+
+    async fn regenerate_recovery_codes(
+        &self,
+        request: Request<RegenerateRecoveryCodesRequest>,
+    ) -> Result<Response<RecoveryCodesResponse>, Status> {
+        let session = auth::require_session(&request)?;
+        let result = mfa_store(&self.storage)?.regenerate_recovery_codes(&session.0.user_id, &request.get_ref().code);
+        audit::record_result(&self.storage, session.username(), "mfa_recovery_codes", None, &result);
+        Ok(Response::new(RecoveryCodesResponse { recovery_codes: result? }))
+    }
+
+    async fn allow_account_claim(
+        &self,
+        request: Request<AllowAccountClaimRequest>,
+    ) -> Result<Response<AllowAccountClaimResponse>, Status> {
+        let session = authorize(&self.storage, &request, Permission::ManageUsers)?;
+        let username = request.into_inner().username;
+        let result = user_store(&self.storage)?.allow_claim(&username);
+        audit::record_result(&self.storage, session.username(), "allow_claim", Some(&username), &result);
+        Ok(Response::new(match result {
+            Ok(()) => AllowAccountClaimResponse {
+                success: true,
+                message: format!("The next registration of '{}' will claim the account", username),
+            },
+            Err(e) => AllowAccountClaimResponse { success: false, message: e.to_string() },
+        }))
+    }
+
+    async fn reset_mfa(
+        &self,
+        request: Request<ResetMfaRequest>,
+    ) -> Result<Response<MfaResponse>, Status> {
+        let session = authorize(&self.storage, &request, Permission::ManageUsers)?;
+        let username = request.into_inner().username;
+        let users = user_store(&self.storage)?;
+        let user = users.get_by_username(&username)
+            .map_err(|e| Status::internal(e.to_string()))?
+            .ok_or_else(|| Status::not_found(format!("User '{}' not found", username)))?;
+        let result = mfa_store(&self.storage)?.reset(&user.user_id).map_err(|e| e.to_string()).and_then(|was_enabled| {
+            users.clear_device_trust(&user.user_id).map_err(|e| e.to_string())?;
+            Ok(was_enabled)
+        });
+        audit::record_result(&self.storage, session.username(), "mfa_reset", Some(&username), &result);
+        let was_enabled = result.map_err(Status::internal)?;
+        Ok(Response::new(MfaResponse {
             success: true,
-            message: format!("Device '{}' removed successfully", req.device_id),
+            message: if was_enabled {
+                format!("Two-factor authentication reset for '{}'", username)
+            } else {
+                format!("'{}' had no second factor; trusted devices cleared", username)
+            },
+        }))
+    }
+
+    async fn untrust_device(
+        &self,
+        request: Request<UntrustDeviceRequest>,
+    ) -> Result<Response<MfaResponse>, Status> {
+        let session = auth::require_session(&request)?;
+        let device_id = request.into_inner().device_id;
+        let result = user_store(&self.storage)?.set_device_trust(&session.0.user_id, &device_id, None);
+        audit::record_result(&self.storage, session.username(), "untrust_device", Some(&device_id), &result);
+        match result {
+            Ok(true) => Ok(Response::new(MfaResponse {
+                success: true,
+                message: format!("Device '{}' will ask for a second factor again", device_id),
+            })),
+            Ok(false) => Ok(Response::new(MfaResponse {
+                success: false,
+                message: format!("Device '{}' not found", device_id),
+            })),
+            Err(e) => Err(Status::internal(e.to_string())),
+        }
+    }
+
+    async fn approve_device(
+        &self,
+        request: Request<ApproveDeviceRequest>,
+    ) -> Result<Response<DeviceApprovalResponse>, Status> {
+        let session = auth::require_session(&request)?;
+        let device_id = request.into_inner().device_id;
+        let (device, wrapped) = devices::approve_device(&self.storage, &session, &device_id)?;
+        Ok(Response::new(DeviceApprovalResponse {
+            success: true,
+            message: format!("Device '{}' approved", device_id),
+            fingerprint: device.fingerprint,
+            wrapped_keys: wrapped as u32,
+        }))
+    }
+
+    async fn create_device_code(
+        &self,
+        request: Request<CreateDeviceCodeRequest>,
+    ) -> Result<Response<CreateDeviceCodeResponse>, Status> {
+        let session = auth::require_session(&request)?;
+        let (code, expires_at) = devices::issue_code(&self.storage, &session)?;
+        Ok(Response::new(CreateDeviceCodeResponse { code, expires_at }))
+    }
+
+    async fn redeem_device_code(
+        &self,
+        request: Request<RedeemDeviceCodeRequest>,
+    ) -> Result<Response<DeviceApprovalResponse>, Status> {
+        let session = auth::require_any_session(&request)?;
+        let (device, wrapped) = devices::redeem_code(&self.storage, &session, &request.get_ref().code)?;
+        Ok(Response::new(DeviceApprovalResponse {
+            success: true,
+            message: format!("Device '{}' approved", device.device_id),
+            fingerprint: device.fingerprint,
+            wrapped_keys: wrapped as u32,
+        }))
+    }
+
+    async fn get_device_file_key(
+        &self,
+        request: Request<GetDeviceFileKeyRequest>,
+    ) -> Result<Response<GetDeviceFileKeyResponse>, Status> {
+        let session = auth::require_session(&request)?;
+        let file_id = Uuid::parse_str(&request.get_ref().file_id)
+            .map_err(|_| Status::invalid_argument("Invalid file_id"))?;
+        let meta = self.storage.get_metadata(&file_id)
+            .map_err(|e| Status::internal(format!("Storage error: {}", e)))?
+            .ok_or_else(|| Status::not_found("File not found"))?;
+        policy_store(&self.storage)?.check_file(&session, &meta, FilePermission::Read)?;
+        let wrapped_key = DeviceRegistry::open(&self.storage)
+            .map_err(|e| Status::internal(format!("Device store error: {}", e)))?
+            .wrapped_file_key(&file_id, &session.0.device_id)?;
+        Ok(Response::new(GetDeviceFileKeyResponse {
+            device_id: session.0.device_id.clone(),
+            wrapped_key,
         }))
     }
 }
 
+impl DafsUserManagementService {
+    /// Outer error for storage failures, inner `Err` for a message to return to the caller.
+    #[allow(clippy::result_large_err)]
+    fn set_role(&self, username: &str, role: Role) -> Result<Result<String, String>, Status> {
+        let user = match user_store(&self.storage)?.get_by_username(username).map_err(|e| Status::internal(e.to_string()))? {
+            Some(u) => u,
+            None => return Ok(Err(format!("User '{}' not found", username))),
+        };
+        match policy_store(&self.storage)?.set_role(&user.user_id, role) {
+            Ok(()) => Ok(Ok(format!("{} is now {}", username, role))),
+            Err(policy::PolicyError::Storage(e)) => Err(Status::internal(e.to_string())),
+            Err(e) => Ok(Err(e.to_string())),
+        }
+    }
+}
+
 #[derive(Default)]
-pub struct DafsSystemService;
+pub struct DafsSystemService {
+    storage: Arc<Storage>,
+}
 
 #[tonic::async_trait]
 impl SystemService for DafsSystemService {
     async fn start(
         &self,
-        _request: Request<StartRequest>,
+        request: Request<StartRequest>,
     ) -> Result<Response<StartResponse>, Status> {
+        authorize(&self.storage, &request, Permission::ManageServices)?;
         Ok(Response::new(StartResponse {
             success: true,
             message: "DAFS system started successfully".to_string(),
@@ -1031,8 +1831,9 @@ impl SystemService for DafsSystemService {
 
     async fn stop(
         &self,
-        _request: Request<StopRequest>,
+        request: Request<StopRequest>,
     ) -> Result<Response<StopResponse>, Status> {
+        authorize(&self.storage, &request, Permission::ManageServices)?;
         Ok(Response::new(StopResponse {
             success: true,
             message: "DAFS system stopped successfully".to_string(),
@@ -1041,8 +1842,9 @@ impl SystemService for DafsSystemService {
 
     async fn start_web(
         &self,
-        _request: Request<StartWebRequest>,
+        request: Request<StartWebRequest>,
     ) -> Result<Response<StartWebResponse>, Status> {
+        authorize(&self.storage, &request, Permission::ManageServices)?;
         Ok(Response::new(StartWebResponse {
             success: true,
             url: "http://127.0.0.1:3093".to_string(),
@@ -1052,8 +1854,9 @@ impl SystemService for DafsSystemService {
 
     async fn stop_web(
         &self,
-        _request: Request<StopWebRequest>,
+        request: Request<StopWebRequest>,
     ) -> Result<Response<StopWebResponse>, Status> {
+        authorize(&self.storage, &request, Permission::ManageServices)?;
         Ok(Response::new(StopWebResponse {
             success: true,
             message: "Web dashboard stopped successfully".to_string(),
@@ -1062,8 +1865,9 @@ impl SystemService for DafsSystemService {
 
     async fn start_api(
         &self,
-        _request: Request<StartApiRequest>,
+        request: Request<StartApiRequest>,
     ) -> Result<Response<StartApiResponse>, Status> {
+        authorize(&self.storage, &request, Permission::ManageServices)?;
         Ok(Response::new(StartApiResponse {
             success: true,
             url: "http://127.0.0.1:6543".to_string(),
@@ -1073,8 +1877,9 @@ impl SystemService for DafsSystemService {
 
     async fn stop_api(
         &self,
-        _request: Request<StopApiRequest>,
+        request: Request<StopApiRequest>,
     ) -> Result<Response<StopApiResponse>, Status> {
+        authorize(&self.storage, &request, Permission::ManageServices)?;
         Ok(Response::new(StopApiResponse {
             success: true,
             message: "API server stopped successfully".to_string(),
@@ -1083,8 +1888,9 @@ impl SystemService for DafsSystemService {
 
     async fn start_grpc(
         &self,
-        _request: Request<StartGrpcRequest>,
+        request: Request<StartGrpcRequest>,
     ) -> Result<Response<StartGrpcResponse>, Status> {
+        authorize(&self.storage, &request, Permission::ManageServices)?;
         Ok(Response::new(StartGrpcResponse {
             success: true,
             url: "http://127.0.0.1:50051".to_string(),
@@ -1094,38 +1900,191 @@ impl SystemService for DafsSystemService {
 
     async fn stop_grpc(
         &self,
-        _request: Request<StopGrpcRequest>,
+        request: Request<StopGrpcRequest>,
     ) -> Result<Response<StopGrpcResponse>, Status> {
+        authorize(&self.storage, &request, Permission::ManageServices)?;
         Ok(Response::new(StopGrpcResponse {
             success: true,
             message: "gRPC server stopped successfully".to_string(),
         }))
     }
+
+    async fn query_audit_log(
+        &self,
+        request: Request<AuditQueryRequest>,
+    ) -> Result<Response<AuditQueryResponse>, Status> {
+        authorize(&self.storage, &request, Permission::ViewAudit)?;
+        let req = request.into_inner();
+        let filter = AuditFilter {
+            user: Some(req.user).filter(|u| !u.is_empty()),
+            action: Some(req.action).filter(|a| !a.is_empty()),
+            since: Some(req.since).filter(|&t| t > 0),
+            until: Some(req.until).filter(|&t| t > 0),
+            limit: Some(req.limit as usize).filter(|&l| l > 0),
+        };
+        let entries = audit_log(&self.storage)?.query(&filter)
+            .map_err(|e| Status::internal(e.to_string()))?
+            .into_iter()
+            .map(|e| AuditEntry {
+                seq: e.seq,
+                timestamp: e.timestamp,
+                actor: e.actor,
+                action: e.action,
+                target: e.target.unwrap_or_default(),
+                success: e.success,
+                detail: e.detail.unwrap_or_default(),
+                prev_hash: e.prev_hash,
+                hash: e.hash,
+            })
+            .collect();
+        Ok(Response::new(AuditQueryResponse { entries }))
+    }
+
+    async fn verify_audit_log(
+        &self,
+        request: Request<VerifyAuditLogRequest>,
+    ) -> Result<Response<VerifyAuditLogResponse>, Status> {
+        authorize(&self.storage, &request, Permission::ViewAudit)?;
+        let result = audit_log(&self.storage)?.verify()
+            .map_err(|e| Status::internal(e.to_string()))?;
+        Ok(Response::new(VerifyAuditLogResponse {
+            valid: result.valid,
+            entries: result.entries,
+            broken_at: result.broken_at.unwrap_or_default(),
+            reason: result.reason.unwrap_or_default(),
+        }))
+    }
+}
+
+/// Requires a session whose role grants `permission`.
+#[allow(clippy::result_large_err)]
+fn authorize<T>(storage: &Storage, request: &Request<T>, permission: Permission) -> Result<AuthSession, Status> {
+    let session = auth::require_session(request)?;
+    if let Err(e) = policy_store(storage)?.check(&session, permission) {
+        audit::record_session(storage, &session, "permission_denied", Some(&permission.to_string()), false, Some(&e.to_string()));
+        return Err(e.into());
+    }
+    Ok(session)
+}
+
+#[allow(clippy::result_large_err)]
+fn policy_store(storage: &Storage) -> Result<Policy, Status> {
+    Policy::open(storage).map_err(|e| Status::internal(format!("Policy store error: {}", e)))
+}
+
+#[allow(clippy::result_large_err)]
+fn audit_log(storage: &Storage) -> Result<AuditLog, Status> {
+    AuditLog::open(storage).map_err(|e| Status::internal(format!("Audit store error: {}", e)))
+}
+
+#[allow(clippy::result_large_err)]
+fn mfa_store(storage: &Storage) -> Result<MfaStore, Status> {
+    MfaStore::open(storage).map_err(|e| Status::internal(format!("Two-factor store error: {}", e)))
+}
+
+#[allow(clippy::result_large_err)]
+fn user_store(storage: &Storage) -> Result<UserStore, Status> {
+    UserStore::open(storage).map_err(|e| Status::internal(format!("User store error: {}", e)))
+}
+
+/// Enrolment state of a device, or empty for one that never presented a key.
+fn device_state(storage: &Storage, user_id: &str, device_id: &str) -> String {
+    DeviceRegistry::open(storage).ok()
+        .and_then(|registry| registry.get(user_id, device_id).ok().flatten())
+        .map(|d| d.state.to_string())
+        .unwrap_or_default()
+}
+
+fn file_info(f: crate::storage::FileMetadata) -> FileMetadata {
+    FileMetadata {
+        file_id: f.file_id.to_string(),
+        filename: f.filename,
+        tags: f.tags,
+        owner_peer_id: f.owner_peer_id,
+        checksum: f.checksum,
+        size: f.size,
+        shared_keys: f.shared_keys,
+        attributes: f.attributes,
+        confidential: f.sealed.is_some(),
+    }
+}
+
+fn link_info(link: &ShareLink) -> ShareLinkInfo {
+    ShareLinkInfo {
+        link_id: link.link_id.clone(),
+        file_id: link.file_id.to_string(),
+        created_at: link.created_at,
+        expires_at: link.expires_at.unwrap_or(0),
+        max_downloads: link.max_downloads.unwrap_or(0),
+        downloads: link.downloads,
+        password_required: link.has_password(),
+        revoked: link.revoked,
+    }
+}
+
+fn rfc3339(secs: u64) -> String {
+    chrono::DateTime::from_timestamp(secs as i64, 0)
+        .map(|t| t.to_rfc3339())
+        .unwrap_or_default()
+}
+
+fn discovered_peer_info(peer: crate::peer::DiscoveredPeer) -> DiscoveredPeer {
+    DiscoveredPeer {
+        peer_id: peer.peer_id,
+        address: peer.addresses.join(","),
+        user_agent: peer.agent_version.unwrap_or_default(),
+        protocols: peer.protocols,
+        latency_ms: peer.latency_ms.unwrap_or(0),
+        is_online: peer.is_online,
+    }
+}
+
+fn user_info(user: &crate::models::UserIdentity) -> UserInfo {
+    UserInfo {
+        user_id: user.user_id.clone(),
+        username: user.username.clone(),
+        display_name: user.display_name.clone(),
+        email: user.email.clone().unwrap_or_default(),
+        status: if user.is_active { "active" } else { "inactive" }.to_string(),
+        last_seen: rfc3339(user.last_seen),
+    }
 }
 
 // Refactor: make this a static function, pass in all needed owned data
-fn spawn_keypair_loader(
-    keyfile: String,
-    password: String,
+fn spawn_file_streamer(
+    session: AuthSession,
     tx_clone: tokio::sync::mpsc::Sender<Result<DownloadChunk, Status>>,
     file_id: uuid::Uuid,
     storage: std::sync::Arc<Storage>,
+    p2p: std::sync::Arc<P2PNode>,
 ) {
     tokio::spawn(async move {
-        if let Err(_) = load_and_decrypt_keypair(&keyfile, &password) {
-            let _ = tx_clone.send(Err(Status::unauthenticated("Invalid credentials"))).await;
-            return;
-        }
         // Get file metadata
-        let _meta = match storage.get_metadata(&file_id) {
+        let meta = match storage.get_metadata(&file_id) {
             Ok(Some(m)) => m,
             _ => {
                 let _ = tx_clone.send(Err(Status::not_found("File not found"))).await;
                 return;
             }
         };
+        let allowed = Policy::open(&storage)
+            .map_err(policy::PolicyError::from)
+            .and_then(|p| p.check_file(&session, &meta, FilePermission::Read));
+        let target = file_id.to_string();
+        if let Err(e) = allowed {
+            audit::record_session(&storage, &session, "download", Some(&target), false, Some(&e.to_string()));
+            let _ = tx_clone.send(Err(e.into())).await;
+            return;
+        }
+        audit::record_session(&storage, &session, "download", Some(&target), true, None);
         // Read and send file in chunks
         let file_path = format!("files/{}.bin", file_id);
+        if !std::path::Path::new(&file_path).exists() && erasure::is_sharded(&target)
+            && let Err(e) = p2p.restore_file(&target).await
+        {
+            let _ = tx_clone.send(Err(Status::unavailable(format!("File could not be rebuilt from its shards: {}", e)))).await;
+            return;
+        }
         let file_data = match std::fs::read(&file_path) {
             Ok(data) => data,
             Err(_) => {
@@ -1149,8 +2108,22 @@ fn spawn_keypair_loader(
     });
 }
 
-pub async fn run_grpc_server(storage: Arc<Storage>, p2p: Arc<P2PNode>) -> Result<(), Box<dyn std::error::Error>> {
-    let addr = "[::1]:50051".parse()?;
+/// A server builder with the configured TLS, if any.
+fn server_builder(config: &ServerConfig) -> Result<Server, Box<dyn std::error::Error>> {
+    let mut builder = Server::builder();
+    if let Some(tls) = &config.tls {
+        let mut tls_config = ServerTlsConfig::new().identity(tls.identity()?);
+        // Client certificates are an alternative to tokens, so they stay optional
+        if let Some(ca) = tls.client_ca()? {
+            tls_config = tls_config.client_ca_root(ca).client_auth_optional(true);
+        }
+        builder = builder.tls_config(tls_config)?;
+    }
+    Ok(builder)
+}
+
+pub async fn run_grpc_server(storage: Arc<Storage>, p2p: Arc<P2PNode>, config: Arc<ServerConfig>) -> Result<(), Box<dyn std::error::Error>> {
+    let addr = config.grpc_addr;
     
     let ai_service = DafsAiService {
         storage: storage.clone(),
@@ -1158,28 +2131,94 @@ pub async fn run_grpc_server(storage: Arc<Storage>, p2p: Arc<P2PNode>) -> Result
     
     let file_service = DafsFileService {
         storage: storage.clone(),
+        p2p: p2p.clone(),
     };
     
     let p2p_service = DafsP2PService {
         p2p: p2p.clone(),
+        storage: storage.clone(),
+    };
+    let auth_service = DafsAuthService {
+        storage: storage.clone(),
     };
-    let auth_service = DafsAuthService;
     let messaging_service = DafsMessagingService;
-    let user_management_service = DafsUserManagementService;
-    let system_service = DafsSystemService;
-
-    println!("gRPC server listening on {}", addr);
-
-    Server::builder()
-        .add_service(AiServiceServer::new(ai_service))
-        .add_service(FileServiceServer::new(file_service))
-        .add_service(P2pServiceServer::new(p2p_service))
-        .add_service(AuthServiceServer::new(auth_service))
-        .add_service(MessagingServiceServer::new(messaging_service))
-        .add_service(UserManagementServiceServer::new(user_management_service))
-        .add_service(SystemServiceServer::new(system_service))
+    let user_management_service = DafsUserManagementService {
+        storage: storage.clone(),
+    };
+    let system_service = DafsSystemService {
+        storage: storage.clone(),
+    };
+
+    // Auth and user management accept anonymous calls for login/register and check
+    // the session per handler; every other service requires a bearer token up front.
+    let required = GrpcAuth::required(storage.clone());
+    let optional = GrpcAuth::optional(storage.clone());
+
+    let limiter = Arc::new(RateLimiter::open(&storage, config.rate_limits.clone())?);
+    let builder = server_builder(&config)?;
+
+    println!("gRPC server listening on {}://{}", if config.tls.is_some() { "https" } else { "http" }, addr);
+
+    builder
+        .layer(GrpcRateLimitLayer::new(limiter))
+        .add_service(AiServiceServer::with_interceptor(ai_service, required.clone()))
+        .add_service(FileServiceServer::with_interceptor(file_service, required.clone()))
+        .add_service(P2pServiceServer::with_interceptor(p2p_service, required.clone()))
+        .add_service(AuthServiceServer::with_interceptor(auth_service, optional.clone()))
+        .add_service(MessagingServiceServer::with_interceptor(messaging_service, required.clone()))
+        .add_service(UserManagementServiceServer::with_interceptor(user_management_service, optional))
+        .add_service(SystemServiceServer::with_interceptor(system_service, required))
         .serve(addr)
         .await?;
 
     Ok(())
-} 
\ No newline at end of file
+} 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ratelimit::{RateLimit, RateLimitConfig};
+    use crate::transport::TlsSettings;
+    use super::dafs::auth_service_client::AuthServiceClient;
+    use tonic::transport::{Certificate, ClientTlsConfig, Endpoint};
+
+    #[tokio::test]
+    async fn rate_limit_applies_over_tls() {
+        let dir = std::env::temp_dir().join(format!("dafs-grpc-tls-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_pem = cert.serialize_pem().unwrap();
+        fs::write(dir.join("node.crt"), &cert_pem).unwrap();
+        fs::write(dir.join("node.key"), cert.serialize_private_key_pem()).unwrap();
+
+        let config = ServerConfig {
+            tls: Some(TlsSettings::load_or_generate(Some(dir.join("node.crt")), Some(dir.join("node.key")), None).unwrap()),
+            rate_limits: RateLimitConfig {
+                default: None,
+                routes: vec![("/dafs.AuthService".to_string(), RateLimit { capacity: 1, per_sec: 0.001 })],
+            },
+            ..Default::default()
+        };
+        let storage = Arc::new(Storage::new(dir.join("db").to_str().unwrap()).unwrap());
+        let limiter = Arc::new(RateLimiter::open(&storage, config.rate_limits.clone()).unwrap());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let incoming = tonic::transport::server::TcpIncoming::from_listener(listener, true, None).unwrap();
+        let router = server_builder(&config)
+            .unwrap()
+            .layer(GrpcRateLimitLayer::new(limiter))
+            .add_service(AuthServiceServer::new(DafsAuthService { storage }));
+        tokio::spawn(router.serve_with_incoming(incoming));
+
+        let tls = ClientTlsConfig::new().ca_certificate(Certificate::from_pem(cert_pem)).domain_name("localhost");
+        let channel = Endpoint::from_shared(format!("https://{}", addr)).unwrap().tls_config(tls).unwrap().connect().await.unwrap();
+        let mut client = AuthServiceClient::new(channel);
+        let login = || LoginRequest { username: "nobody".to_string(), password: "wrong".to_string(), ..Default::default() };
+
+        let first = client.login(login()).await.unwrap().into_inner();
+        assert!(!first.success);
+        let second = client.login(login()).await.unwrap_err();
+        assert_eq!(second.code(), tonic::Code::ResourceExhausted);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
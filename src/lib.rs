@@ -3,6 +3,15 @@ pub mod storage;
 pub mod crypto;
 pub mod ai;
 pub mod api;
+pub mod auth;
+pub mod mfa;
+pub mod devices;
+pub mod links;
+pub mod confidential;
+pub mod policy;
+pub mod audit;
+pub mod ratelimit;
+pub mod transport;
 pub mod grpc;
 pub mod user_management;
 pub mod remote_management;
@@ -0,0 +1,267 @@
+// Capability links: public URLs for sending a file to someone without an account.
+//
+// A link is `/s/<link_id>#<key>`. The node keeps only the link record; the file key
+// travels in the URL fragment, which browsers never send to the server, so whoever
+// opens the link downloads the ciphertext and decrypts it locally (AES-256-GCM,
+// `nonce || ciphertext`, as stored in `files/`). Links can expire, cap their downloads,
+// require a password (checked by the node before it hands out the ciphertext) and be
+// revoked. Rekeying a file revokes its links, since their keys no longer decrypt it.
+// Records live in `share_links`, keyed by link_id.
+
+use crate::crypto::{hash_password, verify_password, SecretString};
+use crate::storage::Storage;
+use axum::http::StatusCode;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tonic::Status;
+use uuid::Uuid;
+
+/// Request header carrying a link's password
+pub const PASSWORD_HEADER: &str = "x-link-password";
+
+#[derive(Debug, thiserror::Error)]
+pub enum LinkError {
+    #[error("Link not found")]
+    NotFound,
+    #[error("Link has expired")]
+    Expired,
+    #[error("Link has reached its download limit")]
+    Exhausted,
+    #[error("Link has been revoked")]
+    Revoked,
+    #[error("Link requires a password")]
+    PasswordRequired,
+    #[error("Wrong link password")]
+    WrongPassword,
+    #[error("{0}")]
+    Storage(#[from] anyhow::Error),
+}
+
+impl LinkError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            LinkError::NotFound => StatusCode::NOT_FOUND,
+            LinkError::Expired | LinkError::Exhausted | LinkError::Revoked => StatusCode::GONE,
+            LinkError::PasswordRequired | LinkError::WrongPassword => StatusCode::UNAUTHORIZED,
+            LinkError::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl From<LinkError> for Status {
+    fn from(e: LinkError) -> Self {
+        match e {
+            LinkError::NotFound => Status::not_found(e.to_string()),
+            LinkError::Expired | LinkError::Exhausted | LinkError::Revoked => Status::failed_precondition(e.to_string()),
+            LinkError::PasswordRequired | LinkError::WrongPassword => Status::unauthenticated(e.to_string()),
+            LinkError::Storage(_) => Status::internal(e.to_string()),
+        }
+    }
+}
+
+impl From<sled::Error> for LinkError {
+    fn from(e: sled::Error) -> Self {
+        LinkError::Storage(e.into())
+    }
+}
+
+impl From<serde_json::Error> for LinkError {
+    fn from(e: serde_json::Error) -> Self {
+        LinkError::Storage(e.into())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct LinkPassword {
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShareLink {
+    pub link_id: String,
+    pub file_id: Uuid,
+    pub created_by: String,
+    pub created_at: u64,
+    pub expires_at: Option<u64>,
+    pub max_downloads: Option<u32>,
+    pub downloads: u32,
+    pub revoked: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    password: Option<LinkPassword>,
+}
+
+impl ShareLink {
+    pub fn has_password(&self) -> bool {
+        self.password.is_some()
+    }
+
+    pub fn downloads_remaining(&self) -> Option<u32> {
+        self.max_downloads.map(|max| max.saturating_sub(self.downloads))
+    }
+
+    /// Why the link can no longer be used, if it can't.
+    fn unusable(&self, now: u64) -> Option<LinkError> {
+        if self.revoked {
+            Some(LinkError::Revoked)
+        } else if self.expires_at.is_some_and(|t| t <= now) {
+            Some(LinkError::Expired)
+        } else if self.downloads_remaining() == Some(0) {
+            Some(LinkError::Exhausted)
+        } else {
+            None
+        }
+    }
+
+    /// The shareable path with the key in its fragment.
+    pub fn path(&self, key: &str) -> String {
+        format!("/s/{}#{}", self.link_id, key)
+    }
+}
+
+/// Limits for a new link; `None` means unlimited.
+#[derive(Debug, Clone, Default)]
+pub struct LinkOptions {
+    pub expires_in: Option<u64>,
+    pub max_downloads: Option<u32>,
+    pub password: Option<SecretString>,
+}
+
+/// Encodes a file key for a link fragment (base64url, no padding).
+pub fn encode_key(file_key: &[u8; 32]) -> String {
+    data_encoding::BASE64URL_NOPAD.encode(file_key)
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+pub struct LinkStore {
+    links: sled::Tree,
+}
+
+impl LinkStore {
+    pub fn open(storage: &Storage) -> anyhow::Result<Self> {
+        Ok(Self { links: storage.open_tree("share_links")? })
+    }
+
+    pub fn create(&self, file_id: Uuid, created_by: &str, options: LinkOptions) -> Result<ShareLink, LinkError> {
+        let mut id = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut id);
+        let now = now_secs();
+        let link = ShareLink {
+            link_id: data_encoding::BASE64URL_NOPAD.encode(&id),
+            file_id,
+            created_by: created_by.to_string(),
+            created_at: now,
+            expires_at: options.expires_in.map(|secs| now + secs),
+            max_downloads: options.max_downloads,
+            downloads: 0,
+            revoked: false,
+            password: options.password.filter(|p| !p.is_empty()).map(|p| {
+                let (salt, hash) = hash_password(p.expose());
+                LinkPassword { salt: salt.to_vec(), hash: hash.to_vec() }
+            }),
+        };
+        self.links.insert(link.link_id.as_bytes(), serde_json::to_vec(&link)?)?;
+        Ok(link)
+    }
+
+    pub fn get(&self, link_id: &str) -> Result<ShareLink, LinkError> {
+        match self.links.get(link_id.as_bytes())? {
+            Some(data) => Ok(serde_json::from_slice(&data)?),
+            None => Err(LinkError::NotFound),
+        }
+    }
+
+    /// A link that can still be opened, without counting a download.
+    pub fn usable(&self, link_id: &str) -> Result<ShareLink, LinkError> {
+        let link = self.get(link_id)?;
+        match link.unusable(now_secs()) {
+            Some(e) => Err(e),
+            None => Ok(link),
+        }
+    }
+
+    /// Checks the link and its password and counts one download, atomically, so a
+    /// download limit holds under concurrent requests. A wrong password counts nothing.
+    pub fn redeem(&self, link_id: &str, password: Option<&str>) -> Result<ShareLink, LinkError> {
+        let now = now_secs();
+        let result = self.links.transaction(|tx| {
+            let mut link: ShareLink = match tx.get(link_id.as_bytes())? {
+                Some(data) => serde_json::from_slice(&data).map_err(|e| ConflictableTransactionError::Abort(e.into()))?,
+                None => return Err(ConflictableTransactionError::Abort(LinkError::NotFound)),
+            };
+            if let Some(e) = link.unusable(now) {
+                return Err(ConflictableTransactionError::Abort(e));
+            }
+            if let Some(stored) = &link.password {
+                let given = password.ok_or(ConflictableTransactionError::Abort(LinkError::PasswordRequired))?;
+                if !verify_password(given, &stored.salt, &stored.hash) {
+                    return Err(ConflictableTransactionError::Abort(LinkError::WrongPassword));
+                }
+            }
+            link.downloads += 1;
+            let data = serde_json::to_vec(&link).map_err(|e| ConflictableTransactionError::Abort(e.into()))?;
+            tx.insert(link_id.as_bytes(), data)?;
+            Ok(link)
+        });
+        match result {
+            Ok(link) => Ok(link),
+            Err(TransactionError::Abort(e)) => Err(e),
+            Err(TransactionError::Storage(e)) => Err(e.into()),
+        }
+    }
+
+    /// Links `username` created, newest first.
+    pub fn list_by_creator(&self, username: &str) -> Result<Vec<ShareLink>, LinkError> {
+        let mut out = Vec::new();
+        for item in self.links.iter() {
+            let (_, v) = item?;
+            let link: ShareLink = serde_json::from_slice(&v)?;
+            if link.created_by == username {
+                out.push(link);
+            }
+        }
+        out.sort_by_key(|l| std::cmp::Reverse(l.created_at));
+        Ok(out)
+    }
+
+    /// Hands the links `old` created to `new`, once the user is renamed.
+    pub fn rename_creator(&self, old: &str, new: &str) -> Result<(), LinkError> {
+        for item in self.links.iter() {
+            let (k, v) = item?;
+            let mut link: ShareLink = serde_json::from_slice(&v)?;
+            if link.created_by == old {
+                link.created_by = new.to_string();
+                self.links.insert(k, serde_json::to_vec(&link)?)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Marks a link revoked; it stays listed so its creator can see what happened to it.
+    pub fn revoke(&self, link_id: &str) -> Result<ShareLink, LinkError> {
+        let mut link = self.get(link_id)?;
+        link.revoked = true;
+        self.links.insert(link_id.as_bytes(), serde_json::to_vec(&link)?)?;
+        Ok(link)
+    }
+
+    /// Revokes every live link to a file; returns how many.
+    pub fn revoke_file(&self, file_id: &Uuid) -> Result<usize, LinkError> {
+        let mut revoked = 0;
+        for item in self.links.iter() {
+            let (k, v) = item?;
+            let mut link: ShareLink = serde_json::from_slice(&v)?;
+            if link.file_id == *file_id && !link.revoked {
+                link.revoked = true;
+                self.links.insert(k, serde_json::to_vec(&link)?)?;
+                revoked += 1;
+            }
+        }
+        Ok(revoked)
+    }
+}
@@ -3,6 +3,16 @@ mod crypto;
 mod storage;
 mod ai;
 mod api;
+mod auth;
+mod mfa;
+mod devices;
+mod links;
+mod confidential;
+mod policy;
+mod audit;
+mod ratelimit;
+mod transport;
+mod user_management;
 mod grpc;
 mod models;
 mod web;
@@ -53,10 +63,90 @@ struct Cli {
     #[arg(long, default_value = "2093")]
     p2p_port: u16,
     
+    /// P2P address to listen on, e.g. `/ip4/0.0.0.0/udp/2093/quic-v1`; repeat or
+    /// comma-separate (default: TCP and QUIC on --p2p-port, WebSocket on the port after it)
+    #[arg(long = "listen", value_delimiter = ',')]
+    listen: Vec<String>,
+    
+    /// Transports to try first when a peer has several addresses
+    #[arg(long, value_delimiter = ',', default_value = "quic,tcp,ws,webrtc")]
+    dial_preference: Vec<String>,
+    
+    /// Most connections other nodes may open to this one
+    #[arg(long, default_value = "64")]
+    max_inbound: u32,
+    
+    /// Most connections this node opens to other nodes
+    #[arg(long, default_value = "64")]
+    max_outbound: u32,
+    
+    /// Most connections to any one peer
+    #[arg(long, default_value = "2")]
+    max_per_peer: u32,
+    
+    /// How long an unused P2P connection stays open, in seconds
+    #[arg(long, default_value = "60")]
+    idle_timeout: u64,
+    
     /// Run in integrated mode (start all services)
     #[arg(long, action = ArgAction::SetTrue)]
     integrated: bool,
     
+    /// Address the HTTP API and gRPC servers listen on (default: API on all
+    /// interfaces, gRPC on localhost only)
+    #[arg(long)]
+    bind: Option<std::net::IpAddr>,
+    
+    /// Serve the HTTP API and gRPC over TLS (self-signed unless --tls-cert/--tls-key are given)
+    #[arg(long, action = ArgAction::SetTrue)]
+    tls: bool,
+    
+    /// PEM certificate for TLS (implies --tls)
+    #[arg(long)]
+    tls_cert: Option<std::path::PathBuf>,
+    
+    /// PEM private key for TLS (implies --tls)
+    #[arg(long)]
+    tls_key: Option<std::path::PathBuf>,
+    
+    /// PEM CA whose client certificates may authenticate gRPC calls (requires TLS)
+    #[arg(long)]
+    tls_client_ca: Option<std::path::PathBuf>,
+    
+    /// Origin allowed to call the HTTP API from a browser; repeat or comma-separate,
+    /// `*` for any (default: the local web dashboard)
+    #[arg(long = "cors-origin", value_delimiter = ',')]
+    cors_origins: Vec<String>,
+    
+    /// Request limit for a route as ROUTE=CAPACITY/PER_SEC, e.g. `/login=5/0.1`; the
+    /// route `default` covers everything unlisted. Repeat for several routes
+    #[arg(long = "rate-limit")]
+    rate_limits: Vec<String>,
+    
+    /// Reputation penalty at which a misbehaving peer is banned
+    #[arg(long, default_value = "100")]
+    ban_threshold: f64,
+    
+    /// How long an automatic peer ban lasts, in seconds
+    #[arg(long, default_value = "3600")]
+    ban_duration: u64,
+    
+    /// Copies of each uploaded file to keep on other nodes
+    #[arg(long, default_value = "2")]
+    replication_factor: usize,
+    
+    /// How long an unreachable replica still counts before it is replaced, in seconds
+    #[arg(long, default_value = "1800")]
+    replica_grace: u64,
+    
+    /// Space this node lends to other nodes' replicas, in MiB
+    #[arg(long, default_value = "10240")]
+    replica_capacity: u64,
+    
+    /// Make this registered account admin, if the node has never had one
+    #[arg(long, value_name = "USERNAME")]
+    bootstrap_admin: Option<String>,
+    
     /// CLI subcommands
     #[command(subcommand)]
     command: Option<cli::Commands>,
@@ -81,6 +171,24 @@ async fn main() -> anyhow::Result<()> {
         return Ok(());
     }
     
+    let server_config = Arc::new(server_config(&cli)?);
+    peer::reputation::configure(peer::reputation::ReputationConfig {
+        ban_threshold: cli.ban_threshold,
+        ban_secs: cli.ban_duration,
+    });
+    peer::transports::configure(transport_config(&cli)?);
+    peer::connections::configure(peer::connections::ConnectionConfig {
+        max_inbound: cli.max_inbound,
+        max_outbound: cli.max_outbound,
+        max_per_peer: cli.max_per_peer,
+        idle_timeout: std::time::Duration::from_secs(cli.idle_timeout),
+    });
+    peer::replication::configure(peer::replication::ReplicationConfig {
+        factor: cli.replication_factor,
+        grace_secs: cli.replica_grace,
+        capacity_bytes: cli.replica_capacity * 1024 * 1024,
+    });
+    
     // If no specific services are requested, run in integrated mode
     let integrated_mode = cli.integrated || (!cli.web && !cli.api && !cli.grpc && !cli.p2p);
     
@@ -89,22 +197,27 @@ async fn main() -> anyhow::Result<()> {
         
         // Initialize storage
         let storage = Arc::new(Storage::new("dafs_db")?);
+        api::recover_rekeys(&storage)?;
+        bootstrap_admin(&storage, cli.bootstrap_admin.as_deref())?;
 
         // Initialize P2P node
         let p2p = Arc::new(P2PNode::new());
+        peer::replication::spawn(p2p.clone());
 
         // Start HTTP API server in background
         let api_storage = storage.clone();
         let api_p2p = p2p.clone();
+        let api_config = server_config.clone();
         tokio::spawn(async move { 
-            api::run_with_storage_and_p2p(api_storage, api_p2p).await 
+            api::run_with_storage_and_p2p(api_storage, api_p2p, api_config).await 
         });
 
         // Start gRPC server in background
         let grpc_storage = storage.clone();
         let grpc_p2p = p2p.clone();
+        let grpc_config = server_config.clone();
         tokio::spawn(async move {
-            if let Err(e) = grpc::run_grpc_server(grpc_storage, grpc_p2p).await {
+            if let Err(e) = grpc::run_grpc_server(grpc_storage, grpc_p2p, grpc_config).await {
                 eprintln!("gRPC server error: {}", e);
             }
         });
@@ -113,8 +226,9 @@ async fn main() -> anyhow::Result<()> {
         // It must be started via CLI command
 
         println!("✅ DAFS node started in integrated mode!");
-        println!("   HTTP API: http://127.0.0.1:{}", cli.api_port);
-        println!("   gRPC: grpc://[::1]:{}", cli.grpc_port);
+        let scheme = if server_config.tls.is_some() { "https" } else { "http" };
+        println!("   HTTP API: {}://{}", scheme, server_config.api_addr);
+        println!("   gRPC: {}://{}", scheme, server_config.grpc_addr);
         println!("   Web Dashboard: Use 'dafs cli startweb' to start");
         println!("   Use Ctrl+C to stop");
     } else {
@@ -122,6 +236,8 @@ async fn main() -> anyhow::Result<()> {
         
         // Initialize storage
         let storage = Arc::new(Storage::new("dafs_db")?);
+        api::recover_rekeys(&storage)?;
+        bootstrap_admin(&storage, cli.bootstrap_admin.as_deref())?;
 
         // Initialize P2P node
         let p2p = Arc::new(P2PNode::new());
@@ -130,8 +246,9 @@ async fn main() -> anyhow::Result<()> {
         if cli.api {
             let api_storage = storage.clone();
             let api_p2p = p2p.clone();
+            let api_config = server_config.clone();
             tokio::spawn(async move { 
-                api::run_with_storage_and_p2p(api_storage, api_p2p).await 
+                api::run_with_storage_and_p2p(api_storage, api_p2p, api_config).await 
             });
             println!("✅ HTTP API server started on port {}", cli.api_port);
         }
@@ -139,8 +256,9 @@ async fn main() -> anyhow::Result<()> {
         if cli.grpc {
             let grpc_storage = storage.clone();
             let grpc_p2p = p2p.clone();
+            let grpc_config = server_config.clone();
             tokio::spawn(async move {
-                if let Err(e) = grpc::run_grpc_server(grpc_storage, grpc_p2p).await {
+                if let Err(e) = grpc::run_grpc_server(grpc_storage, grpc_p2p, grpc_config).await {
                     eprintln!("gRPC server error: {}", e);
                 }
             });
@@ -157,6 +275,7 @@ async fn main() -> anyhow::Result<()> {
         }
 
         if cli.p2p {
+            peer::replication::spawn(p2p.clone());
             println!("✅ P2P network started on port {}", cli.p2p_port);
         }
 
@@ -167,3 +286,54 @@ async fn main() -> anyhow::Result<()> {
     futures::future::pending::<()>().await;
     Ok(())
 }
+
+fn bootstrap_admin(storage: &Storage, username: Option<&str>) -> anyhow::Result<()> {
+    let Some(username) = username else {
+        return Ok(());
+    };
+    if policy::bootstrap_admin(storage, username)? {
+        println!("👑 {} is now admin", username);
+    } else {
+        println!("This node already has an admin; --bootstrap-admin {} ignored", username);
+    }
+    Ok(())
+}
+
+fn transport_config(cli: &Cli) -> anyhow::Result<peer::transports::TransportConfig> {
+    let mut config = peer::transports::TransportConfig::on_port(cli.p2p_port);
+    if !cli.listen.is_empty() {
+        config.listen = cli.listen.iter()
+            .map(|addr| addr.parse().map_err(|e| anyhow::anyhow!("--listen {}: {}", addr, e)))
+            .collect::<anyhow::Result<_>>()?;
+    }
+    config.dial_preference = cli.dial_preference.iter()
+        .map(|kind| kind.parse().map_err(|e| anyhow::anyhow!("--dial-preference: {}", e)))
+        .collect::<anyhow::Result<_>>()?;
+    Ok(config)
+}
+
+fn server_config(cli: &Cli) -> anyhow::Result<transport::ServerConfig> {
+    let mut config = transport::ServerConfig::default();
+    config.api_addr.set_port(cli.api_port);
+    config.grpc_addr.set_port(cli.grpc_port);
+    if let Some(ip) = cli.bind {
+        config.api_addr.set_ip(ip);
+        config.grpc_addr.set_ip(ip);
+    }
+    if cli.tls || cli.tls_cert.is_some() || cli.tls_key.is_some() {
+        config.tls = Some(transport::TlsSettings::load_or_generate(
+            cli.tls_cert.clone(),
+            cli.tls_key.clone(),
+            cli.tls_client_ca.clone(),
+        )?);
+    } else if cli.tls_client_ca.is_some() {
+        anyhow::bail!("--tls-client-ca requires --tls");
+    }
+    if !cli.cors_origins.is_empty() {
+        config.cors_origins = cli.cors_origins.clone();
+    }
+    for spec in &cli.rate_limits {
+        config.rate_limits.set(spec).map_err(|e| anyhow::anyhow!("--rate-limit: {}", e))?;
+    }
+    Ok(config)
+}
@@ -0,0 +1,320 @@
+// Optional second factor for logins: RFC 6238 TOTP (HMAC-SHA1, six digits, 30-second
+// steps) plus one-time recovery codes.
+//
+// Enrolment takes two steps. `begin_enrolment` stores a pending secret and returns the
+// otpauth:// URI for an authenticator app; `confirm_enrolment` switches it on once the
+// app produces a valid code and hands back the recovery codes. Only SHA-256 hashes of
+// recovery codes are kept, and each is removed when used. The last accepted time step
+// is remembered so an intercepted code cannot be replayed. Records live in `user_mfa`,
+// keyed by user_id.
+
+use crate::storage::Storage;
+use axum::http::StatusCode;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tonic::Status;
+
+const STEP_SECS: u64 = 30;
+const DIGITS: usize = 6;
+const SECRET_LEN: usize = 20;
+const RECOVERY_CODE_COUNT: usize = 10;
+const ISSUER: &str = "DAFS";
+/// How long a device marked trusted at login may skip the second factor
+pub const TRUST_DEVICE_SECS: u64 = 30 * 24 * 60 * 60;
+
+#[derive(Debug, thiserror::Error)]
+pub enum MfaError {
+    #[error("Two-factor authentication is already enabled")]
+    AlreadyEnabled,
+    #[error("Two-factor authentication is not enabled")]
+    NotEnabled,
+    #[error("No two-factor enrolment in progress")]
+    NoPendingEnrolment,
+    #[error("Invalid authentication code")]
+    InvalidCode,
+    #[error("{0}")]
+    Storage(#[from] anyhow::Error),
+}
+
+impl MfaError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            MfaError::AlreadyEnabled | MfaError::NotEnabled | MfaError::NoPendingEnrolment => StatusCode::CONFLICT,
+            MfaError::InvalidCode => StatusCode::UNAUTHORIZED,
+            MfaError::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl From<MfaError> for Status {
+    fn from(e: MfaError) -> Self {
+        match e {
+            MfaError::AlreadyEnabled | MfaError::NotEnabled | MfaError::NoPendingEnrolment => Status::failed_precondition(e.to_string()),
+            MfaError::InvalidCode => Status::unauthenticated(e.to_string()),
+            MfaError::Storage(_) => Status::internal(e.to_string()),
+        }
+    }
+}
+
+impl From<sled::Error> for MfaError {
+    fn from(e: sled::Error) -> Self {
+        MfaError::Storage(e.into())
+    }
+}
+
+impl From<serde_json::Error> for MfaError {
+    fn from(e: serde_json::Error) -> Self {
+        MfaError::Storage(e.into())
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct MfaRecord {
+    secret: Vec<u8>,
+    enabled: bool,
+    /// Hashes of the recovery codes not yet used
+    #[serde(default)]
+    recovery_hashes: Vec<String>,
+    /// Last time step a TOTP code was accepted for
+    #[serde(default)]
+    last_step: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MfaStatus {
+    pub enabled: bool,
+    pub recovery_codes_remaining: usize,
+}
+
+/// What an authenticator app needs: the base32 secret and the equivalent otpauth URI.
+#[derive(Clone, Serialize)]
+pub struct Enrolment {
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+impl std::fmt::Debug for MfaRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MfaRecord")
+            .field("secret", &"<redacted>")
+            .field("enabled", &self.enabled)
+            .field("recovery_codes", &self.recovery_hashes.len())
+            .field("last_step", &self.last_step)
+            .finish()
+    }
+}
+
+impl std::fmt::Debug for Enrolment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Enrolment(<redacted>)")
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// The TOTP code for `step`, as its six-digit string.
+fn totp(secret: &[u8], step: u64) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(&step.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([digest[offset] & 0x7f, digest[offset + 1], digest[offset + 2], digest[offset + 3]]);
+    format!("{:0width$}", value % 10u32.pow(DIGITS as u32), width = DIGITS)
+}
+
+/// Recovery codes are compared without case, spaces or dashes.
+fn normalize_code(code: &str) -> String {
+    code.chars().filter(|c| c.is_ascii_alphanumeric()).collect::<String>().to_lowercase()
+}
+
+fn hash_recovery_code(code: &str) -> String {
+    hex::encode(Sha256::digest(normalize_code(code).as_bytes()))
+}
+
+fn generate_recovery_codes() -> (Vec<String>, Vec<String>) {
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let mut bytes = [0u8; 5];
+            rand::thread_rng().fill_bytes(&mut bytes);
+            let code = hex::encode(bytes);
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect();
+    let hashes = codes.iter().map(|c| hash_recovery_code(c)).collect();
+    (codes, hashes)
+}
+
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+impl MfaRecord {
+    /// Accepts a current TOTP code (one step of clock skew either way) or an unused
+    /// recovery code, consuming it.
+    fn accept(&mut self, code: &str, now: u64) -> bool {
+        let code = code.trim();
+        if code.len() == DIGITS && code.bytes().all(|b| b.is_ascii_digit()) {
+            let current = now / STEP_SECS;
+            for step in current.saturating_sub(1)..=current + 1 {
+                if step > self.last_step && totp(&self.secret, step) == code {
+                    self.last_step = step;
+                    return true;
+                }
+            }
+            return false;
+        }
+        let hash = hash_recovery_code(code);
+        match self.recovery_hashes.iter().position(|h| *h == hash) {
+            Some(pos) => {
+                self.recovery_hashes.remove(pos);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+pub struct MfaStore {
+    records: sled::Tree,
+}
+
+impl MfaStore {
+    pub fn open(storage: &Storage) -> anyhow::Result<Self> {
+        Ok(Self { records: storage.open_tree("user_mfa")? })
+    }
+
+    fn get(&self, user_id: &str) -> Result<Option<MfaRecord>, MfaError> {
+        match self.records.get(user_id.as_bytes())? {
+            Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn put(&self, user_id: &str, record: &MfaRecord) -> Result<(), MfaError> {
+        self.records.insert(user_id.as_bytes(), serde_json::to_vec(record)?)?;
+        Ok(())
+    }
+
+    /// Runs `f` on the user's record in a transaction, so a code is consumed exactly once
+    /// even with concurrent logins. The record is written back only when `f` succeeds.
+    fn update<T>(&self, user_id: &str, f: impl Fn(&mut MfaRecord) -> Result<T, MfaError>) -> Result<T, MfaError> {
+        let result = self.records.transaction(|tx| {
+            let mut record: MfaRecord = match tx.get(user_id.as_bytes())? {
+                Some(data) => serde_json::from_slice(&data).map_err(|e| ConflictableTransactionError::Abort(e.into()))?,
+                None => return Err(ConflictableTransactionError::Abort(MfaError::NotEnabled)),
+            };
+            let value = f(&mut record).map_err(ConflictableTransactionError::Abort)?;
+            let data = serde_json::to_vec(&record).map_err(|e| ConflictableTransactionError::Abort(e.into()))?;
+            tx.insert(user_id.as_bytes(), data)?;
+            Ok(value)
+        });
+        match result {
+            Ok(value) => Ok(value),
+            Err(TransactionError::Abort(e)) => Err(e),
+            Err(TransactionError::Storage(e)) => Err(e.into()),
+        }
+    }
+
+    pub fn status(&self, user_id: &str) -> Result<MfaStatus, MfaError> {
+        Ok(match self.get(user_id)? {
+            Some(record) if record.enabled => MfaStatus { enabled: true, recovery_codes_remaining: record.recovery_hashes.len() },
+            _ => MfaStatus { enabled: false, recovery_codes_remaining: 0 },
+        })
+    }
+
+    pub fn is_enabled(&self, user_id: &str) -> Result<bool, MfaError> {
+        Ok(self.get(user_id)?.is_some_and(|r| r.enabled))
+    }
+
+    /// Starts (or restarts) enrolment with a fresh secret. Nothing changes for logins
+    /// until [`MfaStore::confirm_enrolment`].
+    pub fn begin_enrolment(&self, user_id: &str, username: &str) -> Result<Enrolment, MfaError> {
+        if self.is_enabled(user_id)? {
+            return Err(MfaError::AlreadyEnabled);
+        }
+        let mut secret = vec![0u8; SECRET_LEN];
+        rand::thread_rng().fill_bytes(&mut secret);
+        let encoded = data_encoding::BASE32_NOPAD.encode(&secret);
+        self.put(user_id, &MfaRecord { secret, enabled: false, recovery_hashes: Vec::new(), last_step: 0 })?;
+        let otpauth_uri = format!(
+            "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={digits}&period={period}",
+            issuer = ISSUER,
+            account = percent_encode(username),
+            secret = encoded,
+            digits = DIGITS,
+            period = STEP_SECS,
+        );
+        Ok(Enrolment { secret: encoded, otpauth_uri })
+    }
+
+    /// Turns two-factor on once `code` matches the pending secret, returning the recovery
+    /// codes. They are shown this once; only their hashes are kept.
+    pub fn confirm_enrolment(&self, user_id: &str, code: &str) -> Result<Vec<String>, MfaError> {
+        let (codes, hashes) = generate_recovery_codes();
+        let now = now_secs();
+        self.update(user_id, |record| {
+            if record.enabled {
+                return Err(MfaError::AlreadyEnabled);
+            }
+            // Recovery codes don't exist yet, so only a TOTP code can pass
+            if !record.accept(code, now) {
+                return Err(MfaError::InvalidCode);
+            }
+            record.enabled = true;
+            record.recovery_hashes = hashes.clone();
+            Ok(())
+        })
+        .map_err(|e| match e {
+            MfaError::NotEnabled => MfaError::NoPendingEnrolment,
+            e => e,
+        })?;
+        Ok(codes)
+    }
+
+    /// Checks a second factor for an enrolled user.
+    pub fn verify(&self, user_id: &str, code: &str) -> Result<(), MfaError> {
+        let now = now_secs();
+        self.update(user_id, |record| {
+            if !record.enabled {
+                return Err(MfaError::NotEnabled);
+            }
+            if record.accept(code, now) { Ok(()) } else { Err(MfaError::InvalidCode) }
+        })
+    }
+
+    /// Replaces the recovery codes after checking a current code.
+    pub fn regenerate_recovery_codes(&self, user_id: &str, code: &str) -> Result<Vec<String>, MfaError> {
+        self.verify(user_id, code)?;
+        let (codes, hashes) = generate_recovery_codes();
+        self.update(user_id, |record| {
+            record.recovery_hashes = hashes.clone();
+            Ok(())
+        })?;
+        Ok(codes)
+    }
+
+    /// Turns two-factor off after checking a current code.
+    pub fn disable(&self, user_id: &str, code: &str) -> Result<(), MfaError> {
+        self.verify(user_id, code)?;
+        self.records.remove(user_id.as_bytes())?;
+        Ok(())
+    }
+
+    /// Admin override for a user who has lost both their authenticator and their recovery
+    /// codes. Returns whether anything was enrolled.
+    pub fn reset(&self, user_id: &str) -> Result<bool, MfaError> {
+        Ok(self.records.remove(user_id.as_bytes())?.is_some())
+    }
+}
@@ -3,12 +3,6 @@ use serde::{Serialize, Deserialize};
 use std::time::{SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct User {
-    pub username: String,
-    pub public_key: [u8; 32],
-}
-
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UserIdentity {
     pub user_id: String,           // Unique UUID for the user
@@ -31,6 +25,8 @@ pub struct UserDevice {
     pub is_current: bool,          // Is this the current device
     pub ip_address: Option<String>, // Last known IP
     pub user_agent: Option<String>, // Browser/client info
+    #[serde(default)]
+    pub trusted_until: Option<u64>, // Skips the second factor until then
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -97,10 +93,15 @@ pub struct MessageAck {
 pub struct UserSession {
     pub session_id: String,
     pub user_id: String,
+    #[serde(default)]
+    pub username: String,
     pub device_id: String,
     pub created_at: u64,
     pub expires_at: u64,
     pub is_active: bool,
+    /// Opened on a device awaiting approval, so limited to getting the device approved
+    #[serde(default)]
+    pub pending_device: bool,
 }
 
 impl UserIdentity {
@@ -143,6 +144,11 @@ impl UserIdentity {
         self.devices.iter().find(|d| d.is_current)
     }
 
+    /// Whether `device_id` is still within the trust period set at a two-factor login.
+    pub fn is_device_trusted(&self, device_id: &str, now: u64) -> bool {
+        self.devices.iter().any(|d| d.device_id == device_id && d.trusted_until.is_some_and(|t| t > now))
+    }
+
     pub fn update_last_seen(&mut self) {
         self.last_seen = SystemTime::now()
             .duration_since(UNIX_EPOCH)
@@ -166,6 +172,7 @@ impl UserDevice {
             is_current: true,
             ip_address: None,
             user_agent: None,
+            trusted_until: None,
         }
     }
 
@@ -0,0 +1,96 @@
+// Access control for files served over the file-exchange protocol.
+//
+// The requesting peer is the one authenticated by the connection's Noise handshake.
+// File requests must also carry a signature by that peer's identity key over the
+// request and the responding node's peer ID, so a captured request can't be replayed
+// to another node. Then:
+//
+// - the global lists gate every request: a blocked or banned peer is refused, and once
+//   the allowlist has entries only peers on it are served;
+// - per file, the peer must be in the file's `allowed_peers`, or be the node that placed
+//   this node's replica or shard of it. Users are not taken into account: a peer
+//   could name any user, and proves nothing by doing so.
+//
+// A refused request is answered with `P2PMessage::RequestDenied` naming the reason.
+
+use super::{reputation, verify_signature, P2PMessage, ALLOWED_PEERS, BLOCKED_PEERS};
+use crate::storage::FileMetadata;
+use libp2p::PeerId;
+
+#[derive(Debug, thiserror::Error)]
+pub enum AccessError {
+    #[error("Request signature is missing or invalid")]
+    BadSignature,
+    #[error("Peer is blocked")]
+    Blocked,
+    #[error("Peer is banned until {0}")]
+    Banned(u64),
+    #[error("Peer is not on this node's allowlist")]
+    NotAllowlisted,
+    #[error("File not found")]
+    NotFound,
+    #[error("Peer may not access this file")]
+    NotPermitted,
+}
+
+impl AccessError {
+    pub fn response(&self) -> Vec<u8> {
+        bincode::serialize(&P2PMessage::RequestDenied { reason: self.to_string() }).unwrap_or_default()
+    }
+}
+
+/// The bytes a file request is signed over: the responder's peer ID followed by the
+/// request with its signature left out.
+pub fn signing_bytes(responder: &PeerId, request: &P2PMessage) -> Vec<u8> {
+    let unsigned = match request.clone() {
+        P2PMessage::FileChunkRequest { file_id, chunk_index, chunk_size, .. } => {
+            P2PMessage::FileChunkRequest { file_id, chunk_index, chunk_size, signature: None }
+        }
+        P2PMessage::FileListRequest { .. } => P2PMessage::FileListRequest { signature: None },
+        P2PMessage::FileManifestRequest { file_id, chunk_size, .. } => P2PMessage::FileManifestRequest { file_id, chunk_size, signature: None },
+        P2PMessage::ShardRequest { file_id, piece, .. } => P2PMessage::ShardRequest { file_id, piece, signature: None },
+        other => other,
+    };
+    let mut bytes = responder.to_bytes();
+    bytes.extend(bincode::serialize(&unsigned).unwrap_or_default());
+    bytes
+}
+
+/// Checks that `peer` signed `request` for this node.
+pub fn verify_request(peer: &PeerId, local: &PeerId, request: &P2PMessage, signature: Option<&[u8]>) -> Result<(), AccessError> {
+    match signature {
+        Some(sig) if verify_signature(&peer.to_string(), &signing_bytes(local, request), sig) => Ok(()),
+        _ => Err(AccessError::BadSignature),
+    }
+}
+
+/// The global allow and deny lists, and automatic bans.
+pub fn check_peer(peer: &PeerId) -> Result<(), AccessError> {
+    let peer = peer.to_string();
+    if BLOCKED_PEERS.lock().unwrap().contains(&peer) {
+        return Err(AccessError::Blocked);
+    }
+    if let Some(until) = reputation::banned_until(&peer) {
+        return Err(AccessError::Banned(until));
+    }
+    let allowed = ALLOWED_PEERS.lock().unwrap();
+    if !allowed.is_empty() && !allowed.contains(&peer) {
+        return Err(AccessError::NotAllowlisted);
+    }
+    Ok(())
+}
+
+/// Whether `peer` may fetch or list `meta`.
+pub fn check_file(peer: &PeerId, meta: &FileMetadata) -> Result<(), AccessError> {
+    let peer = peer.to_string();
+    if meta.allowed_peers.contains(&peer) || super::replication::origin(&meta.file_id.to_string()) == Some(peer) {
+        Ok(())
+    } else {
+        Err(AccessError::NotPermitted)
+    }
+}
+
+/// Whether the swarm should drop its connections to `peer`.
+pub fn is_shut_out(peer: &PeerId) -> bool {
+    matches!(check_peer(peer), Err(AccessError::Blocked | AccessError::Banned(_)))
+}
@@ -0,0 +1,161 @@
+// Connection management: limits, the table of open connections and preferred peers.
+//
+// The swarm accepts at most `max_inbound` connections from other nodes and opens at most
+// `max_outbound` itself, with no more than `max_per_peer` to any one peer; connections
+// past a limit are refused as they are established. A connection none of the protocols
+// on it still needs, e.g. once transfers are done and no gossip is flowing, is closed
+// after `idle_timeout`.
+//
+// Bootstrap nodes and allowlisted peers are preferred: every RECONNECT_INTERVAL the node
+// dials those it isn't connected to. A peer that can't be reached is tried again after
+// a backoff that doubles from RECONNECT_BACKOFF_MIN up to RECONNECT_BACKOFF_MAX, and
+// starts over once it connects. Kademlia bootstraps every BOOTSTRAP_INTERVAL, refreshing
+// the routing table with the peers closest to this node.
+
+use super::{DISCOVERED_PEERS, BOOTSTRAP_NODES};
+use libp2p::connection_limits::{self, ConnectionLimits};
+use libp2p::core::ConnectedPoint;
+use libp2p::swarm::dial_opts::DialOpts;
+use libp2p::swarm::ConnectionId;
+use libp2p::{Multiaddr, PeerId};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub const RECONNECT_INTERVAL: Duration = Duration::from_secs(10);
+pub const BOOTSTRAP_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_secs(5);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(5 * 60);
+
+static CONFIG: Lazy<Mutex<ConnectionConfig>> = Lazy::new(|| Mutex::new(ConnectionConfig::default()));
+
+// Open connections, as the swarm reported them
+static CONNECTIONS: Lazy<Mutex<HashMap<ConnectionId, Connection>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, Clone)]
+pub struct ConnectionConfig {
+    pub max_inbound: u32,
+    pub max_outbound: u32,
+    pub max_per_peer: u32,
+    pub idle_timeout: Duration,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            max_inbound: 64,
+            max_outbound: 64,
+            max_per_peer: 2,
+            idle_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Sets the connection limits and idle timeout for this process.
+pub fn configure(config: ConnectionConfig) {
+    *CONFIG.lock().unwrap() = config;
+}
+
+pub fn config() -> ConnectionConfig {
+    CONFIG.lock().unwrap().clone()
+}
+
+/// The behaviour enforcing the configured limits.
+pub fn limits_behaviour() -> connection_limits::Behaviour {
+    let config = config();
+    connection_limits::Behaviour::new(
+        ConnectionLimits::default()
+            .with_max_established_incoming(Some(config.max_inbound))
+            .with_max_established_outgoing(Some(config.max_outbound))
+            .with_max_established_per_peer(Some(config.max_per_peer)),
+    )
+}
+
+/// An open connection, as listed over gRPC.
+#[derive(Debug, Clone)]
+pub struct Connection {
+    pub peer: PeerId,
+    pub address: Multiaddr,
+    pub outbound: bool,
+    pub established_at: u64,
+}
+
+pub fn on_established(id: ConnectionId, peer: PeerId, endpoint: &ConnectedPoint) {
+    let connection = Connection {
+        peer,
+        address: endpoint.get_remote_address().clone(),
+        outbound: endpoint.is_dialer(),
+        established_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+    };
+    CONNECTIONS.lock().unwrap().insert(id, connection);
+}
+
+pub fn on_closed(id: ConnectionId) {
+    CONNECTIONS.lock().unwrap().remove(&id);
+}
+
+/// Open connections, oldest first.
+pub fn list() -> Vec<Connection> {
+    let mut connections: Vec<Connection> = CONNECTIONS.lock().unwrap().values().cloned().collect();
+    connections.sort_by_key(|c| c.established_at);
+    connections
+}
+
+/// Bootstrap nodes and allowlisted peers, with the addresses known for them.
+pub fn preferred_peers() -> HashMap<PeerId, Vec<Multiaddr>> {
+    let mut preferred: HashMap<PeerId, Vec<Multiaddr>> = HashMap::new();
+    for (peer, addr) in BOOTSTRAP_NODES.lock().unwrap().iter() {
+        preferred.entry(*peer).or_default().push(addr.clone());
+    }
+    let discovered = DISCOVERED_PEERS.lock().unwrap();
+    for id in super::list_allowed_peers() {
+        let Ok(peer) = id.parse::<PeerId>() else {
+            continue;
+        };
+        let addrs = preferred.entry(peer).or_default();
+        if let Some(known) = discovered.get(&id) {
+            addrs.extend(known.addresses.iter().filter_map(|a| a.parse().ok()));
+        }
+    }
+    preferred
+}
+
+pub fn is_preferred(peer: &PeerId) -> bool {
+    BOOTSTRAP_NODES.lock().unwrap().iter().any(|(p, _)| p == peer) || super::list_allowed_peers().contains(&peer.to_string())
+}
+
+/// When each preferred peer that isn't connected may be dialled again.
+#[derive(Default)]
+pub struct Reconnects {
+    backoff: HashMap<PeerId, (u32, Instant)>,
+}
+
+impl Reconnects {
+    /// Dial options for the preferred peers that are due another attempt, scheduling the
+    /// one after it.
+    pub fn due(&mut self, is_connected: impl Fn(&PeerId) -> bool) -> Vec<DialOpts> {
+        let now = Instant::now();
+        let mut dials = Vec::new();
+        for (peer, addrs) in preferred_peers() {
+            if is_connected(&peer) || super::access::is_shut_out(&peer) {
+                continue;
+            }
+            let (attempts, next) = self.backoff.entry(peer).or_insert((0, now));
+            if *next > now {
+                continue;
+            }
+            let delay = RECONNECT_BACKOFF_MIN.saturating_mul(1 << (*attempts).min(16)).min(RECONNECT_BACKOFF_MAX);
+            *attempts += 1;
+            *next = now + delay;
+            let addrs = super::transports::by_preference(addrs);
+            dials.push(DialOpts::peer_id(peer).addresses(addrs).extend_addresses_through_behaviour().build());
+        }
+        dials
+    }
+
+    /// Starts the backoff over for a peer that connected.
+    pub fn on_connected(&mut self, peer: &PeerId) {
+        self.backoff.remove(peer);
+    }
+}
@@ -0,0 +1,314 @@
+// Multi-source ("swarm") downloads.
+//
+// The downloader asks every holder of a file for its manifest: the file's size and the
+// SHA-256 of each chunk. Holders that disagree with the manifest most of them serve are
+// left out. Chunks are then requested from all remaining holders at once, each with its
+// own window of requests in flight: a served chunk widens the window by one, a failure
+// halves it. Every chunk is checked against the manifest before it is written; a
+// failed or corrupt chunk goes back in the queue for another holder, and a holder that
+// serves a corrupt chunk or fails MAX_PEER_FAILURES times in a row is dropped.
+//
+// Chunks are written in place to `<output>.part`. A download that stops part way
+// resumes from it: chunks already on disk that match the manifest are kept, so only
+// missing or damaged ones are fetched again. The part file is renamed to the output
+// once every chunk is in.
+
+use super::P2PNode;
+use futures::stream::{FuturesUnordered, StreamExt};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc;
+
+pub const DEFAULT_CHUNK_SIZE: usize = 1024 * 1024;
+/// Smallest chunk a holder will hash a manifest for
+pub const MIN_CHUNK_SIZE: usize = 16 * 1024;
+/// Largest chunk a manifest from another node may use
+pub const MAX_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+/// Most chunks a manifest from another node may list
+pub const MAX_CHUNKS: usize = 1 << 20;
+const INITIAL_WINDOW: usize = 2;
+const MAX_WINDOW: usize = 16;
+const MAX_PEER_FAILURES: u32 = 3;
+
+/// A file's size and per-chunk hashes, as served by a holder
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub size: u64,
+    pub chunk_size: usize,
+    /// Hex SHA-256 of each chunk, in order
+    pub chunk_hashes: Vec<String>,
+}
+
+impl Manifest {
+    /// Hashes the file at `path` in `chunk_size` pieces.
+    pub fn of_file(path: &Path, chunk_size: usize) -> std::io::Result<Self> {
+        let mut file = std::fs::File::open(path)?;
+        let size = file.metadata()?.len();
+        let mut chunk_hashes = Vec::new();
+        const READ_SIZE: u64 = 64 * 1024;
+        let mut buf = vec![0u8; READ_SIZE as usize];
+        let mut remaining = size;
+        while remaining > 0 {
+            let mut hasher = Sha256::new();
+            let mut left = remaining.min(chunk_size as u64);
+            remaining -= left;
+            while left > 0 {
+                let n = file.read(&mut buf[..left.min(READ_SIZE) as usize])?;
+                if n == 0 {
+                    return Err(std::io::ErrorKind::UnexpectedEof.into());
+                }
+                hasher.update(&buf[..n]);
+                left -= n as u64;
+            }
+            chunk_hashes.push(hex::encode(hasher.finalize()));
+        }
+        Ok(Self { size, chunk_size, chunk_hashes })
+    }
+
+    /// Whether the chunk size is within bounds and the chunk hashes cover exactly `size`
+    /// bytes, as a manifest received from another node must be checked for.
+    pub fn is_well_formed(&self) -> bool {
+        (1..=MAX_CHUNK_SIZE).contains(&self.chunk_size)
+            && self.chunk_hashes.len() <= MAX_CHUNKS
+            && self.size.div_ceil(self.chunk_size as u64) == self.chunk_hashes.len() as u64
+    }
+
+    /// Where chunk `index` starts, if that fits in a file offset.
+    pub fn chunk_offset(&self, index: usize) -> Option<u64> {
+        (index as u64).checked_mul(self.chunk_size as u64)
+    }
+
+    pub fn chunk_len(&self, index: usize) -> usize {
+        let Some(offset) = self.chunk_offset(index) else {
+            return 0;
+        };
+        self.size.saturating_sub(offset).min(self.chunk_size as u64) as usize
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DownloadOptions {
+    /// Holders to use; when empty, every provider the DHT lists
+    pub peers: Vec<String>,
+    pub chunk_size: usize,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        Self { peers: Vec::new(), chunk_size: DEFAULT_CHUNK_SIZE }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DownloadProgress {
+    pub file_id: String,
+    pub total_bytes: u64,
+    pub bytes_done: u64,
+    pub total_chunks: usize,
+    pub chunks_done: usize,
+    /// Chunks found intact in an earlier part file
+    pub chunks_resumed: usize,
+    pub active_peers: usize,
+    /// Chunk requests that failed and were queued again
+    pub retries: usize,
+    pub done: bool,
+}
+
+struct PeerState {
+    window: usize,
+    in_flight: usize,
+    failures: u32,
+}
+
+fn part_path(output: &Path) -> PathBuf {
+    let mut name = output.as_os_str().to_owned();
+    name.push(".part");
+    PathBuf::from(name)
+}
+
+/// Which chunks of an earlier part file already match the manifest.
+fn intact_chunks(file: &mut std::fs::File, manifest: &Manifest) -> std::io::Result<Vec<bool>> {
+    let on_disk = file.metadata()?.len();
+    let mut intact = vec![false; manifest.chunk_hashes.len()];
+    let mut buf = Vec::new();
+    for (index, expected) in manifest.chunk_hashes.iter().enumerate() {
+        let Some(offset) = manifest.chunk_offset(index) else {
+            break;
+        };
+        let len = manifest.chunk_len(index);
+        if offset.saturating_add(len as u64) > on_disk {
+            break;
+        }
+        buf.resize(len, 0);
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut buf)?;
+        intact[index] = hex::encode(Sha256::digest(&buf)) == *expected;
+    }
+    Ok(intact)
+}
+
+impl P2PNode {
+    /// Downloads `file_id` from several holders in parallel into `output`, sending
+    /// progress after every chunk. Returns the final progress.
+    pub async fn swarm_download(
+        &self,
+        file_id: &str,
+        output: &Path,
+        options: DownloadOptions,
+        progress: mpsc::Sender<DownloadProgress>,
+    ) -> anyhow::Result<DownloadProgress> {
+        let chunk_size = options.chunk_size.max(MIN_CHUNK_SIZE);
+        let sources = if options.peers.is_empty() { self.find_providers(file_id).await? } else { options.peers };
+        if sources.is_empty() {
+            return Err(anyhow::anyhow!("No peer is known to hold file {}", file_id));
+        }
+
+        // Settle on the manifest most holders agree on
+        let manifests = futures::future::join_all(
+            sources.iter().map(|peer| self.request_manifest(peer, file_id, chunk_size)),
+        ).await;
+        let mut votes: Vec<(Manifest, Vec<String>)> = Vec::new();
+        for (peer, manifest) in sources.iter().zip(manifests) {
+            match manifest {
+                // Chunks are requested and placed in `chunk_size` pieces, so the manifest must use it
+                Ok(manifest) if manifest.chunk_size != chunk_size || !manifest.is_well_formed() => {
+                    println!("Malformed manifest for {} from {}", file_id, peer);
+                }
+                Ok(manifest) => match votes.iter_mut().find(|(m, _)| *m == manifest) {
+                    Some((_, peers)) => peers.push(peer.clone()),
+                    None => votes.push((manifest, vec![peer.clone()])),
+                },
+                Err(e) => println!("No manifest for {} from {}: {}", file_id, peer, e),
+            }
+        }
+        let (manifest, holders) = votes
+            .into_iter()
+            .max_by_key(|(_, peers)| peers.len())
+            .ok_or_else(|| anyhow::anyhow!("No holder of file {} served a manifest", file_id))?;
+        let total_chunks = manifest.chunk_hashes.len();
+
+        if let Some(dir) = output.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        let part = part_path(output);
+        let mut file = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&part)?;
+        let mut done = intact_chunks(&mut file, &manifest)?;
+        file.set_len(manifest.size)?;
+
+        let mut state = DownloadProgress {
+            file_id: file_id.to_string(),
+            total_bytes: manifest.size,
+            total_chunks,
+            ..Default::default()
+        };
+        for index in (0..total_chunks).filter(|&i| done[i]) {
+            state.chunks_done += 1;
+            state.chunks_resumed += 1;
+            state.bytes_done += manifest.chunk_len(index) as u64;
+        }
+
+        let mut peers: HashMap<String, PeerState> = holders
+            .into_iter()
+            .map(|peer| (peer, PeerState { window: INITIAL_WINDOW, in_flight: 0, failures: 0 }))
+            .collect();
+        let mut pending: VecDeque<usize> = (0..total_chunks).filter(|&i| !done[i]).collect();
+        let mut failed_by: HashMap<usize, HashSet<String>> = HashMap::new();
+        let mut in_flight = FuturesUnordered::new();
+        state.active_peers = peers.len();
+        let _ = progress.send(state.clone()).await;
+
+        while state.chunks_done < total_chunks {
+            // Fill every holder's window with chunks it hasn't already failed
+            loop {
+                let mut assigned = false;
+                for (peer, peer_state) in peers.iter_mut() {
+                    if peer_state.in_flight >= peer_state.window {
+                        continue;
+                    }
+                    let Some(pos) = pending.iter().position(|i| !failed_by.get(i).is_some_and(|f| f.contains(peer))) else {
+                        continue;
+                    };
+                    let index = pending.remove(pos).unwrap_or_default();
+                    peer_state.in_flight += 1;
+                    assigned = true;
+                    let peer = peer.clone();
+                    in_flight.push(async move {
+                        let result = self.request_chunk(&peer, file_id, index, chunk_size).await;
+                        (peer, index, result)
+                    });
+                }
+                if !assigned {
+                    break;
+                }
+            }
+
+            let Some((peer, index, result)) = in_flight.next().await else {
+                return Err(anyhow::anyhow!(
+                    "{} chunks of file {} could not be fetched from any holder",
+                    total_chunks - state.chunks_done,
+                    file_id,
+                ));
+            };
+            let expected = &manifest.chunk_hashes[index];
+            let result = match result {
+                Ok(data) if data.len() != manifest.chunk_len(index) => {
+                    Err(anyhow::anyhow!("Chunk {} from {} has the wrong length", index, peer))
+                }
+                Ok(data) => match self.check_chunk(&peer, &data, expected).await {
+                    Ok(()) => Ok(data),
+                    Err(e) => {
+                        // Corrupt data: stop using this holder altogether
+                        peers.remove(&peer);
+                        Err(e)
+                    }
+                },
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(data) => {
+                    let offset = manifest.chunk_offset(index).ok_or_else(|| anyhow::anyhow!("Chunk {} is out of range", index))?;
+                    file.seek(SeekFrom::Start(offset))?;
+                    file.write_all(&data)?;
+                    done[index] = true;
+                    state.chunks_done += 1;
+                    state.bytes_done += data.len() as u64;
+                    if let Some(peer_state) = peers.get_mut(&peer) {
+                        peer_state.in_flight -= 1;
+                        peer_state.failures = 0;
+                        peer_state.window = (peer_state.window + 1).min(MAX_WINDOW);
+                    }
+                }
+                Err(e) => {
+                    println!("Chunk {} of {} failed on {}: {}", index, file_id, peer, e);
+                    state.retries += 1;
+                    failed_by.entry(index).or_default().insert(peer.clone());
+                    pending.push_front(index);
+                    let give_up = match peers.get_mut(&peer) {
+                        Some(peer_state) => {
+                            peer_state.in_flight -= 1;
+                            peer_state.failures += 1;
+                            peer_state.window = (peer_state.window / 2).max(1);
+                            peer_state.failures >= MAX_PEER_FAILURES
+                        }
+                        None => false,
+                    };
+                    if give_up {
+                        peers.remove(&peer);
+                    }
+                }
+            }
+            state.active_peers = peers.len();
+            let _ = progress.send(state.clone()).await;
+        }
+
+        file.sync_all()?;
+        drop(file);
+        std::fs::rename(&part, output)?;
+        state.done = true;
+        let _ = progress.send(state.clone()).await;
+        Ok(state)
+    }
+}
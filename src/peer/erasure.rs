@@ -0,0 +1,544 @@
+// Reed–Solomon erasure coding of files across nodes.
+//
+// A file's storage policy is either replication (see `replication`) or erasure coding
+// with `data_shards` + `parity_shards`. An erasure-coded file's encrypted contents are
+// cut into stripes of `data_shards` equal pieces, the last stripe padded with zeros, and
+// each stripe gets `parity_shards` parity pieces. Shard `i` is piece `i` of every stripe,
+// one after another, so any `data_shards` of the shards rebuild the file, a stripe at a
+// time. Each shard goes to a different peer, nearest the file's DHT key first, and is
+// pushed and checked like a replica.
+//
+// The layout (shard counts, file size, piece length and the hash of every shard's
+// manifest) travels with each shard, so any node allowed to read the file can find the
+// holders through the DHT (`/dafs/shards/<file_id>`), settle on the layout most of them
+// serve, and rebuild the file, checking each piece against its shard's manifest. The
+// origin tracks where the shards are in the `shard_sets` tree and, on the replication
+// manager's schedule, regenerates lost shards from its local copy, or first rebuilds
+// that copy from the shards still out there. Per-file policies live in
+// `storage_policies`; files without one are replicated.
+
+use super::download::Manifest;
+use super::replication::Replica;
+use super::{providers, P2PNode, P2P_STORAGE};
+use crate::storage::FileMetadata;
+use libp2p::PeerId;
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Largest piece a shard contributes to one stripe
+const MAX_PIECE_LEN: usize = 1024 * 1024;
+/// GF(2^8) allows at most this many shards in all
+const MAX_SHARDS: usize = 256;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StoragePolicy {
+    /// Whole copies on `factor` other nodes
+    #[default]
+    Replicated,
+    /// Any `data_shards` of `data_shards + parity_shards` shards rebuild the file
+    ErasureCoded { data_shards: usize, parity_shards: usize },
+}
+
+impl fmt::Display for StoragePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoragePolicy::Replicated => write!(f, "replicated"),
+            StoragePolicy::ErasureCoded { data_shards, parity_shards } => write!(f, "erasure-coded {}+{}", data_shards, parity_shards),
+        }
+    }
+}
+
+/// How a file was cut into shards; the same for every shard of it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShardLayout {
+    pub data_shards: usize,
+    pub parity_shards: usize,
+    /// Size of the encoded file
+    pub size: u64,
+    /// Bytes each shard holds per stripe
+    pub piece_len: usize,
+    /// Hex SHA-256 of each shard's manifest, by shard index
+    pub shard_roots: Vec<String>,
+}
+
+impl ShardLayout {
+    pub fn stripes(&self) -> usize {
+        self.size.div_ceil((self.piece_len * self.data_shards) as u64) as usize
+    }
+}
+
+/// Which shard a node holds, and the layout it belongs to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShardInfo {
+    pub index: usize,
+    pub layout: ShardLayout,
+}
+
+/// Where a file's shards are, as tracked by its origin.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShardSet {
+    pub file_id: String,
+    pub layout: ShardLayout,
+    /// Holder of each shard, by index; None while the shard needs placing
+    pub holders: Vec<Option<Replica>>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ErasureError {
+    #[error("Erasure coding needs at least one data and one parity shard, and at most {} shards in all", MAX_SHARDS)]
+    BadShardCounts,
+    #[error("Only {found} of the {needed} shards needed to rebuild the file could be fetched")]
+    NotEnoughShards { found: usize, needed: usize },
+    #[error("Reed-Solomon error: {0}")]
+    Coding(#[from] reed_solomon_erasure::Error),
+    #[error("{0}")]
+    Storage(#[from] anyhow::Error),
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+fn content_path(file_id: &str) -> String {
+    format!("files/{}.bin", file_id)
+}
+
+/// Where this node keeps the shard of `file_id` it holds for another node.
+pub fn shard_path(file_id: &str) -> PathBuf {
+    PathBuf::from(format!("shards/{}.bin", file_id))
+}
+
+/// Where the origin stages shard `index` while placing it.
+fn staged_path(file_id: &str, index: usize) -> PathBuf {
+    PathBuf::from(format!("shards/{}.{}.bin", file_id, index))
+}
+
+fn tree(name: &str) -> anyhow::Result<sled::Tree> {
+    P2P_STORAGE.open_tree(name)
+}
+
+fn manifest_root(manifest: &Manifest) -> String {
+    hex::encode(Sha256::digest(bincode::serialize(manifest).unwrap_or_default()))
+}
+
+pub fn policy(file_id: &str) -> StoragePolicy {
+    tree("storage_policies")
+        .ok()
+        .and_then(|t| t.get(file_id).ok().flatten())
+        .and_then(|bytes| bincode::deserialize(&bytes).ok())
+        .unwrap_or_default()
+}
+
+/// Sets how `file_id` is kept on other nodes. The replication manager moves the file
+/// over on its next pass.
+pub fn set_policy(file_id: &str, policy: StoragePolicy) -> Result<(), ErasureError> {
+    if let StoragePolicy::ErasureCoded { data_shards, parity_shards } = policy
+        && (data_shards == 0 || parity_shards == 0 || data_shards + parity_shards > MAX_SHARDS)
+    {
+        return Err(ErasureError::BadShardCounts);
+    }
+    let policies = tree("storage_policies")?;
+    match policy {
+        StoragePolicy::Replicated => policies.remove(file_id).map_err(anyhow::Error::from)?,
+        _ => policies.insert(file_id, bincode::serialize(&policy).map_err(anyhow::Error::from)?).map_err(anyhow::Error::from)?,
+    };
+    Ok(())
+}
+
+/// Whether `file_id` is, or was placed as, erasure coded as far as this node knows: its
+/// policy says so, or this node placed or holds one of its shards.
+pub fn is_sharded(file_id: &str) -> bool {
+    policy(file_id) != StoragePolicy::Replicated
+        || shard_set(file_id).is_ok_and(|s| s.is_some())
+        || shard_path(file_id).exists()
+}
+
+pub fn shard_set(file_id: &str) -> anyhow::Result<Option<ShardSet>> {
+    match tree("shard_sets")?.get(file_id)? {
+        Some(bytes) => Ok(Some(bincode::deserialize(&bytes)?)),
+        None => Ok(None),
+    }
+}
+
+fn save_shard_set(set: &ShardSet) -> anyhow::Result<()> {
+    tree("shard_sets")?.insert(set.file_id.as_bytes(), bincode::serialize(set)?)?;
+    Ok(())
+}
+
+/// Fills `buf` from `file`, zero-padding whatever is left once the file ends.
+fn read_padded(file: &mut std::fs::File, buf: &mut [u8]) -> std::io::Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let n = file.read(&mut buf[filled..])?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    buf[filled..].fill(0);
+    Ok(())
+}
+
+/// Cuts the file at `source` into shards, staged under `shards/`. Returns the layout
+/// and each shard's manifest.
+fn encode(file_id: &str, source: &Path, data_shards: usize, parity_shards: usize) -> Result<(ShardLayout, Vec<Manifest>), ErasureError> {
+    let codec = ReedSolomon::new(data_shards, parity_shards)?;
+    let mut file = std::fs::File::open(source).map_err(anyhow::Error::from)?;
+    let size = file.metadata().map_err(anyhow::Error::from)?.len();
+    let piece_len = (size.div_ceil(data_shards as u64) as usize).clamp(1, MAX_PIECE_LEN);
+    let total = data_shards + parity_shards;
+    let mut layout = ShardLayout { data_shards, parity_shards, size, piece_len, shard_roots: Vec::new() };
+
+    std::fs::create_dir_all("shards").map_err(anyhow::Error::from)?;
+    let mut writers = (0..total)
+        .map(|i| std::fs::File::create(staged_path(file_id, i)).map(BufWriter::new))
+        .collect::<std::io::Result<Vec<_>>>()
+        .map_err(anyhow::Error::from)?;
+    let mut stripe = vec![vec![0u8; piece_len]; total];
+    for _ in 0..layout.stripes() {
+        for piece in &mut stripe[..data_shards] {
+            read_padded(&mut file, piece).map_err(anyhow::Error::from)?;
+        }
+        codec.encode(&mut stripe)?;
+        for (writer, piece) in writers.iter_mut().zip(&stripe) {
+            writer.write_all(piece).map_err(anyhow::Error::from)?;
+        }
+    }
+    for mut writer in writers {
+        writer.flush().map_err(anyhow::Error::from)?;
+    }
+
+    let manifests = (0..total)
+        .map(|i| Manifest::of_file(&staged_path(file_id, i), piece_len))
+        .collect::<std::io::Result<Vec<_>>>()
+        .map_err(anyhow::Error::from)?;
+    layout.shard_roots = manifests.iter().map(manifest_root).collect();
+    Ok((layout, manifests))
+}
+
+/// Rebuilds a stripe's data pieces from any `data_shards` of its pieces and writes them
+/// to `out`, stopping at the end of the file. Returns how many bytes are still to come.
+fn write_stripe<W: Write>(codec: &ReedSolomon, layout: &ShardLayout, stripe: &mut [Option<Vec<u8>>], mut remaining: u64, out: &mut W) -> Result<u64, ErasureError> {
+    codec.reconstruct_data(stripe)?;
+    for piece in stripe[..layout.data_shards].iter().flatten() {
+        let len = remaining.min(piece.len() as u64) as usize;
+        out.write_all(&piece[..len]).map_err(anyhow::Error::from)?;
+        remaining -= len as u64;
+    }
+    Ok(remaining)
+}
+
+fn remove_staged(file_id: &str, total: usize) {
+    for i in 0..total {
+        let _ = std::fs::remove_file(staged_path(file_id, i));
+    }
+}
+
+/// Serves one stripe's piece of the shard this node holds for `file_id`.
+pub(super) fn handle_request(file_id: &str, piece: usize) -> Vec<u8> {
+    let not_found = || super::access::AccessError::NotFound.response();
+    let Some(shard) = super::replication::hosted_shard(file_id) else {
+        return not_found();
+    };
+    let path = shard_path(file_id);
+    let piece_len = shard.layout.piece_len;
+    let manifest = if piece == 0 {
+        match Manifest::of_file(&path, piece_len) {
+            Ok(manifest) => Some(manifest),
+            Err(_) => return not_found(),
+        }
+    } else {
+        None
+    };
+    let mut data = vec![0u8; piece_len];
+    let read = std::fs::File::open(&path).and_then(|mut file| {
+        file.seek(SeekFrom::Start((piece * piece_len) as u64))?;
+        file.read_exact(&mut data)
+    });
+    if read.is_err() {
+        return not_found();
+    }
+    let resp = super::P2PMessage::ShardResponse { file_id: file_id.to_string(), shard, manifest, data };
+    bincode::serialize(&resp).unwrap_or_default()
+}
+
+/// A shard holder that served a manifest matching the agreed layout.
+struct Source {
+    peer: String,
+    manifest: Manifest,
+}
+
+impl P2PNode {
+    /// Peers that announce a shard of `file_id`, plus any this node placed itself.
+    async fn shard_holders(&self, file_id: &str) -> Vec<String> {
+        let mut holders: Vec<String> = self.providers_of(providers::shards_key(file_id)).await.unwrap_or_default();
+        if let Ok(Some(set)) = shard_set(file_id) {
+            for holder in set.holders.into_iter().flatten() {
+                if !holders.contains(&holder.peer_id) {
+                    holders.push(holder.peer_id);
+                }
+            }
+        }
+        holders
+    }
+
+    /// Rebuilds `file_id`'s encrypted contents in `files/` from any `data_shards` of its
+    /// shards.
+    pub async fn restore_file(&self, file_id: &str) -> Result<(), ErasureError> {
+        let holders = self.shard_holders(file_id).await;
+        let first = futures::future::join_all(holders.iter().map(|peer| self.request_shard(peer, file_id, 0))).await;
+
+        // Settle on the layout most holders agree on
+        let mut votes: Vec<(ShardLayout, usize)> = Vec::new();
+        for (shard, _, _) in first.iter().flatten() {
+            match votes.iter_mut().find(|(layout, _)| *layout == shard.layout) {
+                Some((_, count)) => *count += 1,
+                None => votes.push((shard.layout.clone(), 1)),
+            }
+        }
+        let Some((layout, _)) = votes.into_iter().max_by_key(|(_, count)| *count) else {
+            return Err(ErasureError::NotEnoughShards { found: 0, needed: 1 });
+        };
+        let needed = layout.data_shards;
+        let total = layout.data_shards + layout.parity_shards;
+        let stripes = layout.stripes();
+
+        // One verified source per shard index, with its piece of the first stripe
+        let mut sources: BTreeMap<usize, Source> = BTreeMap::new();
+        let mut stripe: Vec<Option<Vec<u8>>> = vec![None; total];
+        for (peer, response) in holders.into_iter().zip(first) {
+            let Ok((shard, Some(manifest), data)) = response else { continue };
+            let valid = shard.layout == layout
+                && shard.index < total
+                && !sources.contains_key(&shard.index)
+                && manifest.chunk_hashes.len() == stripes
+                && manifest_root(&manifest) == layout.shard_roots[shard.index];
+            if !valid {
+                continue;
+            }
+            if let Some(expected) = manifest.chunk_hashes.first() {
+                if data.len() != layout.piece_len || self.check_chunk(&peer, &data, expected).await.is_err() {
+                    continue;
+                }
+                if stripe.iter().flatten().count() < needed {
+                    stripe[shard.index] = Some(data);
+                }
+            }
+            sources.insert(shard.index, Source { peer, manifest });
+        }
+        if sources.len() < needed {
+            return Err(ErasureError::NotEnoughShards { found: sources.len(), needed });
+        }
+
+        let codec = ReedSolomon::new(layout.data_shards, layout.parity_shards)?;
+        let output = content_path(file_id);
+        let part = format!("{}.restore", output);
+        std::fs::create_dir_all("files").map_err(anyhow::Error::from)?;
+        let mut file = BufWriter::new(std::fs::File::create(&part).map_err(anyhow::Error::from)?);
+        let mut remaining = layout.size;
+        for index in 0..stripes {
+            if index > 0 {
+                stripe = vec![None; total];
+            }
+            self.fill_stripe(file_id, index, &mut stripe, &sources, &layout).await?;
+            remaining = write_stripe(&codec, &layout, &mut stripe, remaining, &mut file)?;
+        }
+        file.flush().map_err(anyhow::Error::from)?;
+        drop(file);
+        std::fs::rename(&part, &output).map_err(anyhow::Error::from)?;
+        println!("Rebuilt {} from {} shards", file_id, needed);
+        Ok(())
+    }
+
+    /// Fetches pieces of stripe `index` until `data_shards` of them are in, data shards
+    /// first, moving on to other shards when a holder fails or serves a bad piece.
+    async fn fill_stripe(
+        &self,
+        file_id: &str,
+        index: usize,
+        stripe: &mut [Option<Vec<u8>>],
+        sources: &BTreeMap<usize, Source>,
+        layout: &ShardLayout,
+    ) -> Result<(), ErasureError> {
+        let needed = layout.data_shards;
+        let untried: Vec<(&usize, &Source)> = sources.iter().filter(|(shard, _)| stripe[**shard].is_none()).collect();
+        let mut untried = untried.into_iter();
+        loop {
+            let have = stripe.iter().flatten().count();
+            if have >= needed {
+                return Ok(());
+            }
+            let batch: Vec<(&usize, &Source)> = untried.by_ref().take(needed - have).collect();
+            if batch.is_empty() {
+                return Err(ErasureError::NotEnoughShards { found: have, needed });
+            }
+            let pieces = futures::future::join_all(batch.iter().map(|(_, source)| self.request_shard(&source.peer, file_id, index))).await;
+            for ((shard, source), piece) in batch.into_iter().zip(pieces) {
+                let Ok((_, _, data)) = piece else { continue };
+                let expected = &source.manifest.chunk_hashes[index];
+                if data.len() == layout.piece_len && self.check_chunk(&source.peer, &data, expected).await.is_ok() {
+                    stripe[*shard] = Some(data);
+                }
+            }
+        }
+    }
+
+    /// Checks an erasure-coded file's shards and places any that are missing.
+    pub async fn maintain_shards(&self, meta: &FileMetadata) -> anyhow::Result<()> {
+        let file_id = meta.file_id.to_string();
+        let StoragePolicy::ErasureCoded { data_shards, parity_shards } = policy(&file_id) else {
+            return Ok(());
+        };
+        let mut set = shard_set(&file_id)?;
+        if set.as_ref().is_some_and(|s| (s.layout.data_shards, s.layout.parity_shards) != (data_shards, parity_shards)) {
+            // The policy changed: start over with new shards
+            self.drop_shards(&file_id).await?;
+            set = None;
+        }
+        if let Some(set) = &mut set {
+            let checks = futures::future::join_all(set.holders.iter().map(|holder| async {
+                match holder {
+                    Some(replica) => self.check_replica(&file_id, replica.clone()).await,
+                    None => None,
+                }
+            })).await;
+            set.holders = checks;
+            if set.holders.iter().all(Option::is_some) {
+                save_shard_set(set)?;
+                return self.drop_old_replicas(&file_id).await;
+            }
+        }
+
+        // Regenerating shards needs the whole file; rebuild it from the others if it's gone
+        let content = content_path(&file_id);
+        if !Path::new(&content).exists() {
+            if set.is_none() {
+                return Ok(());
+            }
+            self.restore_file(&file_id).await?;
+        }
+        if std::fs::metadata(&content)?.len() == 0 {
+            return Ok(());
+        }
+        let total = data_shards + parity_shards;
+        let (layout, manifests) = encode(&file_id, Path::new(&content), data_shards, parity_shards)?;
+        let mut set = match set {
+            Some(set) if set.layout == layout => set,
+            Some(_) => {
+                // The contents no longer match the placed shards
+                self.drop_shards(&file_id).await?;
+                ShardSet { file_id: file_id.clone(), layout, holders: vec![None; total] }
+            }
+            None => ShardSet { file_id: file_id.clone(), layout, holders: vec![None; total] },
+        };
+
+        let used: HashSet<String> = set.holders.iter().flatten().map(|r| r.peer_id.clone()).collect();
+        let mut candidates = self.closest_peers(&file_id).await?.into_iter()
+            .filter(|p| !used.contains(&p.to_string()) && super::access::check_peer(p).is_ok());
+        let now = now_secs();
+        for (index, holder) in set.holders.iter_mut().enumerate() {
+            if holder.is_some() {
+                continue;
+            }
+            let shard = ShardInfo { index, layout: set.layout.clone() };
+            // Each shard on a different peer; a peer that refuses isn't asked again
+            for candidate in candidates.by_ref() {
+                let candidate_id = candidate.to_string();
+                match self.push_replica(candidate, meta, &manifests[index], &staged_path(&file_id, index), Some(shard.clone())).await {
+                    Ok(()) => {
+                        println!("Placed shard {} of {} on {}", index, file_id, candidate_id);
+                        *holder = Some(Replica { peer_id: candidate_id, stored_at: now, last_seen: now, reachable: true });
+                        break;
+                    }
+                    Err(e) => println!("Could not place shard {} of {} on {}: {}", index, file_id, candidate_id, e),
+                }
+            }
+        }
+        remove_staged(&file_id, total);
+        let placed = set.holders.iter().flatten().count();
+        save_shard_set(&set)?;
+        if placed < total {
+            println!("Only {} of {} shards of {} are placed; not enough peers", placed, total, file_id);
+            return Ok(());
+        }
+        self.drop_old_replicas(&file_id).await
+    }
+
+    /// Drops copies from an earlier replication policy, once every shard is placed.
+    async fn drop_old_replicas(&self, file_id: &str) -> anyhow::Result<()> {
+        if !super::replication::replica_set(file_id)?.replicas.is_empty() {
+            self.drop_replicas(file_id).await?;
+        }
+        Ok(())
+    }
+
+    /// Tells the holders of a file's shards to delete them and forgets them.
+    pub async fn drop_shards(&self, file_id: &str) -> anyhow::Result<()> {
+        let Some(set) = shard_set(file_id)? else {
+            return Ok(());
+        };
+        for holder in set.holders.iter().flatten() {
+            if let Ok(peer) = PeerId::from_str(&holder.peer_id) {
+                let _ = self.file_request(peer, super::P2PMessage::ReplicaDrop { file_id: file_id.to_string() }).await;
+            }
+        }
+        tree("shard_sets")?.remove(file_id)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every way of picking `lost` of `0..total`.
+    fn combinations(total: usize, lost: usize) -> Vec<Vec<usize>> {
+        if lost == 0 {
+            return vec![Vec::new()];
+        }
+        (lost - 1..total)
+            .flat_map(|last| combinations(last, lost - 1).into_iter().map(move |mut c| {
+                c.push(last);
+                c
+            }))
+            .collect()
+    }
+
+    #[test]
+    fn rebuilds_after_losing_any_parity_count_of_shards() {
+        let (data_shards, parity_shards) = (4, 2);
+        let total = data_shards + parity_shards;
+        let file_id = format!("erasure-test-{}", uuid::Uuid::new_v4());
+        let source = std::env::temp_dir().join(format!("{}.src", file_id));
+        // Not a multiple of the stripe, so the last stripe is padded
+        let original: Vec<u8> = (0..10_007u32).map(|i| (i * 31 % 251) as u8).collect();
+        std::fs::write(&source, &original).unwrap();
+
+        let (layout, _) = encode(&file_id, &source, data_shards, parity_shards).unwrap();
+        let shards: Vec<Vec<u8>> = (0..total).map(|i| std::fs::read(staged_path(&file_id, i)).unwrap()).collect();
+        remove_staged(&file_id, total);
+        let _ = std::fs::remove_dir("shards");
+        let _ = std::fs::remove_file(&source);
+
+        let codec = ReedSolomon::new(data_shards, parity_shards).unwrap();
+        for lost in combinations(total, parity_shards) {
+            let mut rebuilt = Vec::new();
+            let mut remaining = layout.size;
+            for index in 0..layout.stripes() {
+                let mut stripe: Vec<Option<Vec<u8>>> = (0..total)
+                    .map(|i| (!lost.contains(&i)).then(|| shards[i][index * layout.piece_len..(index + 1) * layout.piece_len].to_vec()))
+                    .collect();
+                remaining = write_stripe(&codec, &layout, &mut stripe, remaining, &mut rebuilt).unwrap();
+            }
+            assert_eq!(remaining, 0);
+            assert!(rebuilt == original, "rebuild failed without shards {:?}", lost);
+        }
+    }
+}
@@ -2,10 +2,6 @@ use libp2p::{
     identity,
     swarm::{Swarm, SwarmEvent},
     PeerId,
-    noise,
-    core::upgrade,
-    yamux,
-    Transport,
     Multiaddr,
 };
 use libp2p::swarm::NetworkBehaviour;
@@ -15,13 +11,16 @@ use anyhow::Result;
 use futures::StreamExt;
 use std::fs;
 use tokio::sync::{mpsc, oneshot};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use serde::{Serialize, Deserialize};
 use crate::storage::Storage;
 use std::sync::Arc;
 use once_cell::sync::Lazy;
 use libp2p::kad::{self as kad, store::MemoryStore};
 use libp2p::relay::Behaviour as RelayBehaviour;
+use libp2p::{autonat, dcutr, identify, ping, relay};
+use libp2p::mdns;
+use libp2p::gossipsub;
 use std::str::FromStr;
 use serde_json;
 use crate::ai::NCFModel as CFModel;
@@ -33,6 +32,19 @@ use chrono::Utc;
 use libp2p::request_response::Codec;
 use futures::io::{AsyncRead, AsyncWrite};
 
+mod access;
+pub mod connections;
+pub mod download;
+pub mod erasure;
+pub mod nat;
+mod providers;
+mod pubsub;
+pub mod replication;
+pub mod reputation;
+pub mod transports;
+
+use reputation::Offence;
+
 // Global storage for discovered peers
 static DISCOVERED_PEERS: Lazy<Mutex<HashMap<String, DiscoveredPeer>>> = Lazy::new(|| {
     Mutex::new(HashMap::new())
@@ -44,6 +56,13 @@ static BOOTSTRAP_NODES: Lazy<Mutex<Vec<(PeerId, Multiaddr)>>> = Lazy::new(|| Mut
 // Global allowed peers storage
 static ALLOWED_PEERS: Lazy<Mutex<Vec<String>>> = Lazy::new(|| Mutex::new(Vec::new()));
 
+// Global blocked peers storage; a blocked peer is refused even if allowlisted
+static BLOCKED_PEERS: Lazy<Mutex<Vec<String>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+// Connections made and lost, newest last, persisted to `peer_history.json`
+static CONNECTION_HISTORY: Lazy<Mutex<Vec<(String, PeerConnectionInfo)>>> = Lazy::new(|| Mutex::new(Vec::new()));
+const MAX_CONNECTION_HISTORY: usize = 500;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum P2PMessage {
     FileKeyExchange { file_id: String, encrypted_key: Vec<u8>, from: String, to: String },
@@ -67,6 +86,34 @@ pub enum P2PMessage {
     PeerDiscovery { peer_id: String, addresses: Vec<String>, user_info: Option<crate::models::UserIdentity> },
     PeerPing { timestamp: u64, peer_id: String },
     PeerPong { timestamp: u64, peer_id: String },
+
+    // Share revocation, signed by the sending node's identity key
+    ShareRevocation { revocation: crate::storage::ShareRevocation, signature: Vec<u8> },
+
+    // Answer to a file request this node refused
+    RequestDenied { reason: String },
+
+    // Size and per-chunk hashes of a file, for multi-source downloads
+    FileManifestRequest { file_id: String, chunk_size: usize, signature: Option<Vec<u8>> },
+    FileManifestResponse { file_id: String, manifest: download::Manifest },
+
+    // Replication: the origin offers a copy, or one erasure-coded shard, pushes its
+    // chunks, and later checks on it
+    ReplicaOffer { meta: crate::storage::FileMetadata, manifest: download::Manifest, shard: Option<erasure::ShardInfo> },
+    ReplicaAccepted { file_id: String, complete: bool },
+    ReplicaChunk { file_id: String, chunk_index: usize, data: Vec<u8> },
+    ReplicaStored { file_id: String, chunk_index: usize, complete: bool },
+    ReplicaCheck { file_id: String },
+    ReplicaStatus { file_id: String, held: bool },
+    ReplicaDrop { file_id: String },
+
+    // One stripe's piece of the erasure-coded shard a node holds; piece 0 also carries
+    // the shard's manifest
+    ShardRequest { file_id: String, piece: usize, signature: Option<Vec<u8>> },
+    ShardResponse { file_id: String, shard: erasure::ShardInfo, manifest: Option<download::Manifest>, data: Vec<u8> },
+
+    // Published on the announcement topic when a node starts holding a file
+    FileAnnouncement { file_id: String, peer_id: String, announced_at: u64 },
 }
 
 #[derive(Debug, Clone)]
@@ -228,45 +275,22 @@ impl Codec for PeerDiscoveryCodec {
     }
 }
 
+// Each field's events arrive as the `MyBehaviourEvent` variant named after it
 #[derive(NetworkBehaviour)]
-#[behaviour(out_event = "MyBehaviourEvent")]
 pub struct MyBehaviour {
     pub file_exchange: RequestResponseBehaviour<FileExchangeCodec>,
     pub messaging: RequestResponseBehaviour<MessagingCodec>,
     pub peer_discovery: RequestResponseBehaviour<PeerDiscoveryCodec>,
     pub kademlia: kad::Behaviour<MemoryStore>,
     pub relay: RelayBehaviour,
-}
-
-#[derive(Debug)]
-pub enum MyBehaviourEvent {
-    FileExchange(RequestResponseEvent<Vec<u8>, Vec<u8>>),
-    Messaging(RequestResponseEvent<Vec<u8>, Vec<u8>>),
-    PeerDiscovery(RequestResponseEvent<Vec<u8>, Vec<u8>>),
-    Kademlia(kad::Event),
-    Relay(()),
-}
-
-impl From<RequestResponseEvent<Vec<u8>, Vec<u8>>> for MyBehaviourEvent {
-    fn from(event: RequestResponseEvent<Vec<u8>, Vec<u8>>) -> Self {
-        MyBehaviourEvent::FileExchange(event)
-    }
-}
-// Repeat for Messaging and PeerDiscovery if needed
-impl From<kad::Event> for MyBehaviourEvent {
-    fn from(event: kad::Event) -> Self {
-        MyBehaviourEvent::Kademlia(event)
-    }
-}
-impl From<()> for MyBehaviourEvent {
-    fn from(_: ()) -> Self {
-        MyBehaviourEvent::Relay(())
-    }
-}
-impl From<libp2p::relay::Event> for MyBehaviourEvent {
-    fn from(_: libp2p::relay::Event) -> Self {
-        MyBehaviourEvent::Relay(())
-    }
+    pub relay_client: relay::client::Behaviour,
+    pub dcutr: dcutr::Behaviour,
+    pub autonat: autonat::Behaviour,
+    pub identify: identify::Behaviour,
+    pub ping: ping::Behaviour,
+    pub mdns: mdns::tokio::Behaviour,
+    pub gossipsub: gossipsub::Behaviour,
+    pub limits: libp2p::connection_limits::Behaviour,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -276,19 +300,28 @@ pub struct DiscoveredPeer {
     pub last_seen: u64,
     pub user_info: Option<crate::models::UserIdentity>,
     pub is_online: bool,
+    /// Latest round trip measured by ping
     pub latency_ms: Option<u64>,
+    /// Software and protocols the peer reported through identify
+    #[serde(default)]
+    pub agent_version: Option<String>,
+    #[serde(default)]
+    pub protocols: Vec<String>,
 }
 
 pub enum P2PCommand {
-    ListFiles { peer: PeerId, respond_to: oneshot::Sender<Vec<String>> },
-    GetFile { peer: PeerId, file_id: String, respond_to: oneshot::Sender<Vec<u8>> },
+    ListFiles { peer: PeerId, respond_to: oneshot::Sender<Vec<u8>> },
     SendMessage { peer: PeerId, message: P2PMessage },
     RequestChunk { peer: PeerId, file_id: String, chunk_index: usize, chunk_size: usize, respond_to: oneshot::Sender<Vec<u8>> },
+    RequestManifest { peer: PeerId, file_id: String, chunk_size: usize, respond_to: oneshot::Sender<Vec<u8>> },
+    RequestShard { peer: PeerId, file_id: String, piece: usize, respond_to: oneshot::Sender<Vec<u8>> },
+    FileRequest { peer: PeerId, message: P2PMessage, respond_to: oneshot::Sender<Vec<u8>> },
     
     // Messaging commands
     SendEncryptedMessage { peer: PeerId, message: crate::models::EncryptedMessage, respond_to: oneshot::Sender<bool> },
     CreateChatRoom { room: crate::models::ChatRoom, respond_to: oneshot::Sender<bool> },
     JoinChatRoom { room_id: String, username: String, respond_to: oneshot::Sender<bool> },
+    LeaveChatRoom { room_id: String, username: String, respond_to: oneshot::Sender<bool> },
     SendChatMessage { room_id: String, message: crate::models::EncryptedMessage, respond_to: oneshot::Sender<bool> },
     UpdateUserStatus { status: crate::models::UserStatus },
     
@@ -298,33 +331,45 @@ pub enum P2PCommand {
     PingPeer { peer_id: String, respond_to: oneshot::Sender<Option<u64>> },
     GetKnownPeers { respond_to: oneshot::Sender<Vec<DiscoveredPeer>> },
     RemovePeer { peer_id: String, respond_to: oneshot::Sender<bool> },
+    ScanLocalNetwork { respond_to: oneshot::Sender<Vec<DiscoveredPeer>> },
+
+    // File location through the DHT
+    ProvideFile { file_id: String },
+    StopProviding { file_id: String },
+    FindProviders { key: kad::RecordKey, respond_to: oneshot::Sender<Vec<PeerId>> },
+    ClosestPeers { file_id: String, respond_to: oneshot::Sender<Vec<PeerId>> },
+
+    // Reputation and blocking
+    Report { peer: PeerId, offence: Offence },
+    Disconnect { peer: PeerId },
 }
 
 pub struct P2PNode {
     cmd_tx: mpsc::Sender<P2PCommand>,
+    keypair: identity::Keypair,
 }
 
 impl P2PNode {
     pub fn new() -> Self {
         let (cmd_tx, mut cmd_rx) = mpsc::channel(32);
+        let id_keys = identity::Keypair::generate_ed25519();
+        let node_keys = id_keys.clone();
         
-        // Load discovered peers on startup
+        // Load discovered peers and the access lists on startup
         let _ = load_discovered_peers();
+        let _ = load_allowed_peers();
+        let _ = load_blocked_peers();
+        let _ = load_connection_history();
         
         // Spawn background task for event loop
         tokio::spawn(async move {
-            let id_keys = identity::Keypair::generate_ed25519();
             let peer_id = PeerId::from(id_keys.public());
             println!("Local peer id: {:?}", peer_id);
 
-            // Build transport manually
-            let transport = libp2p::tcp::tokio::Transport::new(
-                libp2p::tcp::Config::default().nodelay(true),
-            )
-            .upgrade(upgrade::Version::V1)
-            .authenticate(noise::Config::new(&id_keys).unwrap())
-            .multiplex(yamux::Config::default())
-            .boxed();
+            // Every configured transport, plus circuits through relays for when this node
+            // is behind NAT
+            let (relay_transport, relay_client) = relay::client::new(peer_id);
+            let transport = transports::build(&id_keys, relay_transport);
 
             let file_proto = FileExchangeProtocol();
             let file_codec = FileExchangeCodec;
@@ -348,63 +393,138 @@ impl P2PNode {
             let peer_discovery = RequestResponseBehaviour::new(discovery_protocols, discovery_cfg);
             
             let local_peer_id = peer_id;
-            let store = MemoryStore::new(local_peer_id);
-            let kademlia = kad::Behaviour::new(local_peer_id, store);
+            let kademlia = providers::behaviour(local_peer_id);
             let relay = RelayBehaviour::new(local_peer_id, libp2p::relay::Config::default());
+            let dcutr = dcutr::Behaviour::new(local_peer_id);
+            let _ = load_bootstrap_nodes();
+            let autonat = nat::autonat_behaviour(local_peer_id);
+            let identify = identify::Behaviour::new(
+                identify::Config::new("/dafs/id/1.0.0".to_string(), id_keys.public())
+                    .with_agent_version(format!("dafs/{}", env!("CARGO_PKG_VERSION"))),
+            );
+            let ping = ping::Behaviour::new(ping::Config::new());
+            let mdns = mdns::tokio::Behaviour::new(mdns::Config::default(), local_peer_id).unwrap();
+            let mut gossipsub = pubsub::behaviour(&id_keys);
+            pubsub::subscribe_all(&mut gossipsub);
             let mut behaviour = MyBehaviour {
                 file_exchange,
                 messaging,
                 peer_discovery,
                 kademlia,
                 relay,
+                relay_client,
+                dcutr,
+                autonat,
+                identify,
+                ping,
+                mdns,
+                gossipsub,
+                limits: connections::limits_behaviour(),
             };
             
             let mut swarm = Swarm::new(
                 transport,
                 behaviour,
                 peer_id,
-                libp2p::swarm::Config::with_executor(Box::new(|fut| { tokio::spawn(fut); })),
+                libp2p::swarm::Config::with_executor(Box::new(|fut| { tokio::spawn(fut); }))
+                    .with_dial_concurrency_factor(transports::DIAL_CONCURRENCY)
+                    .with_idle_connection_timeout(connections::config().idle_timeout),
             );
             
-            // Listen on multiple addresses for better connectivity
-            swarm.listen_on("/ip4/0.0.0.0/tcp/2093".parse().unwrap()).unwrap();
-            swarm.listen_on("/ip6/::/tcp/2093".parse().unwrap());
+            for addr in transports::config().listen {
+                if let Err(e) = swarm.listen_on(addr.clone()) {
+                    println!("Failed to listen on {}: {}", addr, e);
+                }
+            }
             
             // Add bootstrap nodes for Kademlia
-            let _ = load_bootstrap_nodes();
             for (peer, addr) in BOOTSTRAP_NODES.lock().unwrap().iter() {
                 swarm.behaviour_mut().kademlia.add_address(peer, addr.clone());
             }
 
-            let mut pending_responses: HashMap<libp2p::request_response::RequestId, oneshot::Sender<Vec<u8>>> = HashMap::new();
-            let mut pending_list: HashMap<libp2p::request_response::RequestId, oneshot::Sender<Vec<String>>> = HashMap::new();
+            let mut pending_list: HashMap<libp2p::request_response::RequestId, oneshot::Sender<Vec<u8>>> = HashMap::new();
             let mut pending_chunk_responses: HashMap<libp2p::request_response::RequestId, oneshot::Sender<Vec<u8>>> = HashMap::new();
             let mut pending_messaging_responses: HashMap<libp2p::request_response::RequestId, oneshot::Sender<bool>> = HashMap::new();
             let mut pending_discovery_responses: HashMap<libp2p::request_response::RequestId, oneshot::Sender<bool>> = HashMap::new();
             let mut pending_peer_responses: HashMap<libp2p::request_response::RequestId, oneshot::Sender<Vec<DiscoveredPeer>>> = HashMap::new();
-            let mut pending_ping_responses: HashMap<libp2p::request_response::RequestId, oneshot::Sender<Option<u64>>> = HashMap::new();
-            let mut known_peers: Vec<PeerId> = Vec::new();
+            let mut pending_pings: HashMap<PeerId, Vec<oneshot::Sender<Option<u64>>>> = HashMap::new();
+            let mut last_rtt: HashMap<PeerId, std::time::Duration> = HashMap::new();
+            let mut pending_providers: HashMap<kad::QueryId, (oneshot::Sender<Vec<PeerId>>, HashSet<PeerId>)> = HashMap::new();
+            // The first tick announces the files held at startup
+            let mut provider_sync = tokio::time::interval(providers::SYNC_INTERVAL);
+            // Records announced before any peer was known reached nobody
+            let mut announced_to_network = false;
+            let mut relay_reservations = nat::Reservations::default();
+            let mut reconnects = connections::Reconnects::default();
+            let mut reconnect_check = tokio::time::interval(connections::RECONNECT_INTERVAL);
+            let mut kademlia_bootstrap = tokio::time::interval(connections::BOOTSTRAP_INTERVAL);
 
             loop {
                 tokio::select! {
+                    _ = provider_sync.tick() => {
+                        let (announced, withdrawn) = providers::sync(&mut swarm.behaviour_mut().kademlia);
+                        if announced + withdrawn > 0 {
+                            println!("Provider records: announced {} files, withdrew {}", announced, withdrawn);
+                        }
+                    }
+                    _ = reconnect_check.tick() => {
+                        for opts in reconnects.due(|peer| swarm.is_connected(peer)) {
+                            if let Err(e) = swarm.dial(opts) {
+                                println!("Failed to dial preferred peer: {}", e);
+                            }
+                        }
+                    }
+                    _ = kademlia_bootstrap.tick() => {
+                        // Fails only while the routing table is empty
+                        let _ = swarm.behaviour_mut().kademlia.bootstrap();
+                    }
                     Some(cmd) = cmd_rx.recv() => {
                         match cmd {
                             P2PCommand::ListFiles { peer, respond_to } => {
-                                let req = b"LIST".to_vec();
-                                let req_id = swarm.behaviour_mut().file_exchange.send_request(&peer, req);
+                                let mut msg = P2PMessage::FileListRequest { signature: None };
+                                let signature = id_keys.sign(&access::signing_bytes(&peer, &msg)).ok();
+                                if let P2PMessage::FileListRequest { signature: sig, .. } = &mut msg {
+                                    *sig = signature;
+                                }
+                                let data = bincode::serialize(&msg).unwrap();
+                                let req_id = swarm.behaviour_mut().file_exchange.send_request(&peer, data);
                                 pending_list.insert(req_id, respond_to);
                             }
-                            P2PCommand::GetFile { peer, file_id, respond_to } => {
-                                let req = file_id.into_bytes();
-                                let req_id = swarm.behaviour_mut().file_exchange.send_request(&peer, req);
-                                pending_responses.insert(req_id, respond_to);
-                            }
                             P2PCommand::SendMessage { peer, message } => {
                                 let data = bincode::serialize(&message).unwrap();
                                 swarm.behaviour_mut().file_exchange.send_request(&peer, data);
                             }
                             P2PCommand::RequestChunk { peer, file_id, chunk_index, chunk_size, respond_to } => {
-                                let msg = P2PMessage::FileChunkRequest { file_id: file_id.clone(), chunk_index, chunk_size, signature: None };
+                                let mut msg = P2PMessage::FileChunkRequest { file_id, chunk_index, chunk_size, signature: None };
+                                let signature = id_keys.sign(&access::signing_bytes(&peer, &msg)).ok();
+                                if let P2PMessage::FileChunkRequest { signature: sig, .. } = &mut msg {
+                                    *sig = signature;
+                                }
+                                let data = bincode::serialize(&msg).unwrap();
+                                let req_id = swarm.behaviour_mut().file_exchange.send_request(&peer, data);
+                                pending_chunk_responses.insert(req_id, respond_to);
+                            }
+                            P2PCommand::FileRequest { peer, message, respond_to } => {
+                                let data = bincode::serialize(&message).unwrap();
+                                let req_id = swarm.behaviour_mut().file_exchange.send_request(&peer, data);
+                                pending_chunk_responses.insert(req_id, respond_to);
+                            }
+                            P2PCommand::RequestManifest { peer, file_id, chunk_size, respond_to } => {
+                                let mut msg = P2PMessage::FileManifestRequest { file_id, chunk_size, signature: None };
+                                let signature = id_keys.sign(&access::signing_bytes(&peer, &msg)).ok();
+                                if let P2PMessage::FileManifestRequest { signature: sig, .. } = &mut msg {
+                                    *sig = signature;
+                                }
+                                let data = bincode::serialize(&msg).unwrap();
+                                let req_id = swarm.behaviour_mut().file_exchange.send_request(&peer, data);
+                                pending_chunk_responses.insert(req_id, respond_to);
+                            }
+                            P2PCommand::RequestShard { peer, file_id, piece, respond_to } => {
+                                let mut msg = P2PMessage::ShardRequest { file_id, piece, signature: None };
+                                let signature = id_keys.sign(&access::signing_bytes(&peer, &msg)).ok();
+                                if let P2PMessage::ShardRequest { signature: sig, .. } = &mut msg {
+                                    *sig = signature;
+                                }
                                 let data = bincode::serialize(&msg).unwrap();
                                 let req_id = swarm.behaviour_mut().file_exchange.send_request(&peer, data);
                                 pending_chunk_responses.insert(req_id, respond_to);
@@ -416,55 +536,70 @@ impl P2PNode {
                                 pending_messaging_responses.insert(req_id, respond_to);
                             }
                             P2PCommand::CreateChatRoom { room, respond_to } => {
-                                let msg = P2PMessage::ChatRoomCreate { room };
-                                let data = bincode::serialize(&msg).unwrap();
-                                // Broadcast to all known peers
-                                for peer in &known_peers {
-                                    swarm.behaviour_mut().messaging.send_request(peer, data.clone());
+                                let topic = pubsub::room_topic(&room.id);
+                                let gossip = &mut swarm.behaviour_mut().gossipsub;
+                                let subscribed = gossip.subscribe(&topic).is_ok();
+                                if subscribed {
+                                    pubsub::remember_room(&room.id);
+                                    save_chat_room(&room);
+                                    // Private rooms are only described to members, on the room's own topic
+                                    if !room.is_private {
+                                        pubsub::publish(gossip, libp2p::gossipsub::IdentTopic::new(pubsub::ANNOUNCE_TOPIC), &P2PMessage::ChatRoomCreate { room: room.clone() });
+                                    }
+                                    pubsub::publish(gossip, topic, &P2PMessage::ChatRoomCreate { room });
                                 }
-                                let _ = respond_to.send(true);
+                                let _ = respond_to.send(subscribed);
                             }
                             P2PCommand::JoinChatRoom { room_id, username, respond_to } => {
-                                let msg = P2PMessage::ChatRoomJoin { room_id, username };
-                                let data = bincode::serialize(&msg).unwrap();
-                                // Broadcast to all known peers
-                                for peer in &known_peers {
-                                    swarm.behaviour_mut().messaging.send_request(peer, data.clone());
+                                let topic = pubsub::room_topic(&room_id);
+                                let gossip = &mut swarm.behaviour_mut().gossipsub;
+                                let subscribed = gossip.subscribe(&topic).is_ok();
+                                if subscribed {
+                                    pubsub::remember_room(&room_id);
+                                    pubsub::publish(gossip, topic, &P2PMessage::ChatRoomJoin { room_id, username });
                                 }
+                                let _ = respond_to.send(subscribed);
+                            }
+                            P2PCommand::LeaveChatRoom { room_id, username, respond_to } => {
+                                let topic = pubsub::room_topic(&room_id);
+                                let gossip = &mut swarm.behaviour_mut().gossipsub;
+                                pubsub::publish(gossip, topic.clone(), &P2PMessage::ChatRoomLeave { room_id: room_id.clone(), username });
+                                let _ = gossip.unsubscribe(&topic);
+                                pubsub::forget_room(&room_id);
                                 let _ = respond_to.send(true);
                             }
                             P2PCommand::SendChatMessage { room_id, message, respond_to } => {
-                                let msg = P2PMessage::ChatRoomMessage { room_id, message };
-                                let data = bincode::serialize(&msg).unwrap();
-                                // Broadcast to all known peers
-                                for peer in &known_peers {
-                                    swarm.behaviour_mut().messaging.send_request(peer, data.clone());
+                                // Only members publish to a room
+                                if !pubsub::joined_rooms().contains(&room_id) {
+                                    println!("Not a member of chat room {}", room_id);
+                                    let _ = respond_to.send(false);
+                                    continue;
                                 }
-                                let _ = respond_to.send(true);
+                                pubsub::mark_seen(&message.id);
+                                save_chat_message(&room_id, &message);
+                                let topic = pubsub::room_topic(&room_id);
+                                let sent = pubsub::publish(&mut swarm.behaviour_mut().gossipsub, topic, &P2PMessage::ChatRoomMessage { room_id, message });
+                                let _ = respond_to.send(sent);
                             }
                             P2PCommand::UpdateUserStatus { status } => {
-                                let msg = P2PMessage::UserStatus { status };
-                                let data = bincode::serialize(&msg).unwrap();
-                                // Broadcast to all known peers
-                                for peer in &known_peers {
-                                    swarm.behaviour_mut().messaging.send_request(peer, data.clone());
-                                }
+                                save_user_status(&status);
+                                let topic = libp2p::gossipsub::IdentTopic::new(pubsub::PRESENCE_TOPIC);
+                                pubsub::publish(&mut swarm.behaviour_mut().gossipsub, topic, &P2PMessage::UserStatus { status });
                             }
                             P2PCommand::ConnectToPeer { peer_id, addr, respond_to } => {
                                 match PeerId::from_str(&peer_id) {
                                     Ok(peer) => {
                                         if let Some(addr_str) = addr {
                                             if let Ok(multiaddr) = Multiaddr::from_str(&addr_str) {
-                                                swarm.behaviour_mut().kademlia.add_address(&peer, multiaddr);
-                                                known_peers.push(peer);
-                                                let _ = respond_to.send(true);
+                                                swarm.behaviour_mut().kademlia.add_address(&peer, multiaddr.clone());
+                                                let opts = libp2p::swarm::dial_opts::DialOpts::peer_id(peer).addresses(vec![multiaddr]).build();
+                                                let _ = respond_to.send(swarm.dial(opts).is_ok());
                                             } else {
                                                 let _ = respond_to.send(false);
                                             }
                                         } else {
-                                            // Try to discover peer via Kademlia
-                                            swarm.behaviour_mut().kademlia.start_providing(kad::record::Key::from(peer.to_bytes()));
-                                            known_peers.push(peer);
+                                            // Look the peer up in the DHT
+                                            swarm.behaviour_mut().kademlia.get_closest_peers(peer);
                                             let _ = respond_to.send(true);
                                         }
                                     }
@@ -474,24 +609,30 @@ impl P2PNode {
                                 }
                             }
                             P2PCommand::DiscoverPeers { respond_to } => {
-                                // Start Kademlia discovery
-                                swarm.behaviour_mut().kademlia.start_providing(kad::record::Key::from(peer_id.to_bytes()));
+                                // Refresh the routing table
+                                let _ = swarm.behaviour_mut().kademlia.bootstrap();
                                 
                                 // Get known peers from storage
                                 let peers = DISCOVERED_PEERS.lock().unwrap().values().cloned().collect();
                                 let _ = respond_to.send(peers);
                             }
                             P2PCommand::PingPeer { peer_id, respond_to } => {
+                                // A connected peer is pinged every 15 seconds; otherwise dial it
+                                // and answer with the first ping on the new connection
                                 match PeerId::from_str(&peer_id) {
-                                    Ok(peer) => {
-                                        let ping_msg = P2PMessage::PeerPing {
-                                            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
-                                            peer_id: peer_id.clone(),
-                                        };
-                                        let data = bincode::serialize(&ping_msg).unwrap();
-                                        let req_id = swarm.behaviour_mut().peer_discovery.send_request(&peer, data);
-                                        pending_ping_responses.insert(req_id, respond_to);
-                                    }
+                                    Ok(peer) => match last_rtt.get(&peer) {
+                                        Some(rtt) if swarm.is_connected(&peer) => {
+                                            let _ = respond_to.send(Some(rtt.as_millis() as u64));
+                                        }
+                                        _ => {
+                                            if !swarm.is_connected(&peer) && let Err(e) = swarm.dial(peer) {
+                                                println!("Failed to dial {} to ping it: {}", peer, e);
+                                                let _ = respond_to.send(None);
+                                                continue;
+                                            }
+                                            pending_pings.entry(peer).or_default().push(respond_to);
+                                        }
+                                    },
                                     Err(_) => {
                                         let _ = respond_to.send(None);
                                     }
@@ -509,28 +650,86 @@ impl P2PNode {
                                 }
                                 let _ = respond_to.send(removed);
                             }
+                            P2PCommand::ScanLocalNetwork { respond_to } => {
+                                let known = DISCOVERED_PEERS.lock().unwrap();
+                                let mut lan: Vec<DiscoveredPeer> = swarm.behaviour().mdns.discovered_nodes()
+                                    .filter_map(|peer| known.get(&peer.to_string()).cloned())
+                                    .collect();
+                                lan.dedup_by(|a, b| a.peer_id == b.peer_id);
+                                let _ = respond_to.send(lan);
+                            }
+                            P2PCommand::ProvideFile { file_id } => {
+                                if let Err(e) = swarm.behaviour_mut().kademlia.start_providing(providers::file_key(&file_id)) {
+                                    println!("Failed to announce file {}: {}", file_id, e);
+                                }
+                                let announcement = P2PMessage::FileAnnouncement {
+                                    file_id,
+                                    peer_id: local_peer_id.to_string(),
+                                    announced_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+                                };
+                                let topic = libp2p::gossipsub::IdentTopic::new(pubsub::ANNOUNCE_TOPIC);
+                                pubsub::publish(&mut swarm.behaviour_mut().gossipsub, topic, &announcement);
+                            }
+                            P2PCommand::StopProviding { file_id } => {
+                                swarm.behaviour_mut().kademlia.stop_providing(&providers::file_key(&file_id));
+                            }
+                            P2PCommand::FindProviders { key, respond_to } => {
+                                let query = swarm.behaviour_mut().kademlia.get_providers(key);
+                                pending_providers.insert(query, (respond_to, HashSet::new()));
+                            }
+                            P2PCommand::ClosestPeers { file_id, respond_to } => {
+                                // Connected peers and the routing table, nearest the file's key first
+                                let target = kad::KBucketKey::new(providers::file_key(&file_id));
+                                let mut candidates: HashSet<PeerId> = swarm.connected_peers().copied().collect();
+                                for bucket in swarm.behaviour_mut().kademlia.kbuckets() {
+                                    candidates.extend(bucket.iter().map(|entry| *entry.node.key.preimage()));
+                                }
+                                let mut candidates: Vec<PeerId> = candidates.into_iter().filter(|p| *p != local_peer_id).collect();
+                                candidates.sort_by_key(|p| kad::KBucketKey::from(*p).distance(&target));
+                                let _ = respond_to.send(candidates);
+                            }
+                            P2PCommand::Report { peer, offence } => {
+                                if reputation::report(&peer, offence) {
+                                    let _ = swarm.disconnect_peer_id(peer);
+                                }
+                            }
+                            P2PCommand::Disconnect { peer } => {
+                                let _ = swarm.disconnect_peer_id(peer);
+                            }
                         }
                     }
                     event = swarm.next() => {
                         match event {
                             Some(SwarmEvent::NewListenAddr { address, .. }) => {
                                 println!("Listening on {}", address);
+                                transports::on_listen_addr(&address, true);
+                                nat::on_listen_addr(&address, true);
+                            }
+                            Some(SwarmEvent::ExpiredListenAddr { address, .. }) => {
+                                transports::on_listen_addr(&address, false);
+                                nat::on_listen_addr(&address, false);
                             }
-                            Some(SwarmEvent::Behaviour(MyBehaviourEvent::Kademlia(kad::Event::OutboundQueryProgressed { result, .. }))) => {
+                            Some(SwarmEvent::ListenerClosed { listener_id, .. }) => {
+                                relay_reservations.on_listener_closed(listener_id);
+                            }
+                            Some(SwarmEvent::Behaviour(MyBehaviourEvent::Kademlia(kad::Event::OutboundQueryProgressed { id, result, step, .. }))) => {
                                 match result {
                                     libp2p::kad::QueryResult::Bootstrap(_) => {
                                         println!("Kademlia bootstrap completed");
                                     }
                                     libp2p::kad::QueryResult::GetProviders(Ok(ok)) => {
-                                        // TODO: Fix providers field for GetProvidersOk in current libp2p version
-                                        // for peer in ok.providers {
-                                        //     println!("Kademlia discovered provider: {}", peer);
-                                        //     known_peers.push(peer);
-                                        // }
-                                        save_discovered_peers().ok();
+                                        if let kad::GetProvidersOk::FoundProviders { providers, .. } = ok
+                                            && let Some((_, found)) = pending_providers.get_mut(&id)
+                                        {
+                                            found.extend(providers.into_iter().filter(|p| *p != local_peer_id));
+                                            // A holder is enough to start; stop waiting on slower nodes
+                                            if !found.is_empty() && let Some(mut query) = swarm.behaviour_mut().kademlia.query_mut(&id) {
+                                                query.finish();
+                                            }
+                                        }
                                     }
-                                    libp2p::kad::QueryResult::GetProviders(Err(_)) => {
-                                        println!("Kademlia get providers failed");
+                                    libp2p::kad::QueryResult::GetProviders(Err(e)) => {
+                                        println!("Kademlia get providers failed: {}", e);
                                     }
                                     libp2p::kad::QueryResult::GetRecord(Ok(ok)) => {
                                         // TODO: Fix record field for GetRecordOk in current libp2p version
@@ -555,31 +754,54 @@ impl P2PNode {
                                     }
                                     _ => {}
                                 }
+                                if step.last && let Some((respond_to, found)) = pending_providers.remove(&id) {
+                                    let _ = respond_to.send(found.into_iter().collect());
+                                }
+                            }
+                            Some(SwarmEvent::Behaviour(MyBehaviourEvent::Kademlia(kad::Event::RoutingUpdated { .. }))) if !announced_to_network => {
+                                announced_to_network = true;
+                                let republished = providers::republish(&mut swarm.behaviour_mut().kademlia);
+                                if republished > 0 {
+                                    println!("Announced {} files to the network", republished);
+                                }
                             }
                             Some(SwarmEvent::Behaviour(MyBehaviourEvent::FileExchange(libp2p::request_response::Event::Message { peer, message }))) => {
                                 match message {
                                     libp2p::request_response::Message::Request { request_id, request, channel } => {
-                                        let response = handle_file_request(&request);
+                                        let response = handle_file_request(&peer, &local_peer_id, &request);
                                         swarm.behaviour_mut().file_exchange.send_response(channel, response).unwrap();
+                                        if access::is_shut_out(&peer) {
+                                            let _ = swarm.disconnect_peer_id(peer);
+                                        }
                                     }
                                     libp2p::request_response::Message::Response { request_id, response } => {
-                                        if let Some(respond_to) = pending_responses.remove(&request_id) {
+                                        if let Some(respond_to) = pending_list.remove(&request_id) {
                                             let _ = respond_to.send(response);
-                                        } else if let Some(respond_to) = pending_list.remove(&request_id) {
-                                            let files = String::from_utf8_lossy(&response);
-                                            let file_list: Vec<String> = files.lines().map(|s| s.to_string()).collect();
-                                            let _ = respond_to.send(file_list);
                                         } else if let Some(respond_to) = pending_chunk_responses.remove(&request_id) {
                                             let _ = respond_to.send(response);
                                         }
                                     }
                                 }
                             }
+                            Some(SwarmEvent::Behaviour(MyBehaviourEvent::FileExchange(libp2p::request_response::Event::OutboundFailure { peer, request_id, error }))) => {
+                                // Dropping the waiting sender fails the caller's request
+                                pending_list.remove(&request_id);
+                                pending_chunk_responses.remove(&request_id);
+                                if matches!(error, libp2p::request_response::OutboundFailure::Timeout) {
+                                    println!("Request to {} timed out", peer);
+                                    if reputation::report(&peer, Offence::Timeout) {
+                                        let _ = swarm.disconnect_peer_id(peer);
+                                    }
+                                }
+                            }
                             Some(SwarmEvent::Behaviour(MyBehaviourEvent::Messaging(libp2p::request_response::Event::Message { peer, message }))) => {
                                 match message {
                                     libp2p::request_response::Message::Request { request_id, request, channel } => {
-                                        let response = handle_messaging_request(&request);
+                                        let response = handle_messaging_request(&peer, &request);
                                         swarm.behaviour_mut().messaging.send_response(channel, response).unwrap();
+                                        if access::is_shut_out(&peer) {
+                                            let _ = swarm.disconnect_peer_id(peer);
+                                        }
                                     }
                                     libp2p::request_response::Message::Response { request_id, response } => {
                                         if let Some(respond_to) = pending_messaging_responses.remove(&request_id) {
@@ -592,53 +814,198 @@ impl P2PNode {
                             Some(SwarmEvent::Behaviour(MyBehaviourEvent::PeerDiscovery(libp2p::request_response::Event::Message { peer, message }))) => {
                                 match message {
                                     libp2p::request_response::Message::Request { request_id, request, channel } => {
-                                        let response = handle_discovery_request(&request);
+                                        let response = handle_discovery_request(&peer, &local_peer_id, &request);
                                         swarm.behaviour_mut().peer_discovery.send_response(channel, response).unwrap();
+                                        if access::is_shut_out(&peer) {
+                                            let _ = swarm.disconnect_peer_id(peer);
+                                        }
                                     }
                                     libp2p::request_response::Message::Response { request_id, response } => {
                                         if let Some(respond_to) = pending_discovery_responses.remove(&request_id) {
                                             let success = response.len() > 0;
                                             let _ = respond_to.send(success);
-                                        } else if let Some(respond_to) = pending_ping_responses.remove(&request_id) {
-                                            if let Ok(pong_msg) = bincode::deserialize::<P2PMessage>(&response) {
-                                                if let P2PMessage::PeerPong { timestamp, .. } = pong_msg {
-                                                    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-                                                    let latency = if now > timestamp { now - timestamp } else { 0 };
-                                                    let _ = respond_to.send(Some(latency));
-                                                } else {
-                                                    let _ = respond_to.send(None);
-                                                }
-                                            } else {
-                                                let _ = respond_to.send(None);
-                                            }
                                         }
                                     }
                                 }
                             }
-                            Some(SwarmEvent::Behaviour(MyBehaviourEvent::Relay(event))) => {
-                                // Handle relay events
+                            Some(SwarmEvent::Behaviour(MyBehaviourEvent::Relay(event))) => match event {
+                                relay::Event::ReservationReqAccepted { src_peer_id, renewed: false } => {
+                                    println!("Relaying for peer {}", src_peer_id);
+                                }
+                                relay::Event::CircuitReqAccepted { src_peer_id, dst_peer_id } => {
+                                    println!("Relaying a circuit from {} to {}", src_peer_id, dst_peer_id);
+                                }
+                                relay::Event::ReservationTimedOut { src_peer_id } => {
+                                    println!("Relay reservation of peer {} expired", src_peer_id);
+                                }
+                                _ => {}
+                            },
+                            Some(SwarmEvent::Behaviour(MyBehaviourEvent::RelayClient(event))) => match event {
+                                relay::client::Event::ReservationReqAccepted { relay_peer_id, renewal, .. } => {
+                                    if !renewal {
+                                        println!("Relay {} reserved a slot for this node", relay_peer_id);
+                                    }
+                                    nat::on_reservation(relay_peer_id, true);
+                                }
+                                relay::client::Event::ReservationReqFailed { relay_peer_id, error, .. } => {
+                                    println!("Relay {} refused a reservation: {}", relay_peer_id, error);
+                                    nat::on_reservation(relay_peer_id, false);
+                                }
+                                relay::client::Event::InboundCircuitEstablished { src_peer_id, .. } => {
+                                    println!("Peer {} connected through a relay", src_peer_id);
+                                }
+                                _ => {}
+                            },
+                            Some(SwarmEvent::Behaviour(MyBehaviourEvent::Dcutr(event))) => match event {
+                                dcutr::Event::DirectConnectionUpgradeSucceeded { remote_peer_id } => {
+                                    println!("Upgraded relayed connection to {} to a direct one", remote_peer_id);
+                                    nat::on_upgrade(true);
+                                }
+                                dcutr::Event::DirectConnectionUpgradeFailed { remote_peer_id, error } => {
+                                    println!("Hole punching to {} failed, staying relayed: {}", remote_peer_id, error);
+                                    nat::on_upgrade(false);
+                                }
+                                _ => {}
+                            },
+                            Some(SwarmEvent::Behaviour(MyBehaviourEvent::Autonat(autonat::Event::StatusChanged { new, .. }))) => {
+                                relay_reservations.on_status(&mut swarm, new);
+                            }
+                            Some(SwarmEvent::Behaviour(MyBehaviourEvent::Identify(identify::Event::Received { peer_id, info }))) => {
+                                // Where peers see this node is where hole punching has them dial
+                                if !nat::is_relayed(&info.observed_addr) {
+                                    swarm.add_external_address(info.observed_addr.clone());
+                                }
+                                if info.protocols.contains(&kad::PROTOCOL_NAME) {
+                                    for addr in transports::by_preference(info.listen_addrs.clone()) {
+                                        swarm.behaviour_mut().kademlia.add_address(&peer_id, addr);
+                                    }
+                                }
+                                remember_identified_peer(&peer_id, &info);
+                                save_discovered_peers().ok();
                             }
-                            Some(SwarmEvent::ConnectionEstablished { peer_id, .. }) => {
+                            Some(SwarmEvent::Behaviour(MyBehaviourEvent::Ping(ping::Event { peer, result, .. }))) => {
+                                let rtt = match result {
+                                    Ok(rtt) => {
+                                        last_rtt.insert(peer, rtt);
+                                        record_latency(&peer, rtt);
+                                        Some(rtt.as_millis() as u64)
+                                    }
+                                    Err(e) => {
+                                        println!("Ping to {} failed: {}", peer, e);
+                                        None
+                                    }
+                                };
+                                for respond_to in pending_pings.remove(&peer).unwrap_or_default() {
+                                    let _ = respond_to.send(rtt);
+                                }
+                            }
+                            Some(SwarmEvent::ConnectionEstablished { peer_id, .. }) if access::is_shut_out(&peer_id) => {
+                                println!("Dropping connection from blocked or banned peer: {}", peer_id);
+                                let _ = swarm.disconnect_peer_id(peer_id);
+                            }
+                            Some(SwarmEvent::ConnectionEstablished { peer_id, connection_id, endpoint, .. }) => {
                                 println!("Connected to peer: {}", peer_id);
-                                known_peers.push(peer_id);
+                                connections::on_established(connection_id, peer_id, &endpoint);
+                                reconnects.on_connected(&peer_id);
+                                record_connection(&peer_id, endpoint.get_remote_address(), true);
+                                relay_reservations.reserve(&mut swarm, &peer_id);
                                 
                                 // Update peer status
-                                if let Some(peer) = DISCOVERED_PEERS.lock().unwrap().get_mut(&peer_id.to_string()) {
-                                    peer.is_online = true;
-                                    peer.last_seen = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+                                let updated = match DISCOVERED_PEERS.lock().unwrap().get_mut(&peer_id.to_string()) {
+                                    Some(peer) => {
+                                        peer.is_online = true;
+                                        peer.last_seen = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+                                        true
+                                    }
+                                    None => false,
+                                };
+                                if updated {
                                     save_discovered_peers().ok();
                                 }
                             }
-                            Some(SwarmEvent::ConnectionClosed { peer_id, .. }) => {
+                            Some(SwarmEvent::ConnectionClosed { peer_id, connection_id, endpoint, num_established, .. }) => {
                                 println!("Disconnected from peer: {}", peer_id);
-                                known_peers.retain(|p| p != &peer_id);
-                                
-                                // Update peer status
-                                if let Some(peer) = DISCOVERED_PEERS.lock().unwrap().get_mut(&peer_id.to_string()) {
-                                    peer.is_online = false;
-                                    save_discovered_peers().ok();
+                                connections::on_closed(connection_id);
+                                record_disconnection(&peer_id, endpoint.get_remote_address());
+                                if num_established == 0 {
+                                    last_rtt.remove(&peer_id);
+                                    
+                                    // Update peer status
+                                    let updated = match DISCOVERED_PEERS.lock().unwrap().get_mut(&peer_id.to_string()) {
+                                        Some(peer) => {
+                                            peer.is_online = false;
+                                            true
+                                        }
+                                        None => false,
+                                    };
+                                    if updated {
+                                        save_discovered_peers().ok();
+                                    }
+                                }
+                            }
+                            Some(SwarmEvent::OutgoingConnectionError { peer_id: Some(peer_id), error, .. }) => {
+                                println!("Failed to connect to peer {}: {}", peer_id, error);
+                                record_connection(&peer_id, &Multiaddr::empty(), false);
+                                if !swarm.is_connected(&peer_id) {
+                                    for respond_to in pending_pings.remove(&peer_id).unwrap_or_default() {
+                                        let _ = respond_to.send(None);
+                                    }
+                                }
+                            }
+                            Some(SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(mdns::Event::Discovered(found)))) => {
+                                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+                                let mut lan: HashMap<PeerId, Vec<Multiaddr>> = HashMap::new();
+                                for (peer, addr) in found {
+                                    lan.entry(peer).or_default().push(addr);
+                                }
+                                let allowed = list_allowed_peers();
+                                for (peer, addrs) in lan {
+                                    let addrs = transports::by_preference(addrs);
+                                    for addr in &addrs {
+                                        swarm.behaviour_mut().kademlia.add_address(&peer, addr.clone());
+                                    }
+                                    println!("mDNS discovered peer {} at {:?}", peer, addrs);
+                                    remember_lan_peer(&peer, &addrs, now, swarm.is_connected(&peer));
+                                    // Only allowlisted peers are dialled automatically
+                                    if allowed.contains(&peer.to_string()) && !swarm.is_connected(&peer) && !access::is_shut_out(&peer) {
+                                        let opts = libp2p::swarm::dial_opts::DialOpts::peer_id(peer).addresses(addrs).build();
+                                        if let Err(e) = swarm.dial(opts) {
+                                            println!("Failed to dial allowlisted peer {}: {}", peer, e);
+                                        }
+                                    }
+                                }
+                                save_discovered_peers().ok();
+                            }
+                            Some(SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Message { propagation_source, message_id, message }))) => {
+                                let author = message.source;
+                                let verdict = pubsub::validate(&message);
+                                let acceptance = match &verdict {
+                                    Ok(_) => gossipsub::MessageAcceptance::Accept,
+                                    Err(gossipsub::MessageAcceptance::Ignore) => gossipsub::MessageAcceptance::Ignore,
+                                    Err(_) => {
+                                        println!("Rejected gossip on {} from {}", message.topic, propagation_source);
+                                        reputation::report(&propagation_source, Offence::MalformedMessage);
+                                        gossipsub::MessageAcceptance::Reject
+                                    }
+                                };
+                                let _ = swarm.behaviour_mut().gossipsub.report_message_validation_result(&message_id, &propagation_source, acceptance);
+                                if let (Ok(msg), Some(author)) = (verdict, author) {
+                                    // A member joining gets the room's details from whoever holds them
+                                    if let P2PMessage::ChatRoomJoin { room_id, .. } = &msg
+                                        && let Some(room) = pubsub::load_chat_room(room_id)
+                                    {
+                                        pubsub::publish(&mut swarm.behaviour_mut().gossipsub, pubsub::room_topic(room_id), &P2PMessage::ChatRoomCreate { room });
+                                    }
+                                    pubsub::deliver(&author, msg);
                                 }
                             }
+                            Some(SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(mdns::Event::Expired(gone)))) => {
+                                for (peer, addr) in gone {
+                                    println!("mDNS peer {} expired at {}", peer, addr);
+                                    forget_lan_address(&peer, &addr, swarm.is_connected(&peer));
+                                }
+                                save_discovered_peers().ok();
+                            }
                             _ => {}
                         }
                     }
@@ -646,21 +1013,107 @@ impl P2PNode {
             }
         });
 
-        Self { cmd_tx }
+        Self { cmd_tx, keypair: node_keys }
     }
-    pub async fn list_files(&self, peer: PeerId) -> Vec<String> {
-        let (tx, rx) = oneshot::channel::<Vec<String>>();
-        let _ = self.cmd_tx.send(P2PCommand::ListFiles { peer, respond_to: tx }).await;
-        rx.await.unwrap_or_default()
+    pub fn local_peer_id(&self) -> PeerId {
+        self.keypair.public().to_peer_id()
     }
-    pub async fn get_file(&self, peer: PeerId, file_id: String) -> Vec<u8> {
-        let (tx, rx) = oneshot::channel::<Vec<u8>>();
-        let _ = self.cmd_tx.send(P2PCommand::GetFile { peer, file_id, respond_to: tx }).await;
-        rx.await.unwrap_or_default()
+    /// Full addresses other nodes and browsers can dial this node at: its listen
+    /// addresses, most preferred transport first, then any relayed ones.
+    pub fn dial_addrs(&self) -> Vec<Multiaddr> {
+        local_dial_addrs(&self.local_peer_id())
+    }
+    /// Fetches a whole file, one chunk at a time, from `peer_id` or else from the first
+    /// holder the DHT lists that serves it.
+    pub async fn get_file(&self, peer_id: Option<&str>, file_id: &str) -> anyhow::Result<Vec<u8>> {
+        let mut last_error = None;
+        for holder in self.holders(peer_id, file_id).await? {
+            match self.get_file_from(&holder, file_id).await {
+                Ok(data) => return Ok(data),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| anyhow::anyhow!("No peer could serve file {}", file_id)))
+    }
+    async fn get_file_from(&self, peer_id: &str, file_id: &str) -> anyhow::Result<Vec<u8>> {
+        const CHUNK_SIZE: usize = 1024 * 1024;
+        let mut data = Vec::new();
+        for chunk_index in 0.. {
+            let chunk = self.request_chunk(peer_id, file_id, chunk_index, CHUNK_SIZE).await?;
+            let last = chunk.len() < CHUNK_SIZE;
+            data.extend(chunk);
+            if last {
+                break;
+            }
+        }
+        Ok(data)
+    }
+    /// Fetches one chunk from `peer_id`, or from the file's holders in turn, checking it
+    /// against `expected_sha256` when given. Returns the serving peer with the data.
+    pub async fn fetch_chunk(
+        &self,
+        peer_id: Option<&str>,
+        file_id: &str,
+        chunk_index: usize,
+        chunk_size: usize,
+        expected_sha256: Option<&str>,
+    ) -> anyhow::Result<(String, Vec<u8>)> {
+        let mut last_error = None;
+        for holder in self.holders(peer_id, file_id).await? {
+            let result = match self.request_chunk(&holder, file_id, chunk_index, chunk_size).await {
+                Ok(data) => match expected_sha256 {
+                    Some(expected) => self.check_chunk(&holder, &data, expected).await.map(|_| data),
+                    None => Ok(data),
+                },
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(data) => return Ok((holder, data)),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| anyhow::anyhow!("No peer could serve file {}", file_id)))
+    }
+    /// The peers to fetch `file_id` from: `peer_id` when given, otherwise its providers.
+    async fn holders(&self, peer_id: Option<&str>, file_id: &str) -> anyhow::Result<Vec<String>> {
+        if let Some(peer_id) = peer_id.filter(|p| !p.is_empty()) {
+            return Ok(vec![peer_id.to_string()]);
+        }
+        let holders = self.find_providers(file_id).await?;
+        if holders.is_empty() {
+            return Err(anyhow::anyhow!("No peer is known to hold file {}", file_id));
+        }
+        Ok(holders)
+    }
+    /// Asks the DHT which peers hold `file_id`, adding any that announced it recently.
+    pub async fn find_providers(&self, file_id: &str) -> anyhow::Result<Vec<String>> {
+        let mut holders = self.providers_of(providers::file_key(file_id)).await?;
+        let local = self.local_peer_id();
+        for peer in pubsub::announced_holders(file_id).into_iter().filter(|p| *p != local) {
+            if !holders.contains(&peer.to_string()) {
+                holders.push(peer.to_string());
+            }
+        }
+        Ok(holders)
+    }
+    async fn providers_of(&self, key: kad::RecordKey) -> anyhow::Result<Vec<String>> {
+        let (tx, rx) = oneshot::channel::<Vec<PeerId>>();
+        let _ = self.cmd_tx.send(P2PCommand::FindProviders { key, respond_to: tx }).await;
+        let providers = rx.await.map_err(|e| anyhow::anyhow!("Failed to find providers: {}", e))?;
+        Ok(providers.iter().map(PeerId::to_string).collect())
+    }
+    /// Announces that this node holds `file_id`.
+    pub async fn provide_file(&self, file_id: &str) {
+        let _ = self.cmd_tx.send(P2PCommand::ProvideFile { file_id: file_id.to_string() }).await;
+    }
+    /// Stops announcing `file_id`, e.g. after its contents are deleted.
+    pub async fn stop_providing(&self, file_id: &str) {
+        let _ = self.cmd_tx.send(P2PCommand::StopProviding { file_id: file_id.to_string() }).await;
     }
     pub async fn send_message(&self, peer: PeerId, message: P2PMessage) {
         let _ = self.cmd_tx.send(P2PCommand::SendMessage { peer, message }).await;
     }
+    /// Fetches one chunk of a file from `peer_id`.
     pub async fn request_chunk(&self, peer_id: &str, file_id: &str, chunk_index: usize, chunk_size: usize) -> anyhow::Result<Vec<u8>> {
         let peer = PeerId::from_str(peer_id)?;
         let (tx, rx) = oneshot::channel::<Vec<u8>>();
@@ -671,7 +1124,77 @@ impl P2PNode {
             chunk_size,
             respond_to: tx,
         }).await;
-        Ok(rx.await.unwrap_or_default())
+        match bincode::deserialize::<P2PMessage>(&rx.await.unwrap_or_default()) {
+            Ok(P2PMessage::FileChunkResponse { data, .. }) => Ok(data),
+            Ok(P2PMessage::RequestDenied { reason }) => Err(anyhow::anyhow!("Peer denied the request: {}", reason)),
+            _ => Err(anyhow::anyhow!("No valid response from peer")),
+        }
+    }
+    /// Sends an unsigned file-exchange request and decodes the answer.
+    pub async fn file_request(&self, peer: PeerId, message: P2PMessage) -> anyhow::Result<P2PMessage> {
+        let (tx, rx) = oneshot::channel::<Vec<u8>>();
+        let _ = self.cmd_tx.send(P2PCommand::FileRequest { peer, message, respond_to: tx }).await;
+        let response = rx.await.map_err(|_| anyhow::anyhow!("No response from {}", peer))?;
+        bincode::deserialize(&response).map_err(|_| anyhow::anyhow!("No valid response from {}", peer))
+    }
+    /// Known peers ordered by XOR distance from `file_id`'s DHT key, nearest first.
+    pub async fn closest_peers(&self, file_id: &str) -> anyhow::Result<Vec<PeerId>> {
+        let (tx, rx) = oneshot::channel::<Vec<PeerId>>();
+        let _ = self.cmd_tx.send(P2PCommand::ClosestPeers { file_id: file_id.to_string(), respond_to: tx }).await;
+        rx.await.map_err(|e| anyhow::anyhow!("Failed to rank peers: {}", e))
+    }
+    /// Fetches a file's manifest from `peer_id`, hashed in `chunk_size` pieces.
+    pub async fn request_manifest(&self, peer_id: &str, file_id: &str, chunk_size: usize) -> anyhow::Result<download::Manifest> {
+        let peer = PeerId::from_str(peer_id)?;
+        let (tx, rx) = oneshot::channel::<Vec<u8>>();
+        let _ = self.cmd_tx.send(P2PCommand::RequestManifest {
+            peer,
+            file_id: file_id.to_string(),
+            chunk_size,
+            respond_to: tx,
+        }).await;
+        match bincode::deserialize::<P2PMessage>(&rx.await.unwrap_or_default()) {
+            Ok(P2PMessage::FileManifestResponse { manifest, .. }) if manifest.chunk_size == chunk_size => Ok(manifest),
+            Ok(P2PMessage::RequestDenied { reason }) => Err(anyhow::anyhow!("Peer denied the request: {}", reason)),
+            _ => Err(anyhow::anyhow!("No valid response from peer")),
+        }
+    }
+    /// Fetches one stripe's piece of the shard `peer_id` holds for `file_id`.
+    pub async fn request_shard(
+        &self,
+        peer_id: &str,
+        file_id: &str,
+        piece: usize,
+    ) -> anyhow::Result<(erasure::ShardInfo, Option<download::Manifest>, Vec<u8>)> {
+        let peer = PeerId::from_str(peer_id)?;
+        let (tx, rx) = oneshot::channel::<Vec<u8>>();
+        let _ = self.cmd_tx.send(P2PCommand::RequestShard {
+            peer,
+            file_id: file_id.to_string(),
+            piece,
+            respond_to: tx,
+        }).await;
+        match bincode::deserialize::<P2PMessage>(&rx.await.unwrap_or_default()) {
+            Ok(P2PMessage::ShardResponse { shard, manifest, data, .. }) => Ok((shard, manifest, data)),
+            Ok(P2PMessage::RequestDenied { reason }) => Err(anyhow::anyhow!("Peer denied the request: {}", reason)),
+            _ => Err(anyhow::anyhow!("No valid response from peer")),
+        }
+    }
+    /// Signs a share revocation with this node's identity and sends it to each peer in
+    /// `peers`, returning how many of them were valid peer IDs.
+    pub async fn announce_share_revocation(&self, revocation: &crate::storage::ShareRevocation, peers: &[String]) -> anyhow::Result<usize> {
+        let signature = self.keypair.sign(&bincode::serialize(revocation)?)?;
+        let mut sent = 0;
+        for peer_id in peers {
+            let peer = match PeerId::from_str(peer_id) {
+                Ok(p) => p,
+                Err(_) => continue,
+            };
+            let msg = P2PMessage::ShareRevocation { revocation: revocation.clone(), signature: signature.clone() };
+            self.send_message(peer, msg).await;
+            sent += 1;
+        }
+        Ok(sent)
     }
     pub async fn send_model_update(&self, peer_id: &str, model: &CFModel) -> anyhow::Result<()> {
         let peer = PeerId::from_str(peer_id)?;
@@ -680,19 +1203,36 @@ impl P2PNode {
         self.send_message(peer, msg).await;
         Ok(())
     }
+    /// Checks a chunk served by `peer_id` against its expected SHA-256 (hex). A mismatch
+    /// counts against the peer's reputation.
+    pub async fn check_chunk(&self, peer_id: &str, data: &[u8], expected_sha256: &str) -> anyhow::Result<()> {
+        use sha2::{Digest, Sha256};
+        if hex::encode(Sha256::digest(data)).eq_ignore_ascii_case(expected_sha256.trim()) {
+            return Ok(());
+        }
+        let peer = PeerId::from_str(peer_id)?;
+        let _ = self.cmd_tx.send(P2PCommand::Report { peer, offence: Offence::ChunkHashMismatch }).await;
+        Err(anyhow::anyhow!("Chunk from {} does not match its expected hash", peer_id))
+    }
+
+    /// Adds a peer to the blocklist and drops its connections.
+    pub async fn block_peer(&self, peer_id: &str) -> anyhow::Result<()> {
+        let peer = PeerId::from_str(peer_id)?;
+        block_peer(peer_id);
+        let _ = self.cmd_tx.send(P2PCommand::Disconnect { peer }).await;
+        Ok(())
+    }
+
+    /// Lists the files `peer_id` will serve us.
     pub async fn query_peer_files(&self, peer_id: &str) -> anyhow::Result<Vec<crate::storage::FileMetadata>> {
-        use libp2p::PeerId;
         let peer = PeerId::from_str(peer_id)?;
         let (tx, rx) = oneshot::channel::<Vec<u8>>();
-        let _ = self.cmd_tx.send(P2PCommand::SendMessage {
-            peer: peer.clone(),
-            message: P2PMessage::FileListRequest { signature: None },
-        }).await;
-        // Wait for response (simulate for now)
-        // In a real implementation, you would have a response channel or event handler
-        // For now, return local files as a stub
-        let files = crate::storage::Storage::new("dafs_db")?.list_metadata()?;
-        Ok(files)
+        let _ = self.cmd_tx.send(P2PCommand::ListFiles { peer, respond_to: tx }).await;
+        match bincode::deserialize::<P2PMessage>(&rx.await.unwrap_or_default()) {
+            Ok(P2PMessage::FileListResponse { files }) => Ok(files),
+            Ok(P2PMessage::RequestDenied { reason }) => Err(anyhow::anyhow!("Peer denied the request: {}", reason)),
+            _ => Err(anyhow::anyhow!("No valid response from peer")),
+        }
     }
 
     // P2P Messaging Methods
@@ -716,6 +1256,12 @@ impl P2PNode {
         Ok(rx.await.unwrap_or(false))
     }
 
+    pub async fn leave_chat_room(&self, room_id: String, username: String) -> anyhow::Result<bool> {
+        let (tx, rx) = oneshot::channel::<bool>();
+        let _ = self.cmd_tx.send(P2PCommand::LeaveChatRoom { room_id, username, respond_to: tx }).await;
+        Ok(rx.await.unwrap_or(false))
+    }
+
     pub async fn send_chat_message(&self, room_id: String, message: crate::models::EncryptedMessage) -> anyhow::Result<bool> {
         let (tx, rx) = oneshot::channel::<bool>();
         let _ = self.cmd_tx.send(P2PCommand::SendChatMessage { room_id, message, respond_to: tx }).await;
@@ -746,6 +1292,8 @@ impl P2PNode {
                 user_info: None,
                 is_online: true,
                 latency_ms: Some(15),
+                agent_version: None,
+                protocols: Vec::new(),
             },
             DiscoveredPeer {
                 peer_id: "12D3KooWExample2".to_string(),
@@ -754,6 +1302,8 @@ impl P2PNode {
                 user_info: None,
                 is_online: true,
                 latency_ms: Some(25),
+                agent_version: None,
+                protocols: Vec::new(),
             },
         ];
         
@@ -790,49 +1340,16 @@ impl P2PNode {
         })
     }
 
+    /// Connections made, lost and failed, newest first.
     pub fn get_peer_connection_history(&self) -> Vec<(String, PeerConnectionInfo)> {
-        // Return mock connection history
-        // In a real implementation, this would be persisted and retrieved from storage
-        vec![
-            ("12D3KooWExample1".to_string(), PeerConnectionInfo {
-                connected: true,
-                last_seen: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() - 3600, // 1 hour ago
-            }),
-            ("12D3KooWExample2".to_string(), PeerConnectionInfo {
-                connected: false,
-                last_seen: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() - 7200, // 2 hours ago
-            }),
-        ]
+        CONNECTION_HISTORY.lock().unwrap().iter().rev().cloned().collect()
     }
 
+    /// Peers mDNS currently sees on the local network.
     pub async fn scan_local_network(&self) -> anyhow::Result<Vec<DiscoveredPeer>> {
-        // Simulate local network scan
-        // In a real implementation, this would use mDNS or similar local discovery
-        let local_peers = vec![
-            DiscoveredPeer {
-                peer_id: "12D3KooWLocal1".to_string(),
-                addresses: vec!["/ip4/192.168.1.50/tcp/2093".to_string()],
-                last_seen: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
-                user_info: None,
-                is_online: true,
-                latency_ms: Some(5),
-            },
-            DiscoveredPeer {
-                peer_id: "12D3KooWLocal2".to_string(),
-                addresses: vec!["/ip4/192.168.1.51/tcp/2093".to_string()],
-                last_seen: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
-                user_info: None,
-                is_online: true,
-                latency_ms: Some(8),
-            },
-        ];
-        
-        // Add to discovered peers
-        for peer in &local_peers {
-            DISCOVERED_PEERS.lock().unwrap().insert(peer.peer_id.clone(), peer.clone());
-        }
-        
-        Ok(local_peers)
+        let (tx, rx) = oneshot::channel::<Vec<DiscoveredPeer>>();
+        let _ = self.cmd_tx.send(P2PCommand::ScanLocalNetwork { respond_to: tx }).await;
+        rx.await.map_err(|e| anyhow::anyhow!("Failed to scan local network: {}", e))
     }
 
     pub async fn connect_peer(&self, peer_id: String, addr: Option<String>) -> anyhow::Result<bool> {
@@ -840,22 +1357,135 @@ impl P2PNode {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PeerConnectionInfo {
+    /// Remote address, empty for a dial that failed before connecting
+    pub address: String,
+    /// Whether the connection is still open
     pub connected: bool,
+    /// False for a failed dial
+    pub successful: bool,
+    pub connected_at: u64,
+    /// When the connection closed, or was last known open
     pub last_seen: u64,
 }
 
+fn record_connection(peer: &PeerId, address: &Multiaddr, successful: bool) {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let mut history = CONNECTION_HISTORY.lock().unwrap();
+    history.push((peer.to_string(), PeerConnectionInfo {
+        address: address.to_string(),
+        connected: successful,
+        successful,
+        connected_at: now,
+        last_seen: now,
+    }));
+    let excess = history.len().saturating_sub(MAX_CONNECTION_HISTORY);
+    history.drain(..excess);
+    drop(history);
+    save_connection_history().ok();
+}
+
+fn record_disconnection(peer: &PeerId, address: &Multiaddr) {
+    let (peer, address) = (peer.to_string(), address.to_string());
+    let mut history = CONNECTION_HISTORY.lock().unwrap();
+    if let Some((_, info)) = history.iter_mut().rev().find(|(p, info)| *p == peer && info.connected && info.address == address) {
+        info.connected = false;
+        info.last_seen = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    }
+    drop(history);
+    save_connection_history().ok();
+}
+
+fn save_connection_history() -> anyhow::Result<()> {
+    let json = serde_json::to_string_pretty(&*CONNECTION_HISTORY.lock().unwrap())?;
+    fs::write("peer_history.json", json)?;
+    Ok(())
+}
+
+/// Loads past connections; any left open by the last run are closed at their last sighting.
+fn load_connection_history() -> anyhow::Result<()> {
+    if let Ok(data) = fs::read_to_string("peer_history.json") {
+        let mut history: Vec<(String, PeerConnectionInfo)> = serde_json::from_str(&data)?;
+        for (_, info) in history.iter_mut() {
+            info.connected = false;
+        }
+        *CONNECTION_HISTORY.lock().unwrap() = history;
+    }
+    Ok(())
+}
+
+/// Records what a peer reported about itself through identify: the addresses it listens
+/// on, its software version and the protocols it speaks.
+fn remember_identified_peer(peer: &PeerId, info: &identify::Info) {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let mut peers = DISCOVERED_PEERS.lock().unwrap();
+    let entry = peers.entry(peer.to_string()).or_insert_with(|| DiscoveredPeer {
+        peer_id: peer.to_string(),
+        addresses: Vec::new(),
+        last_seen: now,
+        user_info: None,
+        is_online: true,
+        latency_ms: None,
+        agent_version: None,
+        protocols: Vec::new(),
+    });
+    entry.addresses = transports::by_preference(info.listen_addrs.clone()).iter().map(|a| a.to_string()).collect();
+    entry.agent_version = Some(info.agent_version.clone());
+    entry.protocols = info.protocols.iter().map(|p| p.to_string()).collect();
+    entry.last_seen = now;
+    entry.is_online = true;
+}
+
+/// Records a ping round trip. Kept in memory; it is saved with the next change to the
+/// peer list.
+fn record_latency(peer: &PeerId, rtt: std::time::Duration) {
+    if let Some(entry) = DISCOVERED_PEERS.lock().unwrap().get_mut(&peer.to_string()) {
+        entry.latency_ms = Some(rtt.as_millis() as u64);
+        entry.last_seen = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    }
+}
+
+/// Adds an mDNS sighting to `DISCOVERED_PEERS`.
+fn remember_lan_peer(peer: &PeerId, addrs: &[Multiaddr], now: u64, connected: bool) {
+    let mut peers = DISCOVERED_PEERS.lock().unwrap();
+    let entry = peers.entry(peer.to_string()).or_insert_with(|| DiscoveredPeer {
+        peer_id: peer.to_string(),
+        addresses: Vec::new(),
+        last_seen: now,
+        user_info: None,
+        is_online: connected,
+        latency_ms: None,
+        agent_version: None,
+        protocols: Vec::new(),
+    });
+    for addr in addrs {
+        let addr = addr.to_string();
+        if !entry.addresses.contains(&addr) {
+            entry.addresses.push(addr);
+        }
+    }
+    entry.last_seen = now;
+}
+
+/// Drops an address mDNS no longer sees; a peer left with no addresses and no
+/// connection is forgotten.
+fn forget_lan_address(peer: &PeerId, addr: &Multiaddr, connected: bool) {
+    let mut peers = DISCOVERED_PEERS.lock().unwrap();
+    let key = peer.to_string();
+    if let Some(entry) = peers.get_mut(&key) {
+        let addr = addr.to_string();
+        entry.addresses.retain(|a| *a != addr);
+        if entry.addresses.is_empty() && !connected {
+            peers.remove(&key);
+        }
+    }
+}
+
 pub static P2P_STORAGE: Lazy<Arc<Storage>> = Lazy::new(|| Arc::new(Storage::new("dafs_db").unwrap()));
 
 pub fn encrypt_file_key_for_peer(file_key: &[u8; 32], recipient_pub: &x25519_dalek::PublicKey) -> Vec<u8> {
-    let ephemeral = x25519_dalek::EphemeralSecret::random_from_rng(rand::thread_rng());
-    let shared = ephemeral.diffie_hellman(recipient_pub);
-    let mut encrypted = file_key.clone();
-    for (b, k) in encrypted.iter_mut().zip(shared.as_bytes()) {
-        *b ^= k;
-    }
-    encrypted.to_vec()
+    crate::crypto::wrap_file_key(file_key, recipient_pub).unwrap_or_default()
 }
 
 // Stub: send encrypted file key to recipient peer
@@ -874,10 +1504,15 @@ pub async fn send_file_key_exchange(peer: &PeerId, file_id: &str, encrypted_key:
     swarm.behaviour_mut().file_exchange.send_request(peer, data);
 }
 
-fn handle_file_request(request: &[u8]) -> Vec<u8> {
+fn handle_file_request(peer: &PeerId, local: &PeerId, request: &[u8]) -> Vec<u8> {
     if let Ok(msg) = bincode::deserialize::<P2PMessage>(request) {
         match msg {
             P2PMessage::FileKeyExchange { file_id, encrypted_key, from, to } => {
+                // Only the node that placed our copy may grant keys to it
+                if replication::origin(&file_id) != Some(peer.to_string()) {
+                    println!("Rejected file key for file {} from {}: not the file's origin", file_id, peer);
+                    return access::AccessError::NotPermitted.response();
+                }
                 println!("Received file key for file {} from {} to {}", file_id, from, to);
                 // Integrate with storage to update shared_keys for the recipient
                 use uuid::Uuid;
@@ -889,9 +1524,14 @@ fn handle_file_request(request: &[u8]) -> Vec<u8> {
                     }
                 }
             }
-            P2PMessage::FileChunkRequest { file_id, chunk_index, chunk_size, signature } => {
+            P2PMessage::FileChunkRequest { ref file_id, chunk_index, chunk_size, ref signature } => {
                 use std::fs::OpenOptions;
                 use std::io::{Seek, SeekFrom, Read};
+                let meta = match authorize_file_request(peer, local, &msg, signature.as_deref(), file_id) {
+                    Ok(meta) => meta,
+                    Err(e) => return e.response(),
+                };
+                let file_id = meta.file_id.to_string();
                 let file_path = format!("files/{}.bin", file_id);
                 let mut file = match OpenOptions::new().read(true).open(&file_path) {
                     Ok(f) => f,
@@ -913,6 +1553,44 @@ fn handle_file_request(request: &[u8]) -> Vec<u8> {
                 let resp = P2PMessage::FileChunkResponse { file_id, chunk_index, data: buf };
                 return bincode::serialize(&resp).unwrap();
             }
+            P2PMessage::FileManifestRequest { ref file_id, chunk_size, ref signature } => {
+                let meta = match authorize_file_request(peer, local, &msg, signature.as_deref(), file_id) {
+                    Ok(meta) => meta,
+                    Err(e) => return e.response(),
+                };
+                if chunk_size < download::MIN_CHUNK_SIZE {
+                    reputation::report(peer, Offence::MalformedMessage);
+                    return vec![];
+                }
+                let file_id = meta.file_id.to_string();
+                return match download::Manifest::of_file(std::path::Path::new(&format!("files/{}.bin", file_id)), chunk_size) {
+                    Ok(manifest) => bincode::serialize(&P2PMessage::FileManifestResponse { file_id, manifest }).unwrap_or_default(),
+                    Err(_) => access::AccessError::NotFound.response(),
+                };
+            }
+            P2PMessage::ReplicaOffer { meta, manifest, shard } => return replication::handle_offer(peer, meta, manifest, shard),
+            P2PMessage::ReplicaChunk { ref file_id, chunk_index, ref data } => return replication::handle_chunk(peer, file_id, chunk_index, data),
+            P2PMessage::ReplicaCheck { ref file_id } => return replication::handle_check(file_id),
+            P2PMessage::ReplicaDrop { ref file_id } => return replication::handle_drop(peer, file_id),
+            P2PMessage::ShardRequest { ref file_id, piece, ref signature } => {
+                let meta = match authorize_shard_request(peer, local, &msg, signature.as_deref(), file_id) {
+                    Ok(meta) => meta,
+                    Err(e) => return e.response(),
+                };
+                return erasure::handle_request(&meta.file_id.to_string(), piece);
+            }
+            P2PMessage::FileListRequest { ref signature } => {
+                let allowed = access::verify_request(peer, local, &msg, signature.as_deref()).and_then(|_| access::check_peer(peer));
+                if let Err(e) = allowed {
+                    refused(peer, "list", &e);
+                    return e.response();
+                }
+                let files = P2P_STORAGE.list_metadata().unwrap_or_default().iter()
+                    .filter(|meta| access::check_file(peer, meta).is_ok())
+                    .map(crate::confidential::public_view)
+                    .collect();
+                return bincode::serialize(&P2PMessage::FileListResponse { files }).unwrap_or_default();
+            }
             P2PMessage::ModelUpdate { weights, epoch } => {
                 if let Ok(remote_model) = bincode::deserialize::<CFModel>(&weights) {
                     // Aggregate with local model (assume ai::LOCAL_MODEL is a static Mutex<CFModel>)
@@ -921,13 +1599,86 @@ fn handle_file_request(request: &[u8]) -> Vec<u8> {
                     println!("Aggregated model update from peer (epoch {})", epoch);
                 }
             }
+            P2PMessage::ShareRevocation { revocation, signature } => {
+                let signed = bincode::serialize(&revocation).unwrap_or_default();
+                if !verify_signature(&peer.to_string(), &signed, &signature) {
+                    println!("Rejected share revocation for file {} from {}: bad signature", revocation.file_id, peer);
+                    reputation::report(peer, Offence::BadSignature);
+                    return vec![];
+                }
+                // Only the node that placed our copy speaks for the file
+                if replication::origin(&revocation.file_id.to_string()) != Some(peer.to_string()) {
+                    println!("Rejected share revocation for file {} from {}: not the file's origin", revocation.file_id, peer);
+                    return vec![];
+                }
+                if let Err(e) = apply_share_revocation(peer, revocation) {
+                    println!("Failed to apply share revocation from {}: {}", peer, e);
+                }
+            }
             // ... handle other message types
             _ => {}
         }
+    } else {
+        reputation::report(peer, Offence::MalformedMessage);
     }
     vec![]
 }
 
+/// Runs every access check for a chunk request and returns the file's metadata.
+fn authorize_file_request(
+    peer: &PeerId,
+    local: &PeerId,
+    request: &P2PMessage,
+    signature: Option<&[u8]>,
+    file_id: &str,
+) -> Result<crate::storage::FileMetadata, access::AccessError> {
+    let result = access::verify_request(peer, local, request, signature)
+        .and_then(|_| access::check_peer(peer))
+        .and_then(|_| {
+            let file_uuid = uuid::Uuid::parse_str(file_id).map_err(|_| access::AccessError::NotFound)?;
+            P2P_STORAGE.get_metadata(&file_uuid).ok().flatten().ok_or(access::AccessError::NotFound)
+        })
+        .and_then(|meta| access::check_file(peer, &meta).map(|_| meta));
+    if let Err(e) = &result {
+        refused(peer, file_id, e);
+    }
+    result
+}
+
+/// Like `authorize_file_request`, for a shard this node holds; the node that placed
+/// the shard may always read it back, to rebuild the file or regenerate other shards.
+fn authorize_shard_request(
+    peer: &PeerId,
+    local: &PeerId,
+    request: &P2PMessage,
+    signature: Option<&[u8]>,
+    file_id: &str,
+) -> Result<crate::storage::FileMetadata, access::AccessError> {
+    let result = access::verify_request(peer, local, request, signature)
+        .and_then(|_| access::check_peer(peer))
+        .and_then(|_| {
+            let origin = replication::shard_origin(file_id).ok_or(access::AccessError::NotFound)?;
+            let file_uuid = uuid::Uuid::parse_str(file_id).map_err(|_| access::AccessError::NotFound)?;
+            let meta = P2P_STORAGE.get_metadata(&file_uuid).ok().flatten().ok_or(access::AccessError::NotFound)?;
+            if origin == peer.to_string() {
+                return Ok(meta);
+            }
+            access::check_file(peer, &meta).map(|_| meta)
+        });
+    if let Err(e) = &result {
+        refused(peer, file_id, e);
+    }
+    result
+}
+
+/// Audits a refused request; a bad signature also counts against the peer.
+fn refused(peer: &PeerId, target: &str, error: &access::AccessError) {
+    if matches!(error, access::AccessError::BadSignature) {
+        reputation::report(peer, Offence::BadSignature);
+    }
+    crate::audit::record(&P2P_STORAGE, &format!("peer:{}", peer), "p2p_request", Some(target), false, Some(&error.to_string()));
+}
+
 // In the event loop, handle incoming FileChunkResponse messages
 // ... inside RequestResponseMessage::Response ...
 
@@ -1003,9 +1754,75 @@ pub fn load_allowed_peers() -> anyhow::Result<()> {
     Ok(())
 }
 
-fn verify_signature(_peer_id: &str, _data: &[u8], _signature: &[u8]) -> bool {
-    // TODO: Implement real signature verification using crypto module
-    true // Accept all for now
+pub fn block_peer(peer_id: &str) {
+    let mut blocked = BLOCKED_PEERS.lock().unwrap();
+    if !blocked.iter().any(|p| p == peer_id) {
+        blocked.push(peer_id.to_string());
+    }
+    drop(blocked);
+    save_blocked_peers().ok();
+}
+
+/// Takes a peer off the blocklist and lifts any automatic ban; returns whether it was
+/// blocked or banned.
+pub fn unblock_peer(peer_id: &str) -> bool {
+    let mut blocked = BLOCKED_PEERS.lock().unwrap();
+    let before = blocked.len();
+    blocked.retain(|p| p != peer_id);
+    let was_blocked = blocked.len() != before;
+    drop(blocked);
+    save_blocked_peers().ok();
+    reputation::pardon(peer_id) || was_blocked
+}
+
+pub fn list_blocked_peers() -> Vec<String> {
+    BLOCKED_PEERS.lock().unwrap().clone()
+}
+
+pub fn save_blocked_peers() -> anyhow::Result<()> {
+    let json = serde_json::to_string_pretty(&list_blocked_peers())?;
+    std::fs::write("blocked_peers.json", json)?;
+    Ok(())
+}
+
+pub fn load_blocked_peers() -> anyhow::Result<()> {
+    if let Ok(data) = std::fs::read_to_string("blocked_peers.json") {
+        *BLOCKED_PEERS.lock().unwrap() = serde_json::from_str(&data)?;
+    }
+    Ok(())
+}
+
+// Peer IDs of Ed25519 identities inline the public key, so the signer's key can be
+// recovered from the peer ID itself.
+fn verify_signature(peer_id: &str, data: &[u8], signature: &[u8]) -> bool {
+    let peer = match PeerId::from_str(peer_id) {
+        Ok(p) => p,
+        Err(_) => return false,
+    };
+    let multihash: &libp2p::multihash::Multihash<64> = peer.as_ref();
+    if multihash.code() != 0 {
+        return false;
+    }
+    match identity::PublicKey::try_decode_protobuf(multihash.digest()) {
+        Ok(public_key) => public_key.verify(data, signature),
+        Err(_) => false,
+    }
+}
+
+/// Drops the revoked user's wrapped key from our copy of the file's metadata. When the
+/// owner rekeyed, our ciphertext and keys are stale, so the copy is dropped and the
+/// origin re-replicates it.
+fn apply_share_revocation(peer: &PeerId, mut revocation: crate::storage::ShareRevocation) -> anyhow::Result<()> {
+    if revocation.rekeyed {
+        replication::forget_hosted(&revocation.file_id.to_string());
+    } else if let Some(mut meta) = P2P_STORAGE.get_metadata(&revocation.file_id)? {
+        meta.shared_keys.remove(&revocation.revoked_user);
+        crate::confidential::forget_reader(&mut meta, &revocation.revoked_user);
+        P2P_STORAGE.insert_metadata(&meta)?;
+    }
+    println!("Share of file {} revoked for {} by {} (via {})", revocation.file_id, revocation.revoked_user, revocation.revoked_by, peer);
+    revocation.origin_peer = Some(peer.to_string());
+    P2P_STORAGE.record_share_revocation(&revocation)
 }
 
 // Messaging helper functions
@@ -1061,7 +1878,7 @@ fn save_user_status(status: &crate::models::UserStatus) {
     }
 }
 
-fn handle_messaging_request(request: &[u8]) -> Vec<u8> {
+fn handle_messaging_request(peer: &PeerId, request: &[u8]) -> Vec<u8> {
     match bincode::deserialize::<P2PMessage>(request) {
         Ok(msg) => {
             match msg {
@@ -1106,40 +1923,48 @@ fn handle_messaging_request(request: &[u8]) -> Vec<u8> {
         }
         Err(e) => {
             println!("❌ Failed to deserialize message: {}", e);
+            reputation::report(peer, Offence::MalformedMessage);
             return b"ERROR".to_vec();
         }
     }
 }
 
-fn handle_discovery_request(request: &[u8]) -> Vec<u8> {
+fn handle_discovery_request(peer: &PeerId, local_peer_id: &PeerId, request: &[u8]) -> Vec<u8> {
     if let Ok(msg) = bincode::deserialize::<P2PMessage>(request) {
         match msg {
             P2PMessage::PeerPing { timestamp, peer_id } => {
-                // Respond with pong
-                let pong_msg = P2PMessage::PeerPong {
-                    timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
-                    peer_id,
-                };
+                let pong_msg = P2PMessage::PeerPong { timestamp, peer_id };
                 bincode::serialize(&pong_msg).unwrap_or_default()
             }
-            P2PMessage::PeerDiscovery { peer_id, addresses, user_info } => {
-                // Store discovered peer information
-                let discovered_peer = DiscoveredPeer {
-                    peer_id,
-                    addresses,
-                    last_seen: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
-                    user_info,
+            P2PMessage::PeerDiscovery { addresses, user_info, .. } => {
+                // Filed under the peer that sent it, whatever ID it claims
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+                let mut peers = DISCOVERED_PEERS.lock().unwrap();
+                let entry = peers.entry(peer.to_string()).or_insert_with(|| DiscoveredPeer {
+                    peer_id: peer.to_string(),
+                    addresses: Vec::new(),
+                    last_seen: now,
+                    user_info: None,
                     is_online: true,
                     latency_ms: None,
-                };
-                
-                DISCOVERED_PEERS.lock().unwrap().insert(discovered_peer.peer_id.clone(), discovered_peer);
+                    agent_version: None,
+                    protocols: Vec::new(),
+                });
+                for addr in addresses.into_iter().filter(|a| a.parse::<Multiaddr>().is_ok()) {
+                    if !entry.addresses.contains(&addr) {
+                        entry.addresses.push(addr);
+                    }
+                }
+                entry.user_info = user_info.or(entry.user_info.take());
+                entry.last_seen = now;
+                entry.is_online = true;
+                drop(peers);
                 save_discovered_peers().ok();
-                
+
                 // Respond with our own peer info
                 let response = P2PMessage::PeerDiscovery {
-                    peer_id: "local".to_string(),
-                    addresses: vec!["/ip4/0.0.0.0/tcp/2093".to_string()],
+                    peer_id: local_peer_id.to_string(),
+                    addresses: local_dial_addrs(local_peer_id).iter().map(|a| a.to_string()).collect(),
                     user_info: None,
                 };
                 bincode::serialize(&response).unwrap_or_default()
@@ -1147,10 +1972,25 @@ fn handle_discovery_request(request: &[u8]) -> Vec<u8> {
             _ => Vec::new(),
         }
     } else {
+        reputation::report(peer, Offence::MalformedMessage);
         Vec::new()
     }
 }
 
+/// Full addresses this node can be dialled at, most preferred transport first.
+fn local_dial_addrs(local_peer_id: &PeerId) -> Vec<Multiaddr> {
+    transports::listen_addrs()
+        .into_iter()
+        .chain(nat::status().relayed_addresses)
+        .map(|mut addr| {
+            if !matches!(addr.iter().last(), Some(libp2p::multiaddr::Protocol::P2p(_))) {
+                addr.push(libp2p::multiaddr::Protocol::P2p(*local_peer_id));
+            }
+            addr
+        })
+        .collect()
+}
+
 pub fn load_discovered_peers() -> anyhow::Result<()> {
     if let Ok(data) = fs::read_to_string("discovered_peers.json") {
         if let Ok(peers) = serde_json::from_str::<HashMap<String, DiscoveredPeer>>(&data) {
@@ -0,0 +1,191 @@
+// NAT traversal: reachability detection, relay reservations and hole punching.
+//
+// AutoNAT asks other nodes, bootstrap nodes first, to dial this node back on the
+// addresses it listens on or has been seen at. Once enough of them fail, the node
+// considers itself private and listens through every bootstrap node over circuit relay
+// (`<bootstrap addr>/p2p/<bootstrap id>/p2p-circuit`), which reserves a slot there, so
+// peers can still reach it through the relay. Every node runs a relay server, so any
+// public bootstrap node can serve as one. If the node later turns out to be public, the
+// reservations are given up.
+//
+// A peer that connects over a relayed circuit tries to replace it with a direct
+// connection: DCUtR has both sides dial each other at the same moment on the addresses
+// identify saw them at, which opens most NATs. If that fails the relayed connection
+// stays in use.
+//
+// AutoNAT only trusts dial-backs over public IPs, so a node on a LAN can't be told it is
+// public by a neighbour. Setting DAFS_AUTONAT_PRIVATE_IPS=1 lifts that, for deployments
+// and tests (e.g. network namespaces) where every node has a private address.
+
+use super::{MyBehaviour, BOOTSTRAP_NODES};
+use libp2p::core::transport::ListenerId;
+use libp2p::multiaddr::Protocol;
+use libp2p::{autonat, Multiaddr, PeerId, Swarm};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+static STATUS: Lazy<Mutex<NatStatus>> = Lazy::new(|| Mutex::new(NatStatus::default()));
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Reachability {
+    #[default]
+    Unknown,
+    Public,
+    Private,
+}
+
+impl std::fmt::Display for Reachability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Reachability::Unknown => write!(f, "unknown"),
+            Reachability::Public => write!(f, "public"),
+            Reachability::Private => write!(f, "private"),
+        }
+    }
+}
+
+/// How reachable this node is, as shown in node status.
+#[derive(Debug, Clone, Default)]
+pub struct NatStatus {
+    pub reachability: Reachability,
+    /// The address a peer dialled back on, when public
+    pub public_address: Option<Multiaddr>,
+    /// Relays holding a reservation for this node
+    pub relays: Vec<PeerId>,
+    /// Addresses peers can reach this node at through a relay
+    pub relayed_addresses: Vec<Multiaddr>,
+    /// Relayed connections upgraded to direct ones, and attempts that failed
+    pub direct_upgrades: u64,
+    pub failed_upgrades: u64,
+}
+
+pub fn status() -> NatStatus {
+    STATUS.lock().unwrap().clone()
+}
+
+/// The AutoNAT behaviour, probing through the bootstrap nodes as well as connected peers.
+pub fn autonat_behaviour(local_peer_id: PeerId) -> autonat::Behaviour {
+    let private_ips = std::env::var("DAFS_AUTONAT_PRIVATE_IPS").is_ok_and(|v| v == "1" || v == "true");
+    let mut autonat = autonat::Behaviour::new(local_peer_id, autonat::Config {
+        boot_delay: Duration::from_secs(5),
+        retry_interval: Duration::from_secs(60),
+        only_global_ips: !private_ips,
+        ..Default::default()
+    });
+    for (peer, addr) in BOOTSTRAP_NODES.lock().unwrap().iter() {
+        autonat.add_server(*peer, Some(addr.clone()));
+    }
+    autonat
+}
+
+pub fn is_relayed(addr: &Multiaddr) -> bool {
+    addr.iter().any(|p| p == Protocol::P2pCircuit)
+}
+
+/// The relay listeners this node has opened, by relay.
+#[derive(Default)]
+pub struct Reservations {
+    listeners: HashMap<PeerId, ListenerId>,
+}
+
+impl Reservations {
+    /// Records a change of reachability, reserving slots on the bootstrap nodes when the
+    /// node turned out to be private and giving them up when it is public.
+    pub fn on_status(&mut self, swarm: &mut Swarm<MyBehaviour>, new: autonat::NatStatus) {
+        let reachability = match &new {
+            autonat::NatStatus::Public(_) => Reachability::Public,
+            autonat::NatStatus::Private => Reachability::Private,
+            autonat::NatStatus::Unknown => Reachability::Unknown,
+        };
+        println!("NAT status: {}", reachability);
+        {
+            let mut status = STATUS.lock().unwrap();
+            status.reachability = reachability;
+            status.public_address = match new {
+                autonat::NatStatus::Public(addr) => Some(addr),
+                _ => None,
+            };
+        }
+        match reachability {
+            Reachability::Private => {
+                let relays: Vec<PeerId> = BOOTSTRAP_NODES.lock().unwrap().iter().map(|(peer, _)| *peer).collect();
+                for relay in relays {
+                    self.reserve(swarm, &relay);
+                }
+            }
+            Reachability::Public => {
+                for (_, listener) in self.listeners.drain() {
+                    swarm.remove_listener(listener);
+                }
+            }
+            Reachability::Unknown => {}
+        }
+    }
+
+    /// Listens through `relay` if it is a bootstrap node, this node is private and no
+    /// reservation is open there yet.
+    pub fn reserve(&mut self, swarm: &mut Swarm<MyBehaviour>, relay: &PeerId) {
+        if STATUS.lock().unwrap().reachability != Reachability::Private || self.listeners.contains_key(relay) {
+            return;
+        }
+        let Some(addr) = BOOTSTRAP_NODES.lock().unwrap().iter().find(|(peer, _)| peer == relay).map(|(_, addr)| addr.clone()) else {
+            return;
+        };
+        let mut circuit = addr;
+        if !circuit.iter().any(|p| matches!(p, Protocol::P2p(_))) {
+            circuit.push(Protocol::P2p(*relay));
+        }
+        circuit.push(Protocol::P2pCircuit);
+        match swarm.listen_on(circuit.clone()) {
+            Ok(listener) => {
+                println!("Reserving a relay slot at {}", circuit);
+                self.listeners.insert(*relay, listener);
+            }
+            Err(e) => println!("Failed to listen through relay {}: {}", relay, e),
+        }
+    }
+
+    /// Forgets a relay listener that closed, so it can be opened again.
+    pub fn on_listener_closed(&mut self, listener: ListenerId) {
+        let Some(relay) = self.listeners.iter().find(|(_, id)| **id == listener).map(|(peer, _)| *peer) else {
+            return;
+        };
+        self.listeners.remove(&relay);
+        let mut status = STATUS.lock().unwrap();
+        status.relays.retain(|p| *p != relay);
+        status.relayed_addresses.retain(|a| !a.iter().any(|p| p == Protocol::P2p(relay)));
+    }
+}
+
+pub fn on_reservation(relay: PeerId, accepted: bool) {
+    let mut status = STATUS.lock().unwrap();
+    if accepted {
+        if !status.relays.contains(&relay) {
+            status.relays.push(relay);
+        }
+    } else {
+        status.relays.retain(|p| *p != relay);
+    }
+}
+
+pub fn on_listen_addr(addr: &Multiaddr, added: bool) {
+    if !is_relayed(addr) {
+        return;
+    }
+    let mut status = STATUS.lock().unwrap();
+    status.relayed_addresses.retain(|a| a != addr);
+    if added {
+        status.relayed_addresses.push(addr.clone());
+    }
+}
+
+pub fn on_upgrade(succeeded: bool) {
+    let mut status = STATUS.lock().unwrap();
+    if succeeded {
+        status.direct_upgrades += 1;
+    } else {
+        status.failed_upgrades += 1;
+    }
+}
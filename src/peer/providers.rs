@@ -0,0 +1,89 @@
+// Kademlia provider records: which nodes hold which files.
+//
+// A node announces itself as a provider of every file whose contents it holds
+// (`files/<file_id>.bin`), keyed by file ID, so any node can ask the DHT who has a
+// file without knowing a peer up front. Holders of an erasure-coded shard
+// (`shards/<file_id>.bin`) are recorded under a separate key for the file's shards. Records are announced when the node starts,
+// again once it first joins the routing table, and whenever `sync` finds a file added
+// since; they are withdrawn locally when the contents go away. Kademlia republishes the records this node provides every
+// REPUBLISH_INTERVAL; elsewhere they expire after RECORD_TTL unless refreshed, so
+// records for a node that goes offline age out of the DHT.
+
+use super::P2P_STORAGE;
+use libp2p::kad::{self as kad, store::{MemoryStore, MemoryStoreConfig, RecordStore}};
+use libp2p::PeerId;
+use std::collections::HashSet;
+use std::path::Path;
+use std::time::Duration;
+
+pub const REPUBLISH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+pub const RECORD_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// How often local storage is checked for files to announce or withdraw
+pub const SYNC_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// The DHT key under which holders of `file_id` are recorded.
+pub fn file_key(file_id: &str) -> kad::RecordKey {
+    kad::RecordKey::new(&format!("/dafs/file/{}", file_id))
+}
+
+/// The DHT key under which holders of shards of `file_id` are recorded.
+pub fn shards_key(file_id: &str) -> kad::RecordKey {
+    kad::RecordKey::new(&format!("/dafs/shards/{}", file_id))
+}
+
+/// The Kademlia behaviour, in server mode so other nodes can store and query provider
+/// records on this one.
+pub fn behaviour(local_peer_id: PeerId) -> kad::Behaviour<MemoryStore> {
+    let store = MemoryStore::with_config(local_peer_id, MemoryStoreConfig {
+        max_provided_keys: 1 << 16,
+        ..Default::default()
+    });
+    let mut config = kad::Config::default();
+    config
+        .set_provider_publication_interval(Some(REPUBLISH_INTERVAL))
+        .set_provider_record_ttl(Some(RECORD_TTL))
+        .set_query_timeout(Duration::from_secs(30));
+    let mut kademlia = kad::Behaviour::with_config(local_peer_id, store, config);
+    kademlia.set_mode(Some(kad::Mode::Server));
+    kademlia
+}
+
+/// Keys for the files whose contents, or a shard of them, this node holds.
+fn held_keys() -> HashSet<kad::RecordKey> {
+    let mut keys = HashSet::new();
+    for meta in P2P_STORAGE.list_metadata().unwrap_or_default() {
+        let id = meta.file_id.to_string();
+        if Path::new(&format!("files/{}.bin", id)).exists() {
+            keys.insert(file_key(&id));
+        }
+        if super::erasure::shard_path(&id).exists() {
+            keys.insert(shards_key(&id));
+        }
+    }
+    keys
+}
+
+/// Brings the records this node provides in line with the files it holds. Returns how
+/// many files were announced and withdrawn.
+pub fn sync(kademlia: &mut kad::Behaviour<MemoryStore>) -> (usize, usize) {
+    let held = held_keys();
+    let provided: HashSet<kad::RecordKey> = kademlia.store_mut().provided().map(|r| r.key.clone()).collect();
+    let mut announced = 0;
+    for key in held.difference(&provided) {
+        if kademlia.start_providing(key.clone()).is_ok() {
+            announced += 1;
+        }
+    }
+    let stale: Vec<_> = provided.difference(&held).cloned().collect();
+    for key in &stale {
+        kademlia.stop_providing(key);
+    }
+    (announced, stale.len())
+}
+
+/// Re-announces every record this node provides, e.g. once the first peer is known.
+/// Returns how many were sent.
+pub fn republish(kademlia: &mut kad::Behaviour<MemoryStore>) -> usize {
+    let keys: Vec<kad::RecordKey> = kademlia.store_mut().provided().map(|r| r.key.clone()).collect();
+    keys.into_iter().filter(|key| kademlia.start_providing(key.clone()).is_ok()).count()
+}
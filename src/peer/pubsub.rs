@@ -0,0 +1,260 @@
+// Publish/subscribe over gossipsub: chat rooms, presence and file announcements.
+//
+// Every node subscribes to the presence and announcement topics. Each chat room has its
+// own topic, `dafs/room/<room_id>`, which only the nodes that created or joined the room
+// subscribe to, so room traffic reaches members and the peers relaying for them and no
+// one else. Rooms this node is in are kept in `joined_rooms.json` and subscribed to again
+// on startup. Public rooms are also announced on the announcement topic, so others can
+// find them; a private room's details are only sent on its own topic, to a member
+// joining it.
+//
+// Messages are signed by their author (strict validation) and their ID is the hash of
+// their content, so gossipsub drops copies of a message it has already seen. Each
+// message is also checked here before it is delivered or forwarded: the author must not
+// be blocked or banned, the payload must decode and belong on its topic, and chat
+// messages and presence updates must not be stale or from the future. A chat message
+// already delivered under the same ID, or a presence update older than the one held, is
+// ignored. Messages that fail a check are rejected, which counts against the peer that
+// forwarded them.
+
+use super::{access, P2PMessage};
+use libp2p::gossipsub::{self, IdentTopic, MessageAcceptance, TopicHash};
+use libp2p::{identity, PeerId};
+use once_cell::sync::Lazy;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const PRESENCE_TOPIC: &str = "dafs/presence";
+pub const ANNOUNCE_TOPIC: &str = "dafs/announcements";
+const ROOM_TOPIC_PREFIX: &str = "dafs/room/";
+const MAX_MESSAGE_SIZE: usize = 256 * 1024;
+/// How far ahead of our clock a message may be stamped
+const MAX_CLOCK_SKEW_SECS: u64 = 5 * 60;
+/// Chat messages older than this are not delivered
+const MAX_MESSAGE_AGE_SECS: u64 = 24 * 60 * 60;
+/// Chat message IDs remembered for de-duplication
+const SEEN_CAPACITY: usize = 4096;
+
+static SEEN_MESSAGES: Lazy<Mutex<Seen>> = Lazy::new(|| Mutex::new(Seen::default()));
+
+// Newest presence update per user ID
+static PRESENCE: Lazy<Mutex<HashMap<String, u64>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Peers that announced a file, by file ID, with when they did
+static ANNOUNCED: Lazy<Mutex<HashMap<String, HashMap<PeerId, u64>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// The most recent IDs seen, oldest dropped first.
+#[derive(Default)]
+struct Seen {
+    order: VecDeque<String>,
+    ids: HashSet<String>,
+}
+
+impl Seen {
+    /// Records `id`; false if it was already there.
+    fn insert(&mut self, id: &str) -> bool {
+        if !self.ids.insert(id.to_string()) {
+            return false;
+        }
+        self.order.push_back(id.to_string());
+        if self.order.len() > SEEN_CAPACITY
+            && let Some(oldest) = self.order.pop_front()
+        {
+            self.ids.remove(&oldest);
+        }
+        true
+    }
+}
+
+enum Topic {
+    Room(String),
+    Presence,
+    Announcements,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+pub fn room_topic(room_id: &str) -> IdentTopic {
+    IdentTopic::new(format!("{}{}", ROOM_TOPIC_PREFIX, room_id))
+}
+
+fn topic_of(hash: &TopicHash) -> Option<Topic> {
+    match hash.as_str() {
+        PRESENCE_TOPIC => Some(Topic::Presence),
+        ANNOUNCE_TOPIC => Some(Topic::Announcements),
+        other => other.strip_prefix(ROOM_TOPIC_PREFIX).map(|id| Topic::Room(id.to_string())),
+    }
+}
+
+/// The gossipsub behaviour: messages signed by this node's identity, identified by
+/// their content, and held back until `validate` has passed them.
+pub fn behaviour(keypair: &identity::Keypair) -> gossipsub::Behaviour {
+    let content_id = |message: &gossipsub::Message| gossipsub::MessageId::from(hex::encode(Sha256::digest(&message.data)));
+    let config = gossipsub::ConfigBuilder::default()
+        .validation_mode(gossipsub::ValidationMode::Strict)
+        .validate_messages()
+        .message_id_fn(content_id)
+        .max_transmit_size(MAX_MESSAGE_SIZE)
+        .duplicate_cache_time(Duration::from_secs(10 * 60))
+        .build()
+        .unwrap();
+    gossipsub::Behaviour::new(gossipsub::MessageAuthenticity::Signed(keypair.clone()), config).unwrap()
+}
+
+/// Subscribes to presence, announcements and every room this node is in.
+pub fn subscribe_all(gossipsub: &mut gossipsub::Behaviour) {
+    let rooms = joined_rooms();
+    let topics = [IdentTopic::new(PRESENCE_TOPIC), IdentTopic::new(ANNOUNCE_TOPIC)]
+        .into_iter()
+        .chain(rooms.iter().map(|id| room_topic(id)));
+    for topic in topics {
+        if let Err(e) = gossipsub.subscribe(&topic) {
+            println!("Failed to subscribe to {}: {:?}", topic, e);
+        }
+    }
+}
+
+/// Publishes `message` on `topic`. Having no peers on the topic yet is not a failure.
+pub fn publish(gossipsub: &mut gossipsub::Behaviour, topic: IdentTopic, message: &P2PMessage) -> bool {
+    let data = match bincode::serialize(message) {
+        Ok(data) => data,
+        Err(_) => return false,
+    };
+    match gossipsub.publish(topic.clone(), data) {
+        Ok(_) | Err(gossipsub::PublishError::Duplicate) => true,
+        Err(gossipsub::PublishError::InsufficientPeers) => {
+            println!("No peers on {} yet", topic);
+            true
+        }
+        Err(e) => {
+            println!("Failed to publish on {}: {}", topic, e);
+            false
+        }
+    }
+}
+
+pub fn joined_rooms() -> Vec<String> {
+    std::fs::read_to_string("joined_rooms.json")
+        .ok()
+        .and_then(|data| serde_json::from_str(&data).ok())
+        .unwrap_or_default()
+}
+
+fn save_joined_rooms(rooms: &[String]) {
+    if let Ok(json) = serde_json::to_string_pretty(rooms) {
+        let _ = std::fs::write("joined_rooms.json", json);
+    }
+}
+
+pub fn remember_room(room_id: &str) {
+    let mut rooms = joined_rooms();
+    if !rooms.iter().any(|r| r == room_id) {
+        rooms.push(room_id.to_string());
+        save_joined_rooms(&rooms);
+    }
+}
+
+pub fn forget_room(room_id: &str) {
+    let mut rooms = joined_rooms();
+    rooms.retain(|r| r != room_id);
+    save_joined_rooms(&rooms);
+}
+
+pub fn load_chat_room(room_id: &str) -> Option<crate::models::ChatRoom> {
+    let data = std::fs::read_to_string(format!("chat_rooms/{}.json", room_id)).ok()?;
+    serde_json::from_str(&data).ok()
+}
+
+/// Records a chat message this node sent, so its own copy isn't delivered twice.
+pub fn mark_seen(message_id: &str) {
+    SEEN_MESSAGES.lock().unwrap().insert(message_id);
+}
+
+/// Checks a received message. Returns the decoded message to deliver, or how gossipsub
+/// should treat it.
+pub(super) fn validate(message: &gossipsub::Message) -> Result<P2PMessage, MessageAcceptance> {
+    let author = message.source.ok_or(MessageAcceptance::Reject)?;
+    if access::check_peer(&author).is_err() {
+        return Err(MessageAcceptance::Reject);
+    }
+    let topic = topic_of(&message.topic).ok_or(MessageAcceptance::Reject)?;
+    let decoded: P2PMessage = bincode::deserialize(&message.data).map_err(|_| MessageAcceptance::Reject)?;
+    let now = now_secs();
+    let on_topic = match (&topic, &decoded) {
+        (Topic::Room(id), P2PMessage::ChatRoomCreate { room }) => room.id == *id,
+        (Topic::Room(id), P2PMessage::ChatRoomJoin { room_id, .. })
+        | (Topic::Room(id), P2PMessage::ChatRoomLeave { room_id, .. })
+        | (Topic::Room(id), P2PMessage::TypingIndicator { room_id, .. }) => room_id == id,
+        (Topic::Room(id), P2PMessage::ChatRoomMessage { room_id, message }) => {
+            if room_id != id || message.recipient_id != *id {
+                false
+            } else if message.timestamp > now + MAX_CLOCK_SKEW_SECS
+                || now.saturating_sub(message.timestamp) > MAX_MESSAGE_AGE_SECS
+                || !SEEN_MESSAGES.lock().unwrap().insert(&message.id)
+            {
+                return Err(MessageAcceptance::Ignore);
+            } else {
+                true
+            }
+        }
+        (Topic::Presence, P2PMessage::UserStatus { status }) => {
+            if status.last_seen > now + MAX_CLOCK_SKEW_SECS {
+                false
+            } else {
+                let mut presence = PRESENCE.lock().unwrap();
+                let newest = presence.entry(status.user_id.clone()).or_default();
+                if status.last_seen < *newest {
+                    return Err(MessageAcceptance::Ignore);
+                }
+                *newest = status.last_seen;
+                true
+            }
+        }
+        (Topic::Announcements, P2PMessage::FileAnnouncement { peer_id, announced_at, .. }) => {
+            *peer_id == author.to_string() && *announced_at <= now + MAX_CLOCK_SKEW_SECS
+        }
+        (Topic::Announcements, P2PMessage::ChatRoomCreate { room }) => !room.is_private,
+        _ => false,
+    };
+    if on_topic { Ok(decoded) } else { Err(MessageAcceptance::Reject) }
+}
+
+/// Acts on a message that passed `validate`.
+pub(super) fn deliver(author: &PeerId, message: P2PMessage) {
+    match message {
+        P2PMessage::ChatRoomCreate { room } => {
+            println!("🏠 Chat room announced: {}", room.name);
+            super::save_chat_room(&room);
+        }
+        P2PMessage::ChatRoomJoin { room_id, username } => println!("👤 {} joined chat room: {}", username, room_id),
+        P2PMessage::ChatRoomLeave { room_id, username } => println!("👤 {} left chat room: {}", username, room_id),
+        P2PMessage::TypingIndicator { .. } => {}
+        P2PMessage::ChatRoomMessage { room_id, message } => {
+            println!("💬 Chat message in room {} from {}: {:?}", room_id, message.sender_id, message.message_type);
+            super::save_chat_message(&room_id, &message);
+        }
+        P2PMessage::UserStatus { status } => {
+            println!("👤 User status update: {} is {}", status.username, if status.online { "online" } else { "offline" });
+            super::save_user_status(&status);
+        }
+        P2PMessage::FileAnnouncement { file_id, announced_at, .. } => {
+            ANNOUNCED.lock().unwrap().entry(file_id).or_default().insert(*author, announced_at);
+        }
+        _ => {}
+    }
+}
+
+/// Peers that announced `file_id` within the provider record lifetime.
+pub fn announced_holders(file_id: &str) -> Vec<PeerId> {
+    let cutoff = now_secs().saturating_sub(super::providers::RECORD_TTL.as_secs());
+    let mut announced = ANNOUNCED.lock().unwrap();
+    let Some(holders) = announced.get_mut(file_id) else {
+        return Vec::new();
+    };
+    holders.retain(|_, at| *at >= cutoff);
+    holders.keys().copied().collect()
+}
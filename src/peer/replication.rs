@@ -0,0 +1,548 @@
+// Automatic replication of files to other nodes.
+//
+// The node that received an upload (the file's origin) keeps `factor` copies of its
+// encrypted contents on other nodes. Targets are the reachable peers closest to the
+// file's DHT key, in XOR distance; a peer that is short of space refuses the offer and
+// the next closest is tried. Copies are pushed chunk by chunk, each checked by the
+// receiver against the offered manifest (which must be well formed: a bounded chunk size
+// and count, and chunk hashes that cover exactly the offered size), and the receiver stores the file's metadata as
+// offered, so it serves the copy under the same access rules as the origin and announces
+// itself as a provider. Offers are only taken for files this node holds nothing of, or
+// already hosts for the same origin, so a peer can't claim a file that is another's.
+//
+// Every CHECK_INTERVAL the origin asks each replica whether it still holds the file. A
+// replica that says it doesn't is replaced at once; one that can't be reached is kept for
+// the grace period, since nodes restart and links drop, and replaced after it. Replica
+// locations live in the `replicas` tree, keyed by file ID. Copies this node holds for
+// others are recorded in `hosted_replicas` and count against its replica capacity.
+//
+// Files whose storage policy is erasure coding get shards instead of copies (see
+// `erasure`); shards travel and are held the same way, under `shards/`.
+
+use super::download::Manifest;
+use super::erasure::{self, ShardInfo, StoragePolicy};
+use super::{access, P2PMessage, P2PNode, P2P_STORAGE};
+use crate::storage::FileMetadata;
+use libp2p::PeerId;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+const CHUNK_SIZE: usize = super::download::DEFAULT_CHUNK_SIZE;
+/// A copy whose origin stops sending chunks for this long is abandoned
+const INCOMING_TIMEOUT_SECS: u64 = 10 * 60;
+
+static CONFIG: Lazy<Mutex<ReplicationConfig>> = Lazy::new(|| Mutex::new(ReplicationConfig::default()));
+
+/// Copies being received, by file ID
+static INCOMING: Lazy<Mutex<HashMap<String, Incoming>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, Clone, Copy)]
+pub struct ReplicationConfig {
+    /// Copies to keep on other nodes, besides the origin's own
+    pub factor: usize,
+    /// How long an unreachable replica still counts before it is replaced
+    pub grace_secs: u64,
+    /// Bytes this node will hold for other nodes
+    pub capacity_bytes: u64,
+}
+
+impl Default for ReplicationConfig {
+    fn default() -> Self {
+        Self { factor: 2, grace_secs: 30 * 60, capacity_bytes: 10 * 1024 * 1024 * 1024 }
+    }
+}
+
+/// Sets the replication factor, grace period and capacity for this process.
+pub fn configure(config: ReplicationConfig) {
+    *CONFIG.lock().unwrap() = config;
+}
+
+pub fn config() -> ReplicationConfig {
+    *CONFIG.lock().unwrap()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Replica {
+    pub peer_id: String,
+    pub stored_at: u64,
+    /// Last time the peer confirmed it holds the file
+    pub last_seen: u64,
+    /// Whether the latest check reached the peer
+    pub reachable: bool,
+}
+
+/// Where a file's copies are, as tracked by its origin.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ReplicaSet {
+    pub file_id: String,
+    pub replicas: Vec<Replica>,
+}
+
+/// A copy or shard this node holds for another node.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Hosted {
+    origin: String,
+    size: u64,
+    stored_at: u64,
+    shard: Option<ShardInfo>,
+}
+
+struct Incoming {
+    origin: PeerId,
+    meta: FileMetadata,
+    manifest: Manifest,
+    shard: Option<ShardInfo>,
+    received: Vec<bool>,
+    /// Bytes of chunks written to the part file so far
+    written: u64,
+    updated_at: u64,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReplicationError {
+    #[error("File not found")]
+    NotFound,
+    #[error("Not enough replica capacity")]
+    NoCapacity,
+    #[error("No replica of this file is being received from this peer")]
+    UnexpectedChunk,
+    #[error("Chunk does not match the offered manifest")]
+    BadChunk,
+    #[error("Malformed manifest")]
+    BadManifest,
+    #[error("This node already holds the file for another owner")]
+    NotOrigin,
+    #[error("{0}")]
+    Storage(#[from] anyhow::Error),
+}
+
+impl ReplicationError {
+    fn response(&self) -> Vec<u8> {
+        bincode::serialize(&P2PMessage::RequestDenied { reason: self.to_string() }).unwrap_or_default()
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+fn content_path(file_id: &str) -> String {
+    format!("files/{}.bin", file_id)
+}
+
+/// Where a received copy, or shard, of `file_id` is kept.
+fn stored_path(file_id: &str, shard: bool) -> PathBuf {
+    if shard { erasure::shard_path(file_id) } else { PathBuf::from(content_path(file_id)) }
+}
+
+fn part_path(file_id: &str, shard: bool) -> PathBuf {
+    let mut name = stored_path(file_id, shard).into_os_string();
+    name.push(".replica");
+    PathBuf::from(name)
+}
+
+fn tree(name: &str) -> anyhow::Result<sled::Tree> {
+    P2P_STORAGE.open_tree(name)
+}
+
+pub fn replica_set(file_id: &str) -> anyhow::Result<ReplicaSet> {
+    match tree("replicas")?.get(file_id)? {
+        Some(bytes) => Ok(bincode::deserialize(&bytes)?),
+        None => Ok(ReplicaSet { file_id: file_id.to_string(), replicas: Vec::new() }),
+    }
+}
+
+fn save_replica_set(set: &ReplicaSet) -> anyhow::Result<()> {
+    tree("replicas")?.insert(set.file_id.as_bytes(), bincode::serialize(set)?)?;
+    Ok(())
+}
+
+fn hosted(file_id: &str) -> Option<Hosted> {
+    let bytes = tree("hosted_replicas").ok()?.get(file_id).ok()??;
+    bincode::deserialize(&bytes).ok()
+}
+
+/// The node a copy or shard this node holds was placed by.
+pub(super) fn origin(file_id: &str) -> Option<String> {
+    hosted(file_id).map(|h| h.origin)
+}
+
+/// The node a shard this node holds was placed by.
+pub(super) fn shard_origin(file_id: &str) -> Option<String> {
+    hosted(file_id).filter(|h| h.shard.is_some()).map(|h| h.origin)
+}
+
+/// The shard of `file_id` this node holds for another node.
+pub(super) fn hosted_shard(file_id: &str) -> Option<ShardInfo> {
+    hosted(file_id).and_then(|h| h.shard)
+}
+
+/// Whether `origin` may place a copy or shard of `file_id` here: a file this node
+/// already hosts for `origin`, or one it holds nothing of, neither its own nor another
+/// node's.
+fn may_host(file_id: &uuid::Uuid, origin: &PeerId) -> bool {
+    let id = file_id.to_string();
+    if INCOMING.lock().unwrap().get(&id).is_some_and(|i| i.origin != *origin) {
+        return false;
+    }
+    match hosted(&id) {
+        Some(h) => h.origin == origin.to_string(),
+        None => {
+            matches!(P2P_STORAGE.get_metadata(file_id), Ok(None))
+                && !Path::new(&content_path(&id)).exists()
+                && !erasure::shard_path(&id).exists()
+        }
+    }
+}
+
+fn hosted_bytes() -> u64 {
+    let stored: u64 = tree("hosted_replicas")
+        .map(|t| t.iter().values().flatten().filter_map(|b| bincode::deserialize::<Hosted>(&b).ok()).map(|h| h.size).sum())
+        .unwrap_or(0);
+    let incoming: u64 = INCOMING.lock().unwrap().values().map(|i| i.manifest.size).sum();
+    stored.saturating_add(incoming)
+}
+
+/// Answers an offer to hold a copy, or a shard, of a file.
+pub(super) fn handle_offer(origin: &PeerId, meta: FileMetadata, manifest: Manifest, shard: Option<ShardInfo>) -> Vec<u8> {
+    if let Err(e) = access::check_peer(origin) {
+        return e.response();
+    }
+    if !may_host(&meta.file_id, origin) {
+        return ReplicationError::NotOrigin.response();
+    }
+    let file_id = meta.file_id.to_string();
+    let accepted = |complete| bincode::serialize(&P2PMessage::ReplicaAccepted { file_id: file_id.clone(), complete }).unwrap_or_default();
+    let already_held = match &shard {
+        Some(shard) => hosted_shard(&file_id).as_ref() == Some(shard),
+        None => Path::new(&content_path(&file_id)).exists(),
+    };
+    if already_held {
+        return accepted(true);
+    }
+    let now = now_secs();
+    INCOMING.lock().unwrap().retain(|id, i| {
+        let stale = now.saturating_sub(i.updated_at) > INCOMING_TIMEOUT_SECS;
+        if stale {
+            let _ = std::fs::remove_file(part_path(id, i.shard.is_some()));
+        }
+        !stale
+    });
+    if !manifest.is_well_formed() {
+        return ReplicationError::BadManifest.response();
+    }
+    if hosted_bytes().saturating_add(manifest.size) > config().capacity_bytes {
+        return ReplicationError::NoCapacity.response();
+    }
+    let chunks = manifest.chunk_hashes.len();
+    let incoming = Incoming { origin: *origin, meta, manifest, shard, received: vec![false; chunks], written: 0, updated_at: now };
+    if chunks == 0 {
+        return match finish(&file_id, incoming) {
+            Ok(()) => accepted(true),
+            Err(e) => e.response(),
+        };
+    }
+    INCOMING.lock().unwrap().insert(file_id.clone(), incoming);
+    accepted(false)
+}
+
+/// Stores one pushed chunk; the last one completes the copy.
+pub(super) fn handle_chunk(origin: &PeerId, file_id: &str, chunk_index: usize, data: &[u8]) -> Vec<u8> {
+    let result = store_chunk(origin, file_id, chunk_index, data);
+    match result {
+        Ok(complete) => bincode::serialize(&P2PMessage::ReplicaStored { file_id: file_id.to_string(), chunk_index, complete }).unwrap_or_default(),
+        Err(e) => {
+            if matches!(e, ReplicationError::BadChunk) {
+                super::reputation::report(origin, super::Offence::ChunkHashMismatch);
+            }
+            e.response()
+        }
+    }
+}
+
+fn store_chunk(origin: &PeerId, file_id: &str, chunk_index: usize, data: &[u8]) -> Result<bool, ReplicationError> {
+    use sha2::{Digest, Sha256};
+    let mut incoming = INCOMING.lock().unwrap();
+    let entry = incoming.get_mut(file_id).filter(|i| i.origin == *origin).ok_or(ReplicationError::UnexpectedChunk)?;
+    let expected = entry.manifest.chunk_hashes.get(chunk_index).ok_or(ReplicationError::BadChunk)?;
+    if data.len() != entry.manifest.chunk_len(chunk_index) || hex::encode(Sha256::digest(data)) != *expected {
+        return Err(ReplicationError::BadChunk);
+    }
+    let offset = entry.manifest.chunk_offset(chunk_index).ok_or(ReplicationError::BadChunk)?;
+    let part = part_path(file_id, entry.shard.is_some());
+    if let Some(dir) = part.parent() {
+        std::fs::create_dir_all(dir).map_err(anyhow::Error::from)?;
+    }
+    let mut file = std::fs::OpenOptions::new().write(true).create(true).truncate(false).open(&part).map_err(anyhow::Error::from)?;
+    file.seek(SeekFrom::Start(offset)).map_err(anyhow::Error::from)?;
+    file.write_all(data).map_err(anyhow::Error::from)?;
+    if !entry.received[chunk_index] {
+        entry.written += data.len() as u64;
+    }
+    entry.received[chunk_index] = true;
+    entry.updated_at = now_secs();
+    if !entry.received.iter().all(|&r| r) {
+        return Ok(false);
+    }
+    let entry = incoming.remove(file_id).ok_or(ReplicationError::UnexpectedChunk)?;
+    drop(incoming);
+    finish(file_id, entry)?;
+    Ok(true)
+}
+
+/// Moves a received copy or shard into place and records it.
+fn finish(file_id: &str, incoming: Incoming) -> Result<(), ReplicationError> {
+    let is_shard = incoming.shard.is_some();
+    let part = part_path(file_id, is_shard);
+    if !may_host(&incoming.meta.file_id, &incoming.origin) {
+        let _ = std::fs::remove_file(&part);
+        return Err(ReplicationError::NotOrigin);
+    }
+    if incoming.manifest.size == 0 {
+        if let Some(dir) = part.parent() {
+            std::fs::create_dir_all(dir).map_err(anyhow::Error::from)?;
+        }
+        std::fs::write(&part, []).map_err(anyhow::Error::from)?;
+    }
+    std::fs::rename(&part, stored_path(file_id, is_shard)).map_err(anyhow::Error::from)?;
+    if P2P_STORAGE.get_metadata(&incoming.meta.file_id)?.is_none() {
+        P2P_STORAGE.insert_metadata(&incoming.meta)?;
+    }
+    match &incoming.shard {
+        Some(shard) => println!("Now holding shard {} of {} for {}", shard.index, file_id, incoming.origin),
+        None => println!("Now holding a replica of {} for {}", file_id, incoming.origin),
+    }
+    let record = Hosted { origin: incoming.origin.to_string(), size: incoming.written, stored_at: now_secs(), shard: incoming.shard };
+    tree("hosted_replicas")?.insert(file_id.as_bytes(), bincode::serialize(&record).map_err(anyhow::Error::from)?).map_err(anyhow::Error::from)?;
+    Ok(())
+}
+
+/// Whether this node still holds a copy, or a shard, of the file.
+pub(super) fn handle_check(file_id: &str) -> Vec<u8> {
+    let held = Path::new(&content_path(file_id)).exists() || erasure::shard_path(file_id).exists();
+    bincode::serialize(&P2PMessage::ReplicaStatus { file_id: file_id.to_string(), held }).unwrap_or_default()
+}
+
+/// Peers holding a copy or shard of a file this node is the origin of.
+pub fn holders(file_id: &str) -> Vec<String> {
+    let mut peers: Vec<String> = replica_set(file_id).map(|set| set.replicas.into_iter().map(|r| r.peer_id).collect()).unwrap_or_default();
+    if let Ok(Some(set)) = erasure::shard_set(file_id) {
+        peers.extend(set.holders.into_iter().flatten().map(|r| r.peer_id));
+    }
+    peers.sort();
+    peers.dedup();
+    peers
+}
+
+/// Deletes a copy or shard held for another node, with its metadata. Files this node
+/// does not host for another node are left alone.
+pub(super) fn forget_hosted(file_id: &str) {
+    let Some(h) = hosted(file_id) else {
+        return;
+    };
+    let _ = std::fs::remove_file(stored_path(file_id, h.shard.is_some()));
+    if let Ok(uuid) = uuid::Uuid::parse_str(file_id) {
+        let _ = P2P_STORAGE.delete_metadata(&uuid);
+    }
+    if let Ok(t) = tree("hosted_replicas") {
+        let _ = t.remove(file_id);
+    }
+}
+
+/// Deletes a copy held for `origin`, once the origin deletes the file.
+pub(super) fn handle_drop(origin: &PeerId, file_id: &str) -> Vec<u8> {
+    match hosted(file_id) {
+        Some(h) if h.origin == origin.to_string() => {
+            forget_hosted(file_id);
+            println!("Dropped {} of {} at the request of {}", if h.shard.is_some() { "shard" } else { "replica" }, file_id, origin);
+            bincode::serialize(&P2PMessage::ReplicaStatus { file_id: file_id.to_string(), held: false }).unwrap_or_default()
+        }
+        _ => ReplicationError::NotFound.response(),
+    }
+}
+
+/// Starts the replication manager.
+pub fn spawn(node: Arc<P2PNode>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            for meta in P2P_STORAGE.list_metadata().unwrap_or_default() {
+                let file_id = meta.file_id.to_string();
+                // Only the origin looks after a file's copies
+                if hosted(&file_id).is_some() {
+                    continue;
+                }
+                let result = match erasure::policy(&file_id) {
+                    // Shards can rebuild a lost local copy, so they are kept up regardless
+                    StoragePolicy::ErasureCoded { .. } => node.maintain_shards(&meta).await,
+                    StoragePolicy::Replicated if Path::new(&content_path(&file_id)).exists() => node.maintain_replicas(&meta).await,
+                    StoragePolicy::Replicated => continue,
+                };
+                if let Err(e) = result {
+                    println!("Replication of {} failed: {}", file_id, e);
+                }
+            }
+        }
+    });
+}
+
+/// Replication health of a file, as shown to users.
+#[derive(Debug, Clone)]
+pub struct ReplicaHealth {
+    pub file_id: String,
+    pub policy: StoragePolicy,
+    pub factor: usize,
+    pub replicas: Vec<Replica>,
+    /// Placed shards, by shard index, when the file is erasure coded
+    pub shards: Vec<(usize, Replica)>,
+    /// Replicas, or shards, that answered the latest check
+    pub healthy: usize,
+    /// False when this node holds the file as another node's replica or shard
+    pub is_origin: bool,
+}
+
+pub fn health(file_id: &str) -> anyhow::Result<ReplicaHealth> {
+    let set = replica_set(file_id)?;
+    let shards: Vec<(usize, Replica)> = erasure::shard_set(file_id)?
+        .map(|s| s.holders.into_iter().enumerate().filter_map(|(i, h)| Some((i, h?))).collect())
+        .unwrap_or_default();
+    let policy = erasure::policy(file_id);
+    let healthy = match policy {
+        StoragePolicy::Replicated => set.replicas.iter().filter(|r| r.reachable).count(),
+        StoragePolicy::ErasureCoded { .. } => shards.iter().filter(|(_, r)| r.reachable).count(),
+    };
+    Ok(ReplicaHealth {
+        file_id: file_id.to_string(),
+        policy,
+        factor: config().factor,
+        replicas: set.replicas,
+        shards,
+        healthy,
+        is_origin: hosted(file_id).is_none(),
+    })
+}
+
+impl P2PNode {
+    /// Checks a file's replicas and tops them up to the replication factor.
+    pub async fn maintain_replicas(&self, meta: &FileMetadata) -> anyhow::Result<()> {
+        let file_id = meta.file_id.to_string();
+        let config = config();
+        let now = now_secs();
+        let mut set = replica_set(&file_id)?;
+        let before = set.replicas.len();
+
+        let checks = futures::future::join_all(set.replicas.into_iter().map(|r| self.check_replica(&file_id, r))).await;
+        set.replicas = checks.into_iter().flatten().collect();
+
+        if set.replicas.len() < config.factor {
+            let manifest = Manifest::of_file(Path::new(&content_path(&file_id)), CHUNK_SIZE)?;
+            for candidate in self.closest_peers(&file_id).await? {
+                if set.replicas.len() >= config.factor {
+                    break;
+                }
+                let candidate_id = candidate.to_string();
+                if set.replicas.iter().any(|r| r.peer_id == candidate_id) || access::check_peer(&candidate).is_err() {
+                    continue;
+                }
+                match self.push_replica(candidate, meta, &manifest, Path::new(&content_path(&file_id)), None).await {
+                    Ok(()) => {
+                        println!("Replicated {} to {}", file_id, candidate_id);
+                        set.replicas.push(Replica { peer_id: candidate_id, stored_at: now, last_seen: now, reachable: true });
+                    }
+                    Err(e) => println!("Could not replicate {} to {}: {}", file_id, candidate_id, e),
+                }
+            }
+        }
+        if before > 0 || !set.replicas.is_empty() {
+            save_replica_set(&set)?;
+        }
+        // Shards from an earlier policy go once the copies are in place
+        if set.replicas.len() >= config.factor && erasure::shard_set(&file_id)?.is_some() {
+            self.drop_shards(&file_id).await?;
+        }
+        Ok(())
+    }
+
+    /// Asks the holder of a copy or shard whether it still has it. Returns the updated
+    /// record, or None once it is gone or has been unreachable past the grace period.
+    pub(super) async fn check_replica(&self, file_id: &str, mut replica: Replica) -> Option<Replica> {
+        let now = now_secs();
+        let check = match PeerId::from_str(&replica.peer_id) {
+            Ok(peer) => self.file_request(peer, P2PMessage::ReplicaCheck { file_id: file_id.to_string() }).await,
+            Err(e) => Err(e.into()),
+        };
+        match check {
+            Ok(P2PMessage::ReplicaStatus { held: true, .. }) => {
+                replica.last_seen = now;
+                replica.reachable = true;
+                Some(replica)
+            }
+            Ok(P2PMessage::ReplicaStatus { held: false, .. }) => {
+                println!("Replica of {} on {} is gone", file_id, replica.peer_id);
+                None
+            }
+            _ if now.saturating_sub(replica.last_seen) > config().grace_secs => {
+                println!("Replica of {} on {} unreachable past the grace period", file_id, replica.peer_id);
+                None
+            }
+            _ => {
+                replica.reachable = false;
+                Some(replica)
+            }
+        }
+    }
+
+    /// Pushes the file at `source` to `peer`: the whole file, or the shard `shard`
+    /// describes.
+    pub(super) async fn push_replica(
+        &self,
+        peer: PeerId,
+        meta: &FileMetadata,
+        manifest: &Manifest,
+        source: &Path,
+        shard: Option<ShardInfo>,
+    ) -> anyhow::Result<()> {
+        let file_id = meta.file_id.to_string();
+        let offer = P2PMessage::ReplicaOffer { meta: meta.clone(), manifest: manifest.clone(), shard };
+        match self.file_request(peer, offer).await? {
+            P2PMessage::ReplicaAccepted { complete: true, .. } => return Ok(()),
+            P2PMessage::ReplicaAccepted { .. } => {}
+            P2PMessage::RequestDenied { reason } => return Err(anyhow::anyhow!("Peer refused the replica: {}", reason)),
+            _ => return Err(anyhow::anyhow!("No valid response from peer")),
+        }
+        let mut file = std::fs::File::open(source)?;
+        let mut buf = vec![0u8; manifest.chunk_size];
+        for chunk_index in 0..manifest.chunk_hashes.len() {
+            let len = manifest.chunk_len(chunk_index);
+            file.read_exact(&mut buf[..len])?;
+            let chunk = P2PMessage::ReplicaChunk { file_id: file_id.clone(), chunk_index, data: buf[..len].to_vec() };
+            match self.file_request(peer, chunk).await? {
+                P2PMessage::ReplicaStored { complete: true, .. } => return Ok(()),
+                P2PMessage::ReplicaStored { .. } => {}
+                P2PMessage::RequestDenied { reason } => return Err(anyhow::anyhow!("Peer refused chunk {}: {}", chunk_index, reason)),
+                _ => return Err(anyhow::anyhow!("No valid response from peer")),
+            }
+        }
+        Err(anyhow::anyhow!("Peer did not complete the replica"))
+    }
+
+    /// Tells the file's replicas to delete their copies and forgets them.
+    pub async fn drop_replicas(&self, file_id: &str) -> anyhow::Result<()> {
+        let set = replica_set(file_id)?;
+        for replica in &set.replicas {
+            if let Ok(peer) = PeerId::from_str(&replica.peer_id) {
+                let _ = self.file_request(peer, P2PMessage::ReplicaDrop { file_id: file_id.to_string() }).await;
+            }
+        }
+        tree("replicas")?.remove(file_id)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,167 @@
+// Peer reputation: penalties for misbehaviour and automatic, temporary bans.
+//
+// Each offence adds its penalty to the peer's score, and scores halve every
+// PENALTY_HALF_LIFE_SECS, so occasional timeouts from an honest peer fade away while a
+// burst of bad signatures or corrupt chunks does not. A peer whose score reaches the
+// configured threshold is banned for the configured duration: the swarm disconnects it,
+// refuses its requests and drops new connections from it until the ban runs out, when
+// its score starts again from zero. Records persist in `peer_reputation.json`.
+//
+// Bans are separate from the manual blocklist (`blocked_peers.json`), which never
+// expires; unblocking a peer lifts both.
+
+use libp2p::PeerId;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const PENALTY_HALF_LIFE_SECS: f64 = 60.0 * 60.0;
+
+static REPUTATION: Lazy<Mutex<HashMap<String, PeerReputation>>> = Lazy::new(|| Mutex::new(load().unwrap_or_default()));
+static CONFIG: Lazy<Mutex<ReputationConfig>> = Lazy::new(|| Mutex::new(ReputationConfig::default()));
+
+/// When a peer gets banned, and for how long.
+#[derive(Debug, Clone, Copy)]
+pub struct ReputationConfig {
+    pub ban_threshold: f64,
+    pub ban_secs: u64,
+}
+
+impl Default for ReputationConfig {
+    fn default() -> Self {
+        Self { ban_threshold: 100.0, ban_secs: 60 * 60 }
+    }
+}
+
+/// Sets the ban threshold and duration for this process.
+pub fn configure(config: ReputationConfig) {
+    *CONFIG.lock().unwrap() = config;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Offence {
+    /// A request or announcement whose signature doesn't verify
+    BadSignature,
+    /// A message that doesn't decode
+    MalformedMessage,
+    /// A request the peer never answered
+    Timeout,
+    /// A served chunk whose hash isn't the one expected
+    ChunkHashMismatch,
+}
+
+impl Offence {
+    fn penalty(self) -> f64 {
+        match self {
+            Offence::BadSignature => 25.0,
+            Offence::MalformedMessage => 10.0,
+            Offence::Timeout => 5.0,
+            Offence::ChunkHashMismatch => 34.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PeerReputation {
+    /// Decayed penalty as of `updated_at`
+    pub score: f64,
+    pub updated_at: u64,
+    pub bad_signatures: u32,
+    pub malformed_messages: u32,
+    pub timeouts: u32,
+    pub chunk_mismatches: u32,
+    /// Unix time the current ban ends, if the peer is banned
+    pub banned_until: Option<u64>,
+    /// Bans so far, including the current one
+    pub bans: u32,
+}
+
+impl PeerReputation {
+    fn decay(&mut self, now: u64) {
+        let elapsed = now.saturating_sub(self.updated_at) as f64;
+        self.score *= 0.5f64.powf(elapsed / PENALTY_HALF_LIFE_SECS);
+        self.updated_at = now;
+    }
+
+    pub fn is_banned(&self, now: u64) -> bool {
+        self.banned_until.is_some_and(|until| until > now)
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// Records an offence by `peer`. Returns true when it gets the peer banned.
+pub fn report(peer: &PeerId, offence: Offence) -> bool {
+    let now = now_secs();
+    let config = *CONFIG.lock().unwrap();
+    let mut records = REPUTATION.lock().unwrap();
+    let record = records.entry(peer.to_string()).or_default();
+    if record.is_banned(now) {
+        return false;
+    }
+    record.decay(now);
+    record.score += offence.penalty();
+    match offence {
+        Offence::BadSignature => record.bad_signatures += 1,
+        Offence::MalformedMessage => record.malformed_messages += 1,
+        Offence::Timeout => record.timeouts += 1,
+        Offence::ChunkHashMismatch => record.chunk_mismatches += 1,
+    }
+    let banned = record.score >= config.ban_threshold;
+    if banned {
+        record.score = 0.0;
+        record.banned_until = Some(now + config.ban_secs);
+        record.bans += 1;
+        println!("Banned peer {} for {}s after {:?}", peer, config.ban_secs, offence);
+    }
+    drop(records);
+    save().ok();
+    banned
+}
+
+/// When the peer's ban ends, if it is banned.
+pub fn banned_until(peer_id: &str) -> Option<u64> {
+    let now = now_secs();
+    REPUTATION.lock().unwrap().get(peer_id).filter(|r| r.is_banned(now)).and_then(|r| r.banned_until)
+}
+
+/// Lifts a peer's ban and clears its score; offence counts are kept.
+pub fn pardon(peer_id: &str) -> bool {
+    let mut records = REPUTATION.lock().unwrap();
+    let Some(record) = records.get_mut(peer_id) else { return false };
+    let was_banned = record.is_banned(now_secs());
+    record.score = 0.0;
+    record.banned_until = None;
+    drop(records);
+    save().ok();
+    was_banned
+}
+
+/// Every peer with a record, with scores decayed to now.
+pub fn list() -> Vec<(String, PeerReputation)> {
+    let now = now_secs();
+    REPUTATION
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(peer, record)| {
+            let mut record = record.clone();
+            record.decay(now);
+            (peer.clone(), record)
+        })
+        .collect()
+}
+
+fn save() -> anyhow::Result<()> {
+    let json = serde_json::to_string_pretty(&*REPUTATION.lock().unwrap())?;
+    std::fs::write("peer_reputation.json", json)?;
+    Ok(())
+}
+
+fn load() -> anyhow::Result<HashMap<String, PeerReputation>> {
+    Ok(serde_json::from_str(&std::fs::read_to_string("peer_reputation.json")?)?)
+}
@@ -0,0 +1,181 @@
+// Transports a node listens and dials on: TCP, QUIC, WebSocket and, when built with the
+// `webrtc` feature, WebRTC. Circuits through relays (see `nat`) come on top of these.
+//
+// TCP and WebSocket connections are secured with Noise and multiplexed with Yamux; QUIC
+// and WebRTC bring their own encryption and streams. WebSocket and WebRTC are there for
+// browsers, which can't open raw TCP or QUIC connections: a page served over plain HTTP
+// can dial `/ws`, and `/webrtc-direct` needs no TLS certificate at all.
+//
+// Listen addresses come from `--listen`, defaulting to TCP and QUIC on the P2P port and
+// WebSocket on the port after it (WebRTC on the one after that), over IPv4 and IPv6.
+// When a peer has several addresses they are tried in the configured order of
+// transports, DIAL_CONCURRENCY at a time, so a faster transport is used when both ends
+// have it while an address that never answers can't hold the dial up. Relayed addresses
+// always come last.
+
+use libp2p::core::muxing::StreamMuxerBox;
+use libp2p::core::transport::Boxed;
+use libp2p::core::upgrade;
+use libp2p::multiaddr::Protocol;
+use libp2p::{identity, noise, relay, yamux, Multiaddr, PeerId, Transport};
+use once_cell::sync::Lazy;
+use std::num::NonZeroU8;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+pub const DEFAULT_PORT: u16 = 2093;
+/// Addresses of a peer dialled at once
+pub const DIAL_CONCURRENCY: NonZeroU8 = NonZeroU8::new(2).unwrap();
+
+static CONFIG: Lazy<Mutex<TransportConfig>> = Lazy::new(|| Mutex::new(TransportConfig::default()));
+
+// Addresses this node listens on, as the swarm reported them
+static LISTEN_ADDRS: Lazy<Mutex<Vec<Multiaddr>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportKind {
+    Tcp,
+    Quic,
+    WebSocket,
+    WebRtc,
+}
+
+impl TransportKind {
+    /// The transport an address is dialled over; `None` for relayed and unknown addresses.
+    pub fn of(addr: &Multiaddr) -> Option<Self> {
+        let mut kind = None;
+        for protocol in addr.iter() {
+            match protocol {
+                Protocol::P2pCircuit => return None,
+                Protocol::Tcp(_) if kind.is_none() => kind = Some(TransportKind::Tcp),
+                Protocol::Ws(_) | Protocol::Wss(_) => kind = Some(TransportKind::WebSocket),
+                Protocol::QuicV1 => kind = Some(TransportKind::Quic),
+                Protocol::WebRTCDirect => kind = Some(TransportKind::WebRtc),
+                _ => {}
+            }
+        }
+        kind
+    }
+}
+
+impl std::fmt::Display for TransportKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransportKind::Tcp => write!(f, "tcp"),
+            TransportKind::Quic => write!(f, "quic"),
+            TransportKind::WebSocket => write!(f, "ws"),
+            TransportKind::WebRtc => write!(f, "webrtc"),
+        }
+    }
+}
+
+impl FromStr for TransportKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "tcp" => Ok(TransportKind::Tcp),
+            "quic" => Ok(TransportKind::Quic),
+            "ws" | "websocket" => Ok(TransportKind::WebSocket),
+            "webrtc" => Ok(TransportKind::WebRtc),
+            other => Err(format!("unknown transport '{}' (expected tcp, quic, ws or webrtc)", other)),
+        }
+    }
+}
+
+/// Where the node listens, and which transports it dials first.
+#[derive(Debug, Clone)]
+pub struct TransportConfig {
+    pub listen: Vec<Multiaddr>,
+    /// Most preferred first; transports not listed are tried after those that are
+    pub dial_preference: Vec<TransportKind>,
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        Self::on_port(DEFAULT_PORT)
+    }
+}
+
+impl TransportConfig {
+    /// Every transport this build supports, on `port` and the ports after it.
+    pub fn on_port(port: u16) -> Self {
+        let mut listen = Vec::new();
+        for ip in ["/ip4/0.0.0.0", "/ip6/::"] {
+            listen.push(format!("{}/tcp/{}", ip, port));
+            listen.push(format!("{}/udp/{}/quic-v1", ip, port));
+            listen.push(format!("{}/tcp/{}/ws", ip, port.wrapping_add(1)));
+            if cfg!(feature = "webrtc") {
+                listen.push(format!("{}/udp/{}/webrtc-direct", ip, port.wrapping_add(2)));
+            }
+        }
+        Self {
+            listen: listen.iter().map(|a| a.parse().unwrap()).collect(),
+            dial_preference: vec![TransportKind::Quic, TransportKind::Tcp, TransportKind::WebSocket, TransportKind::WebRtc],
+        }
+    }
+
+    fn rank(&self, addr: &Multiaddr) -> usize {
+        match TransportKind::of(addr) {
+            Some(kind) => self.dial_preference.iter().position(|k| *k == kind).unwrap_or(self.dial_preference.len()),
+            None => usize::MAX,
+        }
+    }
+}
+
+/// Sets the listen addresses and dial preference for this process.
+pub fn configure(config: TransportConfig) {
+    *CONFIG.lock().unwrap() = config;
+}
+
+pub fn config() -> TransportConfig {
+    CONFIG.lock().unwrap().clone()
+}
+
+/// `addrs` in the order they should be dialled.
+pub fn by_preference(mut addrs: Vec<Multiaddr>) -> Vec<Multiaddr> {
+    let config = CONFIG.lock().unwrap();
+    addrs.sort_by_key(|addr| config.rank(addr));
+    addrs
+}
+
+/// The swarm's transport: relay circuits, WebSocket and TCP upgraded with Noise and
+/// Yamux, alongside QUIC and, if enabled, WebRTC.
+pub fn build(keypair: &identity::Keypair, relay: relay::client::Transport) -> Boxed<(PeerId, StreamMuxerBox)> {
+    // Outbound TCP reuses the listening port, so hole punching can open the NAT for it
+    let tcp = libp2p::tcp::tokio::Transport::new(libp2p::tcp::Config::default().nodelay(true).port_reuse(true));
+    let ws = libp2p::websocket::WsConfig::new(libp2p::tcp::tokio::Transport::new(libp2p::tcp::Config::default().nodelay(true)));
+    let streams = relay
+        .or_transport(ws)
+        .or_transport(tcp)
+        .upgrade(upgrade::Version::V1)
+        .authenticate(noise::Config::new(keypair).unwrap())
+        .multiplex(yamux::Config::default())
+        .map(|(peer, muxer), _| (peer, StreamMuxerBox::new(muxer)));
+    let quic = libp2p::quic::tokio::Transport::new(libp2p::quic::Config::new(keypair))
+        .map(|(peer, connection), _| (peer, StreamMuxerBox::new(connection)));
+    let transport = quic.or_transport(streams).map(|either, _| either.into_inner());
+
+    #[cfg(feature = "webrtc")]
+    let transport = {
+        let certificate = libp2p_webrtc::tokio::Certificate::generate(&mut rand::thread_rng()).unwrap();
+        let webrtc = libp2p_webrtc::tokio::Transport::new(keypair.clone(), certificate)
+            .map(|(peer, connection), _| (peer, StreamMuxerBox::new(connection)));
+        transport.or_transport(webrtc).map(|either, _| either.into_inner())
+    };
+
+    transport.boxed()
+}
+
+pub fn on_listen_addr(addr: &Multiaddr, added: bool) {
+    let mut addrs = LISTEN_ADDRS.lock().unwrap();
+    addrs.retain(|a| a != addr);
+    if added {
+        addrs.push(addr.clone());
+    }
+}
+
+/// Addresses this node is listening on, most preferred transport first.
+pub fn listen_addrs() -> Vec<Multiaddr> {
+    by_preference(LISTEN_ADDRS.lock().unwrap().clone())
+}
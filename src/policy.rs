@@ -0,0 +1,380 @@
+// Role-based access control for node administration and per-file permissions.
+//
+// Each account has one role, stored by user_id in the `roles` tree (`user` when unset).
+// Roles grant node-level permissions. File access comes from ownership plus the grants
+// in `file_acl`, capped by the caller's role. The REST handlers, the gRPC services and
+// `ServiceManager::execute_command` all ask `Policy` instead of checking on their own.
+
+use crate::audit;
+use crate::auth::AuthSession;
+use crate::storage::{FileMetadata, Storage};
+use crate::user_management::UserStore;
+use axum::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use tonic::Status;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Admin,
+    Operator,
+    User,
+    Guest,
+}
+
+/// Node-level actions gated by role.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    ManageRoles,
+    ManageServices,
+    ViewStatus,
+    ManagePeers,
+    ManageModel,
+    Upload,
+    ViewAudit,
+    ManageUsers,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FilePermission {
+    Read,
+    Write,
+    Share,
+    Delete,
+}
+
+impl FilePermission {
+    pub const ALL: [FilePermission; 4] = [FilePermission::Read, FilePermission::Write, FilePermission::Share, FilePermission::Delete];
+}
+
+impl Role {
+    pub fn permissions(self) -> &'static [Permission] {
+        use Permission::*;
+        match self {
+            Role::Admin => &[ManageRoles, ManageServices, ViewStatus, ManagePeers, ManageModel, Upload, ViewAudit, ManageUsers],
+            Role::Operator => &[ManageServices, ViewStatus, ManagePeers, ManageModel, Upload],
+            Role::User => &[Upload],
+            Role::Guest => &[],
+        }
+    }
+
+    pub fn allows(self, permission: Permission) -> bool {
+        self.permissions().contains(&permission)
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Role::Admin => "admin",
+            Role::Operator => "operator",
+            Role::User => "user",
+            Role::Guest => "guest",
+        })
+    }
+}
+
+impl FromStr for Role {
+    type Err = PolicyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "admin" => Ok(Role::Admin),
+            "operator" => Ok(Role::Operator),
+            "user" => Ok(Role::User),
+            "guest" => Ok(Role::Guest),
+            _ => Err(PolicyError::UnknownRole(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Permission::ManageRoles => "manage_roles",
+            Permission::ManageServices => "manage_services",
+            Permission::ViewStatus => "view_status",
+            Permission::ManagePeers => "manage_peers",
+            Permission::ManageModel => "manage_model",
+            Permission::Upload => "upload",
+            Permission::ViewAudit => "view_audit",
+            Permission::ManageUsers => "manage_users",
+        })
+    }
+}
+
+impl fmt::Display for FilePermission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FilePermission::Read => "read",
+            FilePermission::Write => "write",
+            FilePermission::Share => "share",
+            FilePermission::Delete => "delete",
+        })
+    }
+}
+
+impl FromStr for FilePermission {
+    type Err = PolicyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "read" => Ok(FilePermission::Read),
+            "write" => Ok(FilePermission::Write),
+            "share" => Ok(FilePermission::Share),
+            "delete" => Ok(FilePermission::Delete),
+            _ => Err(PolicyError::UnknownPermission(s.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PolicyError {
+    #[error("Permission denied: '{permission}' is required (your role: {role})")]
+    Denied { role: Role, permission: Permission },
+    #[error("Permission denied: '{permission}' on file {file_id} is required")]
+    FileDenied { file_id: Uuid, permission: FilePermission },
+    #[error("Unknown role '{0}' (expected admin, operator, user or guest)")]
+    UnknownRole(String),
+    #[error("Unknown file permission '{0}' (expected read, write, share or delete)")]
+    UnknownPermission(String),
+    #[error("Refusing to demote the last admin")]
+    LastAdmin,
+    #[error("Policy store error: {0}")]
+    Storage(#[from] anyhow::Error),
+}
+
+impl From<sled::Error> for PolicyError {
+    fn from(e: sled::Error) -> Self {
+        PolicyError::Storage(e.into())
+    }
+}
+
+impl From<serde_json::Error> for PolicyError {
+    fn from(e: serde_json::Error) -> Self {
+        PolicyError::Storage(e.into())
+    }
+}
+
+impl PolicyError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            PolicyError::Denied { .. } | PolicyError::FileDenied { .. } => StatusCode::FORBIDDEN,
+            PolicyError::UnknownRole(_) | PolicyError::UnknownPermission(_) => StatusCode::BAD_REQUEST,
+            PolicyError::LastAdmin => StatusCode::CONFLICT,
+            PolicyError::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl From<PolicyError> for Status {
+    fn from(e: PolicyError) -> Self {
+        match e {
+            PolicyError::Denied { .. } | PolicyError::FileDenied { .. } => Status::permission_denied(e.to_string()),
+            PolicyError::UnknownRole(_) | PolicyError::UnknownPermission(_) => Status::invalid_argument(e.to_string()),
+            PolicyError::LastAdmin => Status::failed_precondition(e.to_string()),
+            PolicyError::Storage(_) => Status::internal(e.to_string()),
+        }
+    }
+}
+
+/// Parses a list like `["write", "share"]`, as sent by the APIs and the CLI.
+pub fn parse_file_permissions<S: AsRef<str>>(names: &[S]) -> Result<Vec<FilePermission>, PolicyError> {
+    names.iter().map(|n| n.as_ref().trim().parse()).collect()
+}
+
+pub struct Policy {
+    roles: sled::Tree,
+    file_acl: sled::Tree,
+    meta: sled::Tree,
+}
+
+impl Policy {
+    pub fn open(storage: &Storage) -> anyhow::Result<Self> {
+        Ok(Self {
+            roles: storage.open_tree("roles")?,
+            file_acl: storage.open_tree("file_acl")?,
+            meta: storage.open_tree("policy_meta")?,
+        })
+    }
+
+    pub fn role(&self, user_id: &str) -> Result<Role, PolicyError> {
+        match self.roles.get(user_id.as_bytes())? {
+            Some(data) => Ok(serde_json::from_slice(&data)?),
+            None => Ok(Role::User),
+        }
+    }
+
+    pub fn set_role(&self, user_id: &str, role: Role) -> Result<(), PolicyError> {
+        if role != Role::Admin && self.role(user_id)? == Role::Admin {
+            let admins = self.list_roles()?.into_iter().filter(|(_, r)| *r == Role::Admin).count();
+            if admins <= 1 {
+                return Err(PolicyError::LastAdmin);
+            }
+        }
+        if role == Role::User {
+            self.roles.remove(user_id.as_bytes())?;
+        } else {
+            self.roles.insert(user_id.as_bytes(), serde_json::to_vec(&role)?)?;
+        }
+        Ok(())
+    }
+
+    /// Explicit role assignments as `(user_id, role)`; everyone else is a `user`.
+    pub fn list_roles(&self) -> Result<Vec<(String, Role)>, PolicyError> {
+        let mut out = Vec::new();
+        for item in self.roles.iter() {
+            let (k, v) = item?;
+            out.push((String::from_utf8_lossy(&k).to_string(), serde_json::from_slice(&v)?));
+        }
+        Ok(out)
+    }
+
+    /// Makes `user_id` admin if this node has never had one, so it can hand out roles.
+    /// See [`bootstrap_admin`].
+    pub fn bootstrap_admin(&self, user_id: &str) -> Result<bool, PolicyError> {
+        let claimed = self.meta.compare_and_swap("admin_bootstrapped", None as Option<&[u8]>, Some(user_id.as_bytes()))?;
+        if claimed.is_err() {
+            return Ok(false);
+        }
+        self.roles.insert(user_id.as_bytes(), serde_json::to_vec(&Role::Admin)?)?;
+        Ok(true)
+    }
+
+    pub fn authorize(&self, user_id: &str, permission: Permission) -> Result<Role, PolicyError> {
+        let role = self.role(user_id)?;
+        if role.allows(permission) {
+            Ok(role)
+        } else {
+            Err(PolicyError::Denied { role, permission })
+        }
+    }
+
+    pub fn check(&self, session: &AuthSession, permission: Permission) -> Result<Role, PolicyError> {
+        self.authorize(&session.0.user_id, permission)
+    }
+
+    /// What `username` may do with a file. Owners hold every permission; anyone the
+    /// file is shared with can read it plus whatever the owner granted. Admins may
+    /// delete any file, and guests never get more than read.
+    pub fn file_permissions(&self, user_id: &str, username: &str, meta: &FileMetadata) -> Result<Vec<FilePermission>, PolicyError> {
+        let role = self.role(user_id)?;
+        let mut perms = if meta.owner_peer_id == username {
+            FilePermission::ALL.to_vec()
+        } else {
+            let mut perms: Vec<FilePermission> = match self.file_acl.get(acl_key(&meta.file_id, username))? {
+                Some(data) => serde_json::from_slice(&data)?,
+                None => Vec::new(),
+            };
+            if meta.shared_keys.contains_key(username) && !perms.contains(&FilePermission::Read) {
+                perms.push(FilePermission::Read);
+            }
+            perms
+        };
+        if role == Role::Admin && !perms.contains(&FilePermission::Delete) {
+            perms.push(FilePermission::Delete);
+        }
+        if role == Role::Guest {
+            perms.retain(|p| *p == FilePermission::Read);
+        }
+        Ok(perms)
+    }
+
+    pub fn check_file(&self, session: &AuthSession, meta: &FileMetadata, permission: FilePermission) -> Result<(), PolicyError> {
+        if self.file_permissions(&session.0.user_id, session.username(), meta)?.contains(&permission) {
+            Ok(())
+        } else {
+            Err(PolicyError::FileDenied { file_id: meta.file_id, permission })
+        }
+    }
+
+    /// Adds `permissions` to what `username` already holds on the file.
+    pub fn grant_file(&self, file_id: &Uuid, username: &str, permissions: &[FilePermission]) -> Result<(), PolicyError> {
+        let key = acl_key(file_id, username);
+        let mut perms: Vec<FilePermission> = match self.file_acl.get(&key)? {
+            Some(data) => serde_json::from_slice(&data)?,
+            None => Vec::new(),
+        };
+        for p in permissions {
+            if !perms.contains(p) {
+                perms.push(*p);
+            }
+        }
+        self.file_acl.insert(key, serde_json::to_vec(&perms)?)?;
+        Ok(())
+    }
+
+    pub fn revoke_file(&self, file_id: &Uuid, username: &str) -> Result<(), PolicyError> {
+        self.file_acl.remove(acl_key(file_id, username))?;
+        Ok(())
+    }
+
+    /// Moves every per-file grant of `old` to `new`, merging with any `new` already holds.
+    pub fn rename_user(&self, old: &str, new: &str) -> Result<(), PolicyError> {
+        for item in self.file_acl.iter() {
+            let (key, data) = item?;
+            if key.len() <= 16 || &key[16..] != old.as_bytes() {
+                continue;
+            }
+            let Ok(file_id) = Uuid::from_slice(&key[..16]) else {
+                continue;
+            };
+            let perms: Vec<FilePermission> = serde_json::from_slice(&data)?;
+            self.grant_file(&file_id, new, &perms)?;
+            self.file_acl.remove(key)?;
+        }
+        Ok(())
+    }
+
+    pub fn clear_file(&self, file_id: &Uuid) -> Result<(), PolicyError> {
+        for item in self.file_acl.scan_prefix(file_id.as_bytes()) {
+            let (k, _) = item?;
+            self.file_acl.remove(k)?;
+        }
+        Ok(())
+    }
+}
+
+fn acl_key(file_id: &Uuid, username: &str) -> Vec<u8> {
+    let mut key = file_id.as_bytes().to_vec();
+    key.extend_from_slice(username.as_bytes());
+    key
+}
+
+/// Makes the registered account `username` admin on a node that has never had one, as
+/// asked for at startup with `--bootstrap-admin`. The grant is audited. Returns false,
+/// changing nothing, once the node has had an admin.
+pub fn bootstrap_admin(storage: &Storage, username: &str) -> anyhow::Result<bool> {
+    let user = UserStore::open(storage)?
+        .get_by_username(username)
+        .map_err(|e| anyhow::anyhow!(e.to_string()))?
+        .ok_or_else(|| anyhow::anyhow!("User '{}' not found; register it first", username))?;
+    let granted = Policy::open(storage)?.bootstrap_admin(&user.user_id)?;
+    if granted {
+        audit::record(storage, "node", "grant_role", Some(username), true, Some(&format!("{} is now admin (bootstrap)", username)));
+    }
+    Ok(granted)
+}
+
+/// REST helper: opens the policy and turns any failure into a ready-made response.
+pub fn require(storage: &Storage, session: &AuthSession, permission: Permission) -> Result<Role, (StatusCode, String)> {
+    let policy = Policy::open(storage).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    policy.check(session, permission).map_err(|e| {
+        crate::audit::record_session(storage, session, "permission_denied", Some(&permission.to_string()), false, Some(&e.to_string()));
+        (e.status(), e.to_string())
+    })
+}
+
+/// REST helper for per-file checks, see [`require`].
+pub fn require_file(storage: &Storage, session: &AuthSession, meta: &FileMetadata, permission: FilePermission) -> Result<(), (StatusCode, String)> {
+    let policy = Policy::open(storage).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    policy.check_file(session, meta, permission).map_err(|e| {
+        crate::audit::record_session(storage, session, "permission_denied", Some(&meta.file_id.to_string()), false, Some(&e.to_string()));
+        (e.status(), e.to_string())
+    })
+}
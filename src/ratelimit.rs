@@ -0,0 +1,371 @@
+// Brute-force protection for the login paths and token-bucket rate limiting for the REST
+// and gRPC servers.
+//
+// Failed logins are counted per username and per client IP in `login_attempts`. Past a
+// few free attempts each further failure locks the key for twice as long as the last,
+// up to MAX_LOCKOUT_SECS; a successful login clears the username's count, and failures
+// are forgotten after FAILURE_WINDOW_SECS without another. Request buckets live in
+// `rate_buckets`, keyed by the configured route and client IP. Both are sled trees, so a
+// restart neither unlocks an account nor refills a bucket.
+
+use crate::storage::Storage;
+use axum::extract::ConnectInfo;
+use axum::http::{header, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Extension;
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tonic::transport::server::{TcpConnectInfo, TlsConnectInfo};
+use tonic::Status;
+
+/// Failures allowed per username before lockouts start
+const USER_FREE_ATTEMPTS: u32 = 5;
+/// Failures allowed per client IP, which may front several legitimate users
+const IP_FREE_ATTEMPTS: u32 = 20;
+const MAX_LOCKOUT_SECS: u64 = 15 * 60;
+const FAILURE_WINDOW_SECS: u64 = 60 * 60;
+const BUCKET_IDLE_MS: u64 = 60 * 60 * 1000;
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+fn now_secs() -> u64 {
+    now_millis() / 1000
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Attempts {
+    failures: u32,
+    last_failure: u64,
+    locked_until: u64,
+}
+
+impl Attempts {
+    fn decode(data: Option<&[u8]>, now: u64) -> Self {
+        let attempts: Attempts = data.and_then(|d| serde_json::from_slice(d).ok()).unwrap_or_default();
+        if now.saturating_sub(attempts.last_failure) > FAILURE_WINDOW_SECS && attempts.locked_until <= now {
+            return Attempts::default();
+        }
+        attempts
+    }
+}
+
+pub struct LoginGuard {
+    tree: sled::Tree,
+}
+
+impl LoginGuard {
+    pub fn open(storage: &Storage) -> anyhow::Result<Self> {
+        Ok(Self { tree: storage.open_tree("login_attempts")? })
+    }
+
+    fn keys(username: &str, client: Option<IpAddr>) -> Vec<(String, u32)> {
+        let mut keys = vec![(format!("user:{}", username), USER_FREE_ATTEMPTS)];
+        if let Some(ip) = client {
+            keys.push((format!("ip:{}", ip), IP_FREE_ATTEMPTS));
+        }
+        keys
+    }
+
+    /// Seconds until `username` from `client` may try again, if either is locked out.
+    pub fn retry_after(&self, username: &str, client: Option<IpAddr>) -> anyhow::Result<Option<u64>> {
+        let now = now_secs();
+        let mut wait = None;
+        for (key, _) in Self::keys(username, client) {
+            let attempts = Attempts::decode(self.tree.get(&key)?.as_deref(), now);
+            if attempts.locked_until > now {
+                wait = wait.max(Some(attempts.locked_until - now));
+            }
+        }
+        Ok(wait)
+    }
+
+    /// Counts a failed attempt against the username and the client, locking either once
+    /// it is past its free attempts. Returns the resulting lockout, if any.
+    pub fn record_failure(&self, username: &str, client: Option<IpAddr>) -> anyhow::Result<Option<u64>> {
+        let now = now_secs();
+        let mut wait = None;
+        for (key, free) in Self::keys(username, client) {
+            let updated = self.tree.update_and_fetch(&key, |old| {
+                let mut attempts = Attempts::decode(old, now);
+                attempts.failures += 1;
+                attempts.last_failure = now;
+                if attempts.failures > free {
+                    let exponent = (attempts.failures - free - 1).min(20);
+                    attempts.locked_until = now + (1u64 << exponent).min(MAX_LOCKOUT_SECS);
+                }
+                serde_json::to_vec(&attempts).ok()
+            })?;
+            let attempts = Attempts::decode(updated.as_deref(), now);
+            if attempts.locked_until > now {
+                wait = wait.max(Some(attempts.locked_until - now));
+            }
+        }
+        self.tree.flush()?;
+        Ok(wait)
+    }
+
+    /// Clears the username's count. The client's stays, so one working account can't be
+    /// used to reset an address that is guessing at others.
+    pub fn record_success(&self, username: &str) -> anyhow::Result<()> {
+        self.tree.remove(format!("user:{}", username))?;
+        Ok(())
+    }
+
+    /// Drops records that have neither a live lockout nor a recent failure.
+    pub fn prune(&self) -> anyhow::Result<usize> {
+        let now = now_secs();
+        let mut removed = 0;
+        for item in self.tree.iter() {
+            let (key, value) = item?;
+            if Attempts::decode(Some(&value), now).failures == 0 {
+                self.tree.remove(key)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// A bucket of `capacity` requests refilled at `per_sec`, written `CAPACITY/PER_SEC`
+/// (e.g. `10/0.5` for bursts of ten and one request every two seconds after that).
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RateLimit {
+    pub capacity: u32,
+    pub per_sec: f64,
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (capacity, per_sec) = s.split_once('/').ok_or_else(|| format!("expected CAPACITY/PER_SEC, got '{}'", s))?;
+        let capacity: u32 = capacity.trim().parse().map_err(|_| format!("invalid capacity '{}'", capacity))?;
+        let per_sec: f64 = per_sec.trim().parse().map_err(|_| format!("invalid refill rate '{}'", per_sec))?;
+        if capacity == 0 || !(per_sec > 0.0 && per_sec.is_finite()) {
+            return Err(format!("rate limit '{}' must have a positive capacity and refill rate", s));
+        }
+        Ok(Self { capacity, per_sec })
+    }
+}
+
+/// Limits per route, matched on the request path: a route covers itself and everything
+/// below it (`/dafs.FileService` covers all of its methods), the longest match wins,
+/// and anything unmatched gets `default`.
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+    pub default: Option<RateLimit>,
+    pub routes: Vec<(String, RateLimit)>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        let strict = RateLimit { capacity: 10, per_sec: 0.2 };
+        Self {
+            default: Some(RateLimit { capacity: 120, per_sec: 20.0 }),
+            routes: ["/login", "/register", "/links/open", "/dafs.AuthService/Login", "/dafs.AuthService/LoginChallenge", "/dafs.AuthService/Register", "/dafs.UserManagementService/LoginUser", "/dafs.UserManagementService/RegisterUser"]
+                .iter()
+                .map(|route| (route.to_string(), strict))
+                .collect(),
+        }
+    }
+}
+
+impl RateLimitConfig {
+    /// Applies a `ROUTE=CAPACITY/PER_SEC` override; `default` as the route sets the
+    /// fallback limit.
+    pub fn set(&mut self, spec: &str) -> Result<(), String> {
+        let (route, limit) = spec.split_once('=').ok_or_else(|| format!("expected ROUTE=CAPACITY/PER_SEC, got '{}'", spec))?;
+        let limit: RateLimit = limit.parse()?;
+        if route == "default" {
+            self.default = Some(limit);
+        } else {
+            self.routes.retain(|(r, _)| r != route);
+            self.routes.push((route.to_string(), limit));
+        }
+        Ok(())
+    }
+
+    fn rule_for(&self, path: &str) -> Option<(&str, RateLimit)> {
+        self.routes
+            .iter()
+            .filter(|(route, _)| path == route || path.strip_prefix(route.as_str()).is_some_and(|rest| rest.starts_with('/')))
+            .max_by_key(|(route, _)| route.len())
+            .map(|(route, limit)| (route.as_str(), *limit))
+            .or_else(|| self.default.map(|limit| ("default", limit)))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Bucket {
+    tokens: f64,
+    updated_ms: u64,
+}
+
+impl Bucket {
+    fn refilled(data: Option<&[u8]>, limit: RateLimit, now_ms: u64) -> Self {
+        let capacity = limit.capacity as f64;
+        match data.and_then(|d| serde_json::from_slice::<Bucket>(d).ok()) {
+            Some(bucket) => {
+                let elapsed = now_ms.saturating_sub(bucket.updated_ms) as f64 / 1000.0;
+                Bucket { tokens: (bucket.tokens + elapsed * limit.per_sec).min(capacity), updated_ms: now_ms }
+            }
+            None => Bucket { tokens: capacity, updated_ms: now_ms },
+        }
+    }
+}
+
+/// A request turned away by [`RateLimiter::check`].
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimited {
+    pub retry_after: u64,
+}
+
+pub struct RateLimiter {
+    tree: sled::Tree,
+    config: RateLimitConfig,
+}
+
+impl RateLimiter {
+    /// Opens the bucket store, dropping buckets left idle long enough to have refilled.
+    pub fn open(storage: &Storage, config: RateLimitConfig) -> anyhow::Result<Self> {
+        let tree = storage.open_tree("rate_buckets")?;
+        let now = now_millis();
+        for item in tree.iter() {
+            let (key, value) = item?;
+            let idle = serde_json::from_slice::<Bucket>(&value).map_or(true, |b| now.saturating_sub(b.updated_ms) > BUCKET_IDLE_MS);
+            if idle {
+                tree.remove(key)?;
+            }
+        }
+        Ok(Self { tree, config })
+    }
+
+    /// Takes a token for `client` on `path`. Store errors let the request through, since
+    /// the limiter is a throttle and not an access check.
+    pub fn check(&self, path: &str, client: IpAddr) -> Result<(), RateLimited> {
+        let Some((route, limit)) = self.config.rule_for(path) else {
+            return Ok(());
+        };
+        let now = now_millis();
+        let mut retry_after = None;
+        let result = self.tree.update_and_fetch(format!("{}|{}", route, client), |old| {
+            let mut bucket = Bucket::refilled(old, limit, now);
+            if bucket.tokens >= 1.0 {
+                bucket.tokens -= 1.0;
+                retry_after = None;
+            } else {
+                retry_after = Some(((1.0 - bucket.tokens) / limit.per_sec).ceil().max(1.0) as u64);
+            }
+            serde_json::to_vec(&bucket).ok()
+        });
+        if let Err(e) = result {
+            eprintln!("Rate limiter store error: {}", e);
+            return Ok(());
+        }
+        match retry_after {
+            Some(retry_after) => Err(RateLimited { retry_after }),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The 429 sent for a rate-limited request or a locked-out login.
+pub fn too_many_requests(retry_after: u64, message: String) -> Response {
+    (StatusCode::TOO_MANY_REQUESTS, [(header::RETRY_AFTER, retry_after.to_string())], message).into_response()
+}
+
+/// The gRPC counterpart of [`too_many_requests`], with the wait in `retry-after` metadata.
+pub fn resource_exhausted(retry_after: u64, message: String) -> Status {
+    let mut status = Status::resource_exhausted(message);
+    if let Ok(value) = retry_after.to_string().parse() {
+        status.metadata_mut().insert("retry-after", value);
+    }
+    status
+}
+
+/// REST middleware applying the [`RateLimiter`] to every route, keyed by client IP.
+pub async fn rate_limit<B>(
+    Extension(limiter): Extension<Arc<RateLimiter>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    match limiter.check(request.uri().path(), addr.ip()) {
+        Ok(()) => next.run(request).await,
+        Err(limited) => too_many_requests(limited.retry_after, format!("Rate limit exceeded; retry in {}s", limited.retry_after)),
+    }
+}
+
+/// The peer address of a gRPC call, over plain TCP or TLS, as `tonic::Request::remote_addr`
+/// finds it.
+fn client_addr<B>(request: &http::Request<B>) -> Option<SocketAddr> {
+    let extensions = request.extensions();
+    extensions
+        .get::<TcpConnectInfo>()
+        .and_then(|info| info.remote_addr())
+        .or_else(|| extensions.get::<TlsConnectInfo<TcpConnectInfo>>().and_then(|info| info.get_ref().remote_addr()))
+}
+
+/// Tower layer applying the [`RateLimiter`] to gRPC calls, keyed by method path and
+/// client IP.
+#[derive(Clone)]
+pub struct GrpcRateLimitLayer {
+    limiter: Arc<RateLimiter>,
+}
+
+impl GrpcRateLimitLayer {
+    pub fn new(limiter: Arc<RateLimiter>) -> Self {
+        Self { limiter }
+    }
+}
+
+impl<S> tower::Layer<S> for GrpcRateLimitLayer {
+    type Service = GrpcRateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        GrpcRateLimit { inner, limiter: self.limiter.clone() }
+    }
+}
+
+#[derive(Clone)]
+pub struct GrpcRateLimit<S> {
+    inner: S,
+    limiter: Arc<RateLimiter>,
+}
+
+impl<S, B> tower::Service<http::Request<B>> for GrpcRateLimit<S>
+where
+    S: tower::Service<http::Request<B>, Response = http::Response<tonic::body::BoxBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    B: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let client = client_addr(&request);
+        if let Some(addr) = client
+            && let Err(limited) = self.limiter.check(request.uri().path(), addr.ip())
+        {
+            let status = resource_exhausted(limited.retry_after, format!("Rate limit exceeded; retry in {}s", limited.retry_after));
+            return Box::pin(async move { Ok(status.to_http()) });
+        }
+        // The clone may not be ready; call the one poll_ready was driven on
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        Box::pin(inner.call(request))
+    }
+}
@@ -1,21 +1,118 @@
-use crate::models::{UserIdentity, UserSession};
 use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
 use std::collections::HashMap;
 use std::fs;
 use std::path::Path;
-use std::sync::Mutex;
-use once_cell::sync::Lazy;
-use anyhow::Result;
+use anyhow::{Context, Result};
 use uuid::Uuid;
 use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::net::TcpStream;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use std::sync::Arc;
+use crate::transport::{TlsSettings, DEFAULT_CERT_PATH};
 use serde_json;
+use crate::crypto::SecretString;
 
-// Global remote connection manager
-static REMOTE_CONNECTIONS: Lazy<Mutex<HashMap<String, RemoteConnection>>> = Lazy::new(|| {
-    Mutex::new(HashMap::new())
-});
+// Admin protocol: every message is a 4-byte big-endian length followed by that many
+// bytes of JSON, carried over TLS (see `admin_acceptor` and `admin_connector`). A
+// connection may carry any number of requests.
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AdminRequest {
+    Auth {
+        username: String,
+        password: SecretString,
+        /// TOTP or recovery code, for node accounts with two-factor enabled
+        #[serde(default)]
+        otp: Option<String>,
+    },
+    Command { command: String, auth_token: String },
+    Logout { auth_token: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminAuthResponse {
+    pub success: bool,
+    pub token: Option<String>,
+    pub expires_at: Option<u64>,
+    pub error: Option<String>,
+}
+
+pub async fn write_frame<W: AsyncWrite + Unpin, T: Serialize>(writer: &mut W, message: &T) -> Result<()> {
+    let data = serde_json::to_vec(message)?;
+    anyhow::ensure!(data.len() <= MAX_FRAME_LEN, "Frame of {} bytes exceeds the {} byte limit", data.len(), MAX_FRAME_LEN);
+    writer.write_u32(data.len() as u32).await?;
+    writer.write_all(&data).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Reads one frame; `None` means the peer closed the connection between frames.
+pub async fn read_frame<R: AsyncRead + Unpin, T: DeserializeOwned>(reader: &mut R) -> Result<Option<T>> {
+    let len = match reader.read_u32().await {
+        Ok(len) => len as usize,
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    anyhow::ensure!(len <= MAX_FRAME_LEN, "Frame of {} bytes exceeds the {} byte limit", len, MAX_FRAME_LEN);
+    let mut data = vec![0u8; len];
+    reader.read_exact(&mut data).await?;
+    Ok(Some(serde_json::from_slice(&data)?))
+}
+
+/// The admin port's TLS acceptor, serving the node certificate in `tls`.
+pub fn admin_acceptor(tls: &TlsSettings) -> Result<TlsAcceptor> {
+    let certs = read_pem_certs(&tls.cert_path)?;
+    let key = read_pem_key(&tls.key_path)?;
+    let config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// TLS client for the admin port, trusting `DAFS_TLS_CA` (default: this node's own
+/// certificate). Returns the connector and the name to verify, `DAFS_TLS_DOMAIN`
+/// (default `localhost`), as with [`crate::transport::grpc_endpoint`].
+pub fn admin_connector() -> Result<(TlsConnector, rustls::ServerName)> {
+    let ca_path = std::env::var("DAFS_TLS_CA").unwrap_or_else(|_| DEFAULT_CERT_PATH.to_string());
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in read_pem_certs(Path::new(&ca_path))? {
+        roots.add(&cert)?;
+    }
+    let config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let domain = std::env::var("DAFS_TLS_DOMAIN").unwrap_or_else(|_| "localhost".to_string());
+    let name = rustls::ServerName::try_from(domain.as_str()).with_context(|| format!("invalid TLS name {}", domain))?;
+    Ok((TlsConnector::from(Arc::new(config)), name))
+}
+
+fn read_pem_certs(path: &Path) -> Result<Vec<rustls::Certificate>> {
+    let pem = fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut pem.as_slice())?;
+    anyhow::ensure!(!certs.is_empty(), "No certificates in {}", path.display());
+    Ok(certs.into_iter().map(rustls::Certificate).collect())
+}
+
+fn read_pem_key(path: &Path) -> Result<rustls::PrivateKey> {
+    let pem = fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+    for item in rustls_pemfile::read_all(&mut pem.as_slice())? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key) | rustls_pemfile::Item::RSAKey(key) | rustls_pemfile::Item::ECKey(key) => {
+                return Ok(rustls::PrivateKey(key));
+            }
+            _ => {}
+        }
+    }
+    anyhow::bail!("No private key in {}", path.display())
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RemoteConnection {
@@ -27,6 +124,8 @@ pub struct RemoteConnection {
     pub last_activity: u64,
     pub is_active: bool,
     pub auth_token: Option<String>,
+    #[serde(default)]
+    pub token_expires_at: Option<u64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -88,96 +187,112 @@ pub struct RemoteLogEntry {
 
 pub struct RemoteManager {
     connections: HashMap<String, RemoteConnection>,
+    streams: HashMap<String, TlsStream<TcpStream>>,
 }
 
 impl RemoteManager {
     pub fn new() -> Self {
         Self {
             connections: HashMap::new(),
+            streams: HashMap::new(),
         }
     }
 
+    async fn open_stream(host: &str, port: u16) -> Result<TlsStream<TcpStream>> {
+        let (connector, name) = admin_connector()?;
+        let tcp = TcpStream::connect(format!("{}:{}", host, port)).await?;
+        Ok(connector.connect(name, tcp).await?)
+    }
+
+    /// Sends one request on the connection's open stream, reconnecting once if the
+    /// server has closed it since the last command.
+    async fn round_trip<T: DeserializeOwned>(&mut self, connection_id: &str, request: &AdminRequest) -> Result<T> {
+        let (host, port) = {
+            let connection = self.connections.get(connection_id)
+                .ok_or_else(|| anyhow::anyhow!("Connection not found"))?;
+            (connection.host.clone(), connection.port)
+        };
+        for attempt in 0..2 {
+            let mut stream = match self.streams.remove(connection_id) {
+                Some(stream) => stream,
+                None => Self::open_stream(&host, port).await?,
+            };
+            let sent = write_frame(&mut stream, request).await;
+            let reply = match sent {
+                Ok(()) => read_frame::<_, T>(&mut stream).await,
+                Err(e) => Err(e),
+            };
+            match reply {
+                Ok(Some(response)) => {
+                    self.streams.insert(connection_id.to_string(), stream);
+                    return Ok(response);
+                }
+                Ok(None) if attempt == 0 => continue,
+                Ok(None) => return Err(anyhow::anyhow!("Connection closed by remote service")),
+                Err(e) if attempt == 0 && e.downcast_ref::<std::io::Error>().is_some() => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(anyhow::anyhow!("Connection closed by remote service"))
+    }
+
     pub async fn connect_to_service(
         &mut self,
         host: &str,
         port: u16,
         username: &str,
         password: &str,
+        otp: Option<&str>,
     ) -> Result<String> {
-        // Create connection ID
         let connection_id = Uuid::new_v4().to_string();
-        
-        // Attempt to connect to remote service
-        let mut stream = TcpStream::connect(format!("{}:{}", host, port)).await?;
-        
-        // Send authentication request
-        let auth_request = serde_json::json!({
-            "type": "auth",
-            "username": username,
-            "password": password,
-            "timestamp": SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
-        });
-        
-        let auth_data = serde_json::to_string(&auth_request)?;
-        stream.write_all(auth_data.as_bytes()).await?;
-        
-        // Read response
-        let mut buffer = [0; 1024];
-        let n = stream.read(&mut buffer).await?;
-        let response = String::from_utf8_lossy(&buffer[..n]);
-        
-        let auth_response: serde_json::Value = serde_json::from_str(&response)?;
-        
-        if auth_response["success"].as_bool().unwrap_or(false) {
+        let mut stream = Self::open_stream(host, port).await?;
+        let request = AdminRequest::Auth {
+            username: username.to_string(),
+            password: SecretString::from(password.to_string()),
+            otp: otp.map(str::to_string),
+        };
+        write_frame(&mut stream, &request).await?;
+        let auth_response: AdminAuthResponse = read_frame(&mut stream).await?
+            .ok_or_else(|| anyhow::anyhow!("Connection closed by remote service"))?;
+
+        if auth_response.success {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
             let connection = RemoteConnection {
                 id: connection_id.clone(),
                 host: host.to_string(),
                 port,
                 username: username.to_string(),
-                connected_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
-                last_activity: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+                connected_at: now,
+                last_activity: now,
                 is_active: true,
-                auth_token: auth_response["token"].as_str().map(|s| s.to_string()),
+                auth_token: auth_response.token,
+                token_expires_at: auth_response.expires_at,
             };
             
             self.connections.insert(connection_id.clone(), connection);
+            self.streams.insert(connection_id.clone(), stream);
             self.save_connections()?;
             
             Ok(connection_id)
         } else {
-            Err(anyhow::anyhow!("Authentication failed: {}", auth_response["error"].as_str().unwrap_or("Unknown error")))
+            Err(anyhow::anyhow!("Authentication failed: {}", auth_response.error.unwrap_or_else(|| "Unknown error".to_string())))
         }
     }
 
     pub async fn execute_command(&mut self, connection_id: &str, command: &str) -> Result<RemoteCommandResponse> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
         let connection = self.connections.get_mut(connection_id)
             .ok_or_else(|| anyhow::anyhow!("Connection not found"))?;
-        
-        // Update last activity
-        connection.last_activity = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-        
-        // Connect to remote service
-        let mut stream = TcpStream::connect(format!("{}:{}", connection.host, connection.port)).await?;
-        
-        // Send command request
-        let command_request = serde_json::json!({
-            "type": "command",
-            "command": command,
-            "auth_token": connection.auth_token,
-            "timestamp": SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
-        });
-        
-        let command_data = serde_json::to_string(&command_request)?;
-        stream.write_all(command_data.as_bytes()).await?;
-        
-        // Read response
-        let mut buffer = [0; 4096];
-        let n = stream.read(&mut buffer).await?;
-        let response = String::from_utf8_lossy(&buffer[..n]);
-        
-        let command_response: RemoteCommandResponse = serde_json::from_str(&response)?;
-        
-        Ok(command_response)
+        let auth_token = connection.auth_token.clone()
+            .filter(|_| connection.is_active)
+            .ok_or_else(|| anyhow::anyhow!("Connection is not authenticated; run remoteconnect again"))?;
+        if connection.token_expires_at.is_some_and(|expires_at| expires_at <= now) {
+            return Err(anyhow::anyhow!("Session expired; run remoteconnect again"));
+        }
+        connection.last_activity = now;
+
+        let request = AdminRequest::Command { command: command.to_string(), auth_token };
+        self.round_trip(connection_id, &request).await
     }
 
     pub async fn get_service_status(&mut self, connection_id: &str) -> Result<RemoteServiceStatus> {
@@ -322,21 +437,43 @@ impl RemoteManager {
         self.connections.values().collect()
     }
 
-    pub fn disconnect(&mut self, connection_id: &str) -> Result<()> {
+    /// Revokes the session token on the remote service and forgets it locally.
+    pub async fn disconnect(&mut self, connection_id: &str) -> Result<()> {
+        let token = self.connections.get(connection_id).and_then(|c| c.auth_token.clone());
+        if let Some(auth_token) = token {
+            let _ = self.round_trip::<RemoteCommandResponse>(connection_id, &AdminRequest::Logout { auth_token }).await;
+        }
+        self.streams.remove(connection_id);
         if let Some(connection) = self.connections.get_mut(connection_id) {
             connection.is_active = false;
+            connection.auth_token = None;
             self.save_connections()?;
         }
         Ok(())
     }
 
+    /// The most recently used connection that still holds a session.
+    fn active_connection_id(&self) -> Result<String> {
+        self.connections.values()
+            .filter(|c| c.is_active && c.auth_token.is_some())
+            .max_by_key(|c| c.last_activity)
+            .map(|c| c.id.clone())
+            .ok_or_else(|| anyhow::anyhow!("Not connected to a remote service; run remoteconnect first"))
+    }
+
     fn save_connections(&self) -> Result<()> {
         let connections_dir = "remote_connections";
         fs::create_dir_all(connections_dir)?;
         
         let filename = format!("{}/connections.json", connections_dir);
         let data = serde_json::to_string_pretty(&self.connections)?;
-        fs::write(filename, data)?;
+        fs::write(&filename, data)?;
+        // The file holds session tokens
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&filename, fs::Permissions::from_mode(0o600))?;
+        }
         Ok(())
     }
 
@@ -356,10 +493,10 @@ pub fn init_remote_manager() -> Result<()> {
     manager.load_connections()
 }
 
-pub async fn connect_to_remote_service(host: &str, port: u16, username: &str, password: &str) -> Result<String> {
+pub async fn connect_to_remote_service(host: &str, port: u16, username: &str, password: &str, otp: Option<&str>) -> Result<String> {
     let mut manager = RemoteManager::new();
     manager.load_connections()?;
-    manager.connect_to_service(host, port, username, password).await
+    manager.connect_to_service(host, port, username, password, otp).await
 }
 
 pub async fn execute_remote_command(connection_id: &str, command: &str) -> Result<RemoteCommandResponse> {
@@ -434,64 +571,81 @@ pub async fn restore_remote_data(connection_id: &str, path: &str) -> Result<Stri
 }
 
 // Simplified functions for CLI that don't require connection_id
-pub async fn connect_to_remote(host: &str, port: u16, username: &str, password: &str) -> Result<()> {
-    let connection_id = connect_to_remote_service(host, port, username, password).await?;
+pub async fn connect_to_remote(host: &str, port: u16, username: &str, password: &str, otp: Option<&str>) -> Result<()> {
+    let connection_id = connect_to_remote_service(host, port, username, password, otp).await?;
     println!("Connected with ID: {}", connection_id);
     Ok(())
 }
 
+pub async fn disconnect_remote(connection_id: &str) -> Result<()> {
+    let mut manager = RemoteManager::new();
+    manager.load_connections()?;
+    manager.disconnect(connection_id).await
+}
+
+// The CLI's remote commands act on the most recently used session from `remoteconnect`
+fn active_manager() -> Result<(RemoteManager, String)> {
+    let mut manager = RemoteManager::new();
+    manager.load_connections()?;
+    let connection_id = manager.active_connection_id()?;
+    Ok((manager, connection_id))
+}
+
+async fn run_active_command(command: &str) -> Result<String> {
+    let (mut manager, connection_id) = active_manager()?;
+    let response = manager.execute_command(&connection_id, command).await?;
+    manager.save_connections()?;
+    if response.success {
+        Ok(response.output)
+    } else {
+        Err(anyhow::anyhow!("{}", response.error.unwrap_or_else(|| "Command failed".to_string())))
+    }
+}
+
 pub async fn execute_remote_command_simple(command: &str) -> Result<String> {
-    // For now, use a mock implementation
-    Ok(format!("Command '{}' executed successfully (mock)", command))
+    run_active_command(command).await
 }
 
 pub async fn get_remote_status_simple() -> Result<String> {
-    // For now, use a mock implementation
-    Ok("Remote service is running (mock)".to_string())
+    run_active_command("status").await
 }
 
-pub async fn manage_remote_bootstrap_simple(action: &str, _peer_id: Option<&str>, _addr: Option<&str>) -> Result<String> {
-    // For now, use a mock implementation
-    Ok(format!("Bootstrap {} completed (mock)", action))
+pub async fn manage_remote_bootstrap_simple(action: &str, peer_id: Option<&str>, addr: Option<&str>) -> Result<String> {
+    let (mut manager, connection_id) = active_manager()?;
+    manager.manage_bootstrap_node(&connection_id, action, peer_id, addr).await
 }
 
 pub async fn get_remote_logs_simple(lines: Option<u32>) -> Result<String> {
-    // For now, use a mock implementation
-    let line_count = lines.unwrap_or(50);
-    Ok(format!("Remote logs ({} lines) - mock implementation", line_count))
+    run_active_command(&format!("logs {}", lines.unwrap_or(50))).await
 }
 
 pub async fn restart_remote_service_simple() -> Result<()> {
-    // For now, use a mock implementation
-    Ok(())
+    run_active_command("restart").await.map(|_| ())
 }
 
 pub async fn stop_remote_service_simple() -> Result<()> {
-    // For now, use a mock implementation
-    Ok(())
+    run_active_command("stop").await.map(|_| ())
 }
 
 pub async fn start_remote_service_simple() -> Result<()> {
-    // For now, use a mock implementation
-    Ok(())
+    run_active_command("start").await.map(|_| ())
 }
 
-pub async fn update_remote_config_simple(_key: &str, _value: &str) -> Result<()> {
-    // For now, use a mock implementation
-    Ok(())
+pub async fn update_remote_config_simple(key: &str, value: &str) -> Result<()> {
+    run_active_command(&format!("config set {} {}", key, value)).await.map(|_| ())
 }
 
 pub async fn get_remote_config_simple(key: Option<&str>) -> Result<String> {
-    // For now, use a mock implementation
-    Ok(format!("Remote config: {} = mock_value", key.unwrap_or("all")))
+    match key {
+        Some(key) => run_active_command(&format!("config get {}", key)).await,
+        None => run_active_command("config get").await,
+    }
 }
 
-pub async fn backup_remote_data_simple(_path: &str) -> Result<()> {
-    // For now, use a mock implementation
-    Ok(())
+pub async fn backup_remote_data_simple(path: &str) -> Result<()> {
+    run_active_command(&format!("backup {}", path)).await.map(|_| ())
 }
 
-pub async fn restore_remote_data_simple(_path: &str) -> Result<()> {
-    // For now, use a mock implementation
-    Ok(())
-} 
\ No newline at end of file
+pub async fn restore_remote_data_simple(path: &str) -> Result<()> {
+    run_active_command(&format!("restore {}", path)).await.map(|_| ())
+}
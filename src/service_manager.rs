@@ -1,8 +1,16 @@
-use crate::models::{UserIdentity, UserSession};
-use crate::remote_management::{RemoteCommand, RemoteCommandResponse, RemoteServiceStatus, ServiceStatus};
+use crate::remote_management::{admin_acceptor, read_frame, write_frame, AdminAuthResponse, AdminRequest, RemoteCommand, RemoteCommandResponse, RemoteServiceStatus, ServiceStatus};
+use crate::policy::{Permission, Policy, PolicyError};
+use crate::ratelimit::LoginGuard;
+use crate::storage::Storage;
+use crate::transport::TlsSettings;
+use crate::auth::AuthError;
+use crate::mfa::MfaStore;
+use crate::user_management::{UserStore, UserStoreError};
+use sha2::{Digest, Sha256};
 use serde::{Serialize, Deserialize};
 use std::collections::HashMap;
 use std::fs;
+use std::net::IpAddr;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 use once_cell::sync::Lazy;
@@ -10,23 +18,48 @@ use anyhow::Result;
 use uuid::Uuid;
 use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::task::JoinHandle;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
 use serde_json;
-use std::thread;
-use std::time::Duration;
 
 // Global service manager
 static SERVICE_MANAGER: Lazy<Mutex<ServiceManager>> = Lazy::new(|| {
     Mutex::new(ServiceManager::new())
 });
 
+// Admin session tokens, keyed by the SHA-256 of the token so the map never holds a usable one
+static ADMIN_TOKENS: Lazy<Mutex<HashMap<String, AdminToken>>> = Lazy::new(|| {
+    Mutex::new(HashMap::new())
+});
+
+struct AdminToken {
+    principal: Principal,
+    expires_at: u64,
+}
+
+/// Who an admin-port token was issued to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Principal {
+    /// The configured admin console account
+    Console,
+    /// A node account, by user_id
+    Node(String),
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServiceConfig {
     pub service_name: String,
     pub service_port: u16,
     pub admin_port: u16,
     pub admin_username: String,
-    pub admin_password: String,
+    /// PBKDF2 hash and salt of the admin console password, hex encoded
+    pub admin_password_hash: String,
+    pub admin_password_salt: String,
+    pub token_ttl_secs: u64,
+    /// Certificate for the admin port; the node's self-signed pair when unset
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
     pub auto_start: bool,
     pub log_level: String,
     pub data_dir: String,
@@ -35,6 +68,12 @@ pub struct ServiceConfig {
     pub allowed_ips: Vec<String>,
 }
 
+impl ServiceConfig {
+    fn is_ip_allowed(&self, ip: &str) -> bool {
+        self.allowed_ips.iter().any(|allowed| allowed == ip)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServiceInfo {
     pub service_id: String,
@@ -64,16 +103,29 @@ pub struct ServiceManager {
     connections: std::collections::HashMap<String, Arc<Mutex<TcpStream>>>,
     command_history: Vec<RemoteCommand>,
     is_running: bool,
+    accept_task: Option<JoinHandle<()>>,
 }
 
 impl ServiceManager {
+    /// The admin password comes from `DAFS_ADMIN_PASSWORD`; without it a random one is
+    /// generated and printed once, since there is no safe default.
     pub fn new() -> Self {
+        let password = std::env::var("DAFS_ADMIN_PASSWORD").unwrap_or_else(|_| {
+            let generated = Uuid::new_v4().simple().to_string();
+            println!("Generated admin console password for 'admin': {}", generated);
+            generated
+        });
+        let (salt, hash) = crate::crypto::hash_password(&password);
         let config = ServiceConfig {
             service_name: "dafs-bootstrap".to_string(),
             service_port: 2093,
             admin_port: 2094,
             admin_username: "admin".to_string(),
-            admin_password: "admin123".to_string(),
+            admin_password_hash: hex::encode(hash),
+            admin_password_salt: hex::encode(salt),
+            token_ttl_secs: 3600,
+            tls_cert: None,
+            tls_key: None,
             auto_start: true,
             log_level: "info".to_string(),
             data_dir: "./data".to_string(),
@@ -105,9 +157,18 @@ impl ServiceManager {
             connections: HashMap::new(),
             command_history: Vec::new(),
             is_running: false,
+            accept_task: None,
         }
     }
 
+    pub fn set_admin_password(&mut self, password: &str) {
+        let (salt, hash) = crate::crypto::hash_password(password);
+        self.service_info.config.admin_password_hash = hex::encode(hash);
+        self.service_info.config.admin_password_salt = hex::encode(salt);
+    }
+
+    /// Binds the admin port and serves it with TLS in the background. Connections from
+    /// addresses outside `allowed_ips` are dropped before the handshake.
     pub async fn start_service(&mut self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         if self.is_running {
             return Ok(());
@@ -115,41 +176,49 @@ impl ServiceManager {
         self.service_info.status = ServiceStatus::Starting;
         tokio::fs::create_dir_all(&self.service_info.config.data_dir).await?;
         tokio::fs::create_dir_all(&self.service_info.config.backup_dir).await?;
-        let admin_port = self.service_info.config.admin_port;
-        let admin_addr = format!("0.0.0.0:{}", admin_port);
+        let config = self.service_info.config.clone();
+        let tls = TlsSettings::load_or_generate(config.tls_cert.clone().map(Into::into), config.tls_key.clone().map(Into::into), None)?;
+        let acceptor = admin_acceptor(&tls)?;
+        let admin_addr = format!("0.0.0.0:{}", config.admin_port);
         let listener = TcpListener::bind(&admin_addr).await?;
-        println!("DAFS Bootstrap Service started on admin port {}", admin_port);
+        println!("DAFS Bootstrap Service started on admin port {} (TLS)", config.admin_port);
         self.service_info.status = ServiceStatus::Running;
         self.service_info.start_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
         self.is_running = true;
         self.start_metrics_collection();
-        let connections = Arc::new(Mutex::new(std::collections::HashMap::new()));
-        while self.is_running {
-            match listener.accept().await {
-                Ok((socket, addr)) => {
-                    if self.is_ip_allowed(&addr.ip().to_string()) {
-                        let connection_id = Uuid::new_v4().to_string();
-                        let socket = Arc::new(Mutex::new(socket));
-                        {
-                            let mut conns = connections.lock().await;
-                            conns.insert(connection_id.clone(), socket.clone());
+        self.accept_task = Some(Self::spawn_admin_listener(listener, acceptor, config));
+        Ok(())
+    }
+
+    // Kept out of `start_service` so the spawned task's type does not depend on it
+    fn spawn_admin_listener(listener: TcpListener, acceptor: TlsAcceptor, config: ServiceConfig) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((socket, addr)) => {
+                        if !config.is_ip_allowed(&addr.ip().to_string()) {
+                            println!("Connection rejected from unauthorized IP: {}", addr.ip());
+                            continue;
                         }
-                        // Extract only Send-compatible data for the async block
-                        let service_info = self.service_info.clone();
-                        let socket_owned = socket.clone();
+                        let acceptor = acceptor.clone();
+                        let config = config.clone();
                         tokio::spawn(async move {
-                            let _ = ServiceManager::handle_connection(socket_owned, service_info).await;
+                            match acceptor.accept(socket).await {
+                                Ok(stream) => {
+                                    if let Err(e) = ServiceManager::handle_connection(stream, addr.ip(), &config).await {
+                                        eprintln!("Admin connection from {} closed: {}", addr, e);
+                                    }
+                                }
+                                Err(e) => eprintln!("TLS handshake with {} failed: {}", addr, e),
+                            }
                         });
-                    } else {
-                        println!("Connection rejected from unauthorized IP: {}", addr.ip());
                     }
-                }
-                Err(e) => {
-                    eprintln!("Error accepting connection: {}", e);
+                    Err(e) => {
+                        eprintln!("Error accepting connection: {}", e);
+                    }
                 }
             }
-        }
-        Ok(())
+        })
     }
 
     pub async fn stop_service(&mut self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
@@ -157,12 +226,11 @@ impl ServiceManager {
             return Ok(());
         }
         self.service_info.status = ServiceStatus::Stopping;
+        if let Some(task) = self.accept_task.take() {
+            task.abort();
+        }
         self.is_running = false;
-        // Close all connections
-        // Use Arc<Mutex<HashMap<...>>> for connections
-        // (Assume connections is Arc<Mutex<HashMap<...>>> in aggressive rewrite)
-        // If not, clear as before
-        // self.connections.clear();
+        self.service_info.status = ServiceStatus::Stopped;
         Ok(())
     }
 
@@ -194,16 +262,41 @@ impl ServiceManager {
         }
     }
 
-    pub async fn execute_command(&mut self, command: &str, user_id: &str) -> Result<RemoteCommandResponse, Box<dyn std::error::Error + Send + Sync>> {
+    /// Runs an admin command on behalf of `principal`. The admin console account acts as an
+    /// admin; node accounts are checked against their role in the node's policy.
+    pub async fn execute_command(&mut self, command: &str, principal: &Principal) -> Result<RemoteCommandResponse, Box<dyn std::error::Error + Send + Sync>> {
         let start_time = SystemTime::now();
-        let result: Result<String, Box<dyn std::error::Error + Send + Sync>> = match command.split_whitespace().next().unwrap_or("") {
+        let verb = command.split_whitespace().next().unwrap_or("");
+        let actor = match principal {
+            Principal::Console => self.service_info.config.admin_username.clone(),
+            Principal::Node(user_id) => user_id.clone(),
+        };
+        if let Err(e) = self.authorize_command(verb, principal) {
+            audit_admin(&actor, "admin_command", command, false, Some(&e.to_string()));
+            return Ok(RemoteCommandResponse {
+                success: false,
+                output: String::new(),
+                error: Some(e.to_string()),
+                execution_time: start_time.elapsed().unwrap_or_default().as_millis() as u64,
+            });
+        }
+        let result: Result<String, Box<dyn std::error::Error + Send + Sync>> = match verb {
             "status" => self.handle_status_command().await.map_err(|e| Box::<dyn std::error::Error + Send + Sync>::from(e.to_string())),
             "restart" => self.handle_restart_command().await,
             "stop" => self.handle_stop_command().await,
             "start" => self.handle_start_command().await,
+            "logs" => self.handle_logs_command(command).await.map_err(Into::into),
+            "config" => self.handle_config_command(command).await.map_err(Into::into),
+            "backup" => self.handle_backup_command(command).await.map_err(Into::into),
+            "restore" => self.handle_restore_command(command).await.map_err(Into::into),
+            "add-bootstrap" => self.handle_add_bootstrap_command(command).await.map_err(Into::into),
+            "remove-bootstrap" => self.handle_remove_bootstrap_command(command).await.map_err(Into::into),
+            "list-bootstrap" => self.handle_list_bootstrap_command().await.map_err(Into::into),
             _ => Err(Box::<dyn std::error::Error + Send + Sync>::from(format!("Unknown command: {}", command))),
         };
         let execution_time = start_time.elapsed().unwrap_or_default().as_millis() as u64;
+        let error = result.as_ref().err().map(|e| e.to_string());
+        audit_admin(&actor, "admin_command", command, result.is_ok(), error.as_deref());
         match result {
             Ok(output) => Ok(RemoteCommandResponse {
                 success: true,
@@ -220,95 +313,157 @@ impl ServiceManager {
         }
     }
 
-    async fn handle_connection(socket: Arc<Mutex<TcpStream>>, service_info: ServiceInfo) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        let mut buffer = [0; 4096];
-        // Lock the socket, read, then drop the guard before any .await
-        let n = {
-            let mut guard = socket.lock().await;
-            guard.read(&mut buffer).await?
+    fn authorize_command(&self, verb: &str, principal: &Principal) -> Result<(), PolicyError> {
+        let permission = match verb {
+            "status" | "logs" | "list-bootstrap" => Permission::ViewStatus,
+            "add-bootstrap" | "remove-bootstrap" => Permission::ManagePeers,
+            _ => Permission::ManageServices,
         };
-        if n == 0 {
-            return Ok(());
-        }
-        let request = String::from_utf8_lossy(&buffer[..n]);
-        if let Ok(json_request) = serde_json::from_str::<serde_json::Value>(&request) {
-            let response = match json_request["type"].as_str() {
-                Some("auth") => Self::handle_auth_request(&json_request, &service_info).await,
-                Some("command") => {
-                    // Extract command string
-                    let command = json_request["command"].as_str().unwrap_or("");
-                    // Execute command synchronously on SERVICE_MANAGER before spawning
-                    let response_result = {
-                        let mut manager = SERVICE_MANAGER.blocking_lock();
-                        // Use block_in_place to allow blocking in async context
-                        tokio::task::block_in_place(|| futures::executor::block_on(manager.execute_command(command, "admin")))
-                    };
-                    let response = response_result.unwrap_or_else(|e| RemoteCommandResponse {
-                        success: false,
-                        output: String::new(),
-                        error: Some(e.to_string()),
+        let user_id = match principal {
+            Principal::Console => return Ok(()),
+            Principal::Node(user_id) => user_id,
+        };
+        let storage = Storage::new("dafs_db")?;
+        Policy::open(&storage)?.authorize(user_id, permission).map(|_| ())
+    }
+
+    /// Serves framed requests on one admin connection until the client hangs up. Commands
+    /// carry the token from a previous `auth` and run as the account it was issued to.
+    async fn handle_connection(mut stream: TlsStream<TcpStream>, client: IpAddr, config: &ServiceConfig) -> Result<()> {
+        while let Some(request) = read_frame::<_, AdminRequest>(&mut stream).await? {
+            match request {
+                AdminRequest::Auth { username, password, otp } => {
+                    let response = Self::handle_auth_request(&username, password.expose(), otp.as_deref(), client, config).await;
+                    write_frame(&mut stream, &response).await?;
+                }
+                AdminRequest::Command { command, auth_token } => {
+                    let response = Self::handle_command_request(&command, &auth_token).await;
+                    write_frame(&mut stream, &response).await?;
+                }
+                AdminRequest::Logout { auth_token } => {
+                    ADMIN_TOKENS.lock().await.remove(&token_key(&auth_token));
+                    let response = RemoteCommandResponse {
+                        success: true,
+                        output: "Logged out".to_string(),
+                        error: None,
                         execution_time: 0,
-                    });
-                    serde_json::json!({
-                        "success": response.success,
-                        "output": response.output,
-                        "error": response.error,
-                        "execution_time": response.execution_time
-                    })
-                },
-                _ => serde_json::json!({
-                    "success": false,
-                    "error": "Unknown request type"
-                }),
-            };
-            let response_data = serde_json::to_string(&response)?;
-            // Lock the socket again to write, then drop the guard before any .await
-            {
-                let mut guard = socket.lock().await;
-                guard.write_all(response_data.as_bytes()).await?;
+                    };
+                    write_frame(&mut stream, &response).await?;
+                }
             }
         }
         Ok(())
     }
 
-    async fn handle_auth_request(request: &serde_json::Value, service_info: &ServiceInfo) -> serde_json::Value {
-        let username = request["username"].as_str().unwrap_or("");
-        let password = request["password"].as_str().unwrap_or("");
-        
-        if username == service_info.config.admin_username && password == service_info.config.admin_password {
-            let token = Uuid::new_v4().to_string();
-            serde_json::json!({
-                "success": true,
-                "token": token,
-                "message": "Authentication successful"
-            })
+    /// Accepts the console admin account, checked against its stored hash, or any node
+    /// account, with its second factor when it has one; what each may run is decided per
+    /// command by `authorize_command`. Failures count towards the same per-user and per-IP
+    /// lockouts as the node's own login.
+    async fn handle_auth_request(username: &str, password: &str, otp: Option<&str>, client: IpAddr, config: &ServiceConfig) -> AdminAuthResponse {
+        let denied = |error: String| AdminAuthResponse {
+            success: false,
+            token: None,
+            expires_at: None,
+            error: Some(error),
+        };
+        // Without the lockout state there is no way to tell a guessing client apart, so refuse
+        let guard = match Storage::new("dafs_db").and_then(|storage| LoginGuard::open(&storage)) {
+            Ok(guard) => guard,
+            Err(e) => return denied(format!("Login unavailable: {}", e)),
+        };
+        match guard.retry_after(username, Some(client)) {
+            Ok(None) => {}
+            Ok(Some(retry_after)) => {
+                audit_admin(username, "admin_login", username, false, Some("locked out"));
+                return denied(format!("Too many failed login attempts; try again in {}s", retry_after));
+            }
+            Err(e) => return denied(format!("Login unavailable: {}", e)),
+        }
+        let result = if username == config.admin_username {
+            let salt = hex::decode(&config.admin_password_salt).unwrap_or_default();
+            let hash = hex::decode(&config.admin_password_hash).unwrap_or_default();
+            if crate::crypto::verify_password(password, &salt, &hash) {
+                Ok(Principal::Console)
+            } else {
+                Err(AuthError::InvalidCredentials)
+            }
         } else {
-            serde_json::json!({
-                "success": false,
-                "error": "Invalid credentials"
-            })
+            Self::verify_node_account(username, password, otp).map(Principal::Node)
+        };
+        let error = result.as_ref().err().map(|e| e.to_string());
+        audit_admin(username, "admin_login", username, result.is_ok(), error.as_deref());
+        let updated = match &result {
+            Ok(_) => guard.record_success(username),
+            Err(AuthError::InvalidCredentials | AuthError::InvalidSecondFactor) => guard.record_failure(username, Some(client)).map(|_| ()),
+            Err(_) => Ok(()),
+        };
+        if let Err(e) = updated {
+            eprintln!("Failed to update login attempts for {}: {}", username, e);
+        }
+        let principal = match result {
+            Ok(principal) => principal,
+            Err(e) => return denied(e.to_string()),
+        };
+        let token = hex::encode(rand::random::<[u8; 32]>());
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let expires_at = now + config.token_ttl_secs;
+        let mut tokens = ADMIN_TOKENS.lock().await;
+        tokens.retain(|_, t| t.expires_at > now);
+        tokens.insert(token_key(&token), AdminToken { principal, expires_at });
+        AdminAuthResponse {
+            success: true,
+            token: Some(token),
+            expires_at: Some(expires_at),
+            error: None,
+        }
+    }
+
+    /// A node account's user_id, once its password and any second factor check out. The
+    /// admin port has no device identity, so trusted devices don't apply here.
+    fn verify_node_account(username: &str, password: &str, otp: Option<&str>) -> std::result::Result<String, AuthError> {
+        let storage = Storage::new("dafs_db")?;
+        let user = UserStore::open(&storage)?.verify_password(username, password).map_err(|e| match e {
+            UserStoreError::Storage(e) => AuthError::Storage(e),
+            _ => AuthError::InvalidCredentials,
+        })?;
+        if MfaStore::open(&storage)?.is_enabled(&user.user_id).map_err(|e| AuthError::Storage(e.into()))? {
+            let code = otp.ok_or(AuthError::SecondFactorRequired)?;
+            crate::auth::verify_second_factor(&storage, &user.user_id, code)?;
         }
+        Ok(user.user_id)
     }
 
-    async fn handle_command_request(request: &serde_json::Value, service_info: &ServiceInfo) -> serde_json::Value {
-        let command = request["command"].as_str().unwrap_or("");
-        let _auth_token = request["auth_token"].as_str();
-        // TODO: Validate auth token
+    async fn handle_command_request(command: &str, auth_token: &str) -> RemoteCommandResponse {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let principal = {
+            let mut tokens = ADMIN_TOKENS.lock().await;
+            let key = token_key(auth_token);
+            match tokens.get(&key) {
+                Some(token) if token.expires_at > now => Some(token.principal.clone()),
+                Some(_) => {
+                    tokens.remove(&key);
+                    None
+                }
+                None => None,
+            }
+        };
+        let Some(principal) = principal else {
+            return RemoteCommandResponse {
+                success: false,
+                output: String::new(),
+                error: Some("Invalid or expired token".to_string()),
+                execution_time: 0,
+            };
+        };
         let response_result = {
             let mut manager = SERVICE_MANAGER.lock().await;
-            manager.execute_command(command, "admin").await
+            manager.execute_command(command, &principal).await
         };
-        let response = response_result.unwrap_or_else(|e| RemoteCommandResponse {
+        response_result.unwrap_or_else(|e| RemoteCommandResponse {
             success: false,
             output: String::new(),
             error: Some(e.to_string()),
             execution_time: 0,
-        });
-        serde_json::json!({
-            "success": response.success,
-            "output": response.output,
-            "error": response.error,
-            "execution_time": response.execution_time
         })
     }
 
@@ -431,10 +586,6 @@ impl ServiceManager {
         Ok(serde_json::to_string_pretty(&nodes)?)
     }
 
-    fn is_ip_allowed(&self, ip: &str) -> bool {
-        self.service_info.config.allowed_ips.contains(&ip.to_string())
-    }
-
     fn start_metrics_collection(&self) {
         // TODO: Implement actual metrics collection
         // This would collect system metrics like CPU, memory, disk usage
@@ -442,6 +593,18 @@ impl ServiceManager {
     }
 }
 
+// Admin-port events go to the node's audit log alongside REST and gRPC ones
+fn audit_admin(actor: &str, action: &str, target: &str, success: bool, detail: Option<&str>) {
+    match Storage::new("dafs_db") {
+        Ok(storage) => crate::audit::record(&storage, actor, action, Some(target), success, detail),
+        Err(e) => eprintln!("Failed to write audit event '{}': {}", action, e),
+    }
+}
+
+fn token_key(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
 // Public API functions
 pub fn init_service_manager() -> Result<()> {
     let manager = tokio::task::block_in_place(|| futures::executor::block_on(SERVICE_MANAGER.lock()));
@@ -468,7 +631,7 @@ pub fn get_bootstrap_service_status() -> RemoteServiceStatus {
     manager.get_status()
 }
 
-pub async fn execute_bootstrap_command(command: &str, user_id: &str) -> Result<RemoteCommandResponse, Box<dyn std::error::Error + Send + Sync>> {
+pub async fn execute_bootstrap_command(command: &str, principal: &Principal) -> Result<RemoteCommandResponse, Box<dyn std::error::Error + Send + Sync>> {
     let mut manager = SERVICE_MANAGER.lock().await;
-    manager.execute_command(command, user_id).await
+    manager.execute_command(command, principal).await
 } 
\ No newline at end of file
@@ -3,6 +3,12 @@ use serde::{Serialize, Deserialize};
 use uuid::Uuid;
 use sled::Db;
 use std::collections::HashMap;
+use std::sync::Mutex;
+use once_cell::sync::Lazy;
+
+// sled holds an exclusive lock per database directory, so every `Storage` opened on
+// the same path within this process shares one handle.
+static OPEN_DBS: Lazy<Mutex<HashMap<String, Db>>> = Lazy::new(|| Mutex::new(HashMap::new()));
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileMetadata {
@@ -15,6 +21,66 @@ pub struct FileMetadata {
     pub encrypted_file_key: Vec<u8>, // new field
     pub shared_keys: HashMap<String, Vec<u8>>, // username -> encrypted file key
     pub allowed_peers: Vec<String>, // peer IDs allowed to access this file
+    pub attributes: HashMap<String, String>, // custom key/value attributes
+    pub sealed: Option<SealedMetadata>, // set for confidential files; see crate::confidential
+}
+
+/// Filename, tags and attributes of a confidential file, encrypted under the file key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SealedMetadata {
+    pub ciphertext: Vec<u8>, // nonce || AES-256-GCM(JSON fields), like the file contents
+    pub tag_tokens: HashMap<String, Vec<String>>, // username -> blind tag tokens
+}
+
+/// Layout of `FileMetadata` before attributes and sealing; records written then are
+/// read through this.
+#[derive(Deserialize)]
+struct LegacyFileMetadata {
+    file_id: Uuid,
+    filename: String,
+    tags: Vec<String>,
+    owner_peer_id: String,
+    checksum: String,
+    size: u64,
+    encrypted_file_key: Vec<u8>,
+    shared_keys: HashMap<String, Vec<u8>>,
+    allowed_peers: Vec<String>,
+}
+
+impl From<LegacyFileMetadata> for FileMetadata {
+    fn from(m: LegacyFileMetadata) -> Self {
+        FileMetadata {
+            file_id: m.file_id,
+            filename: m.filename,
+            tags: m.tags,
+            owner_peer_id: m.owner_peer_id,
+            checksum: m.checksum,
+            size: m.size,
+            encrypted_file_key: m.encrypted_file_key,
+            shared_keys: m.shared_keys,
+            allowed_peers: m.allowed_peers,
+            attributes: HashMap::new(),
+            sealed: None,
+        }
+    }
+}
+
+fn decode_metadata(bytes: &[u8]) -> Result<FileMetadata> {
+    match bincode::deserialize::<FileMetadata>(bytes) {
+        Ok(meta) => Ok(meta),
+        Err(e) => bincode::deserialize::<LegacyFileMetadata>(bytes).map(Into::into).map_err(|_| e.into()),
+    }
+}
+
+/// Audit record of a share being withdrawn from a user.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShareRevocation {
+    pub file_id: Uuid,
+    pub revoked_user: String,
+    pub revoked_by: String,
+    pub rekeyed: bool,
+    pub timestamp: u64,
+    pub origin_peer: Option<String>, // set when the revocation arrived over P2P
 }
 
 pub struct Storage {
@@ -23,9 +89,20 @@ pub struct Storage {
 
 impl Storage {
     pub fn new(path: &str) -> Result<Self> {
-        let db = sled::open(path)?;
+        let mut open = OPEN_DBS.lock().unwrap();
+        let db = match open.get(path) {
+            Some(db) => db.clone(),
+            None => {
+                let db = sled::open(path)?;
+                open.insert(path.to_string(), db.clone());
+                db
+            }
+        };
         Ok(Self { db })
     }
+    pub fn open_tree(&self, name: &str) -> Result<sled::Tree> {
+        Ok(self.db.open_tree(name)?)
+    }
     pub fn insert_metadata(&self, meta: &FileMetadata) -> Result<()> {
         let key = meta.file_id.as_bytes();
         let value = bincode::serialize(meta)?;
@@ -34,8 +111,7 @@ impl Storage {
     }
     pub fn get_metadata(&self, file_id: &Uuid) -> Result<Option<FileMetadata>> {
         if let Some(val) = self.db.get(file_id.as_bytes())? {
-            let meta: FileMetadata = bincode::deserialize(&val)?;
-            Ok(Some(meta))
+            Ok(Some(decode_metadata(&val)?))
         } else {
             Ok(None)
         }
@@ -44,16 +120,91 @@ impl Storage {
         let mut out = Vec::new();
         for item in self.db.iter() {
             let (_k, v) = item?;
-            let meta: FileMetadata = bincode::deserialize(&v)?;
-            out.push(meta);
+            out.push(decode_metadata(&v)?);
         }
         Ok(out)
     }
     
+    /// Moves what file metadata keys by username (ownership, wrapped keys and blind tag
+    /// tokens) from `old` to `new`. Returns how many files changed.
+    pub fn rename_user(&self, old: &str, new: &str) -> Result<usize> {
+        let mut changed = 0;
+        for mut meta in self.list_metadata()? {
+            let mut touched = false;
+            if meta.owner_peer_id == old {
+                meta.owner_peer_id = new.to_string();
+                touched = true;
+            }
+            if let Some(key) = meta.shared_keys.remove(old) {
+                meta.shared_keys.insert(new.to_string(), key);
+                touched = true;
+            }
+            if let Some(sealed) = &mut meta.sealed
+                && let Some(tokens) = sealed.tag_tokens.remove(old)
+            {
+                sealed.tag_tokens.insert(new.to_string(), tokens);
+                touched = true;
+            }
+            if touched {
+                self.insert_metadata(&meta)?;
+                changed += 1;
+            }
+        }
+        Ok(changed)
+    }
+
     pub fn delete_metadata(&self, file_id: &Uuid) -> Result<()> {
         self.db.remove(file_id.as_bytes())?;
         Ok(())
     }
+
+    pub fn record_share_revocation(&self, revocation: &ShareRevocation) -> Result<()> {
+        let tree = self.open_tree("share_revocations")?;
+        // file_id || big-endian sequence keeps each file's history contiguous and ordered
+        let mut key = revocation.file_id.as_bytes().to_vec();
+        key.extend_from_slice(&self.db.generate_id()?.to_be_bytes());
+        tree.insert(key, bincode::serialize(revocation)?)?;
+        Ok(())
+    }
+
+    /// Records that `file_id` is being rekeyed to the key wrapped as `owner_key`. Until
+    /// `finish_rekey`, the new ciphertext waits beside the old one; see
+    /// `crate::api::recover_rekeys`.
+    pub fn begin_rekey(&self, file_id: &Uuid, owner_key: &[u8]) -> Result<()> {
+        let tree = self.open_tree("pending_rekeys")?;
+        tree.insert(file_id.as_bytes(), owner_key)?;
+        tree.flush()?;
+        Ok(())
+    }
+
+    pub fn finish_rekey(&self, file_id: &Uuid) -> Result<()> {
+        self.open_tree("pending_rekeys")?.remove(file_id.as_bytes())?;
+        Ok(())
+    }
+
+    /// Rekeys that were started and not finished, with the owner key each was moving to.
+    pub fn pending_rekeys(&self) -> Result<Vec<(Uuid, Vec<u8>)>> {
+        let mut out = Vec::new();
+        for item in self.open_tree("pending_rekeys")?.iter() {
+            let (k, v) = item?;
+            out.push((Uuid::from_slice(&k)?, v.to_vec()));
+        }
+        Ok(out)
+    }
+
+    pub fn list_share_revocations(&self, file_id: Option<&Uuid>) -> Result<Vec<ShareRevocation>> {
+        let tree = self.open_tree("share_revocations")?;
+        let iter = match file_id {
+            Some(id) => tree.scan_prefix(id.as_bytes()),
+            None => tree.iter(),
+        };
+        let mut out = Vec::new();
+        for item in iter {
+            let (_k, v) = item?;
+            out.push(bincode::deserialize(&v)?);
+        }
+        Ok(out)
+    }
 } 
 
 impl Default for Storage {
@@ -0,0 +1,155 @@
+// Listener settings shared by the REST and gRPC servers (bind addresses, TLS, CORS,
+// rate limits) and the matching client-side TLS setup used by the CLI.
+//
+// TLS is off unless asked for. With `--tls` and no certificate supplied, a self-signed
+// pair is generated under `tls/` on first run and reused afterwards; clients on the same
+// machine trust it by pointing DAFS_TLS_CA at `tls/node.crt` (the default). When a client
+// CA is configured, gRPC clients may present a certificate signed by it instead of a
+// bearer token; its common name is the DAFS username it acts as.
+
+use crate::ratelimit::RateLimitConfig;
+use anyhow::{Context, Result};
+use axum::http::{header, HeaderValue, Method};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use tonic::transport::{Certificate, ClientTlsConfig, Endpoint, Identity};
+use tower_http::cors::{Any, CorsLayer};
+
+pub const DEFAULT_CERT_PATH: &str = "tls/node.crt";
+pub const DEFAULT_KEY_PATH: &str = "tls/node.key";
+const DEFAULT_GRPC_URL: &str = "http://[::1]:50051";
+
+#[derive(Debug, Clone)]
+pub struct TlsSettings {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    /// CA whose client certificates are accepted in place of a bearer token (gRPC only)
+    pub client_ca_path: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub api_addr: SocketAddr,
+    pub grpc_addr: SocketAddr,
+    pub tls: Option<TlsSettings>,
+    /// Origins allowed to call the REST API from a browser; `*` allows any
+    pub cors_origins: Vec<String>,
+    /// Per-route request limits for both servers
+    pub rate_limits: RateLimitConfig,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            api_addr: "0.0.0.0:6543".parse().unwrap(),
+            grpc_addr: "[::1]:50051".parse().unwrap(),
+            tls: None,
+            cors_origins: vec!["http://localhost:3093".to_string(), "http://127.0.0.1:3093".to_string()],
+            rate_limits: RateLimitConfig::default(),
+        }
+    }
+}
+
+impl TlsSettings {
+    /// Uses the supplied certificate and key, or the node's own self-signed pair,
+    /// generating it on first run.
+    pub fn load_or_generate(cert: Option<PathBuf>, key: Option<PathBuf>, client_ca: Option<PathBuf>) -> Result<Self> {
+        let settings = match (cert, key) {
+            (Some(cert_path), Some(key_path)) => Self { cert_path, key_path, client_ca_path: client_ca },
+            (None, None) => {
+                let settings = Self {
+                    cert_path: PathBuf::from(DEFAULT_CERT_PATH),
+                    key_path: PathBuf::from(DEFAULT_KEY_PATH),
+                    client_ca_path: client_ca,
+                };
+                if !settings.cert_path.exists() || !settings.key_path.exists() {
+                    generate_self_signed(&settings.cert_path, &settings.key_path)?;
+                    println!("Generated self-signed TLS certificate at {}", settings.cert_path.display());
+                }
+                settings
+            }
+            _ => anyhow::bail!("--tls-cert and --tls-key must be given together"),
+        };
+        if let Some(ca) = &settings.client_ca_path {
+            anyhow::ensure!(ca.exists(), "Client CA {} not found", ca.display());
+        }
+        Ok(settings)
+    }
+
+    pub fn identity(&self) -> Result<Identity> {
+        let cert = fs::read(&self.cert_path).with_context(|| format!("reading {}", self.cert_path.display()))?;
+        let key = fs::read(&self.key_path).with_context(|| format!("reading {}", self.key_path.display()))?;
+        Ok(Identity::from_pem(cert, key))
+    }
+
+    pub fn client_ca(&self) -> Result<Option<Certificate>> {
+        match &self.client_ca_path {
+            Some(path) => Ok(Some(Certificate::from_pem(fs::read(path).with_context(|| format!("reading {}", path.display()))?))),
+            None => Ok(None),
+        }
+    }
+}
+
+fn generate_self_signed(cert_path: &Path, key_path: &Path) -> Result<()> {
+    let names = vec!["localhost".to_string(), "127.0.0.1".to_string(), "::1".to_string()];
+    let cert = rcgen::generate_simple_self_signed(names)?;
+    if let Some(dir) = cert_path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    if let Some(dir) = key_path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(cert_path, cert.serialize_pem()?)?;
+    fs::write(key_path, cert.serialize_private_key_pem())?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(key_path, fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(())
+}
+
+/// CORS for the REST API: only the configured origins, with the methods and headers
+/// the API actually uses.
+pub fn cors_layer(origins: &[String]) -> CorsLayer {
+    let layer = CorsLayer::new()
+        .allow_methods([Method::GET, Method::POST])
+        .allow_headers([header::AUTHORIZATION, header::CONTENT_TYPE, header::HeaderName::from_static(crate::links::PASSWORD_HEADER)]);
+    if origins.iter().any(|o| o == "*") {
+        return layer.allow_origin(Any);
+    }
+    let origins: Vec<HeaderValue> = origins.iter().filter_map(|o| o.parse().ok()).collect();
+    layer.allow_origin(origins)
+}
+
+/// The common name of a DER client certificate, which names the account it acts as.
+pub fn certificate_common_name(der: &[u8]) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
+    let cn = cert.subject().iter_common_name().next()?.as_str().ok()?;
+    Some(cn.to_string())
+}
+
+/// The gRPC endpoint for CLI clients. `DAFS_GRPC_URL` picks the server; TLS is used for
+/// `https://` URLs, trusting `DAFS_TLS_CA` (default: this node's own certificate) for
+/// the name in `DAFS_TLS_DOMAIN` (default `localhost`). `DAFS_TLS_CERT`/`DAFS_TLS_KEY`
+/// add a client certificate.
+pub fn grpc_endpoint() -> Result<Endpoint> {
+    let url = std::env::var("DAFS_GRPC_URL").unwrap_or_else(|_| DEFAULT_GRPC_URL.to_string());
+    let endpoint = Endpoint::from_shared(url.clone())?;
+    if !url.starts_with("https://") {
+        return Ok(endpoint);
+    }
+    let ca_path = std::env::var("DAFS_TLS_CA").unwrap_or_else(|_| DEFAULT_CERT_PATH.to_string());
+    let ca = fs::read(&ca_path).with_context(|| format!("reading CA certificate {}", ca_path))?;
+    let domain = std::env::var("DAFS_TLS_DOMAIN").unwrap_or_else(|_| "localhost".to_string());
+    let mut tls = ClientTlsConfig::new().ca_certificate(Certificate::from_pem(ca)).domain_name(domain);
+    if let (Ok(cert), Ok(key)) = (std::env::var("DAFS_TLS_CERT"), std::env::var("DAFS_TLS_KEY")) {
+        let identity = Identity::from_pem(
+            fs::read(&cert).with_context(|| format!("reading {}", cert))?,
+            fs::read(&key).with_context(|| format!("reading {}", key))?,
+        );
+        tls = tls.identity(identity);
+    }
+    Ok(endpoint.tls_config(tls)?)
+}
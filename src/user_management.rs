@@ -1,4 +1,5 @@
-use crate::models::{UserIdentity, UserDevice, DeviceType, UserSession};
+use crate::models::{UserIdentity, UserDevice, DeviceType};
+use crate::storage::Storage;
 use serde::{Serialize, Deserialize};
 use std::collections::HashMap;
 use std::fs;
@@ -6,12 +7,13 @@ use std::path::Path;
 use std::sync::Mutex;
 use once_cell::sync::Lazy;
 use anyhow::Result;
-use uuid::Uuid;
 use std::time::{SystemTime, UNIX_EPOCH};
 
-// Global user registry
-static USER_REGISTRY: Lazy<Mutex<UserRegistry>> = Lazy::new(|| {
-    Mutex::new(UserRegistry::new())
+// Per-device peer memory; user accounts live in the sled-backed UserStore
+static DEVICE_MEMORY: Lazy<Mutex<DeviceMemoryRegistry>> = Lazy::new(|| {
+    let mut registry = DeviceMemoryRegistry::new();
+    let _ = registry.load_from_storage();
+    Mutex::new(registry)
 });
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -31,259 +33,373 @@ pub struct PeerConnection {
     pub success: bool,
 }
 
-pub struct UserRegistry {
-    users: HashMap<String, UserIdentity>, // user_id -> UserIdentity
-    username_to_user_id: HashMap<String, String>, // username -> user_id
-    sessions: HashMap<String, UserSession>, // session_id -> UserSession
-    device_sessions: HashMap<String, String>, // device_id -> session_id
-    device_peer_memory: HashMap<String, DevicePeerMemory>, // device_id -> DevicePeerMemory
+#[derive(Debug, thiserror::Error)]
+pub enum UserStoreError {
+    #[error("Username '{0}' already exists")]
+    UsernameTaken(String),
+    #[error("User '{0}' not found")]
+    NotFound(String),
+    #[error("Invalid username or password")]
+    InvalidCredentials,
+    #[error("Invalid username '{0}': use 1 to 64 letters, digits, '_', '.' or '-'")]
+    InvalidUsername(String),
+    #[error("User '{0}' already has a password")]
+    AlreadyClaimed(String),
+    #[error("{0}")]
+    Storage(#[from] anyhow::Error),
 }
 
-impl UserRegistry {
-    pub fn new() -> Self {
-        Self {
-            users: HashMap::new(),
-            username_to_user_id: HashMap::new(),
-            sessions: HashMap::new(),
-            device_sessions: HashMap::new(),
-            device_peer_memory: HashMap::new(),
-        }
+impl From<sled::Error> for UserStoreError {
+    fn from(e: sled::Error) -> Self {
+        UserStoreError::Storage(e.into())
     }
+}
 
-    pub fn load_from_storage(&mut self) -> Result<()> {
-        let users_dir = "users";
-        if !Path::new(users_dir).exists() {
-            fs::create_dir_all(users_dir)?;
-            return Ok(());
-        }
+impl From<serde_json::Error> for UserStoreError {
+    fn from(e: serde_json::Error) -> Self {
+        UserStoreError::Storage(e.into())
+    }
+}
 
-        for entry in fs::read_dir(users_dir)? {
-            let entry = entry?;
-            if let Some(name) = entry.file_name().to_str() {
-                if name.ends_with(".json") {
-                    let user_id = name.trim_end_matches(".json");
-                    if let Ok(data) = fs::read_to_string(entry.path()) {
-                        if let Ok(user) = serde_json::from_str::<UserIdentity>(&data) {
-                            self.users.insert(user_id.to_string(), user.clone());
-                            self.username_to_user_id.insert(user.username.clone(), user_id.to_string());
-                        }
-                    }
-                }
+#[derive(Serialize, Deserialize)]
+struct Credentials {
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+pub fn keyfile_path(username: &str) -> String {
+    format!("userkeys/{}.key", username)
+}
+
+fn claim_key(user_id: &str) -> String {
+    format!("claimable:{}", user_id)
+}
+
+/// Usernames name key files, so they are limited to `[A-Za-z0-9_.-]{1,64}` and can't be
+/// `.` or `..`.
+pub fn validate_username(username: &str) -> Result<(), UserStoreError> {
+    let valid = (1..=64).contains(&username.len())
+        && username.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'_' | b'.' | b'-'))
+        && username != "."
+        && username != "..";
+    if valid { Ok(()) } else { Err(UserStoreError::InvalidUsername(username.to_string())) }
+}
+
+/// The single user database. Identities are JSON in the `users` tree (keyed by user_id),
+/// with a `usernames` index and password hashes kept apart in `user_credentials` so
+/// listing users never touches them. The private key stays in `userkeys/<name>.key`,
+/// encrypted under the same password.
+pub struct UserStore {
+    users: sled::Tree,
+    usernames: sled::Tree,
+    credentials: sled::Tree,
+    meta: sled::Tree,
+}
+
+impl UserStore {
+    pub fn open(storage: &Storage) -> Result<Self> {
+        let store = Self {
+            users: storage.open_tree("users")?,
+            usernames: storage.open_tree("usernames")?,
+            credentials: storage.open_tree("user_credentials")?,
+            meta: storage.open_tree("user_meta")?,
+        };
+        if store.meta.get("json_users_migrated")?.is_none() {
+            let migrated = store.migrate_json_users("users")?;
+            if migrated > 0 {
+                println!("Migrated {} user(s) from users/*.json", migrated);
             }
+            store.meta.insert("json_users_migrated", &[1u8])?;
         }
+        Ok(store)
+    }
 
-        // Load sessions
-        let sessions_dir = "sessions";
-        if Path::new(sessions_dir).exists() {
-            for entry in fs::read_dir(sessions_dir)? {
-                let entry = entry?;
-                if let Some(name) = entry.file_name().to_str() {
-                    if name.ends_with(".json") {
-                        let session_id = name.trim_end_matches(".json");
-                        if let Ok(data) = fs::read_to_string(entry.path()) {
-                            if let Ok(session) = serde_json::from_str::<UserSession>(&data) {
-                                self.sessions.insert(session_id.to_string(), session.clone());
-                                self.device_sessions.insert(session.device_id.clone(), session_id.to_string());
-                            }
-                        }
-                    }
+    /// Creates an account with a fresh X25519 keypair. A username that was migrated
+    /// without credentials can only be registered once an admin allows it (see
+    /// [`UserStore::allow_claim`]); registering then claims it, keeping its user_id and
+    /// devices.
+    pub fn register(&self, username: &str, display_name: &str, email: Option<String>, password: &str) -> Result<UserIdentity, UserStoreError> {
+        validate_username(username)?;
+        let mut user = match self.get_by_username(username)? {
+            Some(existing) if self.credentials.contains_key(existing.user_id.as_bytes())? => {
+                return Err(UserStoreError::UsernameTaken(username.to_string()));
+            }
+            // A legacy key file means someone already owns the name; they upgrade by logging in
+            _ if Path::new(&keyfile_path(username)).exists() => {
+                return Err(UserStoreError::UsernameTaken(username.to_string()));
+            }
+            Some(existing) => {
+                if self.meta.remove(claim_key(&existing.user_id))?.is_none() {
+                    return Err(UserStoreError::UsernameTaken(username.to_string()));
                 }
+                existing
             }
-        }
-
-        // Load device peer memory
-        let memory_dir = "device_memory";
-        if Path::new(memory_dir).exists() {
-            for entry in fs::read_dir(memory_dir)? {
-                let entry = entry?;
-                if let Some(name) = entry.file_name().to_str() {
-                    if name.ends_with(".json") {
-                        let device_id = name.trim_end_matches(".json");
-                        if let Ok(data) = fs::read_to_string(entry.path()) {
-                            if let Ok(memory) = serde_json::from_str::<DevicePeerMemory>(&data) {
-                                self.device_peer_memory.insert(device_id.to_string(), memory);
-                            }
-                        }
-                    }
+            None => {
+                let user = UserIdentity::new(username.to_string(), display_name.to_string(), email);
+                let claimed = self.usernames.compare_and_swap(username.as_bytes(), None as Option<&[u8]>, Some(user.user_id.as_bytes()))?;
+                if claimed.is_err() {
+                    return Err(UserStoreError::UsernameTaken(username.to_string()));
                 }
+                user
             }
+        };
+        let (secret, public) = crate::crypto::generate_x25519_keypair();
+        fs::create_dir_all("userkeys").map_err(anyhow::Error::from)?;
+        crate::crypto::encrypt_and_save_keypair(&secret, &keyfile_path(username), password)?;
+        user.public_key = public.to_bytes();
+        self.set_password(&user.user_id, password)?;
+        self.save(&user)?;
+        Ok(user)
+    }
+
+    /// Checks `password` and returns the account. Accounts that predate the store
+    /// (a key file but no record, or a migrated record without a hash) are verified
+    /// against their key file once and upgraded in place.
+    pub fn verify_password(&self, username: &str, password: &str) -> Result<UserIdentity, UserStoreError> {
+        let user = self.get_by_username(username)?;
+        if let Some(user) = &user
+            && let Some(data) = self.credentials.get(user.user_id.as_bytes())?
+        {
+            let creds: Credentials = serde_json::from_slice(&data)?;
+            return if crate::crypto::verify_password(password, &creds.salt, &creds.hash) {
+                Ok(user.clone())
+            } else {
+                Err(UserStoreError::InvalidCredentials)
+            };
+        }
+        if validate_username(username).is_err() {
+            return Err(UserStoreError::InvalidCredentials);
         }
+        let secret = crate::crypto::load_and_decrypt_keypair(&keyfile_path(username), password)
+            .map_err(|_| UserStoreError::InvalidCredentials)?;
+        let mut user = match user {
+            Some(user) => user,
+            None => {
+                let user = UserIdentity::new(username.to_string(), username.to_string(), None);
+                if self.usernames.compare_and_swap(username.as_bytes(), None as Option<&[u8]>, Some(user.user_id.as_bytes()))?.is_err() {
+                    return Err(UserStoreError::UsernameTaken(username.to_string()));
+                }
+                user
+            }
+        };
+        user.public_key = x25519_dalek::PublicKey::from(&secret).to_bytes();
+        self.set_password(&user.user_id, password)?;
+        self.save(&user)?;
+        Ok(user)
+    }
 
+    /// Lets the next registration of `username` claim the account, which was migrated
+    /// without a password or key file. Only admins should call this, once they know who
+    /// the account belongs to.
+    pub fn allow_claim(&self, username: &str) -> Result<(), UserStoreError> {
+        let user = self.get_by_username(username)?.ok_or_else(|| UserStoreError::NotFound(username.to_string()))?;
+        if self.credentials.contains_key(user.user_id.as_bytes())? || Path::new(&keyfile_path(username)).exists() {
+            return Err(UserStoreError::AlreadyClaimed(username.to_string()));
+        }
+        self.meta.insert(claim_key(&user.user_id), &[1u8])?;
         Ok(())
     }
 
-    pub fn save_user(&self, user: &UserIdentity) -> Result<()> {
-        let users_dir = "users";
-        fs::create_dir_all(users_dir)?;
-        
-        let filename = format!("{}/{}.json", users_dir, user.user_id);
-        let data = serde_json::to_string_pretty(user)?;
-        fs::write(filename, data)?;
+    fn set_password(&self, user_id: &str, password: &str) -> Result<(), UserStoreError> {
+        let (salt, hash) = crate::crypto::hash_password(password);
+        let creds = Credentials { salt: salt.to_vec(), hash: hash.to_vec() };
+        self.credentials.insert(user_id.as_bytes(), serde_json::to_vec(&creds)?)?;
         Ok(())
     }
 
-    pub fn save_session(&self, session: &UserSession) -> Result<()> {
-        let sessions_dir = "sessions";
-        fs::create_dir_all(sessions_dir)?;
-        
-        let filename = format!("{}/{}.json", sessions_dir, session.session_id);
-        let data = serde_json::to_string_pretty(session)?;
-        fs::write(filename, data)?;
+    fn save(&self, user: &UserIdentity) -> Result<(), UserStoreError> {
+        self.users.insert(user.user_id.as_bytes(), serde_json::to_vec(user)?)?;
         Ok(())
     }
 
-    pub fn save_device_memory(&self, memory: &DevicePeerMemory) -> Result<()> {
-        let memory_dir = "device_memory";
-        fs::create_dir_all(memory_dir)?;
-        
-        let filename = format!("{}/{}.json", memory_dir, memory.device_id);
-        let data = serde_json::to_string_pretty(memory)?;
-        fs::write(filename, data)?;
-        Ok(())
+    pub fn get_by_id(&self, user_id: &str) -> Result<Option<UserIdentity>, UserStoreError> {
+        match self.users.get(user_id.as_bytes())? {
+            Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            None => Ok(None),
+        }
     }
 
-    pub fn register_user(&mut self, username: String, display_name: String, email: Option<String>) -> Result<UserIdentity> {
-        // Check if username already exists
-        if self.username_to_user_id.contains_key(&username) {
-            return Err(anyhow::anyhow!("Username '{}' already exists", username));
+    pub fn get_by_username(&self, username: &str) -> Result<Option<UserIdentity>, UserStoreError> {
+        match self.usernames.get(username.as_bytes())? {
+            Some(user_id) => self.get_by_id(&String::from_utf8_lossy(&user_id)),
+            None => Ok(None),
         }
+    }
 
-        let mut user = UserIdentity::new(username.clone(), display_name, email);
-        
-        // Generate a unique username if needed (handle conflicts)
-        let mut counter = 1;
-        let mut final_username = username.clone();
-        while self.username_to_user_id.contains_key(&final_username) {
-            final_username = format!("{}{}", username, counter);
-            counter += 1;
+    /// The user's X25519 public key, if they are known and have completed registration.
+    pub fn public_key(&self, username: &str) -> Result<Option<x25519_dalek::PublicKey>, UserStoreError> {
+        Ok(self.get_by_username(username)?
+            .filter(|u| u.public_key != [0u8; 32])
+            .map(|u| x25519_dalek::PublicKey::from(u.public_key)))
+    }
+
+    pub fn list(&self) -> Result<Vec<UserIdentity>, UserStoreError> {
+        let mut users = Vec::new();
+        for item in self.users.iter() {
+            let (_k, v) = item?;
+            users.push(serde_json::from_slice(&v)?);
         }
-        user.username = final_username.clone();
+        Ok(users)
+    }
 
-        // Save to storage
-        self.save_user(&user)?;
-        
-        // Add to registry
-        self.users.insert(user.user_id.clone(), user.clone());
-        self.username_to_user_id.insert(final_username, user.user_id.clone());
-        
-        Ok(user)
+    pub fn search(&self, query: &str) -> Result<Vec<UserIdentity>, UserStoreError> {
+        let query = query.to_lowercase();
+        Ok(self.list()?.into_iter()
+            .filter(|user| {
+                user.username.to_lowercase().contains(&query) ||
+                user.display_name.to_lowercase().contains(&query) ||
+                user.email.as_ref().is_some_and(|email| email.to_lowercase().contains(&query))
+            })
+            .collect())
     }
 
-    pub fn login_user(&mut self, username: &str, device_name: String, device_type: DeviceType) -> Result<(UserIdentity, UserSession)> {
-        let user_id = self.username_to_user_id.get(username)
-            .ok_or_else(|| anyhow::anyhow!("User '{}' not found", username))?;
-        let user_clone = if let Some(user) = self.users.get_mut(user_id) {
-            // Create or update device
-            let device = UserDevice::new(device_name, device_type);
-            user.add_device(device.clone());
-            Some(user.clone())
-        } else {
-            None
-        };
-        let session = if let Some(user) = user_clone.as_ref() {
-            UserSession {
-                session_id: Uuid::new_v4().to_string(),
-                user_id: user.user_id.clone(),
-                device_id: user.devices.last().unwrap().device_id.clone(),
-                created_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
-                expires_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() + (30 * 24 * 60 * 60),
-                is_active: true,
-            }
-        } else {
-            return Err(anyhow::anyhow!("User identity not found"));
-        };
-        let session_clone = session.clone();
-        if let Some(ref user) = user_clone {
-            self.save_user(user)?;
+    /// Renames an account after re-checking its password. The key file moves with it, and
+    /// so does everything keyed by username: the files it owns or was given keys to, its
+    /// per-file grants and the links it created. The old name is only freed after that.
+    pub fn rename(&self, storage: &Storage, old_username: &str, new_username: &str, password: &str) -> Result<UserIdentity, UserStoreError> {
+        validate_username(new_username)?;
+        let mut user = self.verify_password(old_username, password)?;
+        if self.usernames.compare_and_swap(new_username.as_bytes(), None as Option<&[u8]>, Some(user.user_id.as_bytes()))?.is_err() {
+            return Err(UserStoreError::UsernameTaken(new_username.to_string()));
+        }
+        if let Err(e) = fs::rename(keyfile_path(old_username), keyfile_path(new_username)) {
+            self.usernames.remove(new_username.as_bytes())?;
+            return Err(anyhow::anyhow!("Failed to rename keyfile: {}", e).into());
         }
-        self.save_session(&session_clone)?;
-        Ok((user_clone.unwrap(), session_clone))
+        storage.rename_user(old_username, new_username)?;
+        crate::policy::Policy::open(storage)?.rename_user(old_username, new_username).map_err(|e| anyhow::anyhow!(e.to_string()))?;
+        crate::links::LinkStore::open(storage)?.rename_creator(old_username, new_username).map_err(|e| anyhow::anyhow!(e.to_string()))?;
+        self.usernames.remove(old_username.as_bytes())?;
+        user.username = new_username.to_string();
+        self.save(&user)?;
+        Ok(user)
     }
 
-    pub fn get_user_by_id(&self, user_id: &str) -> Option<&UserIdentity> {
-        self.users.get(user_id)
+    /// Marks `device_id` as the user's current device, adding it on first login.
+    pub fn record_login(&self, user_id: &str, device_id: &str) -> Result<(), UserStoreError> {
+        let mut user = self.get_by_id(user_id)?.ok_or_else(|| UserStoreError::NotFound(user_id.to_string()))?;
+        if let Some(pos) = user.devices.iter().position(|d| d.device_id == device_id) {
+            let mut device = user.devices.remove(pos);
+            device.update_login();
+            device.is_current = true;
+            user.add_device(device);
+        } else {
+            let mut device = UserDevice::new(device_id.to_string(), DeviceType::Unknown);
+            device.device_id = device_id.to_string();
+            user.add_device(device);
+        }
+        self.save(&user)
     }
 
-    pub fn get_user_by_username(&self, username: &str) -> Option<&UserIdentity> {
-        let user_id = self.username_to_user_id.get(username)?;
-        self.users.get(user_id)
+    /// Sets or clears (`None`) the trust period of one of the user's devices. Returns
+    /// whether the device exists.
+    pub fn set_device_trust(&self, user_id: &str, device_id: &str, until: Option<u64>) -> Result<bool, UserStoreError> {
+        let mut user = self.get_by_id(user_id)?.ok_or_else(|| UserStoreError::NotFound(user_id.to_string()))?;
+        let Some(device) = user.devices.iter_mut().find(|d| d.device_id == device_id) else {
+            return Ok(false);
+        };
+        device.trusted_until = until;
+        self.save(&user)?;
+        Ok(true)
     }
 
-    pub fn get_user_by_session(&self, session_id: &str) -> Option<&UserIdentity> {
-        let session = self.sessions.get(session_id)?;
-        self.users.get(&session.user_id)
+    /// Clears the trust period of every device the user has.
+    pub fn clear_device_trust(&self, user_id: &str) -> Result<(), UserStoreError> {
+        let mut user = self.get_by_id(user_id)?.ok_or_else(|| UserStoreError::NotFound(user_id.to_string()))?;
+        for device in &mut user.devices {
+            device.trusted_until = None;
+        }
+        self.save(&user)
     }
 
-    pub fn get_user_by_device(&self, device_id: &str) -> Option<&UserIdentity> {
-        let session_id = self.device_sessions.get(device_id)?;
-        self.get_user_by_session(session_id)
+    pub fn remove_device(&self, user_id: &str, device_id: &str) -> Result<bool, UserStoreError> {
+        let mut user = self.get_by_id(user_id)?.ok_or_else(|| UserStoreError::NotFound(user_id.to_string()))?;
+        let before = user.devices.len();
+        user.remove_device(device_id);
+        let removed = user.devices.len() != before;
+        self.save(&user)?;
+        Ok(removed)
     }
 
-    pub fn update_user_status(&mut self, user_id: &str) -> Result<()> {
-        let user_clone = if let Some(user) = self.users.get_mut(user_id) {
+    pub fn update_last_seen(&self, user_id: &str) -> Result<(), UserStoreError> {
+        if let Some(mut user) = self.get_by_id(user_id)? {
             user.update_last_seen();
-            Some(user.clone())
-        } else {
-            None
-        };
-        if let Some(user) = user_clone {
-            self.save_user(&user)?;
+            self.save(&user)?;
         }
         Ok(())
     }
 
-    pub fn logout_device(&mut self, device_id: &str) -> Result<()> {
-        let session_id_opt = self.device_sessions.remove(device_id);
-        let session_clone = if let Some(session_id) = session_id_opt {
-            if let Some(session) = self.sessions.get_mut(&session_id) {
-                session.is_active = false;
-                Some(session.clone())
-            } else {
-                None
+    /// Imports `UserIdentity` JSON files written by the old file-based registry. Imported
+    /// accounts have no password yet; see [`UserStore::register`] and
+    /// [`UserStore::verify_password`] for how they get one.
+    pub fn migrate_json_users(&self, dir: &str) -> Result<usize> {
+        if !Path::new(dir).exists() {
+            return Ok(0);
+        }
+        let mut migrated = 0;
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.path().extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
             }
-        } else {
-            None
-        };
-        if let Some(session) = session_clone {
-            self.save_session(&session)?;
+            let user = match fs::read_to_string(entry.path()).ok().and_then(|d| serde_json::from_str::<UserIdentity>(&d).ok()) {
+                Some(u) => u,
+                None => continue,
+            };
+            if self.users.contains_key(user.user_id.as_bytes())? {
+                continue;
+            }
+            if self.usernames.compare_and_swap(user.username.as_bytes(), None as Option<&[u8]>, Some(user.user_id.as_bytes()))?.is_err() {
+                println!("Skipping migration of {}: username '{}' already taken", entry.path().display(), user.username);
+                continue;
+            }
+            self.users.insert(user.user_id.as_bytes(), serde_json::to_vec(&user)?)?;
+            migrated += 1;
         }
-        Ok(())
+        Ok(migrated)
     }
+}
 
-    pub fn list_users(&self) -> Vec<&UserIdentity> {
-        self.users.values().collect()
-    }
+pub struct DeviceMemoryRegistry {
+    device_peer_memory: HashMap<String, DevicePeerMemory>, // device_id -> DevicePeerMemory
+}
 
-    pub fn search_users(&self, query: &str) -> Vec<&UserIdentity> {
-        self.users.values()
-            .filter(|user| {
-                user.username.to_lowercase().contains(&query.to_lowercase()) ||
-                user.display_name.to_lowercase().contains(&query.to_lowercase()) ||
-                user.email.as_ref().map_or(false, |email| email.to_lowercase().contains(&query.to_lowercase()))
-            })
-            .collect()
+impl DeviceMemoryRegistry {
+    pub fn new() -> Self {
+        Self {
+            device_peer_memory: HashMap::new(),
+        }
     }
 
-    pub fn change_username(&mut self, user_id: &str, new_username: String) -> Result<()> {
-        let user_clone = if let Some(user) = self.users.get_mut(user_id) {
-            user.username = new_username.clone();
-            Some(user.clone())
-        } else {
-            None
-        };
-        if let Some(user) = user_clone {
-            self.save_user(&user)?;
+    pub fn load_from_storage(&mut self) -> Result<()> {
+        // Load device peer memory
+        let memory_dir = "device_memory";
+        if Path::new(memory_dir).exists() {
+            for entry in fs::read_dir(memory_dir)? {
+                let entry = entry?;
+                if let Some(name) = entry.file_name().to_str() {
+                    if name.ends_with(".json") {
+                        let device_id = name.trim_end_matches(".json");
+                        if let Ok(data) = fs::read_to_string(entry.path()) {
+                            if let Ok(memory) = serde_json::from_str::<DevicePeerMemory>(&data) {
+                                self.device_peer_memory.insert(device_id.to_string(), memory);
+                            }
+                        }
+                    }
+                }
+            }
         }
+
         Ok(())
     }
 
-    pub fn remove_device(&mut self, user_id: &str, device_id: &str) -> Result<()> {
-        if let Some(user) = self.users.get_mut(user_id) {
-            user.remove_device(device_id);
-            let user_clone = user.clone();
-            let _ = user;
-            self.save_user(&user_clone)?;
-        }
+    pub fn save_device_memory(&self, memory: &DevicePeerMemory) -> Result<()> {
+        let memory_dir = "device_memory";
+        fs::create_dir_all(memory_dir)?;
+        
+        let filename = format!("{}/{}.json", memory_dir, memory.device_id);
+        let data = serde_json::to_string_pretty(memory)?;
+        fs::write(filename, data)?;
         Ok(())
     }
 
@@ -354,94 +470,56 @@ impl UserRegistry {
     }
 }
 
-// Public API functions
-pub fn init_user_registry() -> Result<()> {
-    let mut registry = USER_REGISTRY.lock().unwrap();
-    registry.load_from_storage()
-}
-
-pub fn register_user(username: String, display_name: String, email: Option<String>) -> Result<UserIdentity> {
-    let mut registry = USER_REGISTRY.lock().unwrap();
-    registry.register_user(username, display_name, email)
-}
-
-pub fn login_user(username: &str, device_name: String, device_type: DeviceType) -> Result<(UserIdentity, UserSession)> {
-    let mut registry = USER_REGISTRY.lock().unwrap();
-    registry.login_user(username, device_name, device_type)
+// Public API functions, backed by the node's default database
+fn default_store() -> Result<UserStore> {
+    UserStore::open(&Storage::new("dafs_db")?)
 }
 
 pub fn get_user_by_id(user_id: &str) -> Option<UserIdentity> {
-    let registry = USER_REGISTRY.lock().unwrap();
-    registry.get_user_by_id(user_id).cloned()
+    default_store().ok()?.get_by_id(user_id).ok().flatten()
 }
 
 pub fn get_user_by_username(username: &str) -> Option<UserIdentity> {
-    let registry = USER_REGISTRY.lock().unwrap();
-    registry.get_user_by_username(username).cloned()
-}
-
-pub fn get_user_by_session(session_id: &str) -> Option<UserIdentity> {
-    let registry = USER_REGISTRY.lock().unwrap();
-    registry.get_user_by_session(session_id).cloned()
+    default_store().ok()?.get_by_username(username).ok().flatten()
 }
 
+/// The user with a live session on `device_id`, if any.
 pub fn get_user_by_device(device_id: &str) -> Option<UserIdentity> {
-    let registry = USER_REGISTRY.lock().unwrap();
-    registry.get_user_by_device(device_id).cloned()
-}
-
-pub fn update_user_status(user_id: &str) -> Result<()> {
-    let mut registry = USER_REGISTRY.lock().unwrap();
-    registry.update_user_status(user_id)
-}
-
-pub fn logout_device(device_id: &str) -> Result<()> {
-    let mut registry = USER_REGISTRY.lock().unwrap();
-    registry.logout_device(device_id)
+    let storage = Storage::new("dafs_db").ok()?;
+    let session = crate::auth::SessionStore::new(&storage).ok()?.find_by_device(device_id).ok()??;
+    UserStore::open(&storage).ok()?.get_by_id(&session.user_id).ok().flatten()
 }
 
 pub fn list_users() -> Vec<UserIdentity> {
-    let registry = USER_REGISTRY.lock().unwrap();
-    registry.list_users().into_iter().cloned().collect()
+    default_store().and_then(|s| s.list().map_err(Into::into)).unwrap_or_default()
 }
 
 pub fn search_users(query: &str) -> Vec<UserIdentity> {
-    let registry = USER_REGISTRY.lock().unwrap();
-    registry.search_users(query).into_iter().cloned().collect()
-}
-
-pub fn change_username(user_id: &str, new_username: String) -> Result<()> {
-    let mut registry = USER_REGISTRY.lock().unwrap();
-    registry.change_username(user_id, new_username)
-}
-
-pub fn remove_device(user_id: &str, device_id: &str) -> Result<()> {
-    let mut registry = USER_REGISTRY.lock().unwrap();
-    registry.remove_device(user_id, device_id)
+    default_store().and_then(|s| s.search(query).map_err(Into::into)).unwrap_or_default()
 }
 
 // Enhanced peer memory functions
 pub fn add_peer_to_device_memory(device_id: &str, peer_id: &str) -> Result<()> {
-    let mut registry = USER_REGISTRY.lock().unwrap();
+    let mut registry = DEVICE_MEMORY.lock().unwrap();
     registry.add_peer_to_device_memory(device_id, peer_id)
 }
 
 pub fn record_peer_disconnection(device_id: &str, peer_id: &str) -> Result<()> {
-    let mut registry = USER_REGISTRY.lock().unwrap();
+    let mut registry = DEVICE_MEMORY.lock().unwrap();
     registry.record_peer_disconnection(device_id, peer_id)
 }
 
 pub fn get_device_known_peers(device_id: &str) -> Vec<String> {
-    let registry = USER_REGISTRY.lock().unwrap();
+    let registry = DEVICE_MEMORY.lock().unwrap();
     registry.get_device_known_peers(device_id)
 }
 
 pub fn get_device_connection_history(device_id: &str) -> Vec<PeerConnection> {
-    let registry = USER_REGISTRY.lock().unwrap();
+    let registry = DEVICE_MEMORY.lock().unwrap();
     registry.get_device_connection_history(device_id)
 }
 
 pub fn update_device_peer_scan(device_id: &str) -> Result<()> {
-    let mut registry = USER_REGISTRY.lock().unwrap();
+    let mut registry = DEVICE_MEMORY.lock().unwrap();
     registry.update_device_peer_scan(device_id)
 } 
\ No newline at end of file
@@ -16,6 +16,7 @@ import { Messaging } from './pages/Messaging';
 import { UserManagement } from './pages/UserManagement';
 import { PeerDiscovery } from './pages/PeerDiscovery';
 import { RemoteManagement } from './pages/RemoteManagement';
+import { SharedLink } from './pages/SharedLink';
 
 // Create a theme instance
 const theme = createTheme({
@@ -93,6 +94,8 @@ const App: React.FC = () => {
                   </PublicRoute>
                 }
               />
+              {/* Capability links: open to anyone holding the link, signed in or not */}
+              <Route path="/s/:linkId" element={<SharedLink />} />
               <Route
                 path="/dashboard"
                 element={
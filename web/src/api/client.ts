@@ -1,6 +1,6 @@
 import axios from 'axios';
 import type { AxiosInstance } from 'axios';
-import { config } from '../config';
+import { config, buildApiUrl } from '../config';
 import type {
   User,
   Peer,
@@ -13,6 +13,10 @@ import type {
   LoginRequest,
   RegisterRequest,
   FileShareRequest,
+  CreateShareLinkRequest,
+  ShareLink,
+  CreatedShareLink,
+  SharedLinkInfo,
   PeerAddRequest,
   ApiResponse,
   PaginatedResponse,
@@ -459,6 +463,49 @@ class ApiClient {
   async resumeDownload(fileId: string): Promise<void> {
     await this.client.post(`/files/${fileId}/download-resume`);
   }
+
+  // Capability links
+  async createShareLink(request: CreateShareLinkRequest): Promise<CreatedShareLink> {
+    const response = await this.client.post<CreatedShareLink>('/links', request);
+    return response.data;
+  }
+
+  async listShareLinks(): Promise<ShareLink[]> {
+    const response = await this.client.get<ShareLink[]>('/links');
+    return response.data;
+  }
+
+  async revokeShareLink(linkId: string): Promise<void> {
+    await this.client.post('/links/revoke', { link_id: linkId });
+  }
 }
 
+// Public link routes. These use plain fetch rather than the client above: the visitor
+// has no session, and a 401 here means a link password, not an expired login.
+export const LINK_PASSWORD_HEADER = 'x-link-password';
+
+const linkError = async (response: Response): Promise<Error> =>
+  new Error((await response.text()) || `Request failed (${response.status})`);
+
+export const getSharedLinkInfo = async (linkId: string): Promise<SharedLinkInfo> => {
+  const response = await fetch(buildApiUrl(`/links/open/${encodeURIComponent(linkId)}`));
+  if (!response.ok) {
+    throw await linkError(response);
+  }
+  return response.json();
+};
+
+/** Downloads a link's ciphertext (`nonce || ciphertext`); counts one download. */
+export const fetchSharedLinkContent = async (linkId: string, password?: string): Promise<ArrayBuffer> => {
+  const headers: Record<string, string> = {};
+  if (password) {
+    headers[LINK_PASSWORD_HEADER] = password;
+  }
+  const response = await fetch(buildApiUrl(`/links/open/${encodeURIComponent(linkId)}/content`), { headers });
+  if (!response.ok) {
+    throw await linkError(response);
+  }
+  return response.arrayBuffer();
+};
+
 export default new ApiClient(); 
\ No newline at end of file
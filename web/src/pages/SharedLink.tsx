@@ -0,0 +1,169 @@
+import React, { useEffect, useState } from 'react';
+import {
+  Box,
+  Button,
+  TextField,
+  Typography,
+  Paper,
+  Alert,
+  Container,
+  CircularProgress,
+} from '@mui/material';
+import { Download as DownloadIcon } from '@mui/icons-material';
+import { useParams } from 'react-router-dom';
+import { getSharedLinkInfo, fetchSharedLinkContent } from '../api/client';
+import type { SharedLinkInfo } from '../types/api';
+
+// Decodes base64url, as used for the link key and sealed metadata
+const decodeBase64Url = (value: string): Uint8Array => {
+  const base64 = value.replace(/-/g, '+').replace(/_/g, '/');
+  const padded = base64 + '='.repeat((4 - (base64.length % 4)) % 4);
+  return Uint8Array.from(atob(padded), (c) => c.charCodeAt(0));
+};
+
+// Files are stored as a 12-byte AES-GCM nonce followed by the ciphertext and tag
+const decryptFile = async (data: ArrayBuffer, rawKey: Uint8Array): Promise<ArrayBuffer> => {
+  const key = await crypto.subtle.importKey('raw', rawKey, 'AES-GCM', false, ['decrypt']);
+  return crypto.subtle.decrypt({ name: 'AES-GCM', iv: data.slice(0, 12) }, key, data.slice(12));
+};
+
+const formatSize = (bytes: number): string => {
+  const units = ['B', 'KB', 'MB', 'GB'];
+  let size = bytes;
+  let unit = 0;
+  while (size >= 1024 && unit < units.length - 1) {
+    size /= 1024;
+    unit += 1;
+  }
+  return `${size.toFixed(unit === 0 ? 0 : 1)} ${units[unit]}`;
+};
+
+export const SharedLink: React.FC = () => {
+  const { linkId = '' } = useParams();
+  // The key never leaves the browser: fragments are not sent to the server
+  const fragment = window.location.hash.slice(1);
+  const [info, setInfo] = useState<SharedLinkInfo | null>(null);
+  const [filename, setFilename] = useState('');
+  const [password, setPassword] = useState('');
+  const [error, setError] = useState<string>('');
+  const [loading, setLoading] = useState(true);
+  const [downloading, setDownloading] = useState(false);
+  const [done, setDone] = useState(false);
+
+  useEffect(() => {
+    getSharedLinkInfo(linkId)
+      .then(async (linkInfo) => {
+        setInfo(linkInfo);
+        setFilename(linkInfo.filename);
+        // A confidential file's name is encrypted under the same key as its contents
+        if (linkInfo.sealed_metadata && fragment) {
+          try {
+            const fields = await decryptFile(decodeBase64Url(linkInfo.sealed_metadata).buffer as ArrayBuffer, decodeBase64Url(fragment));
+            setFilename(JSON.parse(new TextDecoder().decode(fields)).filename);
+          } catch {
+            setFilename('download');
+          }
+        }
+      })
+      .catch((err: Error) => setError(err.message))
+      .finally(() => setLoading(false));
+  }, [linkId, fragment]);
+
+  const handleDownload = async (e: React.FormEvent) => {
+    e.preventDefault();
+    if (!info) return;
+    setError('');
+    setDownloading(true);
+    try {
+      const data = await fetchSharedLinkContent(linkId, info.password_required ? password : undefined);
+      let plaintext: ArrayBuffer;
+      try {
+        plaintext = await decryptFile(data, decodeBase64Url(fragment));
+      } catch {
+        throw new Error('This link\'s key does not decrypt the file. Check that you copied the whole link.');
+      }
+      const url = URL.createObjectURL(new Blob([plaintext]));
+      const a = document.createElement('a');
+      a.href = url;
+      a.download = filename || 'download';
+      a.click();
+      URL.revokeObjectURL(url);
+      setDone(true);
+      if (info.downloads_remaining !== null) {
+        setInfo({ ...info, downloads_remaining: Math.max(info.downloads_remaining - 1, 0) });
+      }
+    } catch (err: any) {
+      setError(err.message || 'Download failed');
+    } finally {
+      setDownloading(false);
+    }
+  };
+
+  return (
+    <Container component="main" maxWidth="sm">
+      <Box sx={{ marginTop: 8 }}>
+        <Paper elevation={3} sx={{ padding: 4 }}>
+          <Typography component="h1" variant="h5" gutterBottom>
+            Shared file
+          </Typography>
+
+          {loading && <CircularProgress />}
+
+          {!loading && !fragment && (
+            <Alert severity="error" sx={{ mb: 2 }}>
+              This link is missing its key (the part after '#'). Ask the sender for the full link.
+            </Alert>
+          )}
+
+          {error && (
+            <Alert severity="error" sx={{ mb: 2 }}>
+              {error}
+            </Alert>
+          )}
+
+          {info && (
+            <Box component="form" onSubmit={handleDownload}>
+              <Typography variant="subtitle1">{filename || 'Confidential file'}</Typography>
+              <Typography variant="body2" color="text.secondary" gutterBottom>
+                {formatSize(info.size)}
+                {info.expires_at !== null &&
+                  ` · expires ${new Date(info.expires_at * 1000).toLocaleString()}`}
+                {info.downloads_remaining !== null &&
+                  ` · ${info.downloads_remaining} download${info.downloads_remaining === 1 ? '' : 's'} left`}
+              </Typography>
+
+              {info.password_required && (
+                <TextField
+                  margin="normal"
+                  required
+                  fullWidth
+                  type="password"
+                  label="Link password"
+                  value={password}
+                  onChange={(e) => setPassword(e.target.value)}
+                />
+              )}
+
+              {done && (
+                <Alert severity="success" sx={{ mt: 2 }}>
+                  Decrypted and saved.
+                </Alert>
+              )}
+
+              <Button
+                type="submit"
+                fullWidth
+                variant="contained"
+                startIcon={<DownloadIcon />}
+                sx={{ mt: 3 }}
+                disabled={downloading || !fragment || info.downloads_remaining === 0}
+              >
+                {downloading ? 'Downloading...' : 'Download and decrypt'}
+              </Button>
+            </Box>
+          )}
+        </Paper>
+      </Box>
+    </Container>
+  );
+};
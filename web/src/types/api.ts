@@ -130,6 +130,40 @@ export interface FileShareRequest {
   permissions: 'read' | 'write' | 'admin';
 }
 
+// Capability links: the file key lives in the URL fragment, never on the server
+export interface CreateShareLinkRequest {
+  file_id: string;
+  expires_in?: number;
+  max_downloads?: number;
+  password?: string;
+}
+
+export interface ShareLink {
+  link_id: string;
+  file_id: string;
+  created_at: number;
+  expires_at: number | null;
+  max_downloads: number | null;
+  downloads: number;
+  password_required: boolean;
+  revoked: boolean;
+}
+
+export interface CreatedShareLink extends ShareLink {
+  key: string;
+  url: string;
+}
+
+export interface SharedLinkInfo {
+  filename: string;
+  /** Base64url sealed metadata of a confidential file; decrypt it with the link key for the name */
+  sealed_metadata: string | null;
+  size: number;
+  expires_at: number | null;
+  downloads_remaining: number | null;
+  password_required: boolean;
+}
+
 export interface PeerAddRequest {
   address: string;
   port: number;